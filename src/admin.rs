@@ -0,0 +1,222 @@
+//! Admin HTTP API.
+//!
+//! Lets tests add, list and delete stubs on a running agent:
+//!
+//! - `GET /admin/stubs` lists stubs with their match counts
+//! - `POST /admin/stubs` adds the `StubDefinition` in the JSON body
+//! - `DELETE /admin/stubs/{id}` removes a stub
+//! - `GET /admin/stubs/{id}/matches` reports how often a stub has matched
+//! - `POST /admin/reset` zeroes match counts and request counters
+//! - `GET /admin/state` dumps stubs, match counts and request totals
+//! - `GET /admin/journal` lists recent requests and how they were answered
+//! - `POST /admin/events/{name}` releases requests long-polling on an event
+
+use crate::agent::{MockServerAgent, StubMatches};
+use crate::config::StubDefinition;
+use crate::journal::JournalEntry;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::info;
+
+/// A configured stub together with how often it has matched.
+#[derive(Debug, Serialize)]
+struct StubStatus {
+    #[serde(flatten)]
+    stub: StubDefinition,
+    match_count: u32,
+}
+
+/// Routes of the admin API, serving `agent`.
+pub fn router(agent: Arc<MockServerAgent>) -> Router {
+    Router::new()
+        .route("/admin/stubs", get(list_stubs).post(add_stub))
+        .route("/admin/stubs/{id}", delete(delete_stub))
+        .route("/admin/stubs/{id}/matches", get(stub_matches))
+        .route("/admin/reset", post(reset))
+        .route("/admin/state", get(export_state))
+        .route("/admin/journal", get(journal))
+        .route("/admin/events/{name}", post(fire_event))
+        .with_state(agent)
+}
+
+/// Serve the admin API on `listener` until the task is dropped.
+pub async fn serve(listener: TcpListener, agent: Arc<MockServerAgent>) -> anyhow::Result<()> {
+    info!(address = ?listener.local_addr()?, "Admin API listening");
+    axum::serve(listener, router(agent)).await?;
+    Ok(())
+}
+
+async fn list_stubs(State(agent): State<Arc<MockServerAgent>>) -> Json<Vec<StubStatus>> {
+    let counts = agent.match_counts().await;
+    let stubs = agent
+        .stubs()
+        .into_iter()
+        .map(|stub| StubStatus {
+            match_count: counts.get(&stub.id).copied().unwrap_or(0),
+            stub,
+        })
+        .collect();
+    Json(stubs)
+}
+
+async fn add_stub(
+    State(agent): State<Arc<MockServerAgent>>,
+    Json(stub): Json<StubDefinition>,
+) -> Response {
+    if let Err(e) = stub.validate() {
+        return error(StatusCode::BAD_REQUEST, e);
+    }
+    match agent.add_stub(stub.clone()).await {
+        Ok(()) => {
+            info!(stub_id = %stub.id, "Stub added via admin API");
+            (StatusCode::CREATED, Json(stub)).into_response()
+        }
+        Err(e) => error(StatusCode::CONFLICT, e),
+    }
+}
+
+async fn delete_stub(
+    State(agent): State<Arc<MockServerAgent>>,
+    Path(id): Path<String>,
+) -> Response {
+    match agent.remove_stub(&id).await {
+        Ok(_) => {
+            info!(stub_id = %id, "Stub removed via admin API");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => error(StatusCode::NOT_FOUND, e),
+    }
+}
+
+async fn stub_matches(
+    State(agent): State<Arc<MockServerAgent>>,
+    Path(id): Path<String>,
+) -> Result<Json<StubMatches>, Response> {
+    agent.stub_matches(&id).await.map(Json).ok_or_else(|| {
+        error(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("Stub '{}' not found", id),
+        )
+    })
+}
+
+async fn reset(State(agent): State<Arc<MockServerAgent>>) -> StatusCode {
+    agent.reset_counts().await;
+    StatusCode::NO_CONTENT
+}
+
+async fn export_state(State(agent): State<Arc<MockServerAgent>>) -> Json<serde_json::Value> {
+    Json(agent.export_state_json().await)
+}
+
+async fn journal(State(agent): State<Arc<MockServerAgent>>) -> Json<Vec<JournalEntry>> {
+    Json(agent.journal())
+}
+
+async fn fire_event(
+    State(agent): State<Arc<MockServerAgent>>,
+    Path(name): Path<String>,
+) -> StatusCode {
+    agent.fire_event(&name);
+    StatusCode::NO_CONTENT
+}
+
+fn error(status: StatusCode, error: anyhow::Error) -> Response {
+    let body = serde_json::json!({ "error": error.to_string() });
+    (status, Json(body)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MockServerConfig;
+    use crate::seed::SeedRequest;
+
+    async fn start(agent: Arc<MockServerAgent>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, agent));
+        base
+    }
+
+    #[tokio::test]
+    async fn test_admin_stubs() {
+        let agent = Arc::new(MockServerAgent::new(MockServerConfig::default()));
+        let base = start(agent.clone()).await;
+        let client = reqwest::Client::new();
+        let stub =
+            r#"{"id": "pushed", "request": {"path": {"type": "exact", "value": "/pushed"}}}"#;
+
+        let post = |body: &'static str| {
+            client
+                .post(format!("{}/admin/stubs", base))
+                .header("content-type", "application/json")
+                .body(body)
+                .send()
+        };
+        assert_eq!(post(stub).await.unwrap().status(), 201);
+        assert_eq!(agent.stub_count(), 1);
+        assert_eq!(post(stub).await.unwrap().status(), 409);
+        assert_eq!(
+            post(r#"{"id": "", "request": {}}"#).await.unwrap().status(),
+            400
+        );
+
+        let list = client
+            .get(format!("{}/admin/stubs", base))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let list: serde_json::Value = serde_json::from_str(&list).unwrap();
+        assert_eq!(list[0]["id"], "pushed");
+        assert_eq!(list[0]["match_count"], 0);
+
+        let matches = |id: &str| {
+            client
+                .get(format!("{}/admin/stubs/{}/matches", base, id))
+                .send()
+        };
+        let seeds: Vec<SeedRequest> = serde_yaml::from_str("- path: /pushed").unwrap();
+        agent.seed(&seeds).await;
+        let response = matches("pushed").await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value =
+            serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        assert_eq!(body, serde_json::json!({ "id": "pushed", "count": 1 }));
+        assert_eq!(matches("missing").await.unwrap().status(), 404);
+
+        let state = client.get(format!("{}/admin/state", base)).send();
+        let state: serde_json::Value =
+            serde_json::from_str(&state.await.unwrap().text().await.unwrap()).unwrap();
+        assert_eq!(state["stubs"][0]["match_count"], 1);
+        assert_eq!(state["requests"]["matched"], 1);
+
+        let seeds: Vec<SeedRequest> = serde_yaml::from_str("- path: /pushed/nope").unwrap();
+        agent.seed(&seeds).await;
+        let journal = client.get(format!("{}/admin/journal", base)).send();
+        let journal: serde_json::Value =
+            serde_json::from_str(&journal.await.unwrap().text().await.unwrap()).unwrap();
+        assert_eq!(journal[0]["stub_id"], "pushed");
+        assert_eq!(journal[1]["path"], "/pushed/nope");
+        assert!(journal[1].get("stub_id").is_none());
+
+        let reset = client.post(format!("{}/admin/reset", base)).send();
+        assert_eq!(reset.await.unwrap().status(), 204);
+        assert_eq!(agent.stub_matches("pushed").await.unwrap().count, 0);
+        assert_eq!(agent.total_requests(), 0);
+
+        let delete = |id: &str| client.delete(format!("{}/admin/stubs/{}", base, id)).send();
+        assert_eq!(delete("pushed").await.unwrap().status(), 204);
+        assert_eq!(agent.stub_count(), 0);
+        assert_eq!(delete("pushed").await.unwrap().status(), 404);
+    }
+}
@@ -1,41 +1,132 @@
 //! Main Mock Server agent implementation.
 
-use crate::config::{FaultConfig, MockServerConfig, ResponseBody, StubDefinition};
-use crate::matcher::Matcher;
+use crate::config::{
+    joinable_header, parse_status, AbKey, BreakerPhase, FaultConfig, FixtureEnvelope,
+    GlobalSettings, HeaderValues, LongPollConfig, MockServerConfig, ResponseBody,
+    ResponseDefinition, ResponseStatus, SequenceMode, StubDefinition, SCENARIO_STARTED,
+};
+use crate::har;
+use crate::journal::{Journal, JournalEntry};
+use crate::latency::LatencyWindow;
+use crate::matcher::{ClosestMatch, Matcher, RequestParts};
+use crate::record;
+use crate::seed::SeedRequest;
+use crate::session::Sessions;
 use crate::template::TemplateEngine;
+use crate::verify::{Times, VerificationError};
 use async_trait::async_trait;
-use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
-use tracing::{debug, info, warn};
+use tracing::{debug, error, info, warn};
 use zentinel_agent_protocol::v2::{
-    AgentCapabilities, AgentFeatures, AgentHandlerV2, CounterMetric, DrainReason, GaugeMetric,
-    HealthStatus, MetricsReport, ShutdownReason,
+    AgentCapabilities, AgentFeatures, AgentHandlerV2, AgentLimits, CounterMetric, DrainReason,
+    GaugeMetric, HealthStatus, LoadMetrics, MetricsReport, ShutdownReason,
 };
 use zentinel_agent_protocol::EventType;
 use zentinel_agent_sdk::prelude::*;
+use zentinel_agent_sdk::{AgentResponse, ProtocolDecision};
 
 /// Mock Server Agent
 ///
 /// Intercepts requests and returns configured stub responses
 /// for testing and development purposes.
 pub struct MockServerAgent {
-    config: MockServerConfig,
-    matcher: Matcher,
+    /// Current configuration and compiled matcher
+    state: std::sync::RwLock<Arc<StubState>>,
     template_engine: TemplateEngine,
     /// Match counts per stub ID
     match_counts: Arc<RwLock<HashMap<String, AtomicU32>>>,
+    /// Current state per scenario (absent = `Started`)
+    scenario_states: std::sync::RwLock<HashMap<String, String>>,
+    /// Status last served per session, for `after_status`
+    last_statuses: Sessions<u16>,
+    /// Stubs matched per session, for `requires_matched`
+    session_matches: Sessions<HashSet<String>>,
+    /// Response last served per stub with a `duplicate` fault
+    last_responses: std::sync::RwLock<HashMap<String, Decision>>,
+    /// Stub matched by requests sent upstream, by correlation ID, so the
+    /// response phase works with the same stub
+    upstream_matches: std::sync::Mutex<HashMap<String, UpstreamMatch>>,
+    /// Long-poll events, woken by `fire_event`
+    events: std::sync::Mutex<HashMap<String, Arc<tokio::sync::Notify>>>,
+    /// Source of randomness for `selection: random`
+    rng: std::sync::Mutex<StdRng>,
+    /// Recent requests, for verification
+    journal: Journal,
+    /// Time spent answering recent requests, for latency percentiles
+    latencies: LatencyWindow,
+    /// HAR entries not yet written to `har_output`
+    har_entries: std::sync::Mutex<Vec<serde_json::Value>>,
+    /// Serializes writes to the HAR file
+    har_lock: tokio::sync::Mutex<()>,
+    /// When the simulated circuit breaker last tripped
+    breaker_tripped: std::sync::Mutex<tokio::time::Instant>,
     /// Total requests processed.
     requests_total: AtomicU64,
     /// Total requests matched to stubs.
     requests_matched: AtomicU64,
     /// Total requests unmatched.
     requests_unmatched: AtomicU64,
+    /// Upstream responses that didn't match the stub's expected body.
+    contract_mismatches: AtomicU64,
+    /// Stubs at their `max_matches` when last counted
+    stubs_exhausted: AtomicU64,
+    /// Upstream responses recorded as stubs.
+    recorded: AtomicU64,
+    /// Number of times `reset()` was called.
+    resets: AtomicU64,
     /// Whether the agent is draining (not accepting new mock responses).
     draining: AtomicBool,
+    /// Set when `require_all_matched` found stubs that never matched.
+    coverage_failed: AtomicBool,
+}
+
+/// How often a stub has matched, as reported by the admin API.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct StubMatches {
+    /// Stub ID
+    pub id: String,
+    /// Matches since startup or the last reset
+    pub count: u32,
+}
+
+/// The stub a request sent upstream matched, for its response phase.
+#[derive(Debug, Clone)]
+struct UpstreamMatch {
+    stub_id: String,
+    context: crate::matcher::MatchContext,
+    match_number: u32,
+}
+
+/// Most near-miss requests listed in a failed verification.
+const NEAR_MISS_LIMIT: usize = 5;
+
+/// Interval between chunks when pacing a body with `throughput_bps`.
+const THROUGHPUT_TICK: Duration = Duration::from_millis(100);
+
+/// Stub configuration together with the matcher compiled from it.
+///
+/// Replaced as a whole whenever stubs change, so in-flight requests
+/// keep working against the snapshot they started with.
+struct StubState {
+    config: MockServerConfig,
+    matcher: Matcher,
 }
 
+/// Paths answered by the agent itself when `builtin_health_paths` is enabled.
+const BUILTIN_HEALTH_PATHS: &[&str] = &["/__health", "/__ready"];
+
+/// Decision metadata listing every value of headers sent more than once
+/// that can't be joined into one, such as `Set-Cookie`.
+pub const REPEATED_HEADERS: &str = "repeated_headers";
+
 /// Flatten SDK headers (Vec<String>) to single-value HashMap
 fn flatten_headers(headers: &HashMap<String, Vec<String>>) -> HashMap<String, String> {
     headers
@@ -47,8 +138,21 @@ fn flatten_headers(headers: &HashMap<String, Vec<String>>) -> HashMap<String, St
 impl MockServerAgent {
     /// Create a new mock server agent with the given configuration.
     pub fn new(config: MockServerConfig) -> Self {
-        let matcher = Matcher::new(&config.stubs);
-        let template_engine = TemplateEngine::new();
+        let matcher = Matcher::with_path_options(&config.stubs, (&config.settings).into());
+        let (template_engine, rng) = match config.settings.random_seed {
+            Some(seed) => (TemplateEngine::with_seed(seed), StdRng::seed_from_u64(seed)),
+            None => (TemplateEngine::new(), StdRng::from_entropy()),
+        };
+        template_engine.set_vars(config.settings.template_vars.clone());
+        if let Err(e) = template_engine.set_templates(
+            &config.settings.template_partials,
+            config
+                .stubs
+                .iter()
+                .flat_map(StubDefinition::template_strings),
+        ) {
+            warn!(error = %e, "Invalid template partial");
+        }
 
         // Initialize match counts
         let mut match_counts = HashMap::new();
@@ -63,15 +167,427 @@ impl MockServerAgent {
         );
 
         Self {
-            config,
-            matcher,
+            state: std::sync::RwLock::new(Arc::new(StubState { config, matcher })),
             template_engine,
             match_counts: Arc::new(RwLock::new(match_counts)),
+            scenario_states: std::sync::RwLock::new(HashMap::new()),
+            last_statuses: Sessions::new(),
+            session_matches: Sessions::new(),
+            last_responses: std::sync::RwLock::new(HashMap::new()),
+            upstream_matches: std::sync::Mutex::new(HashMap::new()),
+            events: std::sync::Mutex::new(HashMap::new()),
+            rng: std::sync::Mutex::new(rng),
+            journal: Journal::new(),
+            latencies: LatencyWindow::new(),
+            har_entries: std::sync::Mutex::new(Vec::new()),
+            har_lock: tokio::sync::Mutex::new(()),
+            breaker_tripped: std::sync::Mutex::new(tokio::time::Instant::now()),
             requests_total: AtomicU64::new(0),
             requests_matched: AtomicU64::new(0),
             requests_unmatched: AtomicU64::new(0),
+            contract_mismatches: AtomicU64::new(0),
+            stubs_exhausted: AtomicU64::new(0),
+            recorded: AtomicU64::new(0),
+            resets: AtomicU64::new(0),
             draining: AtomicBool::new(false),
+            coverage_failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Snapshot of the current stub state.
+    fn state(&self) -> Arc<StubState> {
+        self.state.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Add a stub, taking effect for the next request.
+    ///
+    /// Fails if a stub with the same ID already exists.
+    pub async fn add_stub(&self, stub: StubDefinition) -> anyhow::Result<()> {
+        self.update_stubs(|stubs| {
+            if stubs.iter().any(|s| s.id == stub.id) {
+                anyhow::bail!("Stub '{}' already exists", stub.id);
+            }
+            stubs.push(stub);
+            Ok(())
+        })
+        .await
+    }
+
+    /// Remove a stub by ID, returning its definition.
+    ///
+    /// Requests already matched against the stub complete normally.
+    pub async fn remove_stub(&self, id: &str) -> anyhow::Result<StubDefinition> {
+        let mut removed = None;
+        self.update_stubs(|stubs| {
+            let index = stub_index(stubs, id)?;
+            removed = Some(stubs.remove(index));
+            Ok(())
+        })
+        .await?;
+        Ok(removed.expect("stub removed on success"))
+    }
+
+    /// Replace the stub with the given ID, resetting its match count.
+    pub async fn replace_stub(&self, id: &str, stub: StubDefinition) -> anyhow::Result<()> {
+        let new_id = stub.id.clone();
+        self.update_stubs(|stubs| {
+            let index = stub_index(stubs, id)?;
+            if new_id != id && stubs.iter().any(|s| s.id == new_id) {
+                anyhow::bail!("Stub '{}' already exists", new_id);
+            }
+            stubs[index] = stub;
+            Ok(())
+        })
+        .await?;
+
+        // The replacement starts counting from zero
+        if let Some(count) = self.match_counts.read().await.get(&new_id) {
+            count.store(0, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Disable every stub whose ID matches `pattern`, returning how many
+    /// matched.
+    ///
+    /// The pattern is a glob (`auth-*`), or a regex when wrapped in slashes
+    /// (`/^auth-(login|logout)$/`).
+    pub async fn disable_matching(&self, pattern: &str) -> anyhow::Result<usize> {
+        let pattern = IdPattern::parse(pattern)?;
+        let mut disabled = 0;
+        self.update_stubs(|stubs| {
+            for stub in stubs.iter_mut().filter(|s| pattern.matches(&s.id)) {
+                stub.enabled = false;
+                disabled += 1;
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(disabled)
+    }
+
+    /// Replace the whole configuration, e.g. after the config file changed.
+    ///
+    /// Stubs that keep their ID keep their match counts. On error the
+    /// current configuration stays in place.
+    pub async fn reload(&self, config: MockServerConfig) -> anyhow::Result<()> {
+        self.update_config(|current| {
+            *current = config;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Apply a change to the stub list.
+    async fn update_stubs<F>(&self, update: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&mut Vec<StubDefinition>) -> anyhow::Result<()>,
+    {
+        self.update_config(|config| update(&mut config.stubs)).await
+    }
+
+    /// Apply a change to the configuration, rebuilding the matcher and
+    /// reconciling match counts with the new set of stub IDs.
+    async fn update_config<F>(&self, update: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&mut MockServerConfig) -> anyhow::Result<()>,
+    {
+        // Holding the counts lock serializes updates and keeps requests from
+        // counting against a stub before its entry exists
+        let mut counts = self.match_counts.write().await;
+
+        let mut config = self.state().config.clone();
+        update(&mut config)?;
+        config.validate()?;
+
+        counts.retain(|id, _| config.stubs.iter().any(|s| &s.id == id));
+        for stub in &config.stubs {
+            counts
+                .entry(stub.id.clone())
+                .or_insert_with(|| AtomicU32::new(0));
+        }
+
+        // A newly configured breaker starts out open
+        if config.settings.breaker.is_some() && self.state().config.settings.breaker.is_none() {
+            self.trip_breaker();
+        }
+
+        let matcher = Matcher::with_path_options(&config.stubs, (&config.settings).into());
+        self.template_engine.set_templates(
+            &config.settings.template_partials,
+            config
+                .stubs
+                .iter()
+                .flat_map(StubDefinition::template_strings),
+        )?;
+        // Only once nothing can fail, right before the config they belong to
+        self.template_engine
+            .set_vars(config.settings.template_vars.clone());
+        info!(stubs = config.stubs.len(), "Stubs updated");
+        *self.state.write().unwrap_or_else(|e| e.into_inner()) =
+            Arc::new(StubState { config, matcher });
+        Ok(())
+    }
+
+    /// Current state of a scenario.
+    pub fn scenario_state(&self, scenario: &str) -> String {
+        self.scenario_states
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(scenario)
+            .cloned()
+            .unwrap_or_else(|| SCENARIO_STARTED.to_string())
+    }
+
+    /// Remember the status a mocked response serves to the request's session.
+    fn remember_status(&self, state: &StubState, request: &Request, response: &AgentResponse) {
+        let headers = flatten_headers(request.headers());
+        let Some(session) = session_id(&state.config.settings, &headers) else {
+            return;
+        };
+        if let ProtocolDecision::Block { status, .. } = &response.decision {
+            self.last_statuses.update(session, |last| *last = *status);
+        }
+    }
+
+    /// Return every scenario to the `Started` state.
+    pub fn reset_scenarios(&self) {
+        self.scenario_states
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        info!("Scenarios reset");
+    }
+
+    /// Move a stub's scenario to its `new_state`, returning the scenario and
+    /// its resulting state.
+    fn advance_scenario(&self, stub: &StubDefinition) -> Option<(String, String)> {
+        let scenario = stub.scenario.as_ref()?;
+        let Some(new_state) = &stub.new_state else {
+            return Some((scenario.clone(), self.scenario_state(scenario)));
+        };
+
+        self.scenario_states
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(scenario.clone(), new_state.clone());
+        debug!(scenario = %scenario, state = %new_state, "Scenario state changed");
+        Some((scenario.clone(), new_state.clone()))
+    }
+
+    /// Trip the simulated circuit breaker, restarting its open phase.
+    pub fn trip_breaker(&self) {
+        *self
+            .breaker_tripped
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = tokio::time::Instant::now();
+    }
+
+    /// Current phase of the circuit breaker, if one is configured.
+    pub fn breaker_phase(&self) -> Option<BreakerPhase> {
+        let state = self.state();
+        let breaker = state.config.settings.breaker.as_ref()?;
+        Some(breaker.phase(self.since_breaker_tripped()))
+    }
+
+    fn since_breaker_tripped(&self) -> Duration {
+        self.breaker_tripped
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .elapsed()
+    }
+
+    /// Rejection for a request the circuit breaker doesn't let through.
+    fn check_breaker(&self, state: &StubState) -> Option<Decision> {
+        let breaker = state.config.settings.breaker.as_ref()?;
+        let phase = breaker.phase(self.since_breaker_tripped());
+        let allowed = match phase {
+            BreakerPhase::Closed => true,
+            BreakerPhase::Open => false,
+            BreakerPhase::HalfOpen => {
+                let roll: f64 = self.rng.lock().unwrap_or_else(|e| e.into_inner()).gen();
+                roll < breaker.half_open_fraction
+            }
+        };
+        if allowed {
+            return None;
+        }
+
+        debug!(phase = phase.as_str(), "Circuit breaker rejected request");
+        Some(
+            Decision::block(breaker.status)
+                .with_body(serde_json::json!({ "error": "circuit_open" }).to_string())
+                .with_block_header("Content-Type", "application/json")
+                .with_tag("mocked")
+                .with_tag("breaker")
+                .with_metadata("breaker_phase", serde_json::json!(phase.as_str())),
+        )
+    }
+
+    /// Number of stubs currently configured.
+    pub fn stub_count(&self) -> usize {
+        self.state().config.stubs.len()
+    }
+
+    /// Stubs currently configured, in configuration order.
+    pub fn stubs(&self) -> Vec<StubDefinition> {
+        self.state().config.stubs.clone()
+    }
+
+    /// The stub's ID and match count, as the admin API reports them.
+    pub async fn stub_matches(&self, id: &str) -> Option<StubMatches> {
+        self.match_count(id).await.map(|count| StubMatches {
+            id: id.to_string(),
+            count,
+        })
+    }
+
+    /// How often the stub with the given ID has matched, or `None` if there
+    /// is no such stub.
+    pub async fn match_count(&self, id: &str) -> Option<u32> {
+        let counts = self.match_counts.read().await;
+        counts.get(id).map(|count| count.load(Ordering::Relaxed))
+    }
+
+    /// Check that a stub matched the expected number of times.
+    ///
+    /// On failure the error lists recent journaled requests that fit the
+    /// stub's path but were answered by another stub or not at all.
+    pub async fn verify(&self, stub_id: &str, times: Times) -> Result<(), VerificationError> {
+        let actual = self
+            .match_count(stub_id)
+            .await
+            .ok_or_else(|| VerificationError::UnknownStub(stub_id.to_string()))?;
+        if times.accepts(actual) {
+            return Ok(());
         }
+
+        let state = self.state();
+        let mut near_misses: Vec<JournalEntry> = self
+            .journal()
+            .into_iter()
+            .filter(|entry| {
+                entry.stub_id.as_deref() != Some(stub_id)
+                    && state
+                        .matcher
+                        .matches_stub_path(&state.config.stubs, stub_id, &entry.path)
+            })
+            .collect();
+        if near_misses.len() > NEAR_MISS_LIMIT {
+            near_misses.drain(..near_misses.len() - NEAR_MISS_LIMIT);
+        }
+
+        Err(VerificationError::CountMismatch {
+            stub_id: stub_id.to_string(),
+            expected: times,
+            actual,
+            near_misses,
+        })
+    }
+
+    /// Zero every stub's match count and the request counters.
+    ///
+    /// Requests that match while the reset is in progress are counted
+    /// against the new, zeroed counters.
+    pub async fn reset_counts(&self) {
+        let counts = self.match_counts.write().await;
+        self.zero_counts(&counts);
+        info!("Match counts reset");
+    }
+
+    /// Return the agent to the state it had right after `new()`: match
+    /// counts (and with them sequence positions), scenario states, the
+    /// statuses served to sessions, the journal, the stubs remembered for
+    /// upstream responses, the counters, the draining flag, the breaker and
+    /// the random seed all start over. Stubs are kept. Buffered HAR entries
+    /// are written out first.
+    ///
+    /// Requests already past matching finish against the old counters; new
+    /// requests see the reset state. Long polls already waiting keep
+    /// waiting for their event.
+    pub async fn reset(&self) {
+        self.flush_har().await;
+        // Holding the counts lock keeps requests from matching mid-reset
+        let counts = self.match_counts.write().await;
+        self.zero_counts(&counts);
+        self.contract_mismatches.store(0, Ordering::Relaxed);
+        self.stubs_exhausted.store(0, Ordering::Relaxed);
+        self.recorded.store(0, Ordering::Relaxed);
+        self.upstream_matches
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        self.har_entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        self.scenario_states
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        self.last_statuses.clear();
+        self.last_responses
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        self.journal.clear();
+        self.latencies.clear();
+        self.draining.store(false, Ordering::SeqCst);
+        self.coverage_failed.store(false, Ordering::SeqCst);
+        self.trip_breaker();
+        if let Some(seed) = self.state().config.settings.random_seed {
+            *self.rng.lock().unwrap_or_else(|e| e.into_inner()) = StdRng::seed_from_u64(seed);
+        }
+        self.resets.fetch_add(1, Ordering::Relaxed);
+        drop(counts);
+        info!("Agent reset");
+    }
+
+    fn zero_counts(&self, counts: &HashMap<String, AtomicU32>) {
+        for count in counts.values() {
+            count.store(0, Ordering::Relaxed);
+        }
+        self.session_matches.clear();
+        self.requests_total.store(0, Ordering::Relaxed);
+        self.requests_matched.store(0, Ordering::Relaxed);
+        self.requests_unmatched.store(0, Ordering::Relaxed);
+    }
+
+    /// Number of times each stub has matched, by stub ID.
+    pub async fn match_counts(&self) -> HashMap<String, u32> {
+        self.match_counts
+            .read()
+            .await
+            .iter()
+            .map(|(id, count)| (id.clone(), count.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Every stub with its `match_count`, plus the request counters, as one
+    /// JSON document for dashboards.
+    pub async fn export_state_json(&self) -> serde_json::Value {
+        let counts = self.match_counts().await;
+        let stubs: Vec<serde_json::Value> = self
+            .stubs()
+            .into_iter()
+            .map(|stub| {
+                let count = counts.get(&stub.id).copied().unwrap_or(0);
+                let mut value = serde_json::to_value(stub).unwrap_or_default();
+                if let Some(fields) = value.as_object_mut() {
+                    fields.insert("match_count".to_string(), serde_json::json!(count));
+                }
+                value
+            })
+            .collect();
+        serde_json::json!({
+            "stubs": stubs,
+            "requests": {
+                "total": self.total_requests(),
+                "matched": self.total_matched(),
+                "unmatched": self.total_unmatched(),
+                "contract_mismatches": self.total_contract_mismatches(),
+            },
+        })
     }
 
     /// Check if the agent is draining.
@@ -79,6 +595,41 @@ impl MockServerAgent {
         self.draining.load(Ordering::Relaxed)
     }
 
+    /// Whether a drain or shutdown found enabled stubs that never matched
+    /// while `require_all_matched` is set. The binary exits non-zero then.
+    pub fn coverage_failed(&self) -> bool {
+        self.coverage_failed.load(Ordering::SeqCst)
+    }
+
+    /// IDs of enabled stubs that haven't matched since startup or the last
+    /// reset, sorted.
+    pub async fn unmatched_stubs(&self) -> Vec<String> {
+        let counts = self.match_counts().await;
+        let mut unmatched: Vec<String> = self
+            .state()
+            .config
+            .stubs
+            .iter()
+            .filter(|stub| stub.enabled)
+            .filter(|stub| counts.get(&stub.id).copied().unwrap_or(0) == 0)
+            .map(|stub| stub.id.clone())
+            .collect();
+        unmatched.sort();
+        unmatched
+    }
+
+    /// Apply `require_all_matched` once the agent stops taking traffic.
+    async fn check_coverage(&self) {
+        if !self.state().config.settings.require_all_matched {
+            return;
+        }
+        let unmatched = self.unmatched_stubs().await;
+        if !unmatched.is_empty() {
+            error!(stubs = ?unmatched, "Stubs never matched (require_all_matched)");
+            self.coverage_failed.store(true, Ordering::SeqCst);
+        }
+    }
+
     /// Get total requests processed.
     pub fn total_requests(&self) -> u64 {
         self.requests_total.load(Ordering::Relaxed)
@@ -94,184 +645,659 @@ impl MockServerAgent {
         self.requests_unmatched.load(Ordering::Relaxed)
     }
 
+    /// Get total contract verification mismatches.
+    pub fn total_contract_mismatches(&self) -> u64 {
+        self.contract_mismatches.load(Ordering::Relaxed)
+    }
+
     /// Create from a YAML configuration string.
     pub fn from_yaml(yaml: &str) -> Result<Self, serde_yaml::Error> {
         let config: MockServerConfig = serde_yaml::from_str(yaml)?;
         Ok(Self::new(config))
     }
 
-    /// Check if a stub has exceeded its max matches.
-    async fn is_stub_exhausted(&self, stub: &StubDefinition) -> bool {
-        if stub.max_matches == 0 {
-            return false; // Unlimited
+    /// Replay synthetic requests through the matching pipeline.
+    ///
+    /// Seeded requests advance match counts exactly like live traffic,
+    /// including any configured delays.
+    pub async fn seed(&self, requests: &[SeedRequest]) {
+        for (i, seed) in requests.iter().enumerate() {
+            let request = seed.to_request(&format!("seed-{}", i));
+            self.on_request(&request).await;
         }
+    }
 
-        let counts = self.match_counts.read().await;
-        if let Some(count) = counts.get(&stub.id) {
-            count.load(Ordering::Relaxed) >= stub.max_matches
-        } else {
-            false
-        }
+    /// Answer a complete request (headers and body) the way the proxy would
+    /// be told to, for callers serving HTTP themselves.
+    pub async fn respond(&self, request: &Request) -> AgentResponse {
+        self.on_request(request).await.build()
     }
 
-    /// Increment the match count for a stub.
-    async fn increment_match_count(&self, stub_id: &str) {
+    /// Number of stubs that have reached their `max_matches`. While the
+    /// counts are being updated, the number from the last count.
+    fn exhausted_stubs(&self, state: &StubState) -> u64 {
+        let Ok(counts) = self.match_counts.try_read() else {
+            return self.stubs_exhausted.load(Ordering::Relaxed);
+        };
+        let exhausted = state
+            .config
+            .stubs
+            .iter()
+            .filter(|stub| stub.max_matches > 0)
+            .filter(|stub| {
+                counts
+                    .get(&stub.id)
+                    .is_some_and(|c| c.load(Ordering::Relaxed) >= stub.max_matches)
+            })
+            .count() as u64;
+        self.stubs_exhausted.store(exhausted, Ordering::Relaxed);
+        exhausted
+    }
+
+    /// Roll whether a stub with `match_probability` claims this request.
+    fn roll_match(&self, stub: &StubDefinition) -> bool {
+        let probability = stub.match_probability.unwrap_or(1.0);
+        let roll: f64 = self.rng.lock().unwrap_or_else(|e| e.into_inner()).gen();
+        roll < probability
+    }
+
+    /// Count a match against a stub, returning the new count, or `None` if
+    /// the stub has already reached its `max_matches`.
+    ///
+    /// The check and the increment are one atomic step, so concurrent
+    /// requests can't take a stub past its limit.
+    async fn claim_match(&self, stub: &StubDefinition) -> Option<u32> {
         let counts = self.match_counts.read().await;
-        if let Some(count) = counts.get(stub_id) {
-            count.fetch_add(1, Ordering::Relaxed);
-        }
+        let Some(count) = counts.get(&stub.id) else {
+            return Some(0);
+        };
+        count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                // 0 = unlimited
+                (stub.max_matches == 0 || current < stub.max_matches).then_some(current + 1)
+            })
+            .ok()
+            .map(|previous| previous + 1)
     }
 
     /// Build a response from a stub definition.
+    #[allow(clippy::too_many_arguments)]
     async fn build_response(
         &self,
         stub: &StubDefinition,
+        response: &ResponseDefinition,
         match_ctx: &crate::matcher::MatchContext,
         method: &str,
         path: &str,
         headers: &HashMap<String, String>,
         body: Option<&[u8]>,
     ) -> Decision {
-        // Check for fault injection
-        if let Some(fault) = &stub.fault {
-            return self.apply_fault(fault, stub).await;
+        // Hold long polls until their event fires
+        if let Some(long_poll) = &stub.long_poll {
+            if !self.wait_for_event(long_poll).await {
+                debug!(stub_id = %stub.id, event = %long_poll.event, "Long poll timed out");
+                let decision = match &long_poll.timeout_response {
+                    Some(timeout) => self.build_fixed_response(timeout, "long_poll_timeout"),
+                    None => self.with_global_headers(
+                        Decision::block(204)
+                            .with_tag("mocked")
+                            .with_tag("long_poll_timeout"),
+                        &HashMap::new(),
+                    ),
+                };
+                return decision.with_metadata("stub_id", serde_json::json!(stub.id));
+            }
         }
 
-        // Apply delay if configured
-        if let Some(delay) = &stub.delay {
-            let delay_ms = delay.calculate();
-            if delay_ms > 0 {
-                debug!(stub_id = %stub.id, delay_ms, "Applying delay");
-                tokio::time::sleep(tokio::time::Duration::from_millis(delay_ms)).await;
+        // Check for fault injection, preferring the response's own
+        let fault = response.fault.as_ref().or(stub.fault.as_ref());
+        let duplicate = match fault {
+            // Replays need the fully built response, so they're handled here
+            Some(FaultConfig::Duplicate { probability }) => {
+                if let Some(previous) = self.replay_response(stub, *probability) {
+                    debug!(stub_id = %stub.id, "Replaying previous response");
+                    // A duplicate takes as long to arrive as the original
+                    self.apply_delay(stub, response, body).await;
+                    return previous
+                        .with_tag("fault_injected")
+                        .with_tag("duplicate")
+                        .with_metadata("fault_type", serde_json::json!("duplicate"));
+                }
+                true
             }
-        }
+            // Cutting or pacing the body needs the rendered body, and closing
+            // follows the full response; applied once it's built
+            Some(
+                FaultConfig::PartialBody { .. }
+                | FaultConfig::ConnectionReset
+                | FaultConfig::SlowResponse { .. }
+                | FaultConfig::GoAway
+                | FaultConfig::WrongContentType { .. }
+                | FaultConfig::CorruptEncoding { .. },
+            ) => false,
+            Some(fault) => {
+                let decision = self.apply_fault(fault, stub, response).await;
+                // Injected failures carry the global headers too
+                return self.with_global_headers(decision, &response.headers);
+            }
+            None => false,
+        };
+
+        self.apply_delay(stub, response, body).await;
 
-        // Build the response
-        let response = &stub.response;
+        // A fixture envelope brings its own status and headers
+        let fixture = match &response.body {
+            Some(ResponseBody::ParamFile { path_template }) => {
+                match self
+                    .read_fixture(path_template, match_ctx, method, path, headers, body)
+                    .and_then(|content| FixtureEnvelope::parse(&content))
+                {
+                    Ok(envelope) => Some(envelope),
+                    Err(e) => {
+                        warn!(stub_id = %stub.id, error = %e, "Fixture unavailable, using default response");
+                        return self.build_default_response();
+                    }
+                }
+            }
+            _ => None,
+        };
+        let fixture_body = fixture.as_ref().and_then(FixtureEnvelope::body_bytes);
 
         // Get body content
-        let body_content = if let Some(body_def) = &response.body {
-            if response.template {
-                // Render template
-                self.render_template_body(body_def, match_ctx, method, path, headers, body)
-            } else {
-                // Static body
-                body_def.to_bytes().ok()
+        let body_content = match &response.body {
+            Some(ResponseBody::ParamFile { .. }) => {
+                fixture_body.as_ref().map(|(content, _)| content.clone())
+            }
+            Some(ResponseBody::RequestEcho) => {
+                Some(echo_request(match_ctx, method, path, headers, body))
+            }
+            // Fixture picked by the request; one that can't be read is a miss
+            Some(ResponseBody::File { path: file }) if response.template => {
+                match self.read_fixture(file, match_ctx, method, path, headers, body) {
+                    Ok(content) => Some(content),
+                    Err(e) => {
+                        warn!(stub_id = %stub.id, error = %e, "Fixture unavailable, using default response");
+                        return self.build_default_response();
+                    }
+                }
+            }
+            // Render template (lookup keys are always templates)
+            Some(body_def)
+                if response.template || matches!(body_def, ResponseBody::Lookup { .. }) =>
+            {
+                match self.render_template_body(body_def, match_ctx, method, path, headers, body) {
+                    Ok(rendered) => Some(rendered),
+                    Err(e) if self.state().config.settings.fail_on_template_errors() => {
+                        warn!(stub_id = %stub.id, error = %e, "Template render failed");
+                        return Decision::block(500)
+                            .with_body(format!(
+                                "Template render error in stub '{}': {}",
+                                stub.id, e
+                            ))
+                            .with_block_header("Content-Type", "text/plain")
+                            .with_tag("mocked")
+                            .with_tag("template_error")
+                            .with_metadata("stub_id", serde_json::json!(stub.id));
+                    }
+                    Err(e) => {
+                        warn!(stub_id = %stub.id, error = %e, "Template render failed, omitting body");
+                        None
+                    }
+                }
             }
+            // Static body
+            Some(body_def) => body_def.to_bytes().ok(),
+            None => None,
+        };
+
+        // Render templated header values, dropping those that render empty
+        let mut response_headers: Vec<(String, String)> = if response.template {
+            header_pairs(&response.headers)
+                .filter_map(|(name, value)| {
+                    let value =
+                        self.render_header(stub, value, match_ctx, method, path, headers, body);
+                    (!value.is_empty()).then(|| (name.to_string(), value))
+                })
+                .collect()
         } else {
-            None
+            header_pairs(&response.headers)
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
         };
+        if let Some(fixture) = &fixture {
+            response_headers.retain(|(name, _)| {
+                !fixture
+                    .headers
+                    .keys()
+                    .any(|own| own.eq_ignore_ascii_case(name))
+            });
+            response_headers.extend(
+                header_pairs(&fixture.headers)
+                    .map(|(name, value)| (name.to_string(), value.to_string())),
+            );
+        }
 
         // Determine content type
-        let content_type = response
-            .headers
-            .get("content-type")
-            .or_else(|| response.headers.get("Content-Type"))
-            .cloned()
+        let content_type = response_headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+            .map(|(_, value)| value.clone())
+            .or_else(|| fixture_body.map(|(_, content_type)| content_type.to_string()))
             .unwrap_or_else(|| {
                 response
                     .body
                     .as_ref()
                     .map(|b| b.content_type().to_string())
-                    .unwrap_or_else(|| self.config.settings.default_content_type.clone())
+                    .unwrap_or_else(|| self.state().config.settings.default_content_type.clone())
             });
 
         // Build decision
-        let mut decision = Decision::block(response.status)
+        let status = match fixture.as_ref().and_then(|f| f.status) {
+            Some(status) => status,
+            None => self.render_status(stub, response, match_ctx, method, path, headers, body),
+        };
+
+        // Normalize JSON bodies for byte-for-byte comparison
+        let body_content = match body_content {
+            Some(content)
+                if self.state().config.settings.sort_json_keys
+                    && content_type.to_ascii_lowercase().contains("json") =>
+            {
+                Some(sorted_json(&content).unwrap_or(content))
+            }
+            other => other,
+        };
+
+        // Tag the rendered body, and answer conditional GETs that already have it
+        if let Some(content) = body_content.as_ref().filter(|_| response.auto_etag) {
+            let own_etag = response_headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("etag"))
+                .map(|(_, value)| value.clone());
+            let etag = own_etag.clone().unwrap_or_else(|| body_etag(content));
+            if (200..300).contains(&status)
+                && matches!(method.to_ascii_uppercase().as_str(), "GET" | "HEAD")
+                && if_none_match(headers, &etag)
+            {
+                debug!(stub_id = %stub.id, etag = %etag, "ETag matched, answering 304");
+                let decision = Decision::block(304)
+                    .with_block_header("ETag", &etag)
+                    .with_tag("mocked")
+                    .with_tag("not_modified")
+                    .with_metadata("stub_id", serde_json::json!(stub.id));
+                return self.with_global_headers(decision, &response.headers);
+            }
+            if own_etag.is_none() {
+                response_headers.push(("ETag".to_string(), etag));
+            }
+        }
+
+        // A misleading type stands in for the real one
+        let content_type = match fault {
+            Some(FaultConfig::WrongContentType { declared }) => declared.clone(),
+            _ => content_type,
+        };
+
+        let mut decision = Decision::block(status)
             .with_block_header("Content-Type", &content_type)
             .with_tag("mocked")
             .with_metadata("stub_id", serde_json::json!(stub.id));
 
         // Add response headers
-        for (name, value) in &response.headers {
-            if name.to_lowercase() != "content-type" {
-                decision = decision.with_block_header(name, value);
+        decision = with_block_headers(
+            decision,
+            response_headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        );
+        decision = self.with_global_headers(decision, &response.headers);
+
+        // The SDK has no connection-level control, so signal closure the way
+        // HTTP/1.1 servers do after the final response
+        if let Some(FaultConfig::GoAway) = fault {
+            decision = decision
+                .with_block_header("Connection", "close")
+                .with_tag("fault_injected")
+                .with_tag("goaway")
+                .with_metadata("fault_type", serde_json::json!("go_away"));
+        }
+        if let Some(FaultConfig::WrongContentType { .. }) = fault {
+            decision = decision
+                .with_tag("fault_injected")
+                .with_metadata("fault_type", serde_json::json!("wrong_content_type"));
+        }
+        // Printable garbage never starts with the gzip magic bytes, so
+        // decoders fail on the first bytes
+        let body_content = match fault {
+            Some(FaultConfig::CorruptEncoding { encoding }) => {
+                decision = decision
+                    .with_block_header("Content-Encoding", encoding)
+                    .with_tag("fault_injected")
+                    .with_metadata("fault_type", serde_json::json!("corrupt_encoding"));
+                Some(generate_garbage().into_bytes())
             }
+            _ => body_content,
+        };
+
+        if let Some(fault @ (FaultConfig::PartialBody { .. } | FaultConfig::ConnectionReset)) =
+            fault
+        {
+            return cut_body(fault, decision, &body_content.unwrap_or_default());
         }
 
         // Add body
+        if let Some(FaultConfig::SlowResponse {
+            bytes_per_second,
+            chunk_size,
+        }) = fault
+        {
+            let len = body_content.as_ref().map_or(0, Vec::len);
+            slow_body(len, *bytes_per_second, *chunk_size).await;
+        }
         if let Some(content) = body_content {
+            if let Some(bps) = response.throughput_bps {
+                pace_body(content.len(), bps).await;
+            }
             decision = decision.with_body(String::from_utf8_lossy(&content).to_string());
         }
 
+        if duplicate {
+            self.last_responses
+                .write()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(stub.id.clone(), decision.clone());
+        }
         decision
     }
 
-    /// Render a template body.
-    fn render_template_body(
+    /// Sleep for the response's delay, or the stub's, if one is configured.
+    async fn apply_delay(
         &self,
-        body_def: &ResponseBody,
-        match_ctx: &crate::matcher::MatchContext,
-        method: &str,
-        path: &str,
-        headers: &HashMap<String, String>,
+        stub: &StubDefinition,
+        response: &ResponseDefinition,
         body: Option<&[u8]>,
-    ) -> Option<Vec<u8>> {
-        match body_def {
-            ResponseBody::Text { content } => self
-                .template_engine
-                .render(content, match_ctx, method, path, headers, body)
-                .ok()
-                .map(|s| s.into_bytes()),
-            ResponseBody::Json { content } => self
-                .template_engine
-                .render_json(content, match_ctx, method, path, headers, body)
-                .ok()
-                .and_then(|v| serde_json::to_vec(&v).ok()),
-            _ => body_def.to_bytes().ok(),
+    ) {
+        if let Some(delay) = response.delay.as_ref().or(stub.delay.as_ref()) {
+            let delay_ms = delay.calculate_for_body(body.map(|b| b.len()).unwrap_or(0));
+            if delay_ms > 0 {
+                debug!(stub_id = %stub.id, delay_ms, "Applying delay");
+                tokio::time::sleep(tokio::time::Duration::from_millis(delay_ms)).await;
+            }
         }
     }
 
-    /// Apply fault injection.
-    async fn apply_fault(&self, fault: &FaultConfig, stub: &StubDefinition) -> Decision {
-        match fault {
-            FaultConfig::Error { status, message } => {
-                let body = message.clone().unwrap_or_else(|| "Error".to_string());
-                Decision::block(*status)
-                    .with_body(body)
-                    .with_block_header("Content-Type", "text/plain")
-                    .with_tag("mocked")
-                    .with_tag("fault_injected")
-                    .with_metadata("stub_id", serde_json::json!(stub.id))
-                    .with_metadata("fault_type", serde_json::json!("error"))
+    /// Wait for the long poll's event; `false` if the timeout passed first.
+    async fn wait_for_event(&self, long_poll: &LongPollConfig) -> bool {
+        let notify = self
+            .events
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(long_poll.event.clone())
+            .or_default()
+            .clone();
+        let fired = {
+            // Registered before sleeping, so an event fired meanwhile isn't missed
+            let notified = notify.notified();
+            tokio::select! {
+                _ = notified => true,
+                _ = tokio::time::sleep(Duration::from_millis(long_poll.timeout_ms)) => false,
             }
+        };
 
-            FaultConfig::Timeout { duration_ms } => {
-                debug!(
-                    stub_id = %stub.id,
-                    duration_ms,
-                    "Simulating timeout"
-                );
-                // Sleep for the timeout duration
-                tokio::time::sleep(tokio::time::Duration::from_millis(*duration_ms)).await;
+        // Forget the event once its last waiter is gone
+        let mut events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        if Arc::strong_count(&notify) == 2 {
+            events.remove(&long_poll.event);
+        }
+        fired
+    }
 
-                // Return a gateway timeout
-                Decision::block(504)
-                    .with_body("Gateway Timeout (simulated)")
-                    .with_block_header("Content-Type", "text/plain")
-                    .with_tag("mocked")
-                    .with_tag("fault_injected")
-                    .with_metadata("stub_id", serde_json::json!(stub.id))
-                    .with_metadata("fault_type", serde_json::json!("timeout"))
-            }
+    /// Release every request currently long-polling on `name`. Requests
+    /// arriving afterwards wait for the next firing.
+    pub fn fire_event(&self, name: &str) {
+        info!(event = %name, "Long-poll event fired");
+        let events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(notify) = events.get(name) {
+            notify.notify_waiters();
+        }
+    }
 
-            FaultConfig::Empty => Decision::block(200)
-                .with_body("")
-                .with_tag("mocked")
-                .with_tag("fault_injected")
-                .with_metadata("stub_id", serde_json::json!(stub.id))
-                .with_metadata("fault_type", serde_json::json!("empty")),
+    /// The stub's previous response, if there is one and the `duplicate`
+    /// fault's roll says to replay it.
+    fn replay_response(&self, stub: &StubDefinition, probability: f64) -> Option<Decision> {
+        let roll: f64 = self.rng.lock().unwrap_or_else(|e| e.into_inner()).gen();
+        if roll >= probability {
+            return None;
+        }
+        self.last_responses
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&stub.id)
+            .cloned()
+    }
 
-            FaultConfig::Corrupt { probability } => {
-                use rand::Rng;
-                let should_corrupt = {
-                    let mut rng = rand::thread_rng();
-                    rng.gen::<f64>() < *probability
-                };
+    /// Build a response configured outside any stub, so without request
+    /// context: no templates, delays or faults.
+    fn build_fixed_response(&self, response: &ResponseDefinition, tag: &str) -> Decision {
+        let body_content = response.body.as_ref().and_then(|b| b.to_bytes().ok());
 
-                if should_corrupt {
-                    // Return corrupted response
-                    Decision::block(200)
+        let content_type = header_pairs(&response.headers)
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+            .map(|(_, value)| value.to_string())
+            .unwrap_or_else(|| self.state().config.settings.default_content_type.clone());
+
+        let mut decision = Decision::block(self.fixed_status(&response.status))
+            .with_block_header("Content-Type", &content_type)
+            .with_tag("mocked")
+            .with_tag(tag);
+
+        decision = with_block_headers(decision, header_pairs(&response.headers));
+        decision = self.with_global_headers(decision, &response.headers);
+
+        if let Some(content) = body_content {
+            decision = decision.with_body(String::from_utf8_lossy(&content).to_string());
+        }
+
+        decision
+    }
+
+    /// Add `settings.global_headers` that `own` doesn't set itself.
+    fn with_global_headers(
+        &self,
+        decision: Decision,
+        own: &HashMap<String, HeaderValues>,
+    ) -> Decision {
+        let state = self.state();
+        let globals = header_pairs(&state.config.settings.global_headers)
+            .filter(|(name, _)| !own.keys().any(|own| own.eq_ignore_ascii_case(name)));
+        with_block_headers(decision, globals)
+    }
+
+    /// Resolve the response status, rendering it if it's a template.
+    #[allow(clippy::too_many_arguments)]
+    fn render_status(
+        &self,
+        stub: &StubDefinition,
+        response: &ResponseDefinition,
+        match_ctx: &crate::matcher::MatchContext,
+        method: &str,
+        path: &str,
+        headers: &HashMap<String, String>,
+        body: Option<&[u8]>,
+    ) -> u16 {
+        let ResponseStatus::Template(template) = &response.status else {
+            return self.fixed_status(&response.status);
+        };
+        self.template_engine
+            .render(template, match_ctx, method, path, headers, body)
+            .map_err(anyhow::Error::from)
+            .and_then(|rendered| parse_status(&rendered))
+            .unwrap_or_else(|e| {
+                let fallback = self.state().config.settings.template_status_fallback;
+                warn!(stub_id = %stub.id, error = %e, fallback, "Status template failed, using fallback");
+                fallback
+            })
+    }
+
+    /// Status for responses built without request context.
+    fn fixed_status(&self, status: &ResponseStatus) -> u16 {
+        status
+            .fixed()
+            .unwrap_or_else(|| self.state().config.settings.template_status_fallback)
+    }
+
+    /// Render a templated header value, falling back to the raw value on error.
+    #[allow(clippy::too_many_arguments)]
+    fn render_header(
+        &self,
+        stub: &StubDefinition,
+        value: &str,
+        match_ctx: &crate::matcher::MatchContext,
+        method: &str,
+        path: &str,
+        headers: &HashMap<String, String>,
+        body: Option<&[u8]>,
+    ) -> String {
+        if !value.contains("{{") {
+            return value.to_string();
+        }
+        self.template_engine
+            .render(value, match_ctx, method, path, headers, body)
+            .unwrap_or_else(|e| {
+                warn!(stub_id = %stub.id, error = %e, "Header template render failed, using raw value");
+                value.to_string()
+            })
+    }
+
+    /// Read a fixture whose path is a template, refusing paths that resolve
+    /// outside `settings.fixtures_root`.
+    #[allow(clippy::too_many_arguments)]
+    fn read_fixture(
+        &self,
+        file: &str,
+        match_ctx: &crate::matcher::MatchContext,
+        method: &str,
+        path: &str,
+        headers: &HashMap<String, String>,
+        body: Option<&[u8]>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let rendered = self
+            .template_engine
+            .render(file, match_ctx, method, path, headers, body)?;
+        let root = self
+            .state()
+            .config
+            .settings
+            .fixtures_root
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .canonicalize()?;
+        let fixture = root
+            .join(&rendered)
+            .canonicalize()
+            .map_err(|e| anyhow::anyhow!("Failed to read fixture {}: {}", rendered, e))?;
+        if !fixture.starts_with(&root) {
+            anyhow::bail!("Fixture {} is outside {}", rendered, root.display());
+        }
+        Ok(std::fs::read(fixture)?)
+    }
+
+    /// Render a template body.
+    fn render_template_body(
+        &self,
+        body_def: &ResponseBody,
+        match_ctx: &crate::matcher::MatchContext,
+        method: &str,
+        path: &str,
+        headers: &HashMap<String, String>,
+        body: Option<&[u8]>,
+    ) -> anyhow::Result<Vec<u8>> {
+        match body_def {
+            ResponseBody::Text { content } => Ok(self
+                .template_engine
+                .render(content, match_ctx, method, path, headers, body)?
+                .into_bytes()),
+            ResponseBody::Json { content } => {
+                let rendered = self
+                    .template_engine
+                    .render_json(content, match_ctx, method, path, headers, body)?;
+                Ok(serde_json::to_vec(&rendered)?)
+            }
+            ResponseBody::Lookup {
+                key,
+                table,
+                default,
+            } => {
+                let key = self
+                    .template_engine
+                    .render(key, match_ctx, method, path, headers, body)?;
+                let Some(value) = table.get(&key).or(default.as_ref()) else {
+                    anyhow::bail!("No lookup entry for '{}' and no default", key);
+                };
+                let rendered = self
+                    .template_engine
+                    .render_json(value, match_ctx, method, path, headers, body)?;
+                Ok(serde_json::to_vec(&rendered)?)
+            }
+            _ => body_def.to_bytes(),
+        }
+    }
+
+    /// Apply fault injection.
+    async fn apply_fault(
+        &self,
+        fault: &FaultConfig,
+        stub: &StubDefinition,
+        response: &ResponseDefinition,
+    ) -> Decision {
+        match fault {
+            FaultConfig::Error { status, message } => {
+                let body = message.clone().unwrap_or_else(|| "Error".to_string());
+                Decision::block(*status)
+                    .with_body(body)
+                    .with_block_header("Content-Type", "text/plain")
+                    .with_tag("mocked")
+                    .with_tag("fault_injected")
+                    .with_metadata("stub_id", serde_json::json!(stub.id))
+                    .with_metadata("fault_type", serde_json::json!("error"))
+            }
+
+            FaultConfig::Timeout { duration_ms } => {
+                debug!(
+                    stub_id = %stub.id,
+                    duration_ms,
+                    "Simulating timeout"
+                );
+                // Sleep for the timeout duration
+                tokio::time::sleep(tokio::time::Duration::from_millis(*duration_ms)).await;
+
+                // Return a gateway timeout
+                Decision::block(504)
+                    .with_body("Gateway Timeout (simulated)")
+                    .with_block_header("Content-Type", "text/plain")
+                    .with_tag("mocked")
+                    .with_tag("fault_injected")
+                    .with_metadata("stub_id", serde_json::json!(stub.id))
+                    .with_metadata("fault_type", serde_json::json!("timeout"))
+            }
+
+            FaultConfig::Empty => Decision::block(200)
+                .with_body("")
+                .with_tag("mocked")
+                .with_tag("fault_injected")
+                .with_metadata("stub_id", serde_json::json!(stub.id))
+                .with_metadata("fault_type", serde_json::json!("empty")),
+
+            FaultConfig::Corrupt { probability } => {
+                use rand::Rng;
+                let should_corrupt = {
+                    let mut rng = rand::thread_rng();
+                    rng.gen::<f64>() < *probability
+                };
+
+                if should_corrupt {
+                    // Return corrupted response
+                    Decision::block(200)
                         .with_body(generate_garbage())
                         .with_block_header("Content-Type", "application/octet-stream")
                         .with_tag("mocked")
@@ -280,58 +1306,61 @@ impl MockServerAgent {
                         .with_metadata("fault_type", serde_json::json!("corrupt"))
                 } else {
                     // Return normal response
-                    self.build_normal_response(stub).await
+                    self.build_normal_response(stub, response).await
                 }
             }
 
-            FaultConfig::SlowResponse { bytes_per_second } => {
-                // For now, just simulate with a delay
-                // A real implementation would drip-feed the response
-                let body_size = stub
-                    .response
-                    .body
-                    .as_ref()
-                    .and_then(|b| b.to_bytes().ok())
-                    .map(|b| b.len())
-                    .unwrap_or(100);
+            // Paced by the rendered body's length, so build_response applies it
+            FaultConfig::SlowResponse { .. } => self.build_normal_response(stub, response).await,
 
-                let delay_ms = (body_size as u64 * 1000) / (*bytes_per_second).max(1);
-                tokio::time::sleep(tokio::time::Duration::from_millis(delay_ms)).await;
+            // Added to the full response, so build_response applies it
+            FaultConfig::GoAway => self.build_normal_response(stub, response).await,
+
+            // Declared in place of the built response's type, so
+            // build_response applies it
+            FaultConfig::WrongContentType { .. } => {
+                self.build_normal_response(stub, response).await
+            }
 
-                self.build_normal_response(stub).await
+            // Cuts need the rendered body, so build_response applies them
+            FaultConfig::ConnectionReset | FaultConfig::PartialBody { .. } => {
+                self.build_normal_response(stub, response).await
             }
+
+            // Replays are handled in build_response; here there's nothing
+            // to replay
+            FaultConfig::Duplicate { .. } => self.build_normal_response(stub, response).await,
+
+            // Replaces the built response's body, so build_response applies it
+            FaultConfig::CorruptEncoding { .. } => self.build_normal_response(stub, response).await,
         }
     }
 
     /// Build a normal response (no fault injection).
-    async fn build_normal_response(&self, stub: &StubDefinition) -> Decision {
-        let response = &stub.response;
-
+    async fn build_normal_response(
+        &self,
+        stub: &StubDefinition,
+        response: &ResponseDefinition,
+    ) -> Decision {
         let body_content = response.body.as_ref().and_then(|b| b.to_bytes().ok());
 
-        let content_type = response
-            .headers
-            .get("content-type")
-            .or_else(|| response.headers.get("Content-Type"))
-            .cloned()
+        let content_type = header_pairs(&response.headers)
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+            .map(|(_, value)| value.to_string())
             .unwrap_or_else(|| {
                 response
                     .body
                     .as_ref()
                     .map(|b| b.content_type().to_string())
-                    .unwrap_or_else(|| self.config.settings.default_content_type.clone())
+                    .unwrap_or_else(|| self.state().config.settings.default_content_type.clone())
             });
 
-        let mut decision = Decision::block(response.status)
+        let mut decision = Decision::block(self.fixed_status(&response.status))
             .with_block_header("Content-Type", &content_type)
             .with_tag("mocked")
             .with_metadata("stub_id", serde_json::json!(stub.id));
 
-        for (name, value) in &response.headers {
-            if name.to_lowercase() != "content-type" {
-                decision = decision.with_block_header(name, value);
-            }
-        }
+        decision = with_block_headers(decision, header_pairs(&response.headers));
 
         if let Some(content) = body_content {
             decision = decision.with_body(String::from_utf8_lossy(&content).to_string());
@@ -340,66 +1369,281 @@ impl MockServerAgent {
         decision
     }
 
-    /// Build a default response for unmatched requests.
-    fn build_default_response(&self) -> Decision {
-        if let Some(default) = &self.config.default_response {
-            let body_content = default.body.as_ref().and_then(|b| b.to_bytes().ok());
-
-            let content_type = default
-                .headers
-                .get("content-type")
-                .or_else(|| default.headers.get("Content-Type"))
-                .cloned()
-                .unwrap_or_else(|| self.config.settings.default_content_type.clone());
-
-            let mut decision = Decision::block(default.status)
-                .with_block_header("Content-Type", &content_type)
-                .with_tag("mocked")
-                .with_tag("default_response");
+    /// Append a stub reproducing an upstream response to the recording file,
+    /// if recording is on and no stub covers the request.
+    async fn record_response(&self, request: &Request, response: &Response) {
+        let state = self.state();
+        let Some(record) = state.config.settings.record.as_ref().filter(|r| r.enabled) else {
+            return;
+        };
+        let covered = state
+            .matcher
+            .find_match(&state.config.stubs, &request_parts(request))
+            .is_some();
+        if covered {
+            return;
+        }
+
+        let id = format!(
+            "recorded-{}-{}",
+            Utc::now().timestamp_millis(),
+            self.recorded.fetch_add(1, Ordering::Relaxed)
+        );
+        let stub = record::recorded_stub(id, request, response, record);
+        match record::append(&record.file, &stub).await {
+            Ok(()) => info!(stub_id = %stub.id, file = ?record.file, "Recorded upstream response"),
+            Err(e) => warn!(error = %e, file = ?record.file, "Failed to record response"),
+        }
+    }
+
+    /// Replace an upstream response with the stub's when its `response_match`
+    /// accepts the body.
+    async fn rewrite_response(&self, request: &Request, response: &Response) -> Option<Decision> {
+        let body = response.body()?;
+        let matched = self.upstream_match(request)?;
+        let state = self.state();
+        let stub = state
+            .config
+            .stubs
+            .iter()
+            .find(|s| s.id == matched.stub_id)?;
+        let response_match = stub.response_match.as_ref()?;
+        if !state.matcher.matches_body(Some(body), response_match) {
+            debug!(stub_id = %stub.id, "Upstream response passed through");
+            return None;
+        }
+
+        let headers = flatten_headers(request.headers());
+        let (stub_response, variant) =
+            self.select_response(stub, matched.match_number, &headers, request.body());
+        let mut decision = self
+            .build_response(
+                stub,
+                stub_response,
+                &matched.context,
+                request.method(),
+                request.path_only(),
+                &headers,
+                request.body(),
+            )
+            .await;
+        if let Some(index) = variant {
+            decision = decision.with_metadata("response_variant", serde_json::json!(index));
+        }
+        Some(decision.with_tag("response_rewritten"))
+    }
+
+    /// Note the stub a request matched before sending it upstream.
+    fn remember_upstream_match(
+        &self,
+        request: &Request,
+        result: &crate::matcher::MatchResult<'_>,
+        match_number: u32,
+    ) {
+        self.upstream_matches
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(
+                request.correlation_id().to_string(),
+                UpstreamMatch {
+                    stub_id: result.stub.id.clone(),
+                    context: result.context.clone(),
+                    match_number,
+                },
+            );
+    }
+
+    /// The stub the request matched before it was sent upstream.
+    fn upstream_match(&self, request: &Request) -> Option<UpstreamMatch> {
+        self.upstream_matches
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(request.correlation_id())
+            .cloned()
+    }
+
+    /// Forget the request's upstream match once its response is handled.
+    fn forget_upstream_match(&self, request: &Request) {
+        self.upstream_matches
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(request.correlation_id());
+    }
+
+    /// The response a stub serves on its `match_number`th match, with its
+    /// index among the stub's `responses` when it was picked from them.
+    fn select_response<'a>(
+        &self,
+        stub: &'a StubDefinition,
+        match_number: u32,
+        headers: &HashMap<String, String>,
+        body: Option<&[u8]>,
+    ) -> (&'a ResponseDefinition, Option<usize>) {
+        if let Some(response) = keyed_response(stub, headers, body) {
+            return (response, None);
+        }
+        let roll = if stub.selection() == SequenceMode::Random {
+            self.rng.lock().unwrap_or_else(|e| e.into_inner()).gen()
+        } else {
+            0.0
+        };
+        (
+            stub.response_for(match_number, roll),
+            stub.response_index(match_number, roll),
+        )
+    }
+
+    /// Compare an upstream response body against the body the matched stub
+    /// would have served.
+    fn verify_contract(&self, request: &Request, response: &Response) {
+        let Some(actual) = response.body() else {
+            return;
+        };
+
+        let state = self.state();
+        let Some((stub, matched)) = self.upstream_match(request).and_then(|matched| {
+            let stub = state
+                .config
+                .stubs
+                .iter()
+                .find(|s| s.id == matched.stub_id)?;
+            Some((stub, matched))
+        }) else {
+            return;
+        };
 
-            for (name, value) in &default.headers {
-                if name.to_lowercase() != "content-type" {
-                    decision = decision.with_block_header(name, value);
+        // Any entry a random pick could serve is a valid answer
+        let headers = flatten_headers(request.headers());
+        let candidates: Vec<&ResponseDefinition> =
+            match keyed_response(stub, &headers, request.body()) {
+                Some(response) => vec![response],
+                None if stub.selection() == SequenceMode::Random => {
+                    stub.sequence_responses().iter().collect()
                 }
-            }
+                None => vec![stub.response_for(matched.match_number, 0.0)],
+            };
 
-            if let Some(content) = body_content {
-                decision = decision.with_body(String::from_utf8_lossy(&content).to_string());
-            }
+        // Templated bodies vary per request and can't be compared statically
+        if candidates.iter().any(|candidate| candidate.template) {
+            debug!(stub_id = %stub.id, "Skipping contract check for template response");
+            return;
+        }
 
-            decision
+        let expected: Vec<Vec<u8>> = candidates
+            .iter()
+            .filter_map(|candidate| candidate.body.as_ref()?.to_bytes().ok())
+            .collect();
+        if expected.is_empty() {
+            return;
+        }
+
+        if !expected
+            .iter()
+            .any(|expected| bodies_equivalent(expected, actual))
+        {
+            self.contract_mismatches.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                stub_id = %stub.id,
+                path = %request.path_only(),
+                status = response.status_code(),
+                "Upstream response does not match stub contract"
+            );
+        }
+    }
+
+    /// Build the response for the built-in health endpoints.
+    fn build_health_response(&self) -> Decision {
+        let (status, state) = if self.is_draining() {
+            (503, "draining")
+        } else {
+            (200, "ok")
+        };
+
+        Decision::block(status)
+            .with_body(serde_json::json!({ "status": state }).to_string())
+            .with_block_header("Content-Type", "application/json")
+            .with_tag("health_check")
+    }
+
+    /// Build a default response for unmatched requests.
+    fn build_default_response(&self) -> Decision {
+        self.build_unmatched_response(None)
+    }
+
+    /// The default response, naming the stub that came closest to matching
+    /// when `settings.debug_unmatched` found one.
+    fn build_unmatched_response(&self, closest: Option<&ClosestMatch>) -> Decision {
+        let state = self.state();
+        let decision = if let Some(default) = &state.config.default_response {
+            self.build_fixed_response(default, "default_response")
         } else {
             // No default configured, return 404
-            Decision::block(404)
-                .with_body(r#"{"error": "not_found", "message": "No matching stub found"}"#)
+            let body = match closest {
+                Some(closest) => serde_json::json!({
+                    "error": "not_found",
+                    "message": "No matching stub found",
+                    "closest": closest,
+                })
+                .to_string(),
+                None => {
+                    r#"{"error": "not_found", "message": "No matching stub found"}"#.to_string()
+                }
+            };
+            let decision = Decision::block(404)
+                .with_body(body)
                 .with_block_header("Content-Type", "application/json")
                 .with_tag("mocked")
-                .with_tag("not_found")
+                .with_tag("not_found");
+            self.with_global_headers(decision, &HashMap::new())
+        };
+        match closest {
+            Some(closest) => decision
+                .with_block_header("x-mock-closest-stub", &closest.stub_id)
+                .with_metadata("closest_match", serde_json::json!(closest)),
+            None => decision,
         }
     }
-}
 
-/// Generate random garbage data for corruption simulation.
-fn generate_garbage() -> String {
-    use rand::Rng;
-    let mut rng = rand::thread_rng();
-    let len = rng.gen_range(50..200);
-    (0..len)
-        .map(|_| rng.gen_range(0x20..0x7e) as u8 as char)
-        .collect()
-}
-
-// The agent needs to be Send + Sync for the SDK
-unsafe impl Send for MockServerAgent {}
-unsafe impl Sync for MockServerAgent {}
+    /// Answer a request and record it in the journal.
+    async fn process_request(&self, state: &StubState, request: &Request) -> Decision {
+        let timestamp = Utc::now();
+        let started = Instant::now();
+        // Whichever way the body arrived, matchers see at most max_body_bytes
+        let max = state.config.settings.max_body_bytes;
+        let truncated = match request.body() {
+            Some(body) if body.len() > max => {
+                debug!(
+                    size = body.len(),
+                    max_body_bytes = max,
+                    "Request body truncated for matching"
+                );
+                Some(request.clone().with_body(body[..max].to_vec()))
+            }
+            _ => None,
+        };
+        let request = truncated.as_ref().unwrap_or(request);
+        let decision = self.handle_request(state, request).await;
+        self.latencies.record(started.elapsed());
 
-#[async_trait]
-impl Agent for MockServerAgent {
-    fn name(&self) -> &str {
-        "mock-server"
+        // Built once, for everything that needs to see the response
+        let settings = &state.config.settings;
+        let records = settings.journal_size > 0 || settings.har_output.is_some();
+        if !records && !state.config.tracks_statuses() {
+            return decision;
+        }
+        let response = decision.clone().build();
+        if state.config.tracks_statuses() {
+            self.remember_status(state, request, &response);
+        }
+        if records {
+            self.record_request(state, request, &response, timestamp, started.elapsed())
+                .await;
+        }
+        decision
     }
 
-    async fn on_request(&self, request: &Request) -> Decision {
+    /// Answer a request from the stubs in `state`.
+    async fn handle_request(&self, state: &StubState, request: &Request) -> Decision {
         // Increment request counter
         self.requests_total.fetch_add(1, Ordering::Relaxed);
 
@@ -409,46 +1653,83 @@ impl Agent for MockServerAgent {
             return Decision::allow();
         }
 
-        let method = request.method();
-        let path = request.path();
-        let query_string = request.query_string();
+        if let Some(rejection) = self.check_breaker(state) {
+            return rejection;
+        }
+
+        let parts = request_parts(request);
+        let method = parts.method;
+        let path = parts.path;
         let headers = flatten_headers(request.headers());
-        let body = request.body();
+        let body = parts.body;
 
-        // Find matching stub
-        let match_result = self.matcher.find_match(
-            &self.config.stubs,
-            method,
-            path,
-            query_string,
-            &headers,
-            body,
-        );
+        // Find matching stub, skipping stubs whose dependencies haven't matched
+        // yet, whose scenario is in another state or that wait for a status
+        let scenarios = self
+            .scenario_states
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let session = session_id(&state.config.settings, &headers);
+        let last_status = session.and_then(|session| self.last_statuses.get(session));
+        let matched = session.and_then(|session| self.session_matches.get(session));
+        let counts = self.match_counts.read().await;
+        // A stub with match_probability that loses its roll is skipped, and
+        // the search continues below it
+        let mut rolls = serde_json::Map::new();
+        let match_result = loop {
+            let result = state
+                .matcher
+                .find_match_filtered(&state.config.stubs, &parts, |stub| {
+                    !rolls.contains_key(&stub.id)
+                        && dependencies_met(stub, matched.as_ref())
+                        && scenario_allows(stub, &scenarios)
+                        && stub
+                            .request
+                            .after_status
+                            .is_none_or(|status| last_status == Some(status))
+                });
+            match result {
+                Some(result) if result.stub.match_probability.is_some() => {
+                    let won = self.roll_match(result.stub);
+                    rolls.insert(result.stub.id.clone(), serde_json::json!(won));
+                    if won {
+                        break Some(result);
+                    }
+                    debug!(stub_id = %result.stub.id, "Stub lost its match_probability roll");
+                }
+                other => break other,
+            }
+        };
+        drop(counts);
 
         match match_result {
             Some(result) => {
-                // Check if stub is exhausted
-                if self.is_stub_exhausted(result.stub).await {
+                // Claim a match, unless the stub is exhausted
+                let Some(match_number) = self.claim_match(result.stub).await else {
                     self.requests_unmatched.fetch_add(1, Ordering::Relaxed);
-                    if self.config.settings.log_unmatched {
+                    if state.config.settings.log_unmatched {
                         info!(
                             stub_id = %result.stub.id,
                             path = %path,
                             "Stub exhausted (max_matches reached)"
                         );
                     }
-                    return if self.config.settings.passthrough_unmatched {
+                    return if state.config.settings.passthrough_unmatched {
                         Decision::allow()
                     } else {
                         self.build_default_response()
                     };
-                }
-
-                // Increment counters
+                };
                 self.requests_matched.fetch_add(1, Ordering::Relaxed);
-                self.increment_match_count(&result.stub.id).await;
+                if let Some(session) = session.filter(|_| state.config.tracks_dependencies()) {
+                    self.session_matches.update(session, |matched| {
+                        matched.insert(result.stub.id.clone());
+                    });
+                }
+                let scenario = self.advance_scenario(result.stub);
 
-                if self.config.settings.log_matches {
+                if state.config.settings.log_matches {
                     info!(
                         stub_id = %result.stub.id,
                         method = %method,
@@ -457,13 +1738,63 @@ impl Agent for MockServerAgent {
                     );
                 }
 
+                // In contract mode the real upstream answers; on_response_body compares
+                if state.config.settings.contract_verification {
+                    self.remember_upstream_match(request, &result, match_number);
+                    return with_scenario(
+                        Decision::allow()
+                            .with_tag("contract_verification")
+                            .with_metadata("stub_id", serde_json::json!(result.stub.id)),
+                        scenario,
+                    );
+                }
+
+                // Whether to mock is decided once the upstream response is in
+                if result.stub.response_match.is_some() {
+                    self.remember_upstream_match(request, &result, match_number);
+                    return with_scenario(
+                        Decision::allow()
+                            .with_tag("response_match")
+                            .with_metadata("stub_id", serde_json::json!(result.stub.id)),
+                        scenario,
+                    );
+                }
+
+                // Degrade with every earlier match, on top of any response delay
+                if let Some(ramp) = &result.stub.ramp {
+                    let delay_ms = ramp.delay_ms(match_number.saturating_sub(1));
+                    if delay_ms > 0 {
+                        debug!(stub_id = %result.stub.id, delay_ms, "Applying ramp delay");
+                        tokio::time::sleep(tokio::time::Duration::from_millis(delay_ms)).await;
+                    }
+                }
+
                 // Build and return response
-                self.build_response(result.stub, &result.context, method, path, &headers, body)
-                    .await
+                let (response, variant) =
+                    self.select_response(result.stub, match_number, &headers, body);
+                let mut decision = self
+                    .build_response(
+                        result.stub,
+                        response,
+                        &result.context,
+                        method,
+                        path,
+                        &headers,
+                        body,
+                    )
+                    .await;
+                if let Some(index) = variant {
+                    decision = decision.with_metadata("response_variant", serde_json::json!(index));
+                }
+                if !rolls.is_empty() {
+                    decision =
+                        decision.with_metadata("match_rolls", serde_json::Value::Object(rolls));
+                }
+                with_scenario(decision, scenario)
             }
             None => {
                 self.requests_unmatched.fetch_add(1, Ordering::Relaxed);
-                if self.config.settings.log_unmatched {
+                if state.config.settings.log_unmatched {
                     warn!(
                         method = %method,
                         path = %path,
@@ -471,8 +1802,35 @@ impl Agent for MockServerAgent {
                     );
                 }
 
-                if self.config.settings.passthrough_unmatched {
+                // Only a disabled stub covers the request
+                if let Some(disabled) = &state.config.settings.disabled_stub_response {
+                    if let Some(stub) = state
+                        .matcher
+                        .find_disabled_match(&state.config.stubs, &parts)
+                    {
+                        debug!(stub_id = %stub.id, "Request matches a disabled stub");
+                        return self
+                            .build_fixed_response(disabled, "stub_disabled")
+                            .with_metadata("stub_id", serde_json::json!(stub.id));
+                    }
+                }
+
+                // Let upstream answer; on_response_body records what it says
+                if state
+                    .config
+                    .settings
+                    .record
+                    .as_ref()
+                    .is_some_and(|r| r.enabled)
+                {
+                    return Decision::allow().with_tag("recording");
+                }
+
+                if state.config.settings.passthrough_unmatched {
                     Decision::allow()
+                } else if state.config.settings.debug_unmatched {
+                    let closest = state.matcher.closest_match(&state.config.stubs, &parts);
+                    self.build_unmatched_response(closest.as_ref())
                 } else {
                     self.build_default_response()
                 }
@@ -480,21 +1838,532 @@ impl Agent for MockServerAgent {
         }
     }
 
-    async fn on_response(&self, _request: &Request, _response: &Response) -> Decision {
-        // Response phase - nothing to do for mock server
-        Decision::allow()
+    /// Write buffered HAR entries to `har_output`.
+    ///
+    /// Called periodically by the binary and when the agent drains or shuts
+    /// down; entries that fail to write are kept for the next flush.
+    pub async fn flush_har(&self) {
+        let Some(path) = self.state().config.settings.har_output.clone() else {
+            return;
+        };
+        let _guard = self.har_lock.lock().await;
+        let entries =
+            std::mem::take(&mut *self.har_entries.lock().unwrap_or_else(|e| e.into_inner()));
+        if entries.is_empty() {
+            return;
+        }
+        if let Err(e) = har::append(&path, entries.clone()).await {
+            warn!(error = %e, path = ?path, "Failed to write HAR entries");
+            let mut pending = self.har_entries.lock().unwrap_or_else(|e| e.into_inner());
+            pending.splice(0..0, entries);
+        }
     }
 
-    async fn on_configure(&self, config: serde_json::Value) -> Result<(), String> {
-        // v2 configuration update support
-        if config.is_null() {
-            return Ok(());
+    /// Add a request and the response decided for it to the journal, and to
+    /// the HAR buffer if a HAR file is configured.
+    async fn record_request(
+        &self,
+        state: &StubState,
+        request: &Request,
+        response: &AgentResponse,
+        timestamp: DateTime<Utc>,
+        latency: Duration,
+    ) {
+        let settings = &state.config.settings;
+        let stub_id = response
+            .audit
+            .custom
+            .get("stub_id")
+            .and_then(|id| id.as_str())
+            .map(str::to_string);
+        let (status, body, headers) = match &response.decision {
+            ProtocolDecision::Block {
+                status,
+                body,
+                headers,
+            } => (Some(*status), body.as_deref(), headers.as_ref()),
+            _ => (None, None, None),
+        };
+
+        if let (Some(_), Some(stub_id), Some(status)) = (&settings.har_output, &stub_id, status) {
+            let no_headers = HashMap::new();
+            let har_response = har::HarResponse {
+                status,
+                headers: headers.unwrap_or(&no_headers),
+                body: body.unwrap_or_default(),
+            };
+            let entry = har::entry(stub_id, request, &har_response, timestamp, latency);
+            // Written by flush_har, off the request path
+            self.har_entries
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(entry);
         }
 
-        info!(config = %config, "Received configuration update");
-        // For now, we acknowledge the config - full hot-reload would require
-        // more complex state management
-        Ok(())
+        if settings.journal_size == 0 {
+            return;
+        }
+        let considered = match &stub_id {
+            Some(_) => Vec::new(),
+            None => state
+                .config
+                .stubs
+                .iter()
+                .filter(|stub| stub.enabled)
+                .filter(|stub| {
+                    state.matcher.matches_stub_path(
+                        &state.config.stubs,
+                        &stub.id,
+                        request.path_only(),
+                    )
+                })
+                .map(|stub| stub.id.clone())
+                .collect(),
+        };
+        let entry = JournalEntry {
+            timestamp,
+            method: request.method().to_string(),
+            path: request.path_only().to_string(),
+            query: request.query_string().map(str::to_string),
+            headers: request.headers().clone(),
+            body: None,
+            body_truncated: false,
+            stub_id,
+            considered,
+            status,
+            agent_error: response.audit.tags.iter().any(|t| t == "template_error"),
+            latency_ms: 0.0,
+        }
+        .with_body(request.body(), settings.journal_body_max_bytes)
+        .with_latency(latency);
+        self.journal.record(entry, settings.journal_size);
+    }
+
+    /// Requests recorded in the journal, oldest first.
+    pub fn journal(&self) -> Vec<JournalEntry> {
+        self.journal.entries()
+    }
+
+    /// Forget every request recorded in the journal.
+    pub fn clear_journal(&self) {
+        self.journal.clear();
+    }
+}
+
+/// Session a request belongs to; requests without the configured key have none.
+fn session_id<'a>(
+    settings: &GlobalSettings,
+    headers: &'a HashMap<String, String>,
+) -> Option<&'a str> {
+    match &settings.session_key {
+        Some(key) => ab_key_value(key, headers),
+        None => Some(""),
+    }
+}
+
+/// The response a stub's body `switch` or, failing that, its A/B bucket
+/// picks for a request.
+fn keyed_response<'a>(
+    stub: &'a StubDefinition,
+    headers: &HashMap<String, String>,
+    body: Option<&[u8]>,
+) -> Option<&'a ResponseDefinition> {
+    stub.switch
+        .as_ref()
+        .and_then(|switch| switch.response_for(body))
+        .or_else(|| {
+            stub.ab_key
+                .as_ref()
+                .and_then(|key| ab_key_value(key, headers))
+                .and_then(|key| stub.ab_response(key))
+        })
+}
+
+/// Read the A/B bucketing key from the request headers.
+fn ab_key_value<'a>(key: &AbKey, headers: &'a HashMap<String, String>) -> Option<&'a str> {
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    };
+
+    match key {
+        AbKey::Header { name } => header(name),
+        AbKey::Cookie { name } => header("cookie")?
+            .split(';')
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v),
+    }
+}
+
+/// Strong ETag for a response body.
+fn body_etag(content: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, content);
+    let hex: String = digest.as_ref()[..16]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("\"{}\"", hex)
+}
+
+/// Apply a `partial_body` or `connection_reset` fault to a response whose
+/// full body is `content`: promise all of it, send part, then close.
+fn cut_body(fault: &FaultConfig, decision: Decision, content: &[u8]) -> Decision {
+    let body = String::from_utf8_lossy(content);
+    let decision = decision
+        .with_block_header("Content-Length", &body.len().to_string())
+        .with_block_header("Connection", "close")
+        .with_tag("fault_injected");
+    match fault {
+        FaultConfig::PartialBody { percent } => {
+            let mut cut = body.len() * (*percent).min(100) as usize / 100;
+            while !body.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            decision
+                .with_body(body[..cut].to_string())
+                .with_metadata("fault_type", serde_json::json!("partial_body"))
+        }
+        // The SDK can't abort the connection, so promise a body and close
+        // without sending any of it, which clients see as a reset mid-body
+        _ => decision
+            .with_body("")
+            .with_tag("connection_reset")
+            .with_metadata("fault_type", serde_json::json!("connection_reset")),
+    }
+}
+
+/// Whether the request's `If-None-Match` lists `etag` (or is `*`).
+fn if_none_match(headers: &HashMap<String, String>, etag: &str) -> bool {
+    headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("if-none-match"))
+        .is_some_and(|(_, value)| {
+            value.split(',').map(str::trim).any(|candidate| {
+                // Weak comparison, as for GET and HEAD
+                candidate == "*" || candidate.trim_start_matches("W/") == etag
+            })
+        })
+}
+
+/// Every configured header value as a `(name, value)` pair.
+fn header_pairs(headers: &HashMap<String, HeaderValues>) -> impl Iterator<Item = (&str, &str)> {
+    headers.iter().flat_map(|(name, values)| {
+        values
+            .values()
+            .iter()
+            .map(move |value| (name.as_str(), value.as_str()))
+    })
+}
+
+/// The parts of `request` that stubs match on.
+fn request_parts(request: &Request) -> RequestParts<'_> {
+    RequestParts {
+        method: request.method(),
+        path: request.path_only(),
+        query: request.query_string(),
+        headers: request.headers(),
+        body: request.body(),
+        client_ip: Some(request.client_ip()),
+    }
+}
+
+/// Add response headers to a block decision, except `Content-Type`.
+///
+/// The SDK keys block headers by name, so repeated values of a header are
+/// joined with `, `. Headers that can't be joined keep their first value
+/// there, and list every value under [`REPEATED_HEADERS`] for callers that
+/// send them separately.
+fn with_block_headers<'a>(
+    mut decision: Decision,
+    headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Decision {
+    let mut joined: Vec<(&str, Vec<&str>)> = Vec::new();
+    for (name, value) in headers {
+        if name.eq_ignore_ascii_case("content-type") {
+            continue;
+        }
+        match joined
+            .iter_mut()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        {
+            Some((_, values)) => values.push(value),
+            None => joined.push((name, vec![value])),
+        }
+    }
+    let mut repeated = serde_json::Map::new();
+    for (name, values) in joined {
+        if joinable_header(name) {
+            decision = decision.with_block_header(name, values.join(", "));
+            continue;
+        }
+        decision = decision.with_block_header(name, values[0]);
+        if values.len() > 1 {
+            repeated.insert(name.to_string(), serde_json::json!(values));
+        }
+    }
+    if !repeated.is_empty() {
+        decision = decision.with_metadata(REPEATED_HEADERS, serde_json::Value::Object(repeated));
+    }
+    decision
+}
+
+/// Whether the request headers announce a body.
+fn has_request_body(request: &Request) -> bool {
+    match request.header("content-length") {
+        Some(len) => len.trim().parse::<u64>().map_or(true, |len| len > 0),
+        None => request.header("transfer-encoding").is_some(),
+    }
+}
+
+/// Pace a body of `len` bytes at `bps`, emitting one chunk per tick.
+///
+/// The SDK can only hand back a complete body, so chunk emission is timed
+/// here and the body is released once the last chunk would have been sent.
+async fn pace_body(len: usize, bps: u64) {
+    let ticks_per_second = 1000 / THROUGHPUT_TICK.as_millis() as u64;
+    let chunk_size = (bps / ticks_per_second).max(1) as usize;
+    pace_chunks(len, chunk_size, THROUGHPUT_TICK).await;
+}
+
+/// Pace a `len`-byte body for a `slow_response` fault.
+///
+/// The SDK only returns whole bodies, so take as long as the chunks would,
+/// then hand over the body at once.
+async fn slow_body(len: usize, bytes_per_second: u64, chunk_size: Option<usize>) {
+    let bps = bytes_per_second.max(1);
+    let chunk_size = chunk_size.unwrap_or((bps / 10).max(1) as usize).max(1);
+    let tick =
+        Duration::from_secs_f64(chunk_size as f64 / bps as f64).max(Duration::from_millis(1));
+    pace_chunks(len, chunk_size, tick).await;
+}
+
+/// Wait one `tick` per `chunk_size` bytes of a `len`-byte body.
+async fn pace_chunks(len: usize, chunk_size: usize, tick: Duration) {
+    let mut ticker = tokio::time::interval(tick);
+    // The first tick completes immediately; consume it so each chunk waits a full tick
+    ticker.tick().await;
+
+    let mut sent = 0;
+    while sent < len {
+        ticker.tick().await;
+        sent += chunk_size;
+    }
+}
+
+/// Check whether a stub's scenario is in the state the stub requires.
+fn scenario_allows(stub: &StubDefinition, states: &HashMap<String, String>) -> bool {
+    match (&stub.scenario, &stub.required_state) {
+        (Some(scenario), Some(required)) => {
+            states
+                .get(scenario)
+                .map(String::as_str)
+                .unwrap_or(SCENARIO_STARTED)
+                == required
+        }
+        _ => true,
+    }
+}
+
+/// Tag a decision with the scenario state after matching, for debugging.
+fn with_scenario(decision: Decision, scenario: Option<(String, String)>) -> Decision {
+    match scenario {
+        Some((name, state)) => decision
+            .with_metadata("scenario", serde_json::json!(name))
+            .with_metadata("scenario_state", serde_json::json!(state)),
+        None => decision,
+    }
+}
+
+/// Pattern over stub IDs: a regex when wrapped in slashes, else a glob.
+enum IdPattern {
+    Glob(globset::GlobMatcher),
+    Regex(regex::Regex),
+}
+
+impl IdPattern {
+    fn parse(pattern: &str) -> anyhow::Result<Self> {
+        match pattern
+            .strip_prefix('/')
+            .and_then(|p| p.strip_suffix('/'))
+            .filter(|p| !p.is_empty())
+        {
+            Some(regex) => regex::Regex::new(regex)
+                .map(IdPattern::Regex)
+                .map_err(|e| anyhow::anyhow!("Invalid stub ID regex: {}", e)),
+            None => globset::Glob::new(pattern)
+                .map(|g| IdPattern::Glob(g.compile_matcher()))
+                .map_err(|e| anyhow::anyhow!("Invalid stub ID glob: {}", e)),
+        }
+    }
+
+    fn matches(&self, id: &str) -> bool {
+        match self {
+            IdPattern::Glob(glob) => glob.is_match(id),
+            IdPattern::Regex(regex) => regex.is_match(id),
+        }
+    }
+}
+
+/// Find the position of a stub by ID.
+fn stub_index(stubs: &[StubDefinition], id: &str) -> anyhow::Result<usize> {
+    stubs
+        .iter()
+        .position(|s| s.id == id)
+        .ok_or_else(|| anyhow::anyhow!("Stub '{}' not found", id))
+}
+
+/// Check whether every stub listed in `requires_matched` has matched earlier
+/// in the request's session.
+fn dependencies_met(stub: &StubDefinition, matched: Option<&HashSet<String>>) -> bool {
+    stub.requires_matched
+        .iter()
+        .all(|id| matched.is_some_and(|matched| matched.contains(id)))
+}
+
+/// Re-serialize a JSON document compactly with object keys sorted at every
+/// level; bodies that aren't JSON have no sorted form.
+fn sorted_json(content: &[u8]) -> Option<Vec<u8>> {
+    fn sort(value: serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::Object(map) => {
+                let mut entries: Vec<_> = map.into_iter().collect();
+                entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+                serde_json::Value::Object(
+                    entries
+                        .into_iter()
+                        .map(|(key, value)| (key, sort(value)))
+                        .collect(),
+                )
+            }
+            serde_json::Value::Array(items) => {
+                serde_json::Value::Array(items.into_iter().map(sort).collect())
+            }
+            other => other,
+        }
+    }
+
+    let value = serde_json::from_slice(content).ok()?;
+    serde_json::to_vec(&sort(value)).ok()
+}
+
+/// Render the incoming request as a JSON document (like httpbin's `/anything`).
+fn echo_request(
+    match_ctx: &crate::matcher::MatchContext,
+    method: &str,
+    path: &str,
+    headers: &HashMap<String, String>,
+    body: Option<&[u8]>,
+) -> Vec<u8> {
+    let body_str = body.map(|b| String::from_utf8_lossy(b).to_string());
+    let json = body.and_then(|b| serde_json::from_slice::<serde_json::Value>(b).ok());
+
+    let echo = serde_json::json!({
+        "method": method,
+        "path": path,
+        "query": match_ctx.query_params,
+        "headers": headers,
+        "body": body_str,
+        "json": json,
+    });
+    serde_json::to_vec(&echo).unwrap_or_default()
+}
+
+/// Compare two bodies, structurally when both are JSON (ignoring key order).
+fn bodies_equivalent(expected: &[u8], actual: &[u8]) -> bool {
+    match (
+        serde_json::from_slice::<serde_json::Value>(expected),
+        serde_json::from_slice::<serde_json::Value>(actual),
+    ) {
+        (Ok(expected), Ok(actual)) => expected == actual,
+        _ => expected == actual,
+    }
+}
+
+/// Generate random garbage data for corruption simulation.
+fn generate_garbage() -> String {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(50..200);
+    (0..len)
+        .map(|_| rng.gen_range(0x20..0x7e) as u8 as char)
+        .collect()
+}
+
+// The agent needs to be Send + Sync for the SDK
+unsafe impl Send for MockServerAgent {}
+unsafe impl Sync for MockServerAgent {}
+
+#[async_trait]
+impl Agent for MockServerAgent {
+    fn name(&self) -> &str {
+        "mock-server"
+    }
+
+    async fn on_request(&self, request: &Request) -> Decision {
+        // Stubs may change mid-request; keep working against this snapshot
+        let state = self.state();
+
+        // Built-in health endpoints bypass stubs and counters
+        if state.config.settings.builtin_health_paths
+            && BUILTIN_HEALTH_PATHS.contains(&request.path_only())
+        {
+            return self.build_health_response();
+        }
+
+        // Body matchers need the body, which arrives in a separate event
+        if request.body().is_none()
+            && has_request_body(request)
+            && state.config.matches_request_bodies()
+        {
+            return Decision::allow().needs_more_data();
+        }
+
+        self.process_request(&state, request).await
+    }
+
+    async fn on_request_body(&self, request: &Request) -> Decision {
+        self.process_request(&self.state(), request).await
+    }
+
+    async fn on_response(&self, request: &Request, response: &Response) -> Decision {
+        self.rewrite_response(request, response)
+            .await
+            .unwrap_or_else(Decision::allow)
+    }
+
+    async fn on_response_body(&self, request: &Request, response: &Response) -> Decision {
+        // Only here, so each response is compared once
+        if self.state().config.settings.contract_verification {
+            self.verify_contract(request, response);
+        }
+        self.record_response(request, response).await;
+        let decision = self.rewrite_response(request, response).await;
+        self.forget_upstream_match(request);
+        decision.unwrap_or_else(Decision::allow)
+    }
+
+    async fn on_request_complete(&self, request: &Request, _status: u16, _duration_ms: u64) {
+        // Responses without a body event leave their match behind
+        self.forget_upstream_match(request);
+    }
+
+    async fn on_configure(&self, config: serde_json::Value) -> Result<(), String> {
+        // v2 configuration update support
+        if config.is_null() || config.as_object().is_some_and(|o| o.is_empty()) {
+            return Ok(());
+        }
+
+        info!("Received configuration update");
+        let config: MockServerConfig =
+            serde_json::from_value(config).map_err(|e| format!("Invalid configuration: {}", e))?;
+        // Same path as a file reload: validate, swap, keep counts for surviving stubs
+        self.reload(config).await.map_err(|e| {
+            warn!(error = %e, "Rejected configuration update, keeping previous");
+            e.to_string()
+        })
     }
 }
 
@@ -502,13 +2371,40 @@ impl Agent for MockServerAgent {
 #[async_trait]
 impl AgentHandlerV2 for MockServerAgent {
     fn capabilities(&self) -> AgentCapabilities {
-        AgentCapabilities::new(
+        let mut caps = AgentCapabilities::new(
             "mock-server",
             "Mock Server Agent",
             env!("CARGO_PKG_VERSION"),
         )
-        .with_event(EventType::RequestHeaders)
-        .with_features(AgentFeatures {
+        .with_event(EventType::RequestHeaders);
+
+        // Contract verification, recording and response matching need to see
+        // upstream responses
+        let state = self.state();
+        let settings = &state.config.settings;
+        if settings.contract_verification
+            || settings.record.as_ref().is_some_and(|r| r.enabled)
+            || state
+                .config
+                .stubs
+                .iter()
+                .any(|s| s.response_match.is_some())
+        {
+            caps = caps
+                .with_event(EventType::ResponseHeaders)
+                .with_event(EventType::ResponseBodyChunk);
+        }
+
+        if state.config.matches_request_bodies() {
+            caps = caps
+                .with_event(EventType::RequestBodyChunk)
+                .with_limits(AgentLimits {
+                    max_body_size: state.config.settings.max_body_bytes,
+                    ..Default::default()
+                });
+        }
+
+        caps.with_features(AgentFeatures {
             streaming_body: false,
             websocket: false,
             guardrails: false,
@@ -521,231 +2417,3106 @@ impl AgentHandlerV2 for MockServerAgent {
         })
     }
 
-    fn health_status(&self) -> HealthStatus {
-        // Report healthy unless we're draining
-        if self.is_draining() {
-            HealthStatus::degraded("mock-server", vec!["stubbing".to_string()], 1.0)
-        } else {
-            HealthStatus::healthy("mock-server")
+    fn health_status(&self) -> HealthStatus {
+        // Degraded while draining, healthy otherwise; the detail goes in
+        // the message either way
+        let mut health = if self.is_draining() {
+            HealthStatus::degraded("mock-server", vec!["stubbing".to_string()], 1.0)
+        } else {
+            HealthStatus::healthy("mock-server")
+        };
+
+        let state = self.state();
+        let detail = serde_json::json!({
+            "stubs": state.config.stubs.len(),
+            "stubs_enabled": state.config.stubs.iter().filter(|s| s.enabled).count(),
+            "stubs_exhausted": self.exhausted_stubs(&state),
+            "error_rate": self.journal.error_rate(),
+        });
+        health.message = Some(detail.to_string());
+        health.load = Some(LoadMetrics {
+            requests_processed: self.total_requests(),
+            ..Default::default()
+        });
+        health
+    }
+
+    fn metrics_report(&self) -> Option<MetricsReport> {
+        let state = self.state();
+        let mut report = MetricsReport::new("mock-server", 10_000);
+
+        // Add counter metrics
+        report.counters.push(CounterMetric::new(
+            "mock_server_requests_total",
+            self.total_requests(),
+        ));
+
+        report.counters.push(CounterMetric::new(
+            "mock_server_requests_matched_total",
+            self.total_matched(),
+        ));
+
+        report.counters.push(CounterMetric::new(
+            "mock_server_requests_unmatched_total",
+            self.total_unmatched(),
+        ));
+
+        report.counters.push(CounterMetric::new(
+            "mock_server_contract_mismatch_total",
+            self.total_contract_mismatches(),
+        ));
+
+        report.counters.push(CounterMetric::new(
+            "mock_server_resets_total",
+            self.resets.load(Ordering::Relaxed),
+        ));
+
+        // Add gauge metrics
+        report.gauges.push(GaugeMetric::new(
+            "mock_server_stubs_configured",
+            state.config.stubs.len() as f64,
+        ));
+
+        report.gauges.push(GaugeMetric::new(
+            "mock_server_stubs_enabled",
+            state.config.stubs.iter().filter(|s| s.enabled).count() as f64,
+        ));
+
+        report.gauges.push(GaugeMetric::new(
+            "mock_server_journal_entries",
+            self.journal.len() as f64,
+        ));
+
+        report.counters.push(CounterMetric::new(
+            "mock_server_journal_evicted_total",
+            self.journal.evicted(),
+        ));
+
+        // One series per scenario, labelled with its current state
+        let mut scenarios: Vec<&String> = state
+            .config
+            .stubs
+            .iter()
+            .filter_map(|s| s.scenario.as_ref())
+            .collect();
+        scenarios.sort();
+        scenarios.dedup();
+        for scenario in scenarios {
+            let mut gauge = GaugeMetric::new("mock_server_scenario_state", 1.0);
+            gauge
+                .labels
+                .insert("scenario".to_string(), scenario.clone());
+            gauge
+                .labels
+                .insert("state".to_string(), self.scenario_state(scenario));
+            report.gauges.push(gauge);
+        }
+
+        // Time spent answering recent requests, delays and faults included
+        let quantiles = [("0.5", 0.5), ("0.95", 0.95), ("0.99", 0.99)];
+        let wanted: Vec<f64> = quantiles.iter().map(|(_, q)| *q).collect();
+        if let Some(percentiles) = self.latencies.percentiles_ms(&wanted) {
+            for ((label, _), value) in quantiles.iter().zip(percentiles) {
+                let mut gauge = GaugeMetric::new("mock_server_response_latency_ms", value);
+                gauge
+                    .labels
+                    .insert("quantile".to_string(), label.to_string());
+                report.gauges.push(gauge);
+            }
+        }
+
+        // One series per stub, so dashboards can show which are hot; skipped
+        // for this report if the counts are being updated right now
+        if let Ok(counts) = self.match_counts.try_read() {
+            for stub in &state.config.stubs {
+                let count = counts
+                    .get(&stub.id)
+                    .map_or(0, |c| c.load(Ordering::Relaxed));
+                let mut gauge = GaugeMetric::new("mock_server_stub_matches", count as f64);
+                gauge.labels.insert("stub".to_string(), stub.id.clone());
+                report.gauges.push(gauge);
+            }
+        }
+
+        report.gauges.push(GaugeMetric::new(
+            "mock_server_agent_draining",
+            if self.is_draining() { 1.0 } else { 0.0 },
+        ));
+
+        Some(report)
+    }
+
+    async fn on_configure(&self, config: serde_json::Value, _version: Option<String>) -> bool {
+        Agent::on_configure(self, config).await.is_ok()
+    }
+
+    async fn on_shutdown(&self, reason: ShutdownReason, grace_period_ms: u64) {
+        info!(
+            reason = ?reason,
+            grace_period_ms = grace_period_ms,
+            "Mock server agent shutdown requested"
+        );
+        // Set draining flag to stop mocking new requests
+        self.draining.store(true, Ordering::SeqCst);
+        self.check_coverage().await;
+        self.flush_har().await;
+    }
+
+    async fn on_drain(&self, duration_ms: u64, reason: DrainReason) {
+        warn!(
+            reason = ?reason,
+            duration_ms = duration_ms,
+            "Mock server agent drain requested - stopping stub matching"
+        );
+        self.draining.store(true, Ordering::SeqCst);
+        self.check_coverage().await;
+        self.flush_har().await;
+    }
+
+    async fn on_stream_closed(&self) {
+        debug!("gRPC stream closed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BreakerConfig;
+
+    fn test_config() -> MockServerConfig {
+        let yaml = r#"
+stubs:
+  - id: hello
+    request:
+      method: [GET]
+      path:
+        type: exact
+        value: /hello
+    response:
+      status: 200
+      body:
+        type: text
+        content: "Hello, World!"
+
+  - id: user-by-id
+    request:
+      method: [GET]
+      path:
+        type: template
+        template: /users/{id}
+    response:
+      status: 200
+      template: true
+      body:
+        type: json
+        content:
+          id: "{{path.id}}"
+          name: "User {{path.id}}"
+
+  - id: error-endpoint
+    request:
+      path:
+        type: exact
+        value: /error
+    response:
+      status: 200
+    fault:
+      type: error
+      status: 500
+      message: "Internal Server Error"
+
+  - id: delayed-endpoint
+    request:
+      path:
+        type: exact
+        value: /slow
+    response:
+      status: 200
+      body:
+        type: text
+        content: "Delayed response"
+    delay:
+      fixed_ms: 100
+
+settings:
+  passthrough_unmatched: false
+"#;
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn make_request(
+        method: &str,
+        uri: &str,
+        headers: Vec<(&str, &str)>,
+        body: Option<&[u8]>,
+    ) -> Request {
+        let mut header_map: HashMap<String, Vec<String>> = HashMap::new();
+        for (name, value) in headers {
+            header_map
+                .entry(name.to_lowercase())
+                .or_default()
+                .push(value.to_string());
+        }
+
+        let event = zentinel_agent_sdk::RequestHeadersEvent {
+            metadata: zentinel_agent_sdk::RequestMetadata {
+                correlation_id: "test-correlation".to_string(),
+                request_id: "test-request".to_string(),
+                client_ip: "127.0.0.1".to_string(),
+                client_port: 12345,
+                server_name: None,
+                protocol: "HTTP/1.1".to_string(),
+                tls_version: None,
+                tls_cipher: None,
+                route_id: None,
+                upstream_id: None,
+                timestamp: "2024-01-01T00:00:00Z".to_string(),
+                traceparent: None,
+            },
+            method: method.to_string(),
+            uri: uri.to_string(),
+            headers: header_map,
+        };
+
+        let request = Request::from_headers_event(&event);
+        match body {
+            Some(body) => request.with_body(body.to_vec()),
+            None => request,
+        }
+    }
+
+    fn block_status(decision: Decision) -> u16 {
+        match decision.build().decision {
+            ProtocolDecision::Block { status, .. } => status,
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_agent_creation() {
+        let config = test_config();
+        let agent = MockServerAgent::new(config);
+        let state = agent.state();
+        assert_eq!(state.config.stubs.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_simple_match() {
+        let config = test_config();
+        let agent = MockServerAgent::new(config);
+        let state = agent.state();
+
+        // Create a mock request (we'll test the matcher directly)
+        let headers = HashMap::new();
+        let match_result = state.matcher.find_match(
+            &state.config.stubs,
+            &RequestParts::new("GET", "/hello", &headers),
+        );
+
+        assert!(match_result.is_some());
+        assert_eq!(match_result.unwrap().stub.id, "hello");
+    }
+
+    #[tokio::test]
+    async fn test_template_match() {
+        let config = test_config();
+        let agent = MockServerAgent::new(config);
+        let state = agent.state();
+
+        let headers = HashMap::new();
+        let match_result = state.matcher.find_match(
+            &state.config.stubs,
+            &RequestParts::new("GET", "/users/123", &headers),
+        );
+
+        assert!(match_result.is_some());
+        let result = match_result.unwrap();
+        assert_eq!(result.stub.id, "user-by-id");
+        assert_eq!(
+            result.context.path_params.get("id"),
+            Some(&"123".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_match() {
+        let config = test_config();
+        let agent = MockServerAgent::new(config);
+        let state = agent.state();
+
+        let headers = HashMap::new();
+        let match_result = state.matcher.find_match(
+            &state.config.stubs,
+            &RequestParts::new("GET", "/nonexistent", &headers),
+        );
+
+        assert!(match_result.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_max_matches() {
+        let mut config = test_config();
+        config.stubs[0].max_matches = 2;
+
+        let agent = MockServerAgent::new(config);
+
+        let state = agent.state();
+
+        // First two matches should work
+        for _ in 0..2 {
+            let headers = HashMap::new();
+            let match_result = state.matcher.find_match(
+                &state.config.stubs,
+                &RequestParts::new("GET", "/hello", &headers),
+            );
+            assert!(match_result.is_some());
+            assert!(agent.claim_match(&state.config.stubs[0]).await.is_some());
+        }
+
+        // Third match - stub should be exhausted
+        assert_eq!(agent.claim_match(&state.config.stubs[0]).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_latency_percentiles() {
+        let agent = MockServerAgent::new(test_config());
+        let latency = |agent: &MockServerAgent| {
+            agent
+                .metrics_report()
+                .unwrap()
+                .gauges
+                .into_iter()
+                .find(|g| {
+                    g.name == "mock_server_response_latency_ms" && g.labels["quantile"] == "0.5"
+                })
+        };
+        assert!(latency(&agent).is_none());
+
+        // The configured 100ms delay shows up end to end
+        for _ in 0..3 {
+            let request = make_request("GET", "/slow", vec![], None);
+            agent.on_request(&request).await;
+        }
+        let p50 = latency(&agent).unwrap();
+        assert!(p50.value >= 100.0, "p50 was {}ms", p50.value);
+
+        agent.reset().await;
+        assert!(latency(&agent).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_delay_per_request_kb() {
+        let mut config = test_config();
+        config.stubs[0].delay = Some(crate::config::DelayConfig {
+            fixed_ms: 0,
+            min_ms: 0,
+            max_ms: 0,
+            per_request_kb_ms: Some(1),
+        });
+        let agent = MockServerAgent::new(config);
+        let state = agent.state();
+
+        let body = vec![b'x'; 100 * 1024];
+        let headers = HashMap::new();
+        let ctx = crate::matcher::MatchContext::default();
+
+        let start = std::time::Instant::now();
+        agent
+            .build_response(
+                &state.config.stubs[0],
+                &state.config.stubs[0].response,
+                &ctx,
+                "POST",
+                "/hello",
+                &headers,
+                Some(&body),
+            )
+            .await;
+        assert!(start.elapsed() >= std::time::Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_ramp_delay_grows() {
+        let mut config = test_config();
+        config.stubs[0].ramp = Some(crate::config::RampConfig {
+            base_ms: 0,
+            step_ms: 40,
+            max_ms: None,
+        });
+        let agent = MockServerAgent::new(config);
+        let request = make_request("GET", "/hello", vec![], None);
+
+        for expected_ms in [0, 40, 80] {
+            let start = std::time::Instant::now();
+            agent.on_request(&request).await;
+            assert!(start.elapsed() >= std::time::Duration::from_millis(expected_ms));
+        }
+        assert_eq!(agent.match_count("hello").await, Some(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_go_away_fault() {
+        let agent = MockServerAgent::new(MockServerConfig::default());
+        let stub = yaml_stub(
+            r#"
+id: closing
+request:
+  path: { type: template, template: "/items/{id}" }
+response:
+  template: true
+  status: "{{path.id}}"
+  headers:
+    X-Item: "{{path.id}}"
+  body: { type: text, content: "item {{path.id}}" }
+delay: { fixed_ms: 50 }
+fault: { type: go_away }
+"#,
+        );
+        agent.add_stub(stub).await.unwrap();
+
+        // The full response is served, then the connection closes
+        let start = tokio::time::Instant::now();
+        let request = make_request("GET", "/items/404", vec![], None);
+        let decision = agent.on_request(&request).await.build();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        match decision.decision {
+            ProtocolDecision::Block {
+                status,
+                body,
+                headers,
+            } => {
+                assert_eq!(status, 404);
+                assert_eq!(body.as_deref(), Some("item 404"));
+                let headers = headers.unwrap();
+                assert_eq!(headers["X-Item"], "404");
+                assert_eq!(headers.get("Connection").map(String::as_str), Some("close"));
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+        assert!(decision.audit.tags.contains(&"goaway".to_string()));
+        assert_eq!(decision.audit.custom["fault_type"], "go_away");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wrong_content_type_fault() {
+        let agent = MockServerAgent::new(MockServerConfig::default());
+        let stub = yaml_stub(
+            r#"
+id: json
+request:
+  path: { type: template, template: "/items/{id}" }
+response:
+  template: true
+  body: { type: json, content: { id: "{{path.id}}" } }
+delay: { fixed_ms: 50 }
+fault: { type: wrong_content_type, declared: text/html }
+"#,
+        );
+        agent.add_stub(stub).await.unwrap();
+
+        let start = tokio::time::Instant::now();
+        let request = make_request("GET", "/items/7", vec![], None);
+        let decision = agent.on_request(&request).await.build();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        match decision.decision {
+            ProtocolDecision::Block { body, headers, .. } => {
+                assert_eq!(body.as_deref(), Some(r#"{"id":"7"}"#));
+                let headers = headers.unwrap();
+                assert_eq!(
+                    headers.get("Content-Type").map(String::as_str),
+                    Some("text/html")
+                );
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+        assert_eq!(decision.audit.custom["fault_type"], "wrong_content_type");
+    }
+
+    #[tokio::test]
+    async fn test_partial_body_and_connection_reset_faults() {
+        let config: MockServerConfig = serde_yaml::from_str(
+            r#"
+stubs:
+  - id: partial
+    request:
+      path: { type: template, template: "/partial/{id}" }
+    response:
+      template: true
+      body: { type: text, content: "id={{path.id}}" }
+    fault: { type: partial_body, percent: 40 }
+  - id: reset
+    request:
+      path: { type: template, template: "/reset/{id}" }
+    response:
+      template: true
+      body: { type: text, content: "id={{path.id}}" }
+    fault: { type: connection_reset }
+"#,
+        )
+        .unwrap();
+        let agent = MockServerAgent::new(config);
+
+        // The rendered body ("id=0123456789") is cut, not the template
+        let request = make_request("GET", "/partial/0123456789", vec![], None);
+        let decision = agent.on_request(&request).await.build();
+        match decision.decision {
+            ProtocolDecision::Block { body, headers, .. } => {
+                assert_eq!(body.as_deref(), Some("id=01"));
+                let headers = headers.unwrap();
+                assert_eq!(headers["Content-Length"], "13");
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+        assert_eq!(decision.audit.custom["fault_type"], "partial_body");
+
+        let request = make_request("GET", "/reset/0123456789", vec![], None);
+        let decision = agent.on_request(&request).await.build();
+        match decision.decision {
+            ProtocolDecision::Block { body, headers, .. } => {
+                assert_eq!(body.as_deref(), Some(""));
+                let headers = headers.unwrap();
+                assert_eq!(headers["Content-Length"], "13");
+                assert_eq!(headers["Connection"], "close");
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+        assert!(decision
+            .audit
+            .tags
+            .contains(&"connection_reset".to_string()));
+
+        let mut invalid = agent.stubs()[0].clone();
+        invalid.fault = Some(FaultConfig::PartialBody { percent: 150 });
+        assert!(invalid.validate().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_response_fault() {
+        let agent = MockServerAgent::new(MockServerConfig::default());
+        let stub = yaml_stub(
+            r#"
+id: slow
+request:
+  path: { type: template, template: "/slow/{id}" }
+response:
+  template: true
+  body: { type: text, content: "{{path.id}}" }
+fault: { type: slow_response, bytes_per_second: 100, chunk_size: 3 }
+"#,
+        );
+        assert!(stub.validate().is_ok());
+        agent.add_stub(stub.clone()).await.unwrap();
+
+        // Six 3-byte chunks of the 16-byte rendered body, 30ms each (the
+        // 11-byte template would take four)
+        let start = tokio::time::Instant::now();
+        let request = make_request("GET", "/slow/0123456789abcdef", vec![], None);
+        let decision = agent.on_request(&request).await.build();
+        let elapsed = start.elapsed();
+        assert!(
+            (Duration::from_millis(179)..=Duration::from_millis(181)).contains(&elapsed),
+            "{:?}",
+            elapsed
+        );
+        match decision.decision {
+            ProtocolDecision::Block { body, .. } => {
+                assert_eq!(body.as_deref(), Some("0123456789abcdef"))
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+
+        let mut invalid = stub;
+        invalid.fault = Some(FaultConfig::SlowResponse {
+            bytes_per_second: 100,
+            chunk_size: Some(0),
+        });
+        assert!(invalid.validate().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_corrupt_encoding_fault() {
+        let agent = MockServerAgent::new(MockServerConfig::default());
+        let stub = yaml_stub(
+            r#"
+id: gzipped
+request:
+  path: { type: template, template: "/items/{id}" }
+response:
+  template: true
+  status: "{{path.id}}"
+  headers:
+    X-Item: "{{path.id}}"
+  body: { type: json, content: { ok: true } }
+delay: { fixed_ms: 50 }
+fault: { type: corrupt_encoding }
+"#,
+        );
+        agent.add_stub(stub).await.unwrap();
+
+        let start = tokio::time::Instant::now();
+        let request = make_request("GET", "/items/202", vec![], None);
+        let decision = agent.on_request(&request).await.build();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        match decision.decision {
+            ProtocolDecision::Block {
+                status,
+                body,
+                headers,
+            } => {
+                assert_eq!(status, 202);
+                let headers = headers.unwrap();
+                assert_eq!(headers["X-Item"], "202");
+                assert_eq!(
+                    headers.get("Content-Encoding").map(String::as_str),
+                    Some("gzip")
+                );
+                // Not a gzip stream, which must open with 0x1f 0x8b
+                let body = body.unwrap();
+                assert!(!body.is_empty());
+                assert!(!body.as_bytes().starts_with(&[0x1f, 0x8b]));
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+        assert_eq!(decision.audit.custom["fault_type"], "corrupt_encoding");
+    }
+
+    #[tokio::test]
+    async fn test_requires_matched() {
+        let yaml = r#"
+stubs:
+  - id: login
+    request:
+      path:
+        type: exact
+        value: /login
+    response:
+      status: 200
+
+  - id: dashboard
+    requires_matched: [login]
+    request:
+      path:
+        type: exact
+        value: /dashboard
+    response:
+      status: 200
+"#;
+        let agent = MockServerAgent::from_yaml(yaml).unwrap();
+
+        // Dashboard is inert until login has matched
+        let decision = agent
+            .on_request(&make_request("GET", "/dashboard", vec![], None))
+            .await;
+        assert_eq!(block_status(decision), 404);
+
+        let decision = agent
+            .on_request(&make_request("POST", "/login", vec![], None))
+            .await;
+        assert_eq!(block_status(decision), 200);
+
+        let decision = agent
+            .on_request(&make_request("GET", "/dashboard", vec![], None))
+            .await;
+        assert_eq!(block_status(decision), 200);
+    }
+
+    #[tokio::test]
+    async fn test_requires_matched_per_session() {
+        let yaml = r#"
+settings:
+  session_key: { type: header, name: X-Session }
+stubs:
+  - id: login
+    request:
+      path: { type: exact, value: /login }
+    response:
+      status: 200
+
+  - id: dashboard
+    requires_matched: [login]
+    request:
+      path: { type: exact, value: /dashboard }
+    response:
+      status: 200
+"#;
+        let agent = MockServerAgent::from_yaml(yaml).unwrap();
+        let request = |path: &str, session: &str| {
+            make_request("GET", path, vec![("X-Session", session)], None)
+        };
+
+        let decision = agent.on_request(&request("/login", "alice")).await;
+        assert_eq!(block_status(decision), 200);
+
+        // Alice's login doesn't unlock the dashboard for Bob
+        let decision = agent.on_request(&request("/dashboard", "bob")).await;
+        assert_eq!(block_status(decision), 404);
+        let decision = agent.on_request(&request("/dashboard", "alice")).await;
+        assert_eq!(block_status(decision), 200);
+
+        // Nor for requests without a session
+        let decision = agent
+            .on_request(&make_request("GET", "/dashboard", vec![], None))
+            .await;
+        assert_eq!(block_status(decision), 404);
+
+        agent.reset_counts().await;
+        let decision = agent.on_request(&request("/dashboard", "alice")).await;
+        assert_eq!(block_status(decision), 404);
+    }
+
+    #[tokio::test]
+    async fn test_seed_requests_advance_match_counts() {
+        let agent = MockServerAgent::new(test_config());
+        let seeds: Vec<SeedRequest> = serde_yaml::from_str(
+            r#"
+- path: /hello
+- method: GET
+  path: /hello
+- path: /nonexistent
+"#,
+        )
+        .unwrap();
+
+        agent.seed(&seeds).await;
+
+        let counts = agent.match_counts.read().await;
+        assert_eq!(counts["hello"].load(Ordering::Relaxed), 2);
+        assert_eq!(agent.total_requests(), 3);
+        assert_eq!(agent.total_unmatched(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_builtin_health_paths() {
+        let mut config = test_config();
+        config.settings.builtin_health_paths = true;
+        let agent = MockServerAgent::new(config);
+
+        for path in ["/__health", "/__ready"] {
+            let decision = agent
+                .on_request(&make_request("GET", path, vec![], None))
+                .await;
+            assert_eq!(block_status(decision), 200);
+        }
+        assert_eq!(agent.total_requests(), 0);
+
+        agent.on_drain(5000, DrainReason::Maintenance).await;
+
+        for path in ["/__health", "/__ready"] {
+            let decision = agent
+                .on_request(&make_request("GET", path, vec![], None))
+                .await;
+            assert_eq!(block_status(decision), 503);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_builtin_health_paths_disabled_by_default() {
+        let agent = MockServerAgent::new(test_config());
+
+        let decision = agent
+            .on_request(&make_request("GET", "/__health", vec![], None))
+            .await;
+        assert_eq!(block_status(decision), 404);
+    }
+
+    #[tokio::test]
+    async fn test_path_match_ignores_query_string() {
+        let agent = MockServerAgent::new(test_config());
+
+        let decision = agent
+            .on_request(&make_request("GET", "/hello?lang=en", vec![], None))
+            .await;
+        assert_eq!(block_status(decision), 200);
+    }
+
+    fn make_response(status: u16, body: &[u8]) -> Response {
+        let event = zentinel_agent_sdk::ResponseHeadersEvent {
+            correlation_id: "test-correlation".to_string(),
+            status,
+            headers: HashMap::new(),
+        };
+        Response::from_headers_event(&event).with_body(body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_contract_verification() {
+        let yaml = r#"
+stubs:
+  - id: user
+    request:
+      path:
+        type: exact
+        value: /users/1
+    response:
+      status: 200
+      body:
+        type: json
+        content:
+          id: 1
+          name: Alice
+
+settings:
+  contract_verification: true
+"#;
+        let agent = MockServerAgent::from_yaml(yaml).unwrap();
+        let request = make_request("GET", "/users/1", vec![], None);
+
+        // Matched requests pass through to upstream
+        let decision = agent.on_request(&request).await.build();
+        assert_eq!(decision.decision, ProtocolDecision::Allow);
+        assert_eq!(agent.total_matched(), 1);
+
+        // Same structure, different key order and whitespace
+        let upstream = make_response(200, br#"{ "name": "Alice", "id": 1 }"#);
+        agent.on_response_body(&request, &upstream).await;
+        assert_eq!(agent.total_contract_mismatches(), 0);
+
+        // A response seen in both phases is compared once
+        agent.on_request(&request).await;
+        let upstream = make_response(200, br#"{"id": 1, "name": "Bob"}"#);
+        agent.on_response(&request, &upstream).await;
+        agent.on_response_body(&request, &upstream).await;
+        assert_eq!(agent.total_contract_mismatches(), 1);
+
+        let report = agent.metrics_report().unwrap();
+        let mismatches = report
+            .counters
+            .iter()
+            .find(|c| c.name == "mock_server_contract_mismatch_total")
+            .unwrap();
+        assert_eq!(mismatches.value, 1);
+    }
+
+    #[tokio::test]
+    async fn test_contract_verification_sequence() {
+        let yaml = r#"
+stubs:
+  - id: job
+    request:
+      path: { type: exact, value: /job }
+    responses:
+      - body: { type: json, content: { state: pending } }
+      - body: { type: json, content: { state: done } }
+  - id: coin
+    request:
+      path: { type: exact, value: /coin }
+    selection: random
+    responses:
+      - body: { type: text, content: heads }
+      - body: { type: text, content: tails }
+
+settings:
+  contract_verification: true
+"#;
+        let agent = MockServerAgent::from_yaml(yaml).unwrap();
+        let job = make_request("GET", "/job", vec![], None);
+
+        // Each match is compared against the entry it would have served
+        for state in ["pending", "done", "pending"] {
+            agent.on_request(&job).await;
+            let body = format!(r#"{{"state":"{}"}}"#, state);
+            agent
+                .on_response_body(&job, &make_response(200, body.as_bytes()))
+                .await;
+        }
+        assert_eq!(agent.total_contract_mismatches(), 0);
+
+        agent.on_request(&job).await;
+        let upstream = make_response(200, br#"{"state":"pending"}"#);
+        agent.on_response_body(&job, &upstream).await;
+        assert_eq!(agent.total_contract_mismatches(), 1);
+
+        // A random pick could have served either entry
+        let coin = make_request("GET", "/coin", vec![], None);
+        for side in [&b"heads"[..], &b"tails"[..]] {
+            agent.on_request(&coin).await;
+            agent
+                .on_response_body(&coin, &make_response(200, side))
+                .await;
+        }
+        assert_eq!(agent.total_contract_mismatches(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_request_echo_body() {
+        let yaml = r#"
+stubs:
+  - id: anything
+    request:
+      path:
+        type: prefix
+        value: /anything
+    response:
+      status: 200
+      body:
+        type: request_echo
+"#;
+        let agent = MockServerAgent::from_yaml(yaml).unwrap();
+        let request = make_request(
+            "POST",
+            "/anything/here?page=2",
+            vec![("X-Trace", "abc")],
+            Some(br#"{"name":"John"}"#),
+        );
+
+        let decision = agent.on_request(&request).await.build();
+        let (status, body, headers) = match decision.decision {
+            ProtocolDecision::Block {
+                status,
+                body,
+                headers,
+            } => (status, body.unwrap(), headers.unwrap()),
+            other => panic!("Expected block decision, got {:?}", other),
+        };
+        assert_eq!(status, 200);
+        assert_eq!(headers["Content-Type"], "application/json");
+
+        let echo: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(echo["method"], "POST");
+        assert_eq!(echo["path"], "/anything/here");
+        assert_eq!(echo["query"]["page"], "2");
+        assert_eq!(echo["headers"]["x-trace"], "abc");
+        assert_eq!(echo["body"], r#"{"name":"John"}"#);
+        assert_eq!(echo["json"]["name"], "John");
+    }
+
+    #[tokio::test]
+    async fn test_throughput_bps() {
+        let mut config = test_config();
+        config.stubs[0].response.body = Some(ResponseBody::Text {
+            content: "x".repeat(1000),
+        });
+        // 500-byte chunks every 100ms: two chunks for the whole body
+        config.stubs[0].response.throughput_bps = Some(5000);
+        let agent = MockServerAgent::new(config);
+
+        let request = make_request("GET", "/hello", vec![], None);
+        let start = std::time::Instant::now();
+        let decision = agent.on_request(&request).await;
+        let elapsed = start.elapsed();
+
+        assert_eq!(block_status(decision), 200);
+        assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(600), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_response_sequence() {
+        let sequence = |mode: &str| {
+            yaml_stub(&format!(
+                r#"
+id: seq
+request:
+  path: {{ type: exact, value: /seq }}
+responses:
+  - status: 202
+  - status: 200
+sequence_mode: {}
+"#,
+                mode
+            ))
+        };
+        let request = make_request("GET", "/seq", vec![], None);
+
+        let agent = MockServerAgent::new(MockServerConfig::default());
+        agent.add_stub(sequence("wrap")).await.unwrap();
+        let mut statuses = Vec::new();
+        for _ in 0..3 {
+            statuses.push(block_status(agent.on_request(&request).await));
+        }
+        assert_eq!(statuses, vec![202, 200, 202]);
+
+        let agent = MockServerAgent::new(MockServerConfig::default());
+        agent.add_stub(sequence("clamp")).await.unwrap();
+        let mut statuses = Vec::new();
+        for _ in 0..3 {
+            statuses.push(block_status(agent.on_request(&request).await));
+        }
+        assert_eq!(statuses, vec![202, 200, 200]);
+    }
+
+    #[tokio::test]
+    async fn test_body_switch() {
+        let config = MockServerConfig {
+            stubs: vec![yaml_stub(
+                r#"
+id: orders
+request: { method: [POST], path: { type: exact, value: /orders } }
+switch:
+  key: $.type
+  cases:
+    express: { status: 201, body: { type: text, content: express } }
+    standard: { status: 202, body: { type: text, content: standard } }
+    "3": { status: 203 }
+response: { status: 400 }
+"#,
+            )],
+            ..Default::default()
+        };
+        let agent = MockServerAgent::new(config);
+        let order = |body: &str| make_request("POST", "/orders", vec![], Some(body.as_bytes()));
+
+        for (body, status, expected) in [
+            (r#"{"type": "express"}"#, 201, Some("express")),
+            (r#"{"type": "standard"}"#, 202, Some("standard")),
+        ] {
+            match agent.on_request(&order(body)).await.build().decision {
+                ProtocolDecision::Block {
+                    status: actual,
+                    body,
+                    ..
+                } => {
+                    assert_eq!(actual, status);
+                    assert_eq!(body.as_deref(), expected);
+                }
+                other => panic!("Expected block decision, got {:?}", other),
+            }
+        }
+        assert_eq!(
+            block_status(agent.on_request(&order(r#"{"type": 3}"#)).await),
+            203
+        );
+        // No case, no default: the stub's own response
+        assert_eq!(
+            block_status(agent.on_request(&order(r#"{"type": "drone"}"#)).await),
+            400
+        );
+        assert_eq!(
+            block_status(agent.on_request(&order("not json")).await),
+            400
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ab_bucket_by_cookie() {
+        let agent = MockServerAgent::new(MockServerConfig::default());
+        agent
+            .add_stub(yaml_stub(
+                r#"
+id: ab
+request:
+  path: { type: exact, value: /ab }
+response: { status: 200 }
+ab_key: { type: cookie, name: session }
+ab_buckets:
+  - percent: 50
+    response: { status: 201 }
+  - percent: 50
+    response: { status: 202 }
+"#,
+            ))
+            .await
+            .unwrap();
+
+        let cookie = "theme=dark; session=abc123";
+        let request = make_request("GET", "/ab", vec![("cookie", cookie)], None);
+        let status = block_status(agent.on_request(&request).await);
+        assert!(status == 201 || status == 202);
+        for _ in 0..5 {
+            assert_eq!(block_status(agent.on_request(&request).await), status);
+        }
+
+        // Without the cookie the stub's regular response is served
+        let request = make_request("GET", "/ab", vec![], None);
+        assert_eq!(block_status(agent.on_request(&request).await), 200);
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_counts() {
+        let agent = MockServerAgent::new(test_config());
+        let request = make_request("GET", "/hello", vec![], None);
+        agent.on_request(&request).await;
+
+        let mut config = test_config();
+        config.stubs.retain(|s| s.id == "hello");
+        agent.reload(config).await.unwrap();
+
+        assert_eq!(agent.stub_count(), 1);
+        let counts = agent.match_counts.read().await;
+        assert_eq!(counts["hello"].load(Ordering::Relaxed), 1);
+        assert!(!counts.contains_key("user-by-id"));
+    }
+
+    #[tokio::test]
+    async fn test_strict_templates() {
+        let broken = r#"
+id: broken
+request:
+  path: { type: exact, value: /broken }
+response:
+  status: 200
+  template: true
+  body: { type: text, content: "{{#if path.id}}unclosed" }
+"#;
+        let request = make_request("GET", "/broken", vec![], None);
+
+        // Lenient mode serves the stub without a body
+        let agent = MockServerAgent::new(MockServerConfig::default());
+        agent.add_stub(yaml_stub(broken)).await.unwrap();
+        assert_eq!(block_status(agent.on_request(&request).await), 200);
+
+        let mut config = MockServerConfig::default();
+        config.settings.strict_templates = true;
+        let agent = MockServerAgent::new(config);
+        agent.add_stub(yaml_stub(broken)).await.unwrap();
+        match agent.on_request(&request).await.build().decision {
+            ProtocolDecision::Block { status, body, .. } => {
+                assert_eq!(status, 500);
+                assert!(body.unwrap().contains("Template render error"));
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+
+        let config: MockServerConfig =
+            serde_yaml::from_str("settings: { template_errors: fail }").unwrap();
+        let agent = MockServerAgent::new(config);
+        agent.add_stub(yaml_stub(broken)).await.unwrap();
+        match agent.on_request(&request).await.build().decision {
+            ProtocolDecision::Block { status, body, .. } => {
+                assert_eq!(status, 500);
+                assert!(body.unwrap().contains("in stub 'broken'"));
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_sequence_entries() {
+        let agent = MockServerAgent::new(MockServerConfig::default());
+        agent
+            .add_stub(yaml_stub(
+                r#"
+id: retry
+request:
+  path: { type: exact, value: /retry }
+responses:
+  - status: 201
+    headers: { X-Attempt: "1" }
+  - status: 200
+    fault: { type: error, status: 409 }
+  - status: 200
+    delay: { fixed_ms: 1 }
+sequence_mode: once_each_then_last
+"#,
+            ))
+            .await
+            .unwrap();
+
+        let request = make_request("GET", "/retry", vec![], None);
+        let mut statuses = Vec::new();
+        for _ in 0..4 {
+            statuses.push(block_status(agent.on_request(&request).await));
+        }
+        assert_eq!(statuses, vec![201, 409, 200, 200]);
+    }
+
+    #[tokio::test]
+    async fn test_response_sequence_concurrent() {
+        let agent = Arc::new(MockServerAgent::new(MockServerConfig::default()));
+        let statuses: Vec<String> = (200..250).map(|s| format!("  - status: {}", s)).collect();
+        agent
+            .add_stub(yaml_stub(&format!(
+                "id: seq\nrequest: {{}}\nsequence_mode: loop\nresponses:\n{}\n",
+                statuses.join("\n")
+            )))
+            .await
+            .unwrap();
+
+        let handles: Vec<_> = (0..50)
+            .map(|_| {
+                let agent = agent.clone();
+                tokio::spawn(async move {
+                    let request = make_request("GET", "/", vec![], None);
+                    block_status(agent.on_request(&request).await)
+                })
+            })
+            .collect();
+
+        let mut seen = Vec::new();
+        for handle in handles {
+            seen.push(handle.await.unwrap());
+        }
+        seen.sort();
+        // Every entry is served exactly once
+        assert_eq!(seen, (200..250).collect::<Vec<u16>>());
+    }
+
+    #[tokio::test]
+    async fn test_templated_headers() {
+        let agent = MockServerAgent::new(MockServerConfig::default());
+        agent
+            .add_stub(yaml_stub(
+                r#"
+id: create
+request:
+  path: { type: template, template: "/users/{id}" }
+response:
+  status: 201
+  template: true
+  headers:
+    Location: "/users/{{path.id}}"
+    Content-Type: "application/{{query.format}}"
+    X-Broken: "{{#if}}"
+"#,
+            ))
+            .await
+            .unwrap();
+
+        let request = make_request("POST", "/users/42?format=xml", vec![], None);
+        match agent.on_request(&request).await.build().decision {
+            ProtocolDecision::Block {
+                status, headers, ..
+            } => {
+                let headers = headers.unwrap();
+                assert_eq!(status, 201);
+                assert_eq!(headers["Location"], "/users/42");
+                assert_eq!(headers["Content-Type"], "application/xml");
+                assert_eq!(headers["X-Broken"], "{{#if}}");
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_graphql_variables_in_templates() {
+        let config = MockServerConfig {
+            stubs: vec![yaml_stub(
+                r#"
+id: get-user
+request:
+  method: [POST]
+  path: { type: exact, value: /graphql }
+  body: { type: graphql, operation_name: GetUser }
+response:
+  template: true
+  body:
+    type: json
+    content: { data: { user: { id: "{{gql.variables.id}}", op: "{{gql.operation_name}}" } } }
+"#,
+            )],
+            ..Default::default()
+        };
+        let agent = MockServerAgent::new(config);
+
+        let body = br#"{"query": "query GetUser($id: ID!) { user(id: $id) { id } }", "variables": {"id": "u-7"}}"#;
+        let request = make_request(
+            "POST",
+            "/graphql",
+            vec![("content-type", "application/json")],
+            Some(body),
+        );
+        match agent.on_request(&request).await.build().decision {
+            ProtocolDecision::Block { body, .. } => {
+                let json: serde_json::Value = serde_json::from_str(&body.unwrap()).unwrap();
+                assert_eq!(json["data"]["user"]["id"], "u-7");
+                assert_eq!(json["data"]["user"]["op"], "GetUser");
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disabled_stub_response() {
+        let mut config = MockServerConfig {
+            stubs: vec![yaml_stub(
+                r#"
+id: reports
+enabled: false
+request:
+  path: { type: prefix, value: /reports }
+response:
+  status: 200
+"#,
+            )],
+            ..Default::default()
+        };
+        config.settings.disabled_stub_response = Some(ResponseDefinition {
+            status: 503.into(),
+            ..Default::default()
+        });
+        let agent = MockServerAgent::new(config);
+
+        let decision = agent
+            .on_request(&make_request("GET", "/reports/daily", vec![], None))
+            .await
+            .build();
+        assert!(decision.audit.tags.contains(&"stub_disabled".to_string()));
+        assert_eq!(decision.audit.custom["stub_id"], "reports");
+        match decision.decision {
+            ProtocolDecision::Block { status, .. } => assert_eq!(status, 503),
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+
+        // Requests no stub covers still get the default response
+        let other = make_request("GET", "/users", vec![], None);
+        assert_eq!(block_status(agent.on_request(&other).await), 404);
+    }
+
+    #[tokio::test]
+    async fn test_header_volume_limits() {
+        let config = MockServerConfig {
+            stubs: vec![yaml_stub(
+                r#"
+id: too-large
+priority: 10
+request:
+  max_headers: 20
+  max_header_bytes: 4096
+response:
+  status: 431
+  body: { type: text, content: "Request Header Fields Too Large" }
+"#,
+            )],
+            ..Default::default()
+        };
+        let agent = MockServerAgent::new(config);
+
+        let names: Vec<String> = (0..30).map(|i| format!("x-extra-{}", i)).collect();
+        let many: Vec<(&str, &str)> = names.iter().map(|name| (name.as_str(), "1")).collect();
+        let request = make_request("GET", "/", many, None);
+        assert_eq!(block_status(agent.on_request(&request).await), 431);
+
+        let large = "a".repeat(5000);
+        let request = make_request("GET", "/", vec![("cookie", large.as_str())], None);
+        assert_eq!(block_status(agent.on_request(&request).await), 431);
+
+        let request = make_request("GET", "/", vec![("accept", "*/*")], None);
+        assert_eq!(block_status(agent.on_request(&request).await), 404);
+    }
+
+    #[tokio::test]
+    async fn test_jwt_claims_in_templates() {
+        use base64::Engine;
+
+        let config = MockServerConfig {
+            stubs: vec![yaml_stub(
+                r#"
+id: greet
+request:
+  jwt: { claims: { sub: null } }
+response:
+  template: true
+  body: { type: text, content: "hello {{jwt.sub}}" }
+"#,
+            )],
+            ..Default::default()
+        };
+        let agent = MockServerAgent::new(config);
+
+        let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(r#"{"sub":"alice"}"#);
+        let authorization = format!("Bearer eyJhbGciOiJIUzI1NiJ9.{}.sig", payload);
+        let request = make_request(
+            "GET",
+            "/",
+            vec![("authorization", authorization.as_str())],
+            None,
+        );
+        match agent.on_request(&request).await.build().decision {
+            ProtocolDecision::Block { body, .. } => {
+                assert_eq!(body.as_deref(), Some("hello alice"))
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+
+        let anonymous = make_request("GET", "/", vec![], None);
+        assert_eq!(block_status(agent.on_request(&anonymous).await), 404);
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth() {
+        use base64::Engine;
+
+        let config = MockServerConfig {
+            stubs: vec![
+                yaml_stub(
+                    r#"
+id: signed-in
+priority: 10
+request:
+  path: { type: exact, value: /me }
+  basic_auth: { username: alice, password: s3cret }
+response:
+  template: true
+  body: { type: text, content: "hello {{auth.username}}" }
+"#,
+                ),
+                yaml_stub(
+                    r#"
+id: any-password
+priority: 5
+request:
+  path: { type: exact, value: /me }
+  basic_auth: { username: bob }
+response: { status: 403 }
+"#,
+                ),
+                yaml_stub(
+                    r#"
+id: rejected
+request:
+  path: { type: exact, value: /me }
+response: { status: 401 }
+"#,
+                ),
+            ],
+            ..Default::default()
+        };
+        let agent = MockServerAgent::new(config);
+        let me = |credentials: &str| {
+            let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+            let authorization = format!("Basic {}", encoded);
+            make_request(
+                "GET",
+                "/me",
+                vec![("authorization", authorization.as_str())],
+                None,
+            )
+        };
+
+        match agent.on_request(&me("alice:s3cret")).await.build().decision {
+            ProtocolDecision::Block { status, body, .. } => {
+                assert_eq!(status, 200);
+                assert_eq!(body.as_deref(), Some("hello alice"));
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+        assert_eq!(
+            block_status(agent.on_request(&me("alice:wrong")).await),
+            401
+        );
+        assert_eq!(
+            block_status(agent.on_request(&me("bob:anything")).await),
+            403
+        );
+
+        let anonymous = make_request("GET", "/me", vec![], None);
+        assert_eq!(block_status(agent.on_request(&anonymous).await), 401);
+    }
+
+    #[tokio::test]
+    async fn test_template_vars() {
+        let mut config = MockServerConfig {
+            stubs: vec![yaml_stub(
+                r#"
+id: links
+request: {}
+response:
+  template: true
+  body: { type: text, content: "{{vars.base_url}}/users/1" }
+"#,
+            )],
+            ..Default::default()
+        };
+        config.settings.template_vars.insert(
+            "base_url".to_string(),
+            serde_json::json!("https://api.example.com"),
+        );
+        let agent = MockServerAgent::new(config.clone());
+
+        let request = make_request("GET", "/", vec![], None);
+        match agent.on_request(&request).await.build().decision {
+            ProtocolDecision::Block { body, .. } => {
+                assert_eq!(body.as_deref(), Some("https://api.example.com/users/1"));
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+
+        // Reloads pick up new values
+        config.settings.template_vars.insert(
+            "base_url".to_string(),
+            serde_json::json!("http://localhost:8080"),
+        );
+        agent.reload(config).await.unwrap();
+        match agent.on_request(&request).await.build().decision {
+            ProtocolDecision::Block { body, .. } => {
+                assert_eq!(body.as_deref(), Some("http://localhost:8080/users/1"));
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_match_probability() {
+        let config: MockServerConfig = serde_yaml::from_str(
+            r#"
+settings:
+  random_seed: 7
+stubs:
+  - id: canary
+    priority: 10
+    match_probability: 0.25
+    request:
+      path: { type: exact, value: /api }
+    response: { status: 201 }
+  - id: stable
+    request:
+      path: { type: exact, value: /api }
+    response: { status: 200 }
+"#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        let run = |config: MockServerConfig| async move {
+            let agent = MockServerAgent::new(config);
+            let mut statuses = Vec::new();
+            for _ in 0..200 {
+                let request = make_request("GET", "/api", vec![], None);
+                statuses.push(block_status(agent.on_request(&request).await));
+            }
+            statuses
+        };
+        let statuses = run(config.clone()).await;
+        let canary = statuses.iter().filter(|s| **s == 201).count();
+        assert!(
+            (20..=80).contains(&canary),
+            "canary served {} of 200",
+            canary
+        );
+        assert_eq!(canary + statuses.iter().filter(|s| **s == 200).count(), 200);
+        // The same seed rolls the same way
+        assert_eq!(run(config.clone()).await, statuses);
+
+        // The roll is recorded on the decision
+        let agent = MockServerAgent::new(config.clone());
+        let request = make_request("GET", "/api", vec![], None);
+        let response = agent.on_request(&request).await.build();
+        let rolls = &response.audit.custom["match_rolls"];
+        assert!(rolls["canary"].is_boolean());
+
+        let mut invalid = config;
+        invalid.stubs[0].match_probability = Some(1.5);
+        assert!(invalid.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_export_state_json() {
+        let config = MockServerConfig {
+            stubs: vec![
+                yaml_stub("{ id: users, request: { path: { type: exact, value: /users } } }"),
+                yaml_stub("{ id: orders, request: { path: { type: exact, value: /orders } } }"),
+            ],
+            ..Default::default()
+        };
+        let agent = MockServerAgent::new(config);
+        for path in ["/users", "/users", "/nothing"] {
+            let request = make_request("GET", path, vec![], None);
+            agent.on_request(&request).await;
+        }
+
+        let state = agent.export_state_json().await;
+        assert_eq!(state["stubs"][0]["id"], "users");
+        assert_eq!(state["stubs"][0]["match_count"], 2);
+        assert_eq!(state["stubs"][1]["id"], "orders");
+        assert_eq!(state["stubs"][1]["match_count"], 0);
+        assert_eq!(state["stubs"][0]["request"]["path"]["value"], "/users");
+        assert_eq!(
+            state["requests"],
+            serde_json::json!({ "total": 3, "matched": 2, "unmatched": 1, "contract_mismatches": 0 })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_fault() {
+        let config: MockServerConfig = serde_yaml::from_str(
+            r#"
+settings:
+  random_seed: 11
+stubs:
+  - id: events
+    request:
+      path: { type: exact, value: /events }
+    response:
+      template: true
+      body: { type: json, content: { id: "{{uuid}}" } }
+    fault: { type: duplicate, probability: 0.3 }
+"#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let agent = MockServerAgent::new(config);
+
+        let mut bodies = Vec::new();
+        let mut tagged = 0;
+        for _ in 0..200 {
+            let request = make_request("GET", "/events", vec![], None);
+            let response = agent.on_request(&request).await.build();
+            if response.audit.custom.get("fault_type") == Some(&serde_json::json!("duplicate")) {
+                tagged += 1;
+            }
+            match response.decision {
+                ProtocolDecision::Block { status, body, .. } => {
+                    assert_eq!(status, 200);
+                    bodies.push(body.unwrap());
+                }
+                other => panic!("Expected block decision, got {:?}", other),
+            }
+        }
+        let duplicates = bodies.windows(2).filter(|pair| pair[0] == pair[1]).count();
+        assert!(
+            (30..=90).contains(&duplicates),
+            "{} duplicates of 200",
+            duplicates
+        );
+        assert_eq!(duplicates, tagged);
+
+        let invalid: FaultConfig =
+            serde_yaml::from_str("{ type: duplicate, probability: 2.0 }").unwrap();
+        assert!(invalid.validate().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_duplicate_fault_keeps_delay() {
+        let config: MockServerConfig = serde_yaml::from_str(
+            r#"
+stubs:
+  - id: events
+    request:
+      path: { type: exact, value: /events }
+    response:
+      body: { type: text, content: event }
+    delay: { fixed_ms: 200 }
+    fault: { type: duplicate, probability: 1.0 }
+"#,
+        )
+        .unwrap();
+        let agent = MockServerAgent::new(config);
+        let request = make_request("GET", "/events", vec![], None);
+
+        agent.on_request(&request).await;
+        let start = tokio::time::Instant::now();
+        let replay = agent.on_request(&request).await.build();
+        assert_eq!(replay.audit.custom["fault_type"], "duplicate");
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_long_poll() {
+        let config: MockServerConfig = serde_yaml::from_str(
+            r#"
+stubs:
+  - id: poll
+    request:
+      path: { type: exact, value: /poll }
+    response:
+      body: { type: text, content: ready }
+    long_poll: { event: job-done, timeout_ms: 30000 }
+  - id: poll-custom
+    request:
+      path: { type: exact, value: /poll/custom }
+    response:
+      body: { type: text, content: ready }
+    long_poll:
+      event: job-done
+      timeout_ms: 1000
+      timeout_response: { status: 408, body: { type: text, content: waiting } }
+"#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let agent = MockServerAgent::new(config);
+
+        // Nothing fires: the default timeout answer is an empty 204
+        let started = tokio::time::Instant::now();
+        let response = agent
+            .on_request(&make_request("GET", "/poll", vec![], None))
+            .await
+            .build();
+        assert_eq!(started.elapsed(), Duration::from_millis(30000));
+        match response.decision {
+            ProtocolDecision::Block { status, body, .. } => {
+                assert_eq!(status, 204);
+                assert!(body.unwrap_or_default().is_empty());
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+        assert_eq!(
+            response.audit.custom.get("stub_id"),
+            Some(&serde_json::json!("poll"))
+        );
+
+        let response = agent
+            .on_request(&make_request("GET", "/poll/custom", vec![], None))
+            .await
+            .build();
+        match response.decision {
+            ProtocolDecision::Block { status, body, .. } => {
+                assert_eq!(status, 408);
+                assert_eq!(body.as_deref(), Some("waiting"));
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+
+        // The event releases the waiting request with the stub's response
+        let started = tokio::time::Instant::now();
+        let request = make_request("GET", "/poll", vec![], None);
+        let (response, _) = tokio::join!(agent.on_request(&request), async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            agent.fire_event("job-done");
+        });
+        assert_eq!(started.elapsed(), Duration::from_millis(500));
+        match response.build().decision {
+            ProtocolDecision::Block { status, body, .. } => {
+                assert_eq!(status, 200);
+                assert_eq!(body.as_deref(), Some("ready"));
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+
+        // Firings aren't remembered for later requests
+        let decision = agent
+            .on_request(&make_request("GET", "/poll/custom", vec![], None))
+            .await;
+        assert_eq!(block_status(decision), 408);
+
+        // Events are only tracked while someone waits on them
+        agent.fire_event("nobody-waits");
+        assert!(agent.events.lock().unwrap().is_empty());
+
+        let invalid: LongPollConfig =
+            serde_yaml::from_str("{ event: '', timeout_ms: 10 }").unwrap();
+        assert!(invalid.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_debug_unmatched() {
+        let mut config: MockServerConfig = serde_yaml::from_str(
+            r#"
+settings:
+  debug_unmatched: true
+stubs:
+  - id: create-user
+    request:
+      method: [POST]
+      path: { type: exact, value: /users }
+      headers:
+        content-type: { type: contains, value: json }
+  - id: orders
+    request:
+      path: { type: prefix, value: /orders }
+"#,
+        )
+        .unwrap();
+        let agent = MockServerAgent::new(config.clone());
+
+        let request = make_request("GET", "/users", vec![("content-type", "text/plain")], None);
+        let response = agent.on_request(&request).await.build();
+        assert_eq!(
+            response.audit.custom["closest_match"],
+            serde_json::json!({
+                "stub_id": "create-user",
+                "matched": ["path"],
+                "failed": ["method", "header content-type"],
+            })
+        );
+        match response.decision {
+            ProtocolDecision::Block {
+                status,
+                body,
+                headers,
+            } => {
+                assert_eq!(status, 404);
+                assert_eq!(headers.unwrap()["x-mock-closest-stub"], "create-user");
+                let body: serde_json::Value = serde_json::from_str(&body.unwrap()).unwrap();
+                assert_eq!(body["closest"]["failed"][0], "method");
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+
+        // Nothing close: no header
+        let request = make_request("DELETE", "/nothing", vec![], None);
+        let response = agent.on_request(&request).await.build();
+        assert!(response.audit.custom.get("closest_match").is_none());
+
+        // Off by default
+        config.settings.debug_unmatched = false;
+        agent.reload(config).await.unwrap();
+        let request = make_request("GET", "/users", vec![], None);
+        let response = agent.on_request(&request).await.build();
+        assert!(response.audit.custom.get("closest_match").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_template_partials() {
+        let mut config: MockServerConfig = serde_yaml::from_str(
+            r#"
+settings:
+  template_partials:
+    error_envelope: '{"error": "{{code}}", "path": "{{request_path}}"}'
+stubs:
+  - id: missing
+    request:
+      path: { type: prefix, value: /missing }
+    response:
+      status: 404
+      template: true
+      body: { type: text, content: "{{> error_envelope code=\"not_found\"}}" }
+"#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let agent = MockServerAgent::new(config.clone());
+
+        let request = make_request("GET", "/missing/1", vec![], None);
+        match agent.on_request(&request).await.build().decision {
+            ProtocolDecision::Block { status, body, .. } => {
+                assert_eq!(status, 404);
+                assert_eq!(
+                    body.as_deref(),
+                    Some(r#"{"error": "not_found", "path": "/missing/1"}"#)
+                );
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+
+        // A partial that doesn't compile is rejected up front
+        config
+            .settings
+            .template_partials
+            .insert("broken".to_string(), "{{#if x}}".to_string());
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("'broken'"), "{}", err);
+        assert!(agent.reload(config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_after_status() {
+        let mut config = MockServerConfig {
+            stubs: vec![
+                yaml_stub(
+                    r#"
+id: retry
+priority: 10
+request:
+  path: { type: exact, value: /pay }
+  after_status: 503
+response: { status: 200 }
+"#,
+                ),
+                yaml_stub(
+                    r#"
+id: first
+request:
+  path: { type: exact, value: /pay }
+response: { status: 503 }
+"#,
+                ),
+            ],
+            ..Default::default()
+        };
+        config.settings.session_key = Some(AbKey::Header {
+            name: "x-client".to_string(),
+        });
+        let agent = MockServerAgent::new(config);
+        let pay = |client: &str| make_request("POST", "/pay", vec![("x-client", client)], None);
+
+        // The retry stub is inert until the session has been served a 503
+        assert_eq!(block_status(agent.on_request(&pay("a")).await), 503);
+        assert_eq!(block_status(agent.on_request(&pay("a")).await), 200);
+        // Only the retry reaches it; the 200 it served resets the session
+        assert_eq!(block_status(agent.on_request(&pay("a")).await), 503);
+
+        // Sessions don't see each other's statuses
+        assert_eq!(block_status(agent.on_request(&pay("b")).await), 503);
+        agent.reset().await;
+        assert_eq!(block_status(agent.on_request(&pay("b")).await), 503);
+    }
+
+    #[tokio::test]
+    async fn test_scenario_state_machine() {
+        let config: MockServerConfig = serde_yaml::from_str(
+            r#"
+stubs:
+  - id: list-empty
+    scenario: todo
+    required_state: Started
+    request:
+      method: [GET]
+      path: { type: exact, value: /todo }
+    response:
+      body: { type: json, content: [] }
+  - id: add-item
+    scenario: todo
+    new_state: ItemAdded
+    request:
+      method: [POST]
+      path: { type: exact, value: /todo }
+    response:
+      status: 201
+  - id: list-item
+    scenario: todo
+    required_state: ItemAdded
+    request:
+      method: [GET]
+      path: { type: exact, value: /todo }
+    response:
+      body: { type: json, content: ["buy milk"] }
+"#,
+        )
+        .unwrap();
+        let agent = MockServerAgent::new(config);
+
+        let list = make_request("GET", "/todo", vec![], None);
+        let add = make_request("POST", "/todo", vec![], None);
+        let body = |decision: Decision| match decision.build().decision {
+            ProtocolDecision::Block { body, .. } => body.unwrap_or_default(),
+            other => panic!("Expected block decision, got {:?}", other),
+        };
+
+        assert_eq!(body(agent.on_request(&list).await), "[]");
+
+        let response = agent.on_request(&add).await.build();
+        assert_eq!(response.audit.custom["scenario_state"], "ItemAdded");
+        assert_eq!(agent.scenario_state("todo"), "ItemAdded");
+
+        assert_eq!(body(agent.on_request(&list).await), r#"["buy milk"]"#);
+
+        let report = agent.metrics_report().unwrap();
+        let gauge = report
+            .gauges
+            .iter()
+            .find(|g| g.name == "mock_server_scenario_state")
+            .unwrap();
+        assert_eq!(gauge.labels["state"], "ItemAdded");
+
+        agent.reset_scenarios();
+        assert_eq!(body(agent.on_request(&list).await), "[]");
+    }
+
+    #[tokio::test]
+    async fn test_stub_match_gauges() {
+        let config = MockServerConfig {
+            stubs: vec![
+                yaml_stub("{ id: hot, request: { path: { type: exact, value: /hot } } }"),
+                yaml_stub("{ id: cold, request: { path: { type: exact, value: /cold } } }"),
+            ],
+            ..Default::default()
+        };
+        let agent = MockServerAgent::new(config);
+        for _ in 0..3 {
+            agent
+                .on_request(&make_request("GET", "/hot", vec![], None))
+                .await;
+        }
+
+        let report = agent.metrics_report().unwrap();
+        let matches = |id: &str| {
+            report
+                .gauges
+                .iter()
+                .find(|g| g.name == "mock_server_stub_matches" && g.labels["stub"] == id)
+                .map(|g| g.value)
+        };
+        assert_eq!(matches("hot"), Some(3.0));
+        assert_eq!(matches("cold"), Some(0.0));
+        // Aggregates are still reported
+        assert!(report
+            .counters
+            .iter()
+            .any(|c| c.name == "mock_server_requests_matched_total"));
+    }
+
+    #[tokio::test]
+    async fn test_disable_matching() {
+        let agent = MockServerAgent::new(MockServerConfig::default());
+        for id in ["auth-login", "auth-logout", "users"] {
+            agent
+                .add_stub(yaml_stub(&format!(
+                    "id: {}\nrequest:\n  path: {{ type: exact, value: /{} }}\n",
+                    id, id
+                )))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(agent.disable_matching("auth-*").await.unwrap(), 2);
+        let request = make_request("GET", "/auth-login", vec![], None);
+        assert_eq!(block_status(agent.on_request(&request).await), 404);
+        let request = make_request("GET", "/users", vec![], None);
+        assert_eq!(block_status(agent.on_request(&request).await), 200);
+
+        assert_eq!(agent.disable_matching("/^us/").await.unwrap(), 1);
+        assert!(agent.disable_matching("/(/").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_on_configure_reloads() {
+        let agent = MockServerAgent::new(test_config());
+        let request = make_request("GET", "/hello", vec![], None);
+        agent.on_request(&request).await;
+
+        let pushed = serde_json::json!({
+            "stubs": [
+                { "id": "hello", "request": { "path": { "type": "exact", "value": "/hello" } } },
+                { "id": "new", "request": { "path": { "type": "exact", "value": "/new" } } }
+            ]
+        });
+        Agent::on_configure(&agent, pushed).await.unwrap();
+        assert_eq!(agent.stub_count(), 2);
+        assert_eq!(
+            agent.match_counts.read().await["hello"].load(Ordering::Relaxed),
+            1
+        );
+
+        // A bad push keeps the previous configuration
+        let bad = serde_json::json!({ "stubs": [{ "id": "" , "request": {} }] });
+        assert!(Agent::on_configure(&agent, bad).await.is_err());
+        assert_eq!(agent.stub_count(), 2);
+
+        Agent::on_configure(&agent, serde_json::Value::Null)
+            .await
+            .unwrap();
+        assert_eq!(agent.stub_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_weighted_random_responses() {
+        let run = |seed: u64| async move {
+            let mut config = MockServerConfig::default();
+            config.settings.random_seed = Some(seed);
+            let agent = MockServerAgent::new(config);
+            agent
+                .add_stub(yaml_stub(
+                    r#"
+id: flaky
+request:
+  path: { type: exact, value: /flaky }
+selection: random
+responses:
+  - status: 200
+    weight: 90
+  - status: 503
+    weight: 10
+"#,
+                ))
+                .await
+                .unwrap();
+
+            let request = make_request("GET", "/flaky", vec![], None);
+            let mut variants = Vec::new();
+            for _ in 0..1000 {
+                let response = agent.on_request(&request).await.build();
+                let variant = response.audit.custom["response_variant"].as_u64().unwrap();
+                let expected = if variant == 0 { 200 } else { 503 };
+                match response.decision {
+                    ProtocolDecision::Block { status, .. } => assert_eq!(status, expected),
+                    other => panic!("Expected block decision, got {:?}", other),
+                }
+                variants.push(variant);
+            }
+            variants
+        };
+
+        let first = run(7).await;
+        let failures = first.iter().filter(|&&v| v == 1).count();
+        assert!((50..150).contains(&failures), "got {} failures", failures);
+        assert_eq!(first, run(7).await);
+    }
+
+    #[tokio::test]
+    async fn test_journal() {
+        let mut config = test_config();
+        config.settings.journal_size = 2;
+        config.settings.journal_body_max_bytes = 4;
+        let agent = MockServerAgent::new(config);
+
+        agent
+            .on_request(&make_request("GET", "/missing", vec![], None))
+            .await;
+        agent
+            .on_request(&make_request(
+                "GET",
+                "/hello?lang=en",
+                vec![("x-test", "1")],
+                Some(b"hello world"),
+            ))
+            .await;
+
+        let journal = agent.journal();
+        assert_eq!(journal.len(), 2);
+        assert_eq!(journal[0].path, "/missing");
+        assert_eq!(journal[0].stub_id, None);
+        assert_eq!(journal[0].status, Some(404));
+
+        assert_eq!(journal[1].method, "GET");
+        assert_eq!(journal[1].query.as_deref(), Some("lang=en"));
+        assert_eq!(journal[1].headers["x-test"], vec!["1"]);
+        assert_eq!(journal[1].body.as_deref(), Some("hell"));
+        assert!(journal[1].body_truncated);
+        assert_eq!(journal[1].stub_id.as_deref(), Some("hello"));
+        assert_eq!(journal[1].status, Some(200));
+
+        // The oldest entry makes room for the newest
+        agent
+            .on_request(&make_request("GET", "/hello", vec![], None))
+            .await;
+        let journal = agent.journal();
+        assert_eq!(journal.len(), 2);
+        assert_eq!(journal[0].path, "/hello");
+
+        assert!(journal[1].considered.is_empty());
+
+        // Unmatched requests list the stubs whose path fit
+        agent
+            .on_request(&make_request("POST", "/hello", vec![], None))
+            .await;
+        let journal = agent.journal();
+        assert_eq!(journal[1].stub_id, None);
+        assert_eq!(journal[1].considered, vec!["hello"]);
+
+        agent.clear_journal();
+        assert!(agent.journal().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_circuit_breaker() {
+        let mut config = test_config();
+        config.settings.random_seed = Some(3);
+        config.settings.breaker = Some(BreakerConfig {
+            open_ms: 1000,
+            half_open_ms: 1000,
+            half_open_fraction: 0.5,
+            status: 503,
+        });
+        let agent = MockServerAgent::new(config);
+        let request = make_request("GET", "/hello", vec![], None);
+
+        // Open: everything is rejected
+        assert_eq!(agent.breaker_phase(), Some(BreakerPhase::Open));
+        for _ in 0..10 {
+            assert_eq!(block_status(agent.on_request(&request).await), 503);
+        }
+
+        // Half-open: some requests get through
+        tokio::time::advance(Duration::from_millis(1500)).await;
+        assert_eq!(agent.breaker_phase(), Some(BreakerPhase::HalfOpen));
+        let mut statuses = Vec::new();
+        for _ in 0..100 {
+            statuses.push(block_status(agent.on_request(&request).await));
+        }
+        let served = statuses.iter().filter(|&&s| s == 200).count();
+        assert!((20..80).contains(&served), "served {}", served);
+        assert_eq!(served + statuses.iter().filter(|&&s| s == 503).count(), 100);
+
+        // Closed: back to normal
+        tokio::time::advance(Duration::from_millis(1000)).await;
+        assert_eq!(agent.breaker_phase(), Some(BreakerPhase::Closed));
+        for _ in 0..10 {
+            assert_eq!(block_status(agent.on_request(&request).await), 200);
+        }
+
+        // Tripping again reopens it
+        agent.trip_breaker();
+        assert_eq!(block_status(agent.on_request(&request).await), 503);
+    }
+
+    #[tokio::test]
+    async fn test_response_match() {
+        let agent = MockServerAgent::new(MockServerConfig::default());
+        agent
+            .add_stub(yaml_stub(
+                r#"
+id: patch-marked
+request:
+  path: { type: exact, value: /items }
+response_match: { type: contains, value: MARKER }
+response:
+  status: 200
+  body: { type: text, content: "patched" }
+"#,
+            ))
+            .await
+            .unwrap();
+
+        let request = make_request("GET", "/items", vec![], None);
+        let decision = agent.on_request(&request).await.build();
+        assert!(matches!(decision.decision, ProtocolDecision::Allow));
+
+        let marked = make_response(200, b"item MARKER item");
+        let rewritten = agent.on_response_body(&request, &marked).await.build();
+        match rewritten.decision {
+            ProtocolDecision::Block { status, body, .. } => {
+                assert_eq!(status, 200);
+                assert_eq!(body.as_deref(), Some("patched"));
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+        assert!(rewritten
+            .audit
+            .tags
+            .contains(&"response_rewritten".to_string()));
+
+        agent.on_request(&request).await;
+        let plain = make_response(200, b"item item");
+        let passed = agent.on_response_body(&request, &plain).await.build();
+        assert!(matches!(passed.decision, ProtocolDecision::Allow));
+
+        // The response phase uses the stub the request matched, even when
+        // another stub would match the request without runtime filters
+        agent
+            .add_stub(yaml_stub(
+                r#"
+id: armed-only
+priority: 10
+scenario: items
+required_state: armed
+request:
+  path: { type: exact, value: /items }
+response_match: { type: contains, value: MARKER }
+response:
+  body: { type: text, content: "armed" }
+"#,
+            ))
+            .await
+            .unwrap();
+        agent.on_request(&request).await;
+        let rewritten = agent.on_response_body(&request, &marked).await.build();
+        match rewritten.decision {
+            ProtocolDecision::Block { body, .. } => assert_eq!(body.as_deref(), Some("patched")),
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+        assert!(agent.upstream_matches.lock().unwrap().is_empty());
+
+        // The rewrite serves what the client would have got, A/B bucket included
+        agent
+            .add_stub(yaml_stub(
+                r#"
+id: bucketed
+request:
+  path: { type: exact, value: /bucketed }
+response_match: { type: contains, value: MARKER }
+response: { status: 200 }
+ab_key: { type: header, name: x-user }
+ab_buckets:
+  - percent: 100
+    response: { status: 202 }
+"#,
+            ))
+            .await
+            .unwrap();
+        let request = make_request("GET", "/bucketed", vec![("x-user", "u1")], None);
+        agent.on_request(&request).await;
+        let rewritten = agent.on_response_body(&request, &marked).await;
+        assert_eq!(block_status(rewritten), 202);
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let agent = MockServerAgent::new(test_config());
+        let hello = make_request("GET", "/hello", vec![], None);
+        agent.on_request(&hello).await;
+        agent.on_request(&hello).await;
+        agent
+            .on_request(&make_request("POST", "/hello", vec![], None))
+            .await;
+
+        assert_eq!(agent.match_count("hello").await, Some(2));
+        assert_eq!(agent.match_count("missing").await, None);
+        assert!(agent.verify("hello", Times::Exactly(2)).await.is_ok());
+        assert!(agent.verify("hello", Times::AtLeast(1)).await.is_ok());
+        assert!(agent.verify("user-by-id", Times::Never).await.is_ok());
+        assert!(matches!(
+            agent.verify("missing", Times::Never).await,
+            Err(VerificationError::UnknownStub(_))
+        ));
+
+        let err = agent.verify("hello", Times::Exactly(3)).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Stub 'hello' matched an unexpected number of times\n  \
+             expected: exactly 3 times\n  \
+             actual:   2 times\n\
+             Near misses (path fits, answered otherwise):\n  \
+             POST /hello -> unmatched (404)"
+        );
+
+        agent.reset_counts().await;
+        assert!(agent.verify("hello", Times::Never).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_sort_json_keys() {
+        let stub = r#"
+id: profile
+request:
+  path: { type: exact, value: /profile }
+response:
+  template: true
+  headers: { Content-Type: application/json }
+  body:
+    type: text
+    content: '{"zeta": 1, "alpha": {"y": [{"b": 2, "a": 1}], "x": "{{method}}"}, "mid": null}'
+"#;
+        let body = |agent: MockServerAgent| async move {
+            let request = make_request("GET", "/profile", vec![], None);
+            match agent.on_request(&request).await.build().decision {
+                ProtocolDecision::Block { body, .. } => body.unwrap(),
+                other => panic!("Expected block decision, got {:?}", other),
+            }
+        };
+
+        let mut config = MockServerConfig {
+            stubs: vec![yaml_stub(stub)],
+            ..Default::default()
+        };
+        // Text templates keep the order they were written in
+        let unsorted = body(MockServerAgent::new(config.clone())).await;
+        assert!(unsorted.starts_with(r#"{"zeta": 1"#));
+
+        config.settings.sort_json_keys = true;
+        assert_eq!(
+            body(MockServerAgent::new(config)).await,
+            r#"{"alpha":{"x":"GET","y":[{"a":1,"b":2}]},"mid":null,"zeta":1}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_param_file_body() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("7.json"),
+            r#"{"status": 201, "headers": {"X-User": "7"}, "body": {"id": 7, "name": "Grace"}}"#,
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("8.json"), r#"{"body": "plain"}"#).unwrap();
+
+        let mut config = MockServerConfig {
+            stubs: vec![yaml_stub(
+                r#"
+id: users
+request:
+  path: { type: template, template: "/users/{id}" }
+response:
+  headers: { X-User: stub, X-Source: fixtures }
+  body: { type: param_file, path_template: "{{path.id}}.json" }
+"#,
+            )],
+            ..Default::default()
+        };
+        config.settings.fixtures_root = Some(dir.path().to_path_buf());
+        let agent = MockServerAgent::new(config);
+
+        let request = make_request("GET", "/users/7", vec![], None);
+        match agent.on_request(&request).await.build().decision {
+            ProtocolDecision::Block {
+                status,
+                body,
+                headers,
+            } => {
+                assert_eq!(status, 201);
+                let body: serde_json::Value = serde_json::from_str(&body.unwrap()).unwrap();
+                assert_eq!(body, serde_json::json!({ "id": 7, "name": "Grace" }));
+                let headers = headers.unwrap();
+                assert_eq!(headers["X-User"], "7");
+                assert_eq!(headers["X-Source"], "fixtures");
+                assert_eq!(headers["Content-Type"], "application/json");
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+
+        // The stub's status and a text body when the fixture leaves them out
+        let request = make_request("GET", "/users/8", vec![], None);
+        match agent.on_request(&request).await.build().decision {
+            ProtocolDecision::Block {
+                status,
+                body,
+                headers,
+            } => {
+                assert_eq!(status, 200);
+                assert_eq!(body.as_deref(), Some("plain"));
+                assert_eq!(headers.unwrap()["Content-Type"], "text/plain");
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+
+        let missing = make_request("GET", "/users/9", vec![], None);
+        assert_eq!(block_status(agent.on_request(&missing).await), 404);
+    }
+
+    #[tokio::test]
+    async fn test_templated_fixture_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let fixtures = dir.path().join("fixtures");
+        std::fs::create_dir(&fixtures).unwrap();
+        std::fs::write(fixtures.join("alice.json"), r#"{"name":"Alice"}"#).unwrap();
+        std::fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+
+        let mut config = MockServerConfig {
+            stubs: vec![yaml_stub(
+                r#"
+id: users
+request:
+  path: { type: template, template: "/users/{id}" }
+response:
+  template: true
+  headers: { Content-Type: application/json }
+  body: { type: file, path: "{{path.id}}.json" }
+"#,
+            )],
+            ..Default::default()
+        };
+        config.settings.fixtures_root = Some(fixtures);
+        let agent = MockServerAgent::new(config);
+
+        let request = make_request("GET", "/users/alice", vec![], None);
+        match agent.on_request(&request).await.build().decision {
+            ProtocolDecision::Block { status, body, .. } => {
+                assert_eq!(status, 200);
+                assert_eq!(body.as_deref(), Some(r#"{"name":"Alice"}"#));
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+
+        let missing = make_request("GET", "/users/bob", vec![], None);
+        assert_eq!(block_status(agent.on_request(&missing).await), 404);
+
+        // A rendered path that climbs out of the fixtures root is refused
+        let ctx = crate::matcher::MatchContext::default();
+        let escape = agent.read_fixture("../secret.txt", &ctx, "GET", "/", &HashMap::new(), None);
+        assert!(escape.unwrap_err().to_string().contains("outside"));
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("recorded.yaml");
+        let mut config = test_config();
+        config.settings.record = Some(crate::config::RecordConfig {
+            enabled: true,
+            upstream: "http://backend".to_string(),
+            file: file.clone(),
+            match_headers: vec!["accept".to_string()],
+        });
+        let agent = MockServerAgent::new(config);
+        assert!(agent
+            .capabilities()
+            .supported_events
+            .contains(&EventType::ResponseBodyChunk));
+
+        // Unmatched requests go upstream and the answer is recorded, with
+        // every value of repeated match headers
+        let request = make_request(
+            "GET",
+            "/live",
+            vec![("accept", "text/html"), ("accept", "text/plain")],
+            None,
+        );
+        let decision = agent.on_request(&request).await.build();
+        assert!(matches!(decision.decision, ProtocolDecision::Allow));
+        assert!(decision.audit.tags.contains(&"recording".to_string()));
+        agent
+            .on_response_body(&request, &make_response(200, b"from upstream"))
+            .await;
+
+        // Requests a stub answers are not recorded
+        let hello = make_request("GET", "/hello", vec![], None);
+        agent
+            .on_response_body(&hello, &make_response(200, b"ignored"))
+            .await;
+
+        let replay = MockServerConfig {
+            stubs: record::load(&file).unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(replay.stubs.len(), 1);
+        let replayed = MockServerAgent::new(replay)
+            .on_request(&request)
+            .await
+            .build();
+        match replayed.decision {
+            ProtocolDecision::Block { status, body, .. } => {
+                assert_eq!(status, 200);
+                assert_eq!(body.as_deref(), Some("from upstream"));
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+        let partial = make_request("GET", "/live", vec![("accept", "text/html")], None);
+        let decision = MockServerAgent::new(MockServerConfig {
+            stubs: record::load(&file).unwrap(),
+            ..Default::default()
+        })
+        .on_request(&partial)
+        .await;
+        assert_eq!(block_status(decision), 404);
+    }
+
+    #[tokio::test]
+    async fn test_reset() {
+        let agent = MockServerAgent::new(test_config());
+        agent
+            .add_stub(yaml_stub(
+                r#"
+id: cart
+request:
+  path: { type: exact, value: /cart }
+scenario: shopping
+new_state: Full
+responses:
+  - status: 202
+  - status: 200
+"#,
+            ))
+            .await
+            .unwrap();
+        agent
+            .add_stub(yaml_stub(
+                r#"
+id: rewrite
+request:
+  path: { type: exact, value: /rewrite }
+response_match: { type: contains, value: error }
+"#,
+            ))
+            .await
+            .unwrap();
+        let cart = make_request("GET", "/cart", vec![], None);
+        assert_eq!(block_status(agent.on_request(&cart).await), 202);
+        let rewrite = make_request("GET", "/rewrite", vec![], None);
+        agent.on_request(&rewrite).await;
+        assert!(agent.upstream_match(&rewrite).is_some());
+        agent.on_drain(1000, DrainReason::Manual).await;
+
+        agent.reset().await;
+        assert!(agent.upstream_match(&rewrite).is_none());
+        assert_eq!(agent.match_count("cart").await, Some(0));
+        assert_eq!(agent.total_requests(), 0);
+        assert_eq!(agent.scenario_state("shopping"), SCENARIO_STARTED);
+        assert!(agent.journal().is_empty());
+        assert!(!agent.is_draining());
+        assert_eq!(agent.stub_count(), 6);
+
+        // The sequence starts over
+        assert_eq!(block_status(agent.on_request(&cart).await), 202);
+
+        let report = agent.metrics_report().unwrap();
+        let resets = report
+            .counters
+            .iter()
+            .find(|c| c.name == "mock_server_resets_total")
+            .unwrap();
+        assert_eq!(resets.value, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_max_matches_concurrent() {
+        let mut config = test_config();
+        config.stubs[0].max_matches = 1;
+        let agent = Arc::new(MockServerAgent::new(config));
+        let barrier = Arc::new(tokio::sync::Barrier::new(50));
+
+        let tasks: Vec<_> = (0..50)
+            .map(|_| {
+                let agent = agent.clone();
+                let barrier = barrier.clone();
+                tokio::spawn(async move {
+                    let request = make_request("GET", "/hello", vec![], None);
+                    barrier.wait().await;
+                    block_status(agent.on_request(&request).await)
+                })
+            })
+            .collect();
+        let mut statuses = Vec::new();
+        for task in tasks {
+            statuses.push(task.await.unwrap());
         }
+
+        assert_eq!(statuses.iter().filter(|&&s| s == 200).count(), 1);
+        assert_eq!(statuses.iter().filter(|&&s| s == 404).count(), 49);
+        assert_eq!(agent.match_count("hello").await, Some(1));
     }
 
-    fn metrics_report(&self) -> Option<MetricsReport> {
-        let mut report = MetricsReport::new("mock-server", 10_000);
+    #[tokio::test]
+    async fn test_har_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("capture.har");
+        let mut config = test_config();
+        config.settings.har_output = Some(path.clone());
+        let agent = MockServerAgent::new(config);
 
-        // Add counter metrics
-        report.counters.push(CounterMetric::new(
-            "mock_server_requests_total",
-            self.total_requests(),
-        ));
+        agent
+            .on_request(&make_request(
+                "GET",
+                "/hello?lang=en",
+                vec![("host", "api.test")],
+                None,
+            ))
+            .await;
+        agent
+            .on_request(&make_request("GET", "/slow", vec![], None))
+            .await;
+        // Unmatched requests aren't captured
+        agent
+            .on_request(&make_request("GET", "/missing", vec![], None))
+            .await;
 
-        report.counters.push(CounterMetric::new(
-            "mock_server_requests_matched_total",
-            self.total_matched(),
-        ));
+        // Entries are buffered until flushed
+        assert!(!path.exists());
+        agent.flush_har().await;
 
-        report.counters.push(CounterMetric::new(
-            "mock_server_requests_unmatched_total",
-            self.total_unmatched(),
-        ));
+        let har: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(har["log"]["version"], "1.2");
+        let entries = har["log"]["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
 
-        // Add gauge metrics
-        report.gauges.push(GaugeMetric::new(
-            "mock_server_stubs_configured",
-            self.config.stubs.len() as f64,
-        ));
+        let hello = &entries[0];
+        assert_eq!(hello["_stubId"], "hello");
+        assert_eq!(hello["request"]["method"], "GET");
+        assert_eq!(hello["request"]["url"], "http://api.test/hello?lang=en");
+        assert_eq!(
+            hello["request"]["queryString"][0],
+            serde_json::json!({ "name": "lang", "value": "en" })
+        );
+        assert_eq!(hello["response"]["status"], 200);
+        assert_eq!(hello["response"]["content"]["text"], "Hello, World!");
+        assert!(hello["startedDateTime"].is_string());
 
-        report.gauges.push(GaugeMetric::new(
-            "mock_server_stubs_enabled",
-            self.config.stubs.iter().filter(|s| s.enabled).count() as f64,
-        ));
+        let slow = &entries[1];
+        assert!(slow["timings"]["wait"].as_f64().unwrap() >= 100.0);
+        assert_eq!(slow["time"], slow["timings"]["wait"]);
 
-        report.gauges.push(GaugeMetric::new(
-            "mock_server_agent_draining",
-            if self.is_draining() { 1.0 } else { 0.0 },
+        // Draining flushes what's left onto the archive
+        agent
+            .on_request(&make_request("GET", "/hello", vec![], None))
+            .await;
+        agent.on_drain(1000, DrainReason::Manual).await;
+        let har: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(har["log"]["entries"].as_array().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_request_body_matching() {
+        let mut config = MockServerConfig {
+            stubs: vec![yaml_stub(
+                r#"
+id: admin-signup
+request:
+  method: [POST]
+  path: { type: exact, value: /signup }
+  body:
+    type: json_path
+    expressions:
+      $.role: admin
+response:
+  status: 201
+"#,
+            )],
+            ..Default::default()
+        };
+        config.settings.max_body_bytes = 64;
+        let agent = MockServerAgent::new(config);
+        let caps = agent.capabilities();
+        assert!(caps.supported_events.contains(&EventType::RequestBodyChunk));
+        assert_eq!(caps.limits.max_body_size, 64);
+
+        // Headers alone defer the decision until the body arrives
+        let body = br#"{"role": "admin"}"#;
+        let length = body.len().to_string();
+        let headers = make_request(
+            "POST",
+            "/signup",
+            vec![("content-length", length.as_str())],
+            None,
+        );
+        let deferred = agent.on_request(&headers).await.build();
+        assert!(deferred.needs_more);
+        assert!(matches!(deferred.decision, ProtocolDecision::Allow));
+        assert_eq!(agent.total_requests(), 0);
+
+        let with_body = headers.clone().with_body(body.to_vec());
+        assert_eq!(block_status(agent.on_request_body(&with_body).await), 201);
+        assert_eq!(agent.match_count("admin-signup").await, Some(1));
+
+        // Bodies beyond max_body_bytes are matched on their first bytes only
+        let padded = format!(r#"{{"pad": "{}", "role": "admin"}}"#, "x".repeat(64));
+        let oversized = headers.with_body(padded.into_bytes());
+        let decision = agent.on_request_body(&oversized).await.build();
+        assert!(!matches!(
+            decision.decision,
+            ProtocolDecision::Block { status: 201, .. }
         ));
 
-        Some(report)
+        // Requests without a body are answered straight away
+        let bodiless = make_request("POST", "/signup", vec![], None);
+        assert!(!agent.on_request(&bodiless).await.build().needs_more);
     }
 
-    async fn on_shutdown(&self, reason: ShutdownReason, grace_period_ms: u64) {
-        info!(
-            reason = ?reason,
-            grace_period_ms = grace_period_ms,
-            "Mock server agent shutdown requested"
+    #[tokio::test]
+    async fn test_lookup_body() {
+        let stub = yaml_stub(
+            r#"
+id: users
+request:
+  path: { type: template, template: "/users/{id}" }
+response:
+  body:
+    type: lookup
+    key: "{{path.id}}"
+    table:
+      "1": { name: Alice }
+      "2": { name: Bob, seen: "{{method}}" }
+    default: { error: unknown user }
+"#,
         );
-        // Set draining flag to stop mocking new requests
-        self.draining.store(true, Ordering::SeqCst);
-    }
+        let agent = MockServerAgent::new(MockServerConfig {
+            stubs: vec![stub],
+            ..Default::default()
+        });
 
-    async fn on_drain(&self, duration_ms: u64, reason: DrainReason) {
-        warn!(
-            reason = ?reason,
-            duration_ms = duration_ms,
-            "Mock server agent drain requested - stopping stub matching"
+        let body = |decision: Decision| match decision.build().decision {
+            ProtocolDecision::Block { body, .. } => {
+                serde_json::from_str::<serde_json::Value>(&body.unwrap()).unwrap()
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        };
+        let get = |path: &'static str| make_request("GET", path, vec![], None);
+
+        assert_eq!(
+            body(agent.on_request(&get("/users/1")).await),
+            serde_json::json!({ "name": "Alice" })
+        );
+        assert_eq!(
+            body(agent.on_request(&get("/users/2")).await),
+            serde_json::json!({ "name": "Bob", "seen": "GET" })
+        );
+        assert_eq!(
+            body(agent.on_request(&get("/users/3")).await),
+            serde_json::json!({ "error": "unknown user" })
         );
-        self.draining.store(true, Ordering::SeqCst);
     }
 
-    async fn on_stream_closed(&self) {
-        debug!("gRPC stream closed");
-    }
-}
+    #[tokio::test]
+    async fn test_templated_status_and_headers() {
+        let stub = yaml_stub(
+            r#"
+id: create
+request:
+  path: { type: template, template: "/users/{id}" }
+response:
+  template: true
+  status: "{{default query.force_status '201'}}"
+  headers:
+    Location: "/users/{{path.id}}"
+    X-Trace: "{{headers.x-trace}}"
+"#,
+        );
+        let mut config = MockServerConfig {
+            stubs: vec![stub],
+            ..Default::default()
+        };
+        config.settings.template_status_fallback = 502;
+        let agent = &MockServerAgent::new(config);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let response = |uri: &'static str, headers| async move {
+            match agent
+                .on_request(&make_request("POST", uri, headers, None))
+                .await
+                .build()
+                .decision
+            {
+                ProtocolDecision::Block {
+                    status, headers, ..
+                } => (status, headers.unwrap()),
+                other => panic!("Expected block decision, got {:?}", other),
+            }
+        };
 
-    fn test_config() -> MockServerConfig {
-        let yaml = r#"
-stubs:
-  - id: hello
-    request:
-      method: [GET]
-      path:
-        type: exact
-        value: /hello
-    response:
-      status: 200
-      body:
-        type: text
-        content: "Hello, World!"
+        let (status, headers) = response("/users/7", vec![("x-trace", "abc")]).await;
+        assert_eq!(status, 201);
+        assert_eq!(headers["Location"], "/users/7");
+        assert_eq!(headers["X-Trace"], "abc");
 
-  - id: user-by-id
-    request:
-      method: [GET]
-      path:
-        type: template
-        template: /users/{id}
-    response:
-      status: 200
-      template: true
-      body:
-        type: json
-        content:
-          id: "{{path.id}}"
-          name: "User {{path.id}}"
+        // Headers that render empty are left out
+        let (status, headers) = response("/users/7?force_status=409", vec![]).await;
+        assert_eq!(status, 409);
+        assert!(!headers.contains_key("X-Trace"));
 
-  - id: error-endpoint
-    request:
-      path:
-        type: exact
-        value: /error
-    response:
-      status: 200
-    fault:
-      type: error
-      status: 500
-      message: "Internal Server Error"
+        // Statuses that don't render to a valid code use the fallback
+        assert_eq!(response("/users/7?force_status=abc", vec![]).await.0, 502);
+        assert_eq!(response("/users/7?force_status=700", vec![]).await.0, 502);
+    }
 
-  - id: delayed-endpoint
+    #[tokio::test]
+    async fn test_multi_value_headers() {
+        let config: MockServerConfig = serde_yaml::from_str(
+            r#"
+stubs:
+  - id: login
     request:
-      path:
-        type: exact
-        value: /slow
+      path: { type: exact, value: /login }
     response:
-      status: 200
-      body:
-        type: text
-        content: "Delayed response"
-    delay:
-      fixed_ms: 100
+      headers:
+        Set-Cookie: [session=abc, theme=dark]
+        Cache-Control: [no-store, private]
+default_response:
+  status: 404
+  headers:
+    Vary: [Accept, Origin]
+"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        let agent = MockServerAgent::new(config);
 
-settings:
-  passthrough_unmatched: false
-"#;
-        serde_yaml::from_str(yaml).unwrap()
+        let headers = |decision: Decision| match decision.build().decision {
+            ProtocolDecision::Block { headers, .. } => headers.unwrap(),
+            other => panic!("Expected block decision, got {:?}", other),
+        };
+
+        let login = make_request("GET", "/login", vec![], None);
+        let decision = agent.on_request(&login).await;
+        let repeated = decision.clone().build().audit.custom[REPEATED_HEADERS].clone();
+        assert_eq!(
+            repeated,
+            serde_json::json!({ "Set-Cookie": ["session=abc", "theme=dark"] })
+        );
+        let sent = headers(decision);
+        assert_eq!(sent["Set-Cookie"], "session=abc");
+        assert_eq!(sent["Cache-Control"], "no-store, private");
+
+        let unmatched = make_request("GET", "/missing", vec![], None);
+        let sent = headers(agent.on_request(&unmatched).await);
+        assert_eq!(sent["Vary"], "Accept, Origin");
     }
 
-    #[test]
-    fn test_agent_creation() {
-        let config = test_config();
+    #[tokio::test]
+    async fn test_global_headers() {
+        let mut config = test_config();
+        config.settings.global_headers = serde_yaml::from_str(
+            r#"
+X-Mock: "true"
+Access-Control-Allow-Origin: "*"
+Content-Type: text/html
+"#,
+        )
+        .unwrap();
+        config.stubs[0]
+            .response
+            .headers
+            .insert("access-control-allow-origin".into(), "https://app".into());
         let agent = MockServerAgent::new(config);
-        assert_eq!(agent.config.stubs.len(), 4);
+
+        let headers = |decision: Decision| match decision.build().decision {
+            ProtocolDecision::Block { headers, .. } => headers.unwrap(),
+            other => panic!("Expected block decision, got {:?}", other),
+        };
+
+        // Stub headers win, and Content-Type still comes from the body
+        let hello = make_request("GET", "/hello", vec![], None);
+        let sent = headers(agent.on_request(&hello).await);
+        assert_eq!(sent["X-Mock"], "true");
+        assert_eq!(sent["access-control-allow-origin"], "https://app");
+        assert!(!sent.contains_key("Access-Control-Allow-Origin"));
+        assert_eq!(sent["Content-Type"], "text/plain");
+
+        // Injected faults and the default response get them too
+        for path in ["/error", "/missing"] {
+            let request = make_request("GET", path, vec![], None);
+            let sent = headers(agent.on_request(&request).await);
+            assert_eq!(sent["X-Mock"], "true", "{}", path);
+            assert_eq!(sent["Access-Control-Allow-Origin"], "*", "{}", path);
+        }
     }
 
     #[tokio::test]
-    async fn test_simple_match() {
-        let config = test_config();
+    async fn test_multipart_body_matching() {
+        let config = MockServerConfig {
+            stubs: vec![yaml_stub(
+                r#"
+id: invoice-upload
+request:
+  method: [POST]
+  path: { type: exact, value: /upload }
+  body:
+    type: multipart
+    parts:
+      file: { file: true, content_type: application/pdf, max_size: 1024 }
+      description:
+        value: { type: exact, value: invoice }
+response:
+  status: 201
+  template: true
+  body: { type: text, content: "stored {{form.description}}" }
+"#,
+            )],
+            ..Default::default()
+        };
         let agent = MockServerAgent::new(config);
+        let upload = |description: &str| {
+            let body = format!(
+                "--b1\r\n\
+                 Content-Disposition: form-data; name=\"description\"\r\n\r\n\
+                 {}\r\n\
+                 --b1\r\n\
+                 Content-Disposition: form-data; name=\"file\"; filename=\"a.pdf\"\r\n\
+                 Content-Type: application/pdf\r\n\r\n\
+                 %PDF-1.4\r\n\
+                 --b1--\r\n",
+                description
+            );
+            make_request(
+                "POST",
+                "/upload",
+                vec![("content-type", "multipart/form-data; boundary=b1")],
+                Some(body.as_bytes()),
+            )
+        };
 
-        // Create a mock request (we'll test the matcher directly)
-        let headers = HashMap::new();
-        let match_result =
-            agent
-                .matcher
-                .find_match(&agent.config.stubs, "GET", "/hello", None, &headers, None);
-
-        assert!(match_result.is_some());
-        assert_eq!(match_result.unwrap().stub.id, "hello");
+        let decision = agent.on_request(&upload("invoice")).await.build();
+        match decision.decision {
+            ProtocolDecision::Block { status, body, .. } => {
+                assert_eq!(status, 201);
+                assert_eq!(body.as_deref(), Some("stored invoice"));
+            }
+            other => panic!("Expected block decision, got {:?}", other),
+        }
+        assert_ne!(
+            block_status(agent.on_request(&upload("receipt")).await),
+            201
+        );
     }
 
     #[tokio::test]
-    async fn test_template_match() {
-        let config = test_config();
+    async fn test_auto_etag_conditional_get() {
+        let config = MockServerConfig {
+            stubs: vec![yaml_stub(
+                r#"
+id: profile
+request:
+  path: { type: template, template: "/profiles/{name}" }
+response:
+  template: true
+  auto_etag: true
+  body: { type: text, content: "Profile of {{path.name}}" }
+"#,
+            )],
+            ..Default::default()
+        };
         let agent = MockServerAgent::new(config);
 
-        let headers = HashMap::new();
-        let match_result = agent.matcher.find_match(
-            &agent.config.stubs,
+        let first = agent
+            .on_request(&make_request("GET", "/profiles/alice", vec![], None))
+            .await
+            .build();
+        let ProtocolDecision::Block {
+            status, headers, ..
+        } = first.decision
+        else {
+            panic!("Expected block decision");
+        };
+        assert_eq!(status, 200);
+        let etag = headers.unwrap()["ETag"].clone();
+        assert!(etag.starts_with('"'));
+
+        let conditional = make_request(
             "GET",
-            "/users/123",
+            "/profiles/alice",
+            vec![("if-none-match", etag.as_str())],
             None,
-            &headers,
+        );
+        let decision = agent.on_request(&conditional).await.build();
+        let ProtocolDecision::Block { status, body, .. } = decision.decision else {
+            panic!("Expected block decision");
+        };
+        assert_eq!(status, 304);
+        assert!(body.is_none());
+
+        // The rendered body differs, and so does its ETag
+        let other = make_request(
+            "GET",
+            "/profiles/bob",
+            vec![("if-none-match", etag.as_str())],
             None,
         );
+        assert_eq!(block_status(agent.on_request(&other).await), 200);
+    }
 
-        assert!(match_result.is_some());
-        let result = match_result.unwrap();
-        assert_eq!(result.stub.id, "user-by-id");
+    fn yaml_stub(yaml: &str) -> StubDefinition {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_add_stub() {
+        let agent = MockServerAgent::new(test_config());
+        let stub = yaml_stub(
+            r#"
+id: added
+request:
+  path: { type: exact, value: /added }
+response:
+  status: 201
+"#,
+        );
+
+        agent.add_stub(stub.clone()).await.unwrap();
+        let request = make_request("GET", "/added", vec![], None);
+        assert_eq!(block_status(agent.on_request(&request).await), 201);
         assert_eq!(
-            result.context.path_params.get("id"),
-            Some(&"123".to_string())
+            agent.match_counts.read().await["added"].load(Ordering::Relaxed),
+            1
         );
+
+        let err = agent.add_stub(stub).await.unwrap_err();
+        assert!(err.to_string().contains("already exists"));
+        assert_eq!(agent.state().config.stubs.len(), 5);
     }
 
     #[tokio::test]
-    async fn test_no_match() {
-        let config = test_config();
-        let agent = MockServerAgent::new(config);
+    async fn test_remove_stub() {
+        let agent = MockServerAgent::new(test_config());
 
-        let headers = HashMap::new();
-        let match_result = agent.matcher.find_match(
-            &agent.config.stubs,
-            "GET",
-            "/nonexistent",
-            None,
-            &headers,
-            None,
-        );
+        let removed = agent.remove_stub("hello").await.unwrap();
+        assert_eq!(removed.id, "hello");
+        assert!(!agent.match_counts.read().await.contains_key("hello"));
 
-        assert!(match_result.is_none());
+        let request = make_request("GET", "/hello", vec![], None);
+        assert_eq!(block_status(agent.on_request(&request).await), 404);
+        assert!(agent.remove_stub("hello").await.is_err());
     }
 
     #[tokio::test]
-    async fn test_max_matches() {
-        let mut config = test_config();
-        config.stubs[0].max_matches = 2;
+    async fn test_replace_stub() {
+        let agent = MockServerAgent::new(test_config());
+        let request = make_request("GET", "/hello", vec![], None);
+        agent.on_request(&request).await;
 
-        let agent = MockServerAgent::new(config);
+        let stub = yaml_stub(
+            r#"
+id: hello
+request:
+  path: { type: exact, value: /hello }
+response:
+  status: 418
+"#,
+        );
+        agent.replace_stub("hello", stub).await.unwrap();
+        assert_eq!(
+            agent.match_counts.read().await["hello"].load(Ordering::Relaxed),
+            0
+        );
+        assert_eq!(block_status(agent.on_request(&request).await), 418);
+    }
 
-        // First two matches should work
-        for _ in 0..2 {
-            let headers = HashMap::new();
-            let match_result = agent.matcher.find_match(
-                &agent.config.stubs,
-                "GET",
-                "/hello",
-                None,
-                &headers,
-                None,
-            );
-            assert!(match_result.is_some());
-            agent.increment_match_count("hello").await;
-        }
+    #[tokio::test]
+    async fn test_remove_stub_mid_match() {
+        let agent = Arc::new(MockServerAgent::new(test_config()));
 
-        // Third match - stub should be exhausted
-        assert!(agent.is_stub_exhausted(&agent.config.stubs[0]).await);
+        let in_flight = {
+            let agent = agent.clone();
+            tokio::spawn(async move {
+                let request = make_request("GET", "/slow", vec![], None);
+                block_status(agent.on_request(&request).await)
+            })
+        };
+
+        // The delayed stub is sleeping when it gets removed
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        agent.remove_stub("delayed-endpoint").await.unwrap();
+
+        assert_eq!(in_flight.await.unwrap(), 200);
     }
 
     #[test]
@@ -762,6 +5533,55 @@ settings:
         assert_eq!(caps.features.concurrent_requests, 100);
     }
 
+    #[tokio::test]
+    async fn test_health_detail() {
+        let mut config = test_config();
+        config.stubs[0].max_matches = 1;
+        config.settings.strict_templates = true;
+        let agent = MockServerAgent::new(config);
+
+        let detail = |agent: &MockServerAgent| -> serde_json::Value {
+            serde_json::from_str(&agent.health_status().message.unwrap()).unwrap()
+        };
+        assert_eq!(detail(&agent)["stubs_exhausted"], 0);
+
+        let request = make_request("GET", "/hello", vec![], None);
+        agent.on_request(&request).await;
+        // The stub is configured to answer 500; that's not an agent error
+        let error = make_request("GET", "/error", vec![], None);
+        assert_eq!(block_status(agent.on_request(&error).await), 500);
+
+        let health = agent.health_status();
+        assert_eq!(health.load.as_ref().unwrap().requests_processed, 2);
+        let report = detail(&agent);
+        assert_eq!(report["stubs"], 4);
+        assert_eq!(report["stubs_exhausted"], 1);
+        assert_eq!(report["error_rate"], 0.0);
+
+        // Mid-update, the last count is reported
+        let counts = agent.match_counts.write().await;
+        assert_eq!(detail(&agent)["stubs_exhausted"], 1);
+        drop(counts);
+
+        // A stub that fails to render is
+        agent
+            .add_stub(yaml_stub(
+                r#"
+id: broken
+request:
+  path: { type: exact, value: /broken }
+response:
+  template: true
+  body: { type: text, content: "{{#if path.id}}unclosed" }
+"#,
+            ))
+            .await
+            .unwrap();
+        let broken = make_request("GET", "/broken", vec![], None);
+        assert_eq!(block_status(agent.on_request(&broken).await), 500);
+        assert_eq!(detail(&agent)["error_rate"], 1.0 / 3.0);
+    }
+
     #[test]
     fn test_v2_health_status() {
         let config = test_config();
@@ -812,6 +5632,32 @@ settings:
         assert!(agent.is_draining());
     }
 
+    #[tokio::test]
+    async fn test_require_all_matched() {
+        let mut config = test_config();
+        config.settings.require_all_matched = true;
+        let agent = MockServerAgent::new(config);
+        agent
+            .on_request(&make_request("GET", "/hello", vec![], None))
+            .await;
+        agent
+            .on_request(&make_request("GET", "/users/1", vec![], None))
+            .await;
+        agent
+            .on_request(&make_request("GET", "/error", vec![], None))
+            .await;
+
+        assert_eq!(agent.unmatched_stubs().await, vec!["delayed-endpoint"]);
+        assert!(!agent.coverage_failed());
+        agent.on_shutdown(ShutdownReason::Graceful, 30000).await;
+        assert!(agent.coverage_failed());
+
+        // Without the setting, uncovered stubs don't fail the run
+        let agent = MockServerAgent::new(test_config());
+        agent.on_drain(1000, DrainReason::Manual).await;
+        assert!(!agent.coverage_failed());
+    }
+
     #[test]
     fn test_request_counters() {
         let config = test_config();
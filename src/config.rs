@@ -4,7 +4,14 @@
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Timeout for each attempt when fetching configuration from a URL.
+const CONFIG_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pause before retrying a failed configuration fetch.
+const CONFIG_FETCH_RETRY_DELAY: Duration = Duration::from_millis(500);
 
 /// Main configuration for the Mock Server agent.
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -32,14 +39,78 @@ impl MockServerConfig {
         Ok(config)
     }
 
+    /// Load configuration from an `http(s)://` URL.
+    ///
+    /// Each attempt is bounded by a timeout and a failed fetch is retried once.
+    pub async fn from_url(url: &str) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(CONFIG_FETCH_TIMEOUT)
+            .build()?;
+
+        let content = match fetch_text(&client, url).await {
+            Ok(content) => content,
+            Err(e) => {
+                tracing::warn!(url = %url, error = %e, "Configuration fetch failed, retrying");
+                tokio::time::sleep(CONFIG_FETCH_RETRY_DELAY).await;
+                fetch_text(&client, url).await.map_err(|e| {
+                    anyhow::anyhow!("Failed to fetch configuration from {}: {}", url, e)
+                })?
+            }
+        };
+
+        let config: Self = serde_yaml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Invalid configuration from {}: {}", url, e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
     /// Validate the configuration.
     pub fn validate(&self) -> anyhow::Result<()> {
         for (i, stub) in self.stubs.iter().enumerate() {
             stub.validate()
                 .map_err(|e| anyhow::anyhow!("Stub {}: {}", i, e))?;
+
+            for required in &stub.requires_matched {
+                if !self.stubs.iter().any(|s| &s.id == required) {
+                    anyhow::bail!("Stub {}: requires unknown stub '{}'", i, required);
+                }
+            }
         }
-        Ok(())
+        if let Some(default) = &self.default_response {
+            default
+                .validate()
+                .map_err(|e| anyhow::anyhow!("default_response: {}", e))?;
+        }
+        self.settings.validate()
     }
+
+    /// Whether any stub matches on the request body, so bodies must be
+    /// requested from the proxy.
+    pub fn matches_request_bodies(&self) -> bool {
+        self.stubs
+            .iter()
+            .any(|s| s.request.matches_body() || s.switch.is_some())
+    }
+
+    /// Whether some stub depends on the status last served to a session.
+    pub fn tracks_statuses(&self) -> bool {
+        self.stubs.iter().any(|s| s.request.after_status.is_some())
+    }
+
+    /// Whether some stub depends on the stubs a session has matched.
+    pub fn tracks_dependencies(&self) -> bool {
+        self.stubs.iter().any(|s| !s.requires_matched.is_empty())
+    }
+}
+
+/// Fetch a URL as text, treating non-success statuses as errors.
+async fn fetch_text(client: &reqwest::Client, url: &str) -> anyhow::Result<String> {
+    let response = client.get(url).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        anyhow::bail!("server returned {}", status);
+    }
+    Ok(response.text().await?)
 }
 
 /// A single stub definition.
@@ -56,9 +127,49 @@ pub struct StubDefinition {
     /// Request matcher
     pub request: RequestMatcher,
 
+    /// Let the request through and only mock it when the upstream response
+    /// body matches
+    #[serde(default)]
+    pub response_match: Option<BodyMatcher>,
+
     /// Response to return
+    #[serde(default)]
     pub response: ResponseDefinition,
 
+    /// Responses returned in turn on successive matches (overrides `response`)
+    #[serde(default)]
+    pub responses: Vec<ResponseDefinition>,
+
+    /// How an entry of `responses` is picked on each match (default `wrap`)
+    #[serde(default)]
+    pub sequence_mode: Option<SequenceMode>,
+
+    /// Same as `sequence_mode`, which it cannot be combined with
+    #[serde(default)]
+    pub selection: Option<SequenceMode>,
+
+    /// Responses picked at random by `weight` on each match; shorthand for
+    /// `responses` with `selection: random`
+    #[serde(default)]
+    pub weighted_responses: Vec<ResponseDefinition>,
+
+    /// Request value hashed to pick an A/B bucket
+    #[serde(default)]
+    pub ab_key: Option<AbKey>,
+
+    /// A/B variants with percentage splits (must total 100)
+    #[serde(default)]
+    pub ab_buckets: Vec<AbBucket>,
+
+    /// Response picked by a value in the JSON request body
+    #[serde(default)]
+    pub switch: Option<BodySwitch>,
+
+    /// Fraction of matching requests (0.0-1.0) this stub claims; the rest
+    /// fall through to lower-priority stubs
+    #[serde(default)]
+    pub match_probability: Option<f64>,
+
     /// Priority (higher = matched first)
     #[serde(default)]
     pub priority: i32,
@@ -71,15 +182,43 @@ pub struct StubDefinition {
     #[serde(default)]
     pub max_matches: u32,
 
+    /// Stub IDs that must have matched earlier in the same session (see
+    /// `settings.session_key`) before this stub can match
+    #[serde(default)]
+    pub requires_matched: Vec<String>,
+
+    /// Scenario this stub belongs to
+    #[serde(default)]
+    pub scenario: Option<String>,
+
+    /// Scenario state required for this stub to match
+    #[serde(default)]
+    pub required_state: Option<String>,
+
+    /// Scenario state to move to when this stub matches
+    #[serde(default)]
+    pub new_state: Option<String>,
+
     /// Latency simulation
     #[serde(default)]
     pub delay: Option<DelayConfig>,
 
+    /// Extra latency that grows with the number of earlier matches
+    #[serde(default)]
+    pub ramp: Option<RampConfig>,
+
+    /// Hold matched requests until an event fires or a timeout passes
+    #[serde(default)]
+    pub long_poll: Option<LongPollConfig>,
+
     /// Failure simulation
     #[serde(default)]
     pub fault: Option<FaultConfig>,
 }
 
+/// State every scenario starts in.
+pub const SCENARIO_STARTED: &str = "Started";
+
 fn default_true() -> bool {
     true
 }
@@ -90,15 +229,261 @@ impl StubDefinition {
         if self.id.is_empty() {
             anyhow::bail!("Stub id cannot be empty");
         }
+        if self.scenario.is_none() && (self.required_state.is_some() || self.new_state.is_some()) {
+            anyhow::bail!("required_state and new_state need a scenario");
+        }
         self.request.validate()?;
+        if let Some(response_match) = &self.response_match {
+            response_match.validate()?;
+        }
         self.response.validate()?;
+        if let Some(fault) = &self.fault {
+            fault.validate()?;
+        }
+        if !self.responses.is_empty() && !self.weighted_responses.is_empty() {
+            anyhow::bail!("responses and weighted_responses cannot both be set");
+        }
+        if self.sequence_mode.is_some() && self.selection.is_some() {
+            anyhow::bail!("sequence_mode and selection cannot both be set");
+        }
+        if !self.weighted_responses.is_empty()
+            && self
+                .sequence_mode
+                .or(self.selection)
+                .is_some_and(|mode| mode != SequenceMode::Random)
+        {
+            anyhow::bail!("weighted_responses are always picked at random");
+        }
+        let responses = self.sequence_responses();
+        for response in responses {
+            response.validate()?;
+        }
+        if self.selection() == SequenceMode::Random
+            && !responses.is_empty()
+            && responses.iter().map(|r| r.weight as u64).sum::<u64>() == 0
+        {
+            anyhow::bail!("Response weights must sum to more than 0");
+        }
+        if !self.ab_buckets.is_empty() {
+            if self.ab_key.is_none() {
+                anyhow::bail!("ab_buckets requires an ab_key");
+            }
+            let total: u32 = self.ab_buckets.iter().map(|b| b.percent as u32).sum();
+            if total != 100 {
+                anyhow::bail!("ab_buckets percentages must total 100, got {}", total);
+            }
+            for bucket in &self.ab_buckets {
+                bucket.response.validate()?;
+            }
+        }
+        if let Some(switch) = &self.switch {
+            switch.validate()?;
+        }
+        if let Some(long_poll) = &self.long_poll {
+            long_poll.validate()?;
+        }
+        if let Some(p) = self.match_probability {
+            if !(0.0..=1.0).contains(&p) {
+                anyhow::bail!("match_probability must be between 0.0 and 1.0, got {}", p);
+            }
+        }
+        if self.max_matches > 0
+            && self.selection() != SequenceMode::Random
+            && (self.max_matches as usize) < responses.len()
+        {
+            anyhow::bail!(
+                "max_matches ({}) is less than the number of responses ({}), later responses are unreachable",
+                self.max_matches,
+                responses.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Every template string in the stub's responses, for precompiling.
+    pub fn template_strings(&self) -> Vec<&str> {
+        let buckets = self.ab_buckets.iter().map(|bucket| &bucket.response);
+        let switch = self
+            .switch
+            .iter()
+            .flat_map(|switch| switch.cases.values().chain(switch.default.as_ref()));
+        std::iter::once(&self.response)
+            .chain(&self.responses)
+            .chain(&self.weighted_responses)
+            .chain(buckets)
+            .chain(switch)
+            .flat_map(ResponseDefinition::template_strings)
+            .collect()
+    }
+
+    /// The responses served in place of `response`, if any.
+    pub fn sequence_responses(&self) -> &[ResponseDefinition] {
+        if self.weighted_responses.is_empty() {
+            &self.responses
+        } else {
+            &self.weighted_responses
+        }
+    }
+
+    /// How an entry of [`Self::sequence_responses`] is picked.
+    pub fn selection(&self) -> SequenceMode {
+        if self.weighted_responses.is_empty() {
+            self.sequence_mode.or(self.selection).unwrap_or_default()
+        } else {
+            SequenceMode::Random
+        }
+    }
+
+    /// Index into `responses` for the given match number (1-based), or `None`
+    /// when no sequence is configured.
+    ///
+    /// `roll` is a uniform value in `[0, 1)` and is only used by
+    /// [`SequenceMode::Random`].
+    pub fn response_index(&self, match_number: u32, roll: f64) -> Option<usize> {
+        let responses = self.sequence_responses();
+        if responses.is_empty() {
+            return None;
+        }
+
+        let len = responses.len();
+        let n = match_number.saturating_sub(1) as usize;
+        let index = match self.selection() {
+            SequenceMode::Wrap => n % len,
+            SequenceMode::Clamp => n.min(len - 1),
+            SequenceMode::Random => {
+                let total: u64 = responses.iter().map(|r| r.weight as u64).sum();
+                let point = (roll * total as f64) as u64;
+                let mut upper = 0;
+                responses
+                    .iter()
+                    .position(|r| {
+                        upper += r.weight as u64;
+                        point < upper
+                    })
+                    .unwrap_or(len - 1)
+            }
+        };
+        Some(index)
+    }
+
+    /// Response for the given match number, see [`Self::response_index`].
+    pub fn response_for(&self, match_number: u32, roll: f64) -> &ResponseDefinition {
+        match self.response_index(match_number, roll) {
+            Some(index) => &self.sequence_responses()[index],
+            None => &self.response,
+        }
+    }
+
+    /// A/B variant for the given bucketing key, if buckets are configured.
+    ///
+    /// The same key always lands in the same bucket.
+    pub fn ab_response(&self, key: &str) -> Option<&ResponseDefinition> {
+        let point = (ab_hash(key) % 100) as u32;
+        let mut upper = 0;
+        for bucket in &self.ab_buckets {
+            upper += bucket.percent as u32;
+            if point < upper {
+                return Some(&bucket.response);
+            }
+        }
+        None
+    }
+}
+
+/// Responses keyed by the value at a JSON path in the request body.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BodySwitch {
+    /// JSONPath into the request body, e.g. `$.type`
+    pub key: String,
+
+    /// Response per value; strings are compared as they are, other values
+    /// as JSON (`"42"`, `"true"`)
+    pub cases: HashMap<String, ResponseDefinition>,
+
+    /// Response when the value is missing or has no case (default: the
+    /// stub's own response selection)
+    #[serde(default)]
+    pub default: Option<ResponseDefinition>,
+}
+
+impl BodySwitch {
+    fn validate(&self) -> anyhow::Result<()> {
+        jsonpath_rust::JsonPath::try_from(self.key.as_str())
+            .map_err(|e| anyhow::anyhow!("Invalid switch key '{}': {}", self.key, e))?;
+        for response in self.cases.values().chain(&self.default) {
+            response.validate()?;
+        }
         Ok(())
     }
+
+    /// Response for the request body, if it picks one.
+    pub fn response_for(&self, body: Option<&[u8]>) -> Option<&ResponseDefinition> {
+        let value = body
+            .and_then(|body| serde_json::from_slice::<serde_json::Value>(body).ok())
+            .and_then(|json| {
+                let path = jsonpath_rust::JsonPath::try_from(self.key.as_str()).ok()?;
+                match path.find(&json) {
+                    serde_json::Value::Array(mut values) if !values.is_empty() => {
+                        Some(values.swap_remove(0))
+                    }
+                    _ => None,
+                }
+            });
+        let case = value.and_then(|value| match value {
+            serde_json::Value::String(text) => self.cases.get(&text),
+            other => self.cases.get(&other.to_string()),
+        });
+        case.or(self.default.as_ref())
+    }
 }
 
-/// Request matching configuration.
+/// Stable FNV-1a hash, so bucket assignment survives restarts and upgrades.
+fn ab_hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Where a per-client key (A/B bucket, session) is read from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AbKey {
+    /// Value of a request cookie
+    Cookie { name: String },
+    /// Value of a request header
+    Header { name: String },
+}
+
+/// A single A/B variant.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
+pub struct AbBucket {
+    /// Share of keys routed to this variant, in percent
+    pub percent: u8,
+
+    /// Response served to keys in this bucket
+    pub response: ResponseDefinition,
+}
+
+/// Behaviour once a response sequence is exhausted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SequenceMode {
+    /// Start again from the first response
+    #[default]
+    #[serde(alias = "loop")]
+    Wrap,
+    /// Keep returning the last response
+    #[serde(alias = "once_each_then_last")]
+    Clamp,
+    /// Pick a response at random, in proportion to its `weight`
+    Random,
+}
+
+/// Request matching configuration.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct RequestMatcher {
     /// HTTP method(s) to match (empty = any)
     #[serde(default)]
@@ -108,6 +493,18 @@ pub struct RequestMatcher {
     #[serde(default)]
     pub path: Option<PathMatcher>,
 
+    /// Host matching, against `Host` (or `:authority`) without its port
+    #[serde(default)]
+    pub host: Option<HostMatcher>,
+
+    /// Client address matching
+    #[serde(default)]
+    pub client_ip: Option<ClientIpMatcher>,
+
+    /// Client certificate matching, from headers set by the TLS terminator
+    #[serde(default)]
+    pub client_cert: Option<ClientCertMatcher>,
+
     /// Query parameter matching
     #[serde(default)]
     pub query: HashMap<String, QueryMatcher>,
@@ -119,16 +516,212 @@ pub struct RequestMatcher {
     /// Body matching
     #[serde(default)]
     pub body: Option<BodyMatcher>,
+
+    /// Claims of a JWT carried in a request header
+    #[serde(default)]
+    pub jwt: Option<JwtMatcher>,
+
+    /// Credentials of an `Authorization: Basic` header
+    #[serde(default)]
+    pub basic_auth: Option<BasicAuthMatcher>,
+
+    /// Match requests with more header values than this
+    #[serde(default)]
+    pub max_headers: Option<usize>,
+
+    /// Match requests whose headers take more bytes than this, counted as
+    /// `name: value\r\n` per value
+    #[serde(default)]
+    pub max_header_bytes: Option<usize>,
+
+    /// Only match once the last response served to the same session (see
+    /// `settings.session_key`) had this status
+    #[serde(default)]
+    pub after_status: Option<u16>,
+
+    /// Sub-matchers of which at least one must also match
+    #[serde(default)]
+    pub any_of: Vec<RequestMatcher>,
+
+    /// Sub-matchers that must all also match
+    #[serde(default)]
+    pub all_of: Vec<RequestMatcher>,
+
+    /// Sub-matcher that must not match
+    #[serde(default)]
+    pub not: Option<Box<RequestMatcher>>,
 }
 
+/// Deepest nesting of `any_of`/`all_of`/`not` request matchers allowed.
+pub const MAX_MATCHER_DEPTH: usize = 10;
+
 impl RequestMatcher {
     /// Validate the request matcher.
     pub fn validate(&self) -> anyhow::Result<()> {
+        self.validate_at(1)
+    }
+
+    fn validate_at(&self, depth: usize) -> anyhow::Result<()> {
+        if depth > MAX_MATCHER_DEPTH {
+            anyhow::bail!(
+                "Request matchers are nested more than {} deep",
+                MAX_MATCHER_DEPTH
+            );
+        }
         if let Some(path) = &self.path {
             path.validate()?;
         }
+        if let Some(host) = &self.host {
+            host.validate()?;
+        }
+        if let Some(client_ip) = &self.client_ip {
+            for range in &client_ip.ranges {
+                crate::matcher::parse_ip_range(range)?;
+            }
+        }
+        if let Some(client_cert) = &self.client_cert {
+            client_cert.validate()?;
+        }
+        for matcher in self.query.values() {
+            matcher.validate()?;
+        }
+        for matcher in self.headers.values() {
+            matcher.validate()?;
+        }
+        if let Some(body) = &self.body {
+            body.validate()?;
+        }
+        if let Some(jwt) = &self.jwt {
+            validate_json_paths(&jwt.claims)?;
+        }
+        for matcher in self.sub_matchers() {
+            if matcher.after_status.is_some() {
+                anyhow::bail!("after_status is only allowed on a stub's top-level request");
+            }
+            matcher.validate_at(depth + 1)?;
+        }
         Ok(())
     }
+
+    /// The `any_of`, `all_of` and `not` sub-matchers.
+    fn sub_matchers(&self) -> impl Iterator<Item = &RequestMatcher> {
+        self.any_of
+            .iter()
+            .chain(&self.all_of)
+            .chain(self.not.as_deref())
+    }
+
+    /// Whether this matcher or any of its sub-matchers looks at the body.
+    pub fn matches_body(&self) -> bool {
+        self.body.is_some() || self.sub_matchers().any(Self::matches_body)
+    }
+}
+
+/// Match the claims of a JWT. The signature is not checked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JwtMatcher {
+    /// Header carrying the token, with or without a `Bearer ` prefix
+    #[serde(default = "default_jwt_header")]
+    pub header: String,
+
+    /// Expected claims, compared like JSON path expectations (`null` only
+    /// requires the claim to be present)
+    #[serde(default)]
+    pub claims: HashMap<String, serde_json::Value>,
+}
+
+fn default_jwt_header() -> String {
+    "authorization".to_string()
+}
+
+/// Match the credentials of an `Authorization: Basic` header.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BasicAuthMatcher {
+    /// Expected username
+    pub username: String,
+
+    /// Expected password (unset = any)
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+/// Look up a digest algorithm for `BodyMatcher::Hash` by name.
+pub fn digest_algorithm(name: &str) -> anyhow::Result<&'static ring::digest::Algorithm> {
+    match name.to_ascii_lowercase().replace('-', "").as_str() {
+        "sha256" => Ok(&ring::digest::SHA256),
+        "sha384" => Ok(&ring::digest::SHA384),
+        "sha512" => Ok(&ring::digest::SHA512),
+        "sha1" => Ok(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY),
+        _ => anyhow::bail!("Unknown hash algorithm: {}", name),
+    }
+}
+
+fn validate_regex(pattern: &str) -> anyhow::Result<()> {
+    regex::Regex::new(pattern).map_err(|e| anyhow::anyhow!("Invalid regex: {}", e))?;
+    Ok(())
+}
+
+/// Check that every JSON path expectation uses a known operator.
+fn validate_json_paths(expressions: &HashMap<String, serde_json::Value>) -> anyhow::Result<()> {
+    for (path, expected) in expressions {
+        let (op, value) =
+            json_path_expectation(expected).map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+        if op == JsonPathOp::Regex {
+            let pattern = value
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("{}: regex value must be a string", path))?;
+            validate_regex(pattern).map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Comparison applied to the value a JSON path resolves to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonPathOp {
+    /// Equal to the expected value (the default for bare values)
+    Eq,
+    /// Greater than
+    Gt,
+    /// Greater than or equal
+    Gte,
+    /// Less than
+    Lt,
+    /// Less than or equal
+    Lte,
+    /// String contains a substring, or array contains an element
+    Contains,
+    /// String matches a regex
+    Regex,
+}
+
+/// Split a JSON path expectation into its operator and operand.
+///
+/// Bare values compare for equality; an object of the form
+/// `{ "op": "gt", "value": 18 }` selects a comparison operator.
+pub fn json_path_expectation(
+    expected: &serde_json::Value,
+) -> anyhow::Result<(JsonPathOp, &serde_json::Value)> {
+    let Some(obj) = expected.as_object() else {
+        return Ok((JsonPathOp::Eq, expected));
+    };
+    let (Some(op), Some(value), 2) = (obj.get("op"), obj.get("value"), obj.len()) else {
+        return Ok((JsonPathOp::Eq, expected));
+    };
+
+    let op = match op.as_str() {
+        Some("eq") => JsonPathOp::Eq,
+        Some("gt") => JsonPathOp::Gt,
+        Some("gte") => JsonPathOp::Gte,
+        Some("lt") => JsonPathOp::Lt,
+        Some("lte") => JsonPathOp::Lte,
+        Some("contains") => JsonPathOp::Contains,
+        Some("regex") => JsonPathOp::Regex,
+        _ => anyhow::bail!("Unknown JSON path operator: {}", op),
+    };
+    Ok((op, value))
 }
 
 /// Path matching configuration.
@@ -138,13 +731,20 @@ pub enum PathMatcher {
     /// Exact path match
     Exact { value: String },
     /// Path prefix match
-    Prefix { value: String },
+    Prefix {
+        value: String,
+        /// Only match at `/` boundaries, so `/api` matches `/api/x` but not `/apiv2`
+        #[serde(default)]
+        segment_boundary: bool,
+    },
     /// Regex pattern match
     Regex { pattern: String },
     /// Glob pattern match
     Glob { pattern: String },
     /// Path with parameters (e.g., /users/{id})
     Template { template: String },
+    /// Matches when `matcher` does not
+    Not { matcher: Box<PathMatcher> },
 }
 
 impl PathMatcher {
@@ -157,12 +757,116 @@ impl PathMatcher {
             PathMatcher::Glob { pattern } => {
                 globset::Glob::new(pattern).map_err(|e| anyhow::anyhow!("Invalid glob: {}", e))?;
             }
+            PathMatcher::Template { template } => {
+                crate::matcher::validate_path_template(template)?;
+            }
+            PathMatcher::Not { matcher } => matcher.validate()?,
             _ => {}
         }
         Ok(())
     }
 }
 
+/// Host matching. Hosts are compared in lowercase, without a port or a
+/// trailing dot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HostMatcher {
+    /// Exact host match
+    Exact { value: String },
+    /// Regex pattern match
+    Regex { pattern: String },
+    /// `*.example.com`: any subdomain of `example.com`, at any depth
+    Wildcard { pattern: String },
+}
+
+impl HostMatcher {
+    /// Validate the host matcher.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            HostMatcher::Regex { pattern } => {
+                regex::Regex::new(pattern).map_err(|e| anyhow::anyhow!("Invalid regex: {}", e))?;
+            }
+            HostMatcher::Wildcard { pattern } => {
+                let valid = pattern
+                    .strip_prefix("*.")
+                    .is_some_and(|domain| !domain.is_empty() && !domain.contains('*'));
+                if !valid {
+                    anyhow::bail!(
+                        "Invalid host wildcard '{}': expected '*.' followed by a domain",
+                        pattern
+                    );
+                }
+            }
+            HostMatcher::Exact { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// Match the client's address against IPs and CIDR blocks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientIpMatcher {
+    /// Addresses (`10.0.0.5`) and CIDR blocks (`10.0.0.0/8`, `2001:db8::/32`),
+    /// any of which may match
+    pub ranges: Vec<String>,
+
+    /// Header carrying a comma-separated address list (e.g. `X-Forwarded-For`)
+    /// to take the address from instead of the connection's peer
+    #[serde(default)]
+    pub from_header: Option<String>,
+
+    /// Position in `from_header`'s list; negative counts from the end, so
+    /// `-1` is the address the nearest proxy saw
+    #[serde(default)]
+    pub index: isize,
+}
+
+/// Match the client certificate of an mTLS connection.
+///
+/// The agent SDK doesn't expose certificates, so the subject and SANs come
+/// from headers the TLS-terminating proxy sets.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientCertMatcher {
+    /// Regex the subject's common name (`CN`) must match
+    #[serde(default)]
+    pub subject_cn: Option<String>,
+
+    /// Text one of the subject alternative names must contain
+    #[serde(default)]
+    pub san_contains: Option<String>,
+
+    /// Header carrying the subject, e.g. `CN=client,O=Example`
+    #[serde(default = "default_cert_subject_header")]
+    pub subject_header: String,
+
+    /// Header carrying the comma-separated SANs, e.g.
+    /// `DNS:client.example.com, URI:spiffe://example/client`
+    #[serde(default = "default_cert_san_header")]
+    pub san_header: String,
+}
+
+impl ClientCertMatcher {
+    /// Validate the client certificate matcher.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(pattern) = &self.subject_cn {
+            regex::Regex::new(pattern)
+                .map_err(|e| anyhow::anyhow!("Invalid subject_cn regex: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+fn default_cert_subject_header() -> String {
+    "x-client-cert-subject".to_string()
+}
+
+fn default_cert_san_header() -> String {
+    "x-client-cert-san".to_string()
+}
+
 /// Query parameter matching.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
@@ -175,6 +879,35 @@ pub enum QueryMatcher {
     Present,
     /// Parameter must be absent
     Absent,
+    /// Value must contain substring
+    Contains { value: String },
+    /// Comma-separated value must include the given item
+    CsvContains { value: String },
+    /// At least one of the parameter's repeated values must equal `value`
+    AnyValue { value: String },
+    /// The parameter must be repeated exactly `count` times
+    Count { count: usize },
+    /// The parameter's values must be exactly `values`, in any order
+    All { values: Vec<String> },
+    /// JSON path matching against the parameter value parsed as JSON
+    JsonPath {
+        /// JSON path expressions and expected values
+        expressions: HashMap<String, serde_json::Value>,
+    },
+    /// Matches when `matcher` does not
+    Not { matcher: Box<QueryMatcher> },
+}
+
+impl QueryMatcher {
+    /// Validate the query matcher.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            QueryMatcher::Regex { pattern } => validate_regex(pattern),
+            QueryMatcher::JsonPath { expressions } => validate_json_paths(expressions),
+            QueryMatcher::Not { matcher } => matcher.validate(),
+            _ => Ok(()),
+        }
+    }
 }
 
 /// Header matching.
@@ -182,15 +915,67 @@ pub enum QueryMatcher {
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum HeaderMatcher {
     /// Exact value match
-    Exact { value: String },
+    Exact {
+        value: String,
+        #[serde(default, skip_serializing_if = "HeaderValueMode::is_first")]
+        values: HeaderValueMode,
+    },
     /// Regex pattern match
-    Regex { pattern: String },
+    Regex {
+        pattern: String,
+        #[serde(default, skip_serializing_if = "HeaderValueMode::is_first")]
+        values: HeaderValueMode,
+    },
     /// Header must be present (any value)
     Present,
     /// Header must be absent
     Absent,
     /// Value must contain substring
-    Contains { value: String },
+    Contains {
+        value: String,
+        #[serde(default, skip_serializing_if = "HeaderValueMode::is_first")]
+        values: HeaderValueMode,
+    },
+    /// At least one of the header's values must equal `value` (same as
+    /// `exact` with `values: any`)
+    AnyValue { value: String },
+    /// Every one of the header's values must equal `value` (same as `exact`
+    /// with `values: all`)
+    AllValues { value: String },
+    /// Header must be sent exactly `count` times
+    Count { count: usize },
+    /// Matches when `matcher` does not
+    Not { matcher: Box<HeaderMatcher> },
+}
+
+impl HeaderMatcher {
+    /// Validate the header matcher.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            HeaderMatcher::Regex { pattern, .. } => validate_regex(pattern),
+            HeaderMatcher::Not { matcher } => matcher.validate(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Which values of a repeated header a matcher checks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderValueMode {
+    /// Only the first value
+    #[default]
+    First,
+    /// At least one value must match
+    Any,
+    /// Every value must match
+    All,
+}
+
+impl HeaderValueMode {
+    fn is_first(&self) -> bool {
+        *self == HeaderValueMode::First
+    }
 }
 
 /// Body matching configuration.
@@ -210,21 +995,143 @@ pub enum BodyMatcher {
     Contains { value: String },
     /// Body must be valid JSON (any structure)
     Json,
+    /// Body must be JSON structurally equal to `value`
+    JsonEquals {
+        /// Expected JSON
+        value: serde_json::Value,
+        /// Allow object fields `value` doesn't have
+        #[serde(default)]
+        ignore_extra_fields: bool,
+        /// Compare arrays regardless of element order
+        #[serde(default)]
+        ignore_array_order: bool,
+        /// Paths (`$.a.b`, `$.items[*].id`) left out on both sides
+        #[serde(default)]
+        ignore_paths: Vec<String>,
+    },
+    /// JSON path matching against a YAML body
+    YamlPath {
+        /// JSON path expressions and expected values
+        expressions: HashMap<String, serde_json::Value>,
+    },
+    /// Body must be valid YAML (any structure)
+    Yaml,
+    /// Body digest must equal `value` (hex); `algorithm` is one of
+    /// `sha256`, `sha384`, `sha512` or `sha1`
+    Hash { algorithm: String, value: String },
     /// Body must be empty
     Empty,
+    /// `multipart/form-data` body with a part matching each entry, by part name
+    Multipart { parts: HashMap<String, PartMatcher> },
+    /// GraphQL request, as a JSON envelope or an `application/graphql` body
+    #[serde(rename = "graphql")]
+    GraphQl {
+        /// Operation name, from `operationName` or the query itself
+        #[serde(default)]
+        operation_name: Option<String>,
+        /// Substring the query text must contain
+        #[serde(default)]
+        query_contains: Option<String>,
+        /// Expected variables, compared like JSON path expectations
+        #[serde(default)]
+        variables: HashMap<String, serde_json::Value>,
+    },
+    /// Matches when `matcher` does not
+    Not { matcher: Box<BodyMatcher> },
+}
+
+impl BodyMatcher {
+    /// Validate the body matcher.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            BodyMatcher::Regex { pattern } => validate_regex(pattern),
+            BodyMatcher::JsonPath { expressions } | BodyMatcher::YamlPath { expressions } => {
+                validate_json_paths(expressions)
+            }
+            BodyMatcher::GraphQl { variables, .. } => validate_json_paths(variables),
+            BodyMatcher::JsonEquals { ignore_paths, .. } => ignore_paths
+                .iter()
+                .try_for_each(|path| crate::json_equals::parse_path(path).map(|_| ())),
+            BodyMatcher::Hash { algorithm, .. } => digest_algorithm(algorithm).map(|_| ()),
+            BodyMatcher::Multipart { parts } => parts
+                .values()
+                .filter_map(|part| part.value.as_ref())
+                .try_for_each(TextMatcher::validate),
+            BodyMatcher::Not { matcher } => matcher.validate(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Expectations for one part of a multipart body. The part must be present.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PartMatcher {
+    /// Part must be a file upload (have a filename)
+    #[serde(default)]
+    pub file: bool,
+
+    /// Part content type (case-insensitive, parameters ignored)
+    #[serde(default)]
+    pub content_type: Option<String>,
+
+    /// Text value the part content must match
+    #[serde(default)]
+    pub value: Option<TextMatcher>,
+
+    /// Smallest allowed content size in bytes
+    #[serde(default)]
+    pub min_size: Option<usize>,
+
+    /// Largest allowed content size in bytes
+    #[serde(default)]
+    pub max_size: Option<usize>,
+}
+
+/// Match a text value.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TextMatcher {
+    /// Exact value match
+    Exact { value: String },
+    /// Regex pattern match
+    Regex { pattern: String },
+    /// Value must contain substring
+    Contains { value: String },
+}
+
+impl TextMatcher {
+    /// Validate the text matcher.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            TextMatcher::Regex { pattern } => validate_regex(pattern),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether `text` satisfies the matcher.
+    pub fn matches(&self, text: &str) -> bool {
+        match self {
+            TextMatcher::Exact { value } => text == value,
+            TextMatcher::Regex { pattern } => {
+                regex::Regex::new(pattern).is_ok_and(|regex| regex.is_match(text))
+            }
+            TextMatcher::Contains { value } => text.contains(value.as_str()),
+        }
+    }
 }
 
 /// Response definition.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct ResponseDefinition {
-    /// HTTP status code
+    /// HTTP status code, or a template rendering to one
     #[serde(default = "default_status")]
-    pub status: u16,
+    pub status: ResponseStatus,
 
-    /// Response headers
+    /// Response headers, each with one value or a list of values
     #[serde(default)]
-    pub headers: HashMap<String, String>,
+    pub headers: HashMap<String, HeaderValues>,
 
     /// Response body
     #[serde(default)]
@@ -233,61 +1140,378 @@ pub struct ResponseDefinition {
     /// Whether this is a template response
     #[serde(default)]
     pub template: bool,
+
+    /// Send an `ETag` computed from the final body, and answer 304 to GETs
+    /// whose `If-None-Match` lists it
+    #[serde(default)]
+    pub auto_etag: bool,
+
+    /// Maximum body rate in bytes per second, paced in fixed-size chunks
+    #[serde(default)]
+    pub throughput_bps: Option<u64>,
+
+    /// Latency for this response, overriding the stub's `delay`
+    #[serde(default)]
+    pub delay: Option<DelayConfig>,
+
+    /// Failure for this response, overriding the stub's `fault`
+    #[serde(default)]
+    pub fault: Option<FaultConfig>,
+
+    /// Relative likelihood of this entry under `selection: random`
+    #[serde(default = "default_weight")]
+    pub weight: u32,
 }
 
-fn default_status() -> u16 {
-    200
+fn default_status() -> ResponseStatus {
+    ResponseStatus::Fixed(200)
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl Default for ResponseDefinition {
+    fn default() -> Self {
+        Self {
+            status: default_status(),
+            headers: HashMap::new(),
+            body: None,
+            template: false,
+            auto_etag: false,
+            throughput_bps: None,
+            delay: None,
+            fault: None,
+            weight: default_weight(),
+        }
+    }
 }
 
 impl ResponseDefinition {
     /// Validate the response definition.
     pub fn validate(&self) -> anyhow::Result<()> {
-        if self.status < 100 || self.status > 599 {
-            anyhow::bail!("Invalid status code: {}", self.status);
+        match &self.status {
+            ResponseStatus::Fixed(status) => valid_status(*status)?,
+            ResponseStatus::Template(template) if !template.contains("{{") => {
+                let status = parse_status(template)?;
+                valid_status(status)?;
+            }
+            ResponseStatus::Template(_) => {}
+        }
+        if self.throughput_bps == Some(0) {
+            anyhow::bail!("throughput_bps must be greater than 0");
+        }
+        if let Some(fault) = &self.fault {
+            fault.validate()?;
         }
         Ok(())
     }
+
+    /// Strings rendered as templates when this response is served.
+    pub fn template_strings(&self) -> Vec<&str> {
+        let mut strings = Vec::new();
+        if let ResponseStatus::Template(template) = &self.status {
+            strings.push(template.as_str());
+        }
+        if self.template {
+            for values in self.headers.values() {
+                strings.extend(values.values().iter().map(String::as_str));
+            }
+            match &self.body {
+                Some(ResponseBody::Text { content }) => strings.push(content),
+                Some(ResponseBody::Json { content }) => json_strings(content, &mut strings),
+                Some(ResponseBody::File { path }) => strings.push(path),
+                Some(ResponseBody::Lookup {
+                    key,
+                    table,
+                    default,
+                }) => {
+                    strings.push(key);
+                    for value in table.values().chain(default) {
+                        json_strings(value, &mut strings);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Some(ResponseBody::ParamFile { path_template }) = &self.body {
+            strings.push(path_template);
+        }
+        strings.retain(|s| s.contains("{{"));
+        strings
+    }
+}
+
+/// String leaves of a JSON value.
+fn json_strings<'a>(value: &'a serde_json::Value, out: &mut Vec<&'a str>) {
+    match value {
+        serde_json::Value::String(s) => out.push(s),
+        serde_json::Value::Array(items) => items.iter().for_each(|v| json_strings(v, out)),
+        serde_json::Value::Object(map) => map.values().for_each(|v| json_strings(v, out)),
+        _ => {}
+    }
+}
+
+/// Values of a response header: a single string, or a list for list-valued
+/// headers such as `Vary`, sent joined with `, `.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum HeaderValues {
+    /// A single value
+    One(String),
+    /// Several values, joined with `, ` when sent
+    Many(Vec<String>),
+}
+
+impl HeaderValues {
+    /// All values, in order.
+    pub fn values(&self) -> &[String] {
+        match self {
+            HeaderValues::One(value) => std::slice::from_ref(value),
+            HeaderValues::Many(values) => values,
+        }
+    }
+
+    /// The first value, if any.
+    pub fn first(&self) -> Option<&str> {
+        self.values().first().map(String::as_str)
+    }
+}
+
+/// Whether repeated values of a header can be joined with `, ` into one.
+///
+/// `Set-Cookie` can't: cookie values may themselves contain commas, so each
+/// has to be sent as its own header.
+pub fn joinable_header(name: &str) -> bool {
+    !name.eq_ignore_ascii_case("set-cookie")
+}
+
+impl From<&str> for HeaderValues {
+    fn from(value: &str) -> Self {
+        HeaderValues::One(value.to_string())
+    }
+}
+
+impl From<Vec<String>> for HeaderValues {
+    fn from(mut values: Vec<String>) -> Self {
+        if values.len() == 1 {
+            HeaderValues::One(values.remove(0))
+        } else {
+            HeaderValues::Many(values)
+        }
+    }
+}
+
+/// Status code of a response.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ResponseStatus {
+    /// Fixed status code
+    Fixed(u16),
+    /// Template rendered per request, e.g. `"{{query.force_status}}"`
+    Template(String),
+}
+
+impl ResponseStatus {
+    /// The status code, if it doesn't depend on the request.
+    pub fn fixed(&self) -> Option<u16> {
+        match self {
+            ResponseStatus::Fixed(status) => Some(*status),
+            ResponseStatus::Template(template) => parse_status(template).ok(),
+        }
+    }
+}
+
+impl From<u16> for ResponseStatus {
+    fn from(status: u16) -> Self {
+        ResponseStatus::Fixed(status)
+    }
+}
+
+impl PartialEq<u16> for ResponseStatus {
+    fn eq(&self, other: &u16) -> bool {
+        self.fixed() == Some(*other)
+    }
+}
+
+/// Parse a rendered status template into a valid status code.
+pub fn parse_status(rendered: &str) -> anyhow::Result<u16> {
+    let status = rendered
+        .trim()
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Status '{}' is not a number", rendered))?;
+    valid_status(status)?;
+    Ok(status)
+}
+
+fn valid_status(status: u16) -> anyhow::Result<()> {
+    if !(100..=599).contains(&status) {
+        anyhow::bail!("Invalid status code: {}", status);
+    }
+    Ok(())
+}
+
+/// Response body configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseBody {
+    /// Plain text body
+    Text { content: String },
+    /// JSON body
+    Json { content: serde_json::Value },
+    /// Base64 encoded binary
+    Base64 { content: String },
+    /// Load from file
+    File { path: String },
+    /// JSON object reflecting the incoming request (method, path, query, headers, body)
+    RequestEcho,
+    /// JSON value picked from `table` by the rendered `key` template
+    Lookup {
+        key: String,
+        table: HashMap<String, serde_json::Value>,
+        #[serde(default)]
+        default: Option<serde_json::Value>,
+    },
+    /// Status, headers and body from the fixture file `path_template`
+    /// renders to, under `settings.fixtures_root`
+    ParamFile { path_template: String },
+}
+
+impl ResponseBody {
+    /// Get the body content as bytes.
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        match self {
+            ResponseBody::Text { content } => Ok(content.as_bytes().to_vec()),
+            ResponseBody::Json { content } => Ok(serde_json::to_string(content)?.into_bytes()),
+            ResponseBody::Base64 { content } => {
+                use base64::Engine;
+                base64::engine::general_purpose::STANDARD
+                    .decode(content)
+                    .map_err(|e| anyhow::anyhow!("Invalid base64: {}", e))
+            }
+            ResponseBody::File { path } => std::fs::read(path)
+                .map_err(|e| anyhow::anyhow!("Failed to read file {}: {}", path, e)),
+            ResponseBody::RequestEcho => {
+                anyhow::bail!("Request echo bodies are rendered from the request")
+            }
+            ResponseBody::Lookup { .. } => {
+                anyhow::bail!("Lookup bodies are rendered from the request")
+            }
+            ResponseBody::ParamFile { .. } => {
+                anyhow::bail!("Param file bodies are picked by the request")
+            }
+        }
+    }
+
+    /// Get content type for this body.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ResponseBody::Text { .. } => "text/plain",
+            ResponseBody::Json { .. } => "application/json",
+            ResponseBody::Base64 { .. } => "application/octet-stream",
+            ResponseBody::File { .. } => "application/octet-stream",
+            ResponseBody::RequestEcho => "application/json",
+            ResponseBody::Lookup { .. } => "application/json",
+            ResponseBody::ParamFile { .. } => "application/json",
+        }
+    }
+}
+
+/// Fixture file served by `ResponseBody::ParamFile`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FixtureEnvelope {
+    /// Status code (default: the stub's)
+    #[serde(default)]
+    pub status: Option<u16>,
+
+    /// Headers added to (and overriding) the stub's
+    #[serde(default)]
+    pub headers: HashMap<String, HeaderValues>,
+
+    /// Body; strings are sent as they are, anything else as JSON
+    #[serde(default)]
+    pub body: Option<serde_json::Value>,
+}
+
+impl FixtureEnvelope {
+    /// Parse a fixture file.
+    pub fn parse(content: &[u8]) -> anyhow::Result<Self> {
+        let envelope: Self = serde_json::from_slice(content)
+            .map_err(|e| anyhow::anyhow!("Invalid fixture envelope: {}", e))?;
+        if let Some(status) = envelope.status {
+            valid_status(status)?;
+        }
+        Ok(envelope)
+    }
+
+    /// Body bytes, and the content type they imply.
+    pub fn body_bytes(&self) -> Option<(Vec<u8>, &'static str)> {
+        match self.body.as_ref()? {
+            serde_json::Value::String(text) => Some((text.as_bytes().to_vec(), "text/plain")),
+            json => Some((json.to_string().into_bytes(), "application/json")),
+        }
+    }
+}
+
+/// Latency that degrades as a stub keeps matching, to simulate a backend
+/// slowing down under load.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RampConfig {
+    /// Delay of the first match (ms)
+    #[serde(default)]
+    pub base_ms: u64,
+
+    /// Delay added for every earlier match (ms)
+    #[serde(default)]
+    pub step_ms: u64,
+
+    /// Upper bound of the ramped delay (ms)
+    #[serde(default)]
+    pub max_ms: Option<u64>,
+}
+
+impl RampConfig {
+    /// Delay for a match preceded by `previous_matches` others.
+    pub fn delay_ms(&self, previous_matches: u32) -> u64 {
+        let delay = self
+            .base_ms
+            .saturating_add(self.step_ms.saturating_mul(previous_matches as u64));
+        match self.max_ms {
+            Some(max) => delay.min(max),
+            None => delay,
+        }
+    }
 }
 
-/// Response body configuration.
+/// Long-poll simulation: a matched request waits for the named event
+/// (fired with `MockServerAgent::fire_event` or `POST /admin/events/{name}`)
+/// and is then answered with the stub's response.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
-pub enum ResponseBody {
-    /// Plain text body
-    Text { content: String },
-    /// JSON body
-    Json { content: serde_json::Value },
-    /// Base64 encoded binary
-    Base64 { content: String },
-    /// Load from file
-    File { path: String },
+#[serde(deny_unknown_fields)]
+pub struct LongPollConfig {
+    /// Event that releases waiting requests
+    pub event: String,
+
+    /// Longest a request is held (ms)
+    pub timeout_ms: u64,
+
+    /// Response when the timeout passes first (default: an empty 204)
+    #[serde(default)]
+    pub timeout_response: Option<ResponseDefinition>,
 }
 
-impl ResponseBody {
-    /// Get the body content as bytes.
-    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
-        match self {
-            ResponseBody::Text { content } => Ok(content.as_bytes().to_vec()),
-            ResponseBody::Json { content } => Ok(serde_json::to_string(content)?.into_bytes()),
-            ResponseBody::Base64 { content } => {
-                use base64::Engine;
-                base64::engine::general_purpose::STANDARD
-                    .decode(content)
-                    .map_err(|e| anyhow::anyhow!("Invalid base64: {}", e))
-            }
-            ResponseBody::File { path } => std::fs::read(path)
-                .map_err(|e| anyhow::anyhow!("Failed to read file {}: {}", path, e)),
+impl LongPollConfig {
+    /// Validate the long-poll configuration.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.event.is_empty() {
+            anyhow::bail!("long_poll event cannot be empty");
         }
-    }
-
-    /// Get content type for this body.
-    pub fn content_type(&self) -> &'static str {
-        match self {
-            ResponseBody::Text { .. } => "text/plain",
-            ResponseBody::Json { .. } => "application/json",
-            ResponseBody::Base64 { .. } => "application/octet-stream",
-            ResponseBody::File { .. } => "application/octet-stream",
+        if let Some(response) = &self.timeout_response {
+            response.validate()?;
         }
+        Ok(())
     }
 }
 
@@ -306,6 +1530,10 @@ pub struct DelayConfig {
     /// Maximum delay for random range (ms)
     #[serde(default)]
     pub max_ms: u64,
+
+    /// Additional delay per KB of request body (ms)
+    #[serde(default)]
+    pub per_request_kb_ms: Option<u64>,
 }
 
 impl DelayConfig {
@@ -321,6 +1549,113 @@ impl DelayConfig {
         }
         self.min_ms
     }
+
+    /// Calculate the delay including the request body size component.
+    pub fn calculate_for_body(&self, body_len: usize) -> u64 {
+        let base = self.calculate();
+        match self.per_request_kb_ms {
+            Some(per_kb) => base.saturating_add((body_len as u64 / 1024).saturating_mul(per_kb)),
+            None => base,
+        }
+    }
+}
+
+/// Recording of upstream responses as stubs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordConfig {
+    /// Whether recording is active
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+
+    /// Label for the upstream being recorded, noted in each stub's name.
+    /// Requests still go wherever the proxy routes them.
+    pub upstream: String,
+
+    /// File the recorded stubs are appended to
+    #[serde(default = "default_record_file")]
+    pub file: PathBuf,
+
+    /// Request headers the recorded stubs match on, besides method and path
+    #[serde(default)]
+    pub match_headers: Vec<String>,
+}
+
+fn default_record_file() -> PathBuf {
+    PathBuf::from("recorded-stubs.yaml")
+}
+
+/// Circuit breaker simulation.
+///
+/// After tripping, the breaker is open for `open_ms` and rejects every
+/// request, then half-open for `half_open_ms` and lets `half_open_fraction`
+/// of requests through, then closed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BreakerConfig {
+    /// How long the breaker stays open (ms)
+    pub open_ms: u64,
+
+    /// How long the breaker stays half-open before closing (ms)
+    pub half_open_ms: u64,
+
+    /// Share of requests let through while half-open (0.0 - 1.0)
+    pub half_open_fraction: f64,
+
+    /// Status returned for rejected requests
+    #[serde(default = "default_breaker_status")]
+    pub status: u16,
+}
+
+fn default_breaker_status() -> u16 {
+    503
+}
+
+/// Phase of a simulated circuit breaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerPhase {
+    /// Every request is rejected
+    Open,
+    /// Some requests are let through
+    HalfOpen,
+    /// Requests are served normally
+    Closed,
+}
+
+impl BreakerPhase {
+    /// Name used in metadata and metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BreakerPhase::Open => "open",
+            BreakerPhase::HalfOpen => "half_open",
+            BreakerPhase::Closed => "closed",
+        }
+    }
+}
+
+impl BreakerConfig {
+    /// Validate the breaker configuration.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(0.0..=1.0).contains(&self.half_open_fraction) {
+            anyhow::bail!(
+                "breaker half_open_fraction must be between 0.0 and 1.0, got {}",
+                self.half_open_fraction
+            );
+        }
+        Ok(())
+    }
+
+    /// Phase of the breaker `elapsed` after it tripped.
+    pub fn phase(&self, elapsed: Duration) -> BreakerPhase {
+        let ms = elapsed.as_millis() as u64;
+        if ms < self.open_ms {
+            BreakerPhase::Open
+        } else if ms < self.open_ms.saturating_add(self.half_open_ms) {
+            BreakerPhase::HalfOpen
+        } else {
+            BreakerPhase::Closed
+        }
+    }
 }
 
 /// Fault injection configuration.
@@ -352,13 +1687,76 @@ pub enum FaultConfig {
     SlowResponse {
         /// Bytes per second
         bytes_per_second: u64,
+        /// Bytes per chunk (default: a tenth of a second's worth)
+        #[serde(default)]
+        chunk_size: Option<usize>,
+    },
+    /// Serve the response, then signal the client to close the connection
+    GoAway,
+    /// Serve the normal body under a misleading content type
+    WrongContentType {
+        /// Content type to declare instead of the real one
+        declared: String,
+    },
+    /// Claim a `Content-Encoding` but send a body that doesn't decode
+    CorruptEncoding {
+        /// Encoding to declare (default: `gzip`)
+        #[serde(default = "default_encoding")]
+        encoding: String,
+    },
+    /// Advertise the full body, then close the connection without sending it
+    ConnectionReset,
+    /// Send only the first `percent` of the body under the full `Content-Length`
+    PartialBody {
+        /// Share of the body sent (0 - 100)
+        percent: u8,
     },
+    /// Replay the stub's previous response instead of a fresh one
+    Duplicate {
+        /// Replay probability (0.0 - 1.0)
+        #[serde(default = "default_probability")]
+        probability: f64,
+    },
+}
+
+impl FaultConfig {
+    /// Validate the fault configuration.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            FaultConfig::PartialBody { percent } if *percent > 100 => {
+                anyhow::bail!("partial_body percent must be at most 100, got {}", percent)
+            }
+            FaultConfig::SlowResponse {
+                bytes_per_second,
+                chunk_size,
+            } => {
+                if *bytes_per_second == 0 {
+                    anyhow::bail!("slow_response bytes_per_second must be greater than 0");
+                }
+                if *chunk_size == Some(0) {
+                    anyhow::bail!("slow_response chunk_size must be greater than 0");
+                }
+                Ok(())
+            }
+            FaultConfig::Duplicate { probability } if !(0.0..=1.0).contains(probability) => {
+                anyhow::bail!(
+                    "duplicate probability must be between 0.0 and 1.0, got {}",
+                    probability
+                )
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 fn default_probability() -> f64 {
     1.0
 }
 
+fn default_encoding() -> String {
+    "gzip".to_string()
+}
+
 /// Global settings.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -371,6 +1769,11 @@ pub struct GlobalSettings {
     #[serde(default = "default_true")]
     pub log_unmatched: bool,
 
+    /// Name the closest stub, and the criteria it failed, on the default
+    /// response to unmatched requests
+    #[serde(default)]
+    pub debug_unmatched: bool,
+
     /// Pass through unmatched requests to upstream
     #[serde(default)]
     pub passthrough_unmatched: bool,
@@ -379,9 +1782,110 @@ pub struct GlobalSettings {
     #[serde(default = "default_content_type")]
     pub default_content_type: String,
 
+    /// Re-serialize JSON response bodies with object keys sorted, so
+    /// snapshots compare byte for byte
+    #[serde(default)]
+    pub sort_json_keys: bool,
+
     /// Case-insensitive header matching
     #[serde(default = "default_true")]
     pub case_insensitive_headers: bool,
+
+    /// Ignore ASCII case in exact, prefix and template path matching
+    #[serde(default)]
+    pub case_insensitive_paths: bool,
+
+    /// Ignore a trailing slash in exact and template path matching
+    #[serde(default)]
+    pub ignore_trailing_slash: bool,
+
+    /// Answer `/__health` and `/__ready` directly, regardless of stubs
+    #[serde(default)]
+    pub builtin_health_paths: bool,
+
+    /// Pass matched requests to upstream and compare the real response body
+    /// against the stub's expected body instead of mocking it
+    #[serde(default)]
+    pub contract_verification: bool,
+
+    /// Reload the configuration file whenever it changes (same as `--watch`)
+    #[serde(default)]
+    pub watch_config: bool,
+
+    /// What to do when a body template fails to render
+    #[serde(default)]
+    pub template_errors: TemplateErrors,
+
+    /// Same as `template_errors: fail` (kept for older configs)
+    #[serde(default)]
+    pub strict_templates: bool,
+
+    /// Seed for random response selection and template helpers, so runs are
+    /// reproducible
+    #[serde(default)]
+    pub random_seed: Option<u64>,
+
+    /// Number of recent requests kept in the journal (0 disables it)
+    #[serde(default = "default_journal_size")]
+    pub journal_size: usize,
+
+    /// Request body bytes kept per journal entry
+    #[serde(default = "default_journal_body_max_bytes")]
+    pub journal_body_max_bytes: usize,
+
+    /// Headers added to every mocked response, unless the response sets
+    /// the same header itself (`Content-Type` is never taken from here)
+    #[serde(default)]
+    pub global_headers: HashMap<String, HeaderValues>,
+
+    /// Constants available to every template as `{{vars.name}}`
+    #[serde(default)]
+    pub template_vars: HashMap<String, serde_json::Value>,
+
+    /// Named template fragments any stub can include as `{{> name}}`
+    #[serde(default)]
+    pub template_partials: HashMap<String, String>,
+
+    /// Response for requests that only a disabled stub matches, instead of
+    /// the default response
+    #[serde(default)]
+    pub disabled_stub_response: Option<ResponseDefinition>,
+
+    /// Status used when a templated `status` doesn't render to a valid code
+    #[serde(default = "default_template_status_fallback")]
+    pub template_status_fallback: u16,
+
+    /// Fail the run when the agent is drained or shut down while some
+    /// enabled stub has never matched
+    #[serde(default)]
+    pub require_all_matched: bool,
+
+    /// Largest request body buffered for body matching; longer bodies are
+    /// matched on their first `max_body_bytes` bytes
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+
+    /// Simulate a circuit breaker that rejects requests, then recovers
+    #[serde(default)]
+    pub breaker: Option<BreakerConfig>,
+
+    /// Record upstream responses to unmatched requests as stubs
+    #[serde(default)]
+    pub record: Option<RecordConfig>,
+
+    /// HAR file every mocked request and response is appended to
+    #[serde(default)]
+    pub har_output: Option<PathBuf>,
+
+    /// Directory templated `file` body paths are resolved against and may
+    /// not leave (default: the working directory)
+    #[serde(default)]
+    pub fixtures_root: Option<PathBuf>,
+
+    /// Key grouping requests into sessions for `after_status` (unset = all
+    /// requests share one session)
+    #[serde(default)]
+    pub session_key: Option<AbKey>,
 }
 
 impl Default for GlobalSettings {
@@ -389,13 +1893,90 @@ impl Default for GlobalSettings {
         Self {
             log_matches: true,
             log_unmatched: true,
+            debug_unmatched: false,
             passthrough_unmatched: false,
             default_content_type: default_content_type(),
             case_insensitive_headers: true,
+            case_insensitive_paths: false,
+            ignore_trailing_slash: false,
+            builtin_health_paths: false,
+            contract_verification: false,
+            watch_config: false,
+            template_errors: TemplateErrors::default(),
+            strict_templates: false,
+            random_seed: None,
+            journal_size: default_journal_size(),
+            journal_body_max_bytes: default_journal_body_max_bytes(),
+            global_headers: HashMap::new(),
+            template_vars: HashMap::new(),
+            template_partials: HashMap::new(),
+            disabled_stub_response: None,
+            template_status_fallback: default_template_status_fallback(),
+            max_body_bytes: default_max_body_bytes(),
+            require_all_matched: false,
+            breaker: None,
+            record: None,
+            har_output: None,
+            fixtures_root: None,
+            session_key: None,
+            sort_json_keys: false,
+        }
+    }
+}
+
+impl GlobalSettings {
+    /// Validate the settings.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        valid_status(self.template_status_fallback)
+            .map_err(|e| anyhow::anyhow!("template_status_fallback: {}", e))?;
+        if let Some(breaker) = &self.breaker {
+            breaker.validate()?;
+        }
+        if let Some(disabled) = &self.disabled_stub_response {
+            disabled
+                .validate()
+                .map_err(|e| anyhow::anyhow!("disabled_stub_response: {}", e))?;
         }
+        for (name, source) in &self.template_partials {
+            handlebars::Template::compile(source)
+                .map_err(|e| anyhow::anyhow!("Template partial '{}': {}", name, e))?;
+        }
+        Ok(())
+    }
+
+    /// Whether a body template render error answers 500.
+    pub fn fail_on_template_errors(&self) -> bool {
+        self.strict_templates || self.template_errors == TemplateErrors::Fail
     }
 }
 
+/// Handling of body templates that fail to render.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TemplateErrors {
+    /// Answer 500 naming the stub and the error
+    Fail,
+    /// Log the error and serve the stub without a body
+    #[default]
+    Ignore,
+}
+
+fn default_journal_size() -> usize {
+    1000
+}
+
+fn default_journal_body_max_bytes() -> usize {
+    4096
+}
+
+fn default_template_status_fallback() -> u16 {
+    500
+}
+
+fn default_max_body_bytes() -> usize {
+    1024 * 1024
+}
+
 fn default_content_type() -> String {
     "application/json".to_string()
 }
@@ -454,6 +2035,171 @@ stubs:
         }
     }
 
+    #[test]
+    fn test_parse_multi_value_headers() {
+        let yaml = r#"
+id: login
+request: {}
+response:
+  headers:
+    X-Single: one
+    Vary:
+      - Accept
+      - Origin
+"#;
+        let stub: StubDefinition = serde_yaml::from_str(yaml).unwrap();
+        let headers = &stub.response.headers;
+        assert_eq!(headers["X-Single"], HeaderValues::from("one"));
+        assert_eq!(headers["X-Single"].values(), ["one"]);
+        assert_eq!(headers["Vary"].values(), ["Accept", "Origin"]);
+        assert_eq!(headers["Vary"].first(), Some("Accept"));
+        assert!(stub.validate().is_ok());
+    }
+
+    #[test]
+    fn test_sequence_max_matches_validation() {
+        let yaml = r#"
+id: seq
+request: {}
+responses:
+  - status: 200
+  - status: 201
+  - status: 202
+sequence_mode: clamp
+max_matches: 2
+"#;
+        let mut stub: StubDefinition = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(stub.selection(), SequenceMode::Clamp);
+        let err = stub.validate().unwrap_err();
+        assert!(err.to_string().contains("unreachable"));
+
+        stub.max_matches = 3;
+        assert!(stub.validate().is_ok());
+        assert_eq!(stub.response_for(3, 0.0).status, 202);
+        assert_eq!(stub.response_for(10, 0.0).status, 202);
+    }
+
+    #[test]
+    fn test_weighted_responses() {
+        let yaml = r#"
+id: flaky
+request: {}
+selection: random
+responses:
+  - status: 200
+    weight: 9
+  - status: 503
+"#;
+        let mut stub: StubDefinition = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(stub.selection(), SequenceMode::Random);
+        assert!(stub.validate().is_ok());
+        assert_eq!(stub.response_index(1, 0.0), Some(0));
+        assert_eq!(stub.response_index(1, 0.89), Some(0));
+        assert_eq!(stub.response_index(1, 0.9), Some(1));
+        assert_eq!(stub.response_index(1, 0.999), Some(1));
+
+        for response in &mut stub.responses {
+            response.weight = 0;
+        }
+        let err = stub.validate().unwrap_err();
+        assert!(err.to_string().contains("sum to more than 0"));
+
+        stub.responses[0].weight = 1;
+        stub.sequence_mode = Some(SequenceMode::Clamp);
+        let err = stub.validate().unwrap_err();
+        assert!(err.to_string().contains("cannot both be set"));
+    }
+
+    #[test]
+    fn test_weighted_responses_shorthand() {
+        let yaml = r#"
+id: flaky
+request: {}
+weighted_responses:
+  - status: 200
+    weight: 9
+  - status: 500
+    template: true
+    body: { type: text, content: "failed {{request_path}}" }
+"#;
+        let mut stub: StubDefinition = serde_yaml::from_str(yaml).unwrap();
+        assert!(stub.validate().is_ok());
+        assert_eq!(stub.selection(), SequenceMode::Random);
+        assert_eq!(stub.response_index(1, 0.5), Some(0));
+        assert_eq!(stub.response_index(1, 0.95), Some(1));
+        assert!(stub.response_for(2, 0.95).template);
+
+        stub.sequence_mode = Some(SequenceMode::Wrap);
+        let err = stub.validate().unwrap_err();
+        assert!(err.to_string().contains("always picked at random"));
+        stub.sequence_mode = Some(SequenceMode::Random);
+        assert!(stub.validate().is_ok());
+        stub.sequence_mode = None;
+
+        stub.responses = stub.weighted_responses.clone();
+        let err = stub.validate().unwrap_err();
+        assert!(err.to_string().contains("cannot both be set"));
+    }
+
+    #[test]
+    fn test_unknown_json_path_operator() {
+        let yaml = r#"
+id: bad-op
+request:
+  body:
+    type: json_path
+    expressions:
+      $.age: { op: between, value: 18 }
+"#;
+        let stub: StubDefinition = serde_yaml::from_str(yaml).unwrap();
+        let err = stub.validate().unwrap_err();
+        assert!(err.to_string().contains("Unknown JSON path operator"));
+    }
+
+    #[test]
+    fn test_ab_buckets() {
+        let yaml = r#"
+id: ab
+request: {}
+ab_key: { type: cookie, name: session }
+ab_buckets:
+  - percent: 30
+    response: { status: 200 }
+  - percent: 70
+    response: { status: 201 }
+"#;
+        let stub: StubDefinition = serde_yaml::from_str(yaml).unwrap();
+        assert!(stub.validate().is_ok());
+
+        // Same key, same bucket
+        let first = stub.ab_response("user-42").unwrap().status.clone();
+        for _ in 0..10 {
+            assert_eq!(stub.ab_response("user-42").unwrap().status, first);
+        }
+
+        let keys = 10_000;
+        let control = (0..keys)
+            .filter(|i| stub.ab_response(&format!("user-{}", i)).unwrap().status == 200)
+            .count();
+        let share = control as f64 / keys as f64;
+        assert!((0.27..0.33).contains(&share), "control share {}", share);
+    }
+
+    #[test]
+    fn test_ab_buckets_must_total_100() {
+        let yaml = r#"
+id: ab
+request: {}
+ab_key: { type: header, name: x-user }
+ab_buckets:
+  - percent: 30
+    response: { status: 200 }
+"#;
+        let stub: StubDefinition = serde_yaml::from_str(yaml).unwrap();
+        let err = stub.validate().unwrap_err();
+        assert!(err.to_string().contains("must total 100"));
+    }
+
     #[test]
     fn test_parse_delay_config() {
         let yaml = r#"
@@ -521,12 +2267,101 @@ stubs:
         assert!(config.stubs[0].response.template);
     }
 
+    /// Serve a single fixed HTTP response on a local port, returning the URL.
+    fn serve_once(status_line: &'static str, body: &'static str) -> String {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf);
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status_line,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        format!("http://{}/mock-server.yaml", addr)
+    }
+
+    #[tokio::test]
+    async fn test_load_config_from_url() {
+        let url = serve_once(
+            "200 OK",
+            r#"
+stubs:
+  - id: remote
+    request:
+      path:
+        type: exact
+        value: /remote
+    response:
+      status: 200
+"#,
+        );
+
+        let config = MockServerConfig::from_url(&url).await.unwrap();
+        assert_eq!(config.stubs.len(), 1);
+        assert_eq!(config.stubs[0].id, "remote");
+    }
+
+    #[tokio::test]
+    async fn test_load_config_from_url_failure() {
+        // Bind then drop to get a port with nothing listening
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let url = format!("http://{}/mock-server.yaml", addr);
+
+        let err = MockServerConfig::from_url(&url).await.unwrap_err();
+        assert!(err.to_string().contains(&url));
+    }
+
+    #[test]
+    fn test_requires_matched_unknown_stub() {
+        let yaml = r#"
+stubs:
+  - id: confirm
+    requires_matched: [missing]
+    request:
+      path:
+        type: exact
+        value: /confirm
+    response:
+      status: 200
+"#;
+        let config: MockServerConfig = serde_yaml::from_str(yaml).unwrap();
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("missing"));
+    }
+
+    #[test]
+    fn test_template_status_fallback_validation() {
+        let config: MockServerConfig =
+            serde_yaml::from_str("settings: { template_status_fallback: 503 }").unwrap();
+        assert!(config.validate().is_ok());
+
+        for status in [0, 99, 600] {
+            let yaml = format!("settings: {{ template_status_fallback: {} }}", status);
+            let config: MockServerConfig = serde_yaml::from_str(&yaml).unwrap();
+            let err = config.validate().unwrap_err().to_string();
+            assert!(err.contains("template_status_fallback"), "{}", err);
+        }
+    }
+
     #[test]
     fn test_delay_calculation() {
         let fixed = DelayConfig {
             fixed_ms: 100,
             min_ms: 0,
             max_ms: 0,
+            per_request_kb_ms: None,
         };
         assert_eq!(fixed.calculate(), 100);
 
@@ -534,11 +2369,45 @@ stubs:
             fixed_ms: 0,
             min_ms: 50,
             max_ms: 150,
+            per_request_kb_ms: None,
         };
         let delay = range.calculate();
         assert!((50..=150).contains(&delay));
     }
 
+    #[test]
+    fn test_ramp_delay() {
+        let ramp = RampConfig {
+            base_ms: 10,
+            step_ms: 5,
+            max_ms: Some(20),
+        };
+        assert_eq!(ramp.delay_ms(0), 10);
+        assert_eq!(ramp.delay_ms(1), 15);
+        assert_eq!(ramp.delay_ms(2), 20);
+        assert_eq!(ramp.delay_ms(100), 20);
+    }
+
+    #[test]
+    fn test_delay_per_request_kb() {
+        let delay = DelayConfig {
+            fixed_ms: 10,
+            min_ms: 0,
+            max_ms: 0,
+            per_request_kb_ms: Some(2),
+        };
+        assert_eq!(delay.calculate_for_body(0), 10);
+        assert_eq!(delay.calculate_for_body(100 * 1024), 210);
+        // Partial KBs are not counted
+        assert_eq!(delay.calculate_for_body(1023), 10);
+
+        let huge = DelayConfig {
+            per_request_kb_ms: Some(u64::MAX),
+            ..delay
+        };
+        assert_eq!(huge.calculate_for_body(2048), u64::MAX);
+    }
+
     #[test]
     fn test_response_body_to_bytes() {
         let text = ResponseBody::Text {
@@ -0,0 +1,149 @@
+//! HAR capture.
+//!
+//! Appends mocked interactions to an HTTP Archive (HAR 1.2) file that
+//! browsers and HTTP tools can open, for sharing repros. Entries are
+//! buffered by the agent and written in batches, since every write
+//! rewrites the whole archive.
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+use zentinel_agent_sdk::Request;
+
+/// A mocked response, as served to the client.
+pub struct HarResponse<'a> {
+    /// Status code
+    pub status: u16,
+    /// Response headers
+    pub headers: &'a HashMap<String, String>,
+    /// Response body
+    pub body: &'a str,
+}
+
+/// Build a HAR entry for a request and the mocked response it got.
+///
+/// `latency` covers everything the agent did before answering, including
+/// any simulated delay, and is reported as the entry's wait time.
+pub fn entry(
+    stub_id: &str,
+    request: &Request,
+    response: &HarResponse<'_>,
+    started: DateTime<Utc>,
+    latency: Duration,
+) -> Value {
+    let host = request.header("host").unwrap_or("localhost");
+    let url = match request.query_string() {
+        Some(query) => format!("http://{}{}?{}", host, request.path_only(), query),
+        None => format!("http://{}{}", host, request.path_only()),
+    };
+    let request_headers: Vec<Value> = request
+        .headers()
+        .iter()
+        .flat_map(|(name, values)| {
+            values
+                .iter()
+                .map(move |value| json!({ "name": name, "value": value }))
+        })
+        .collect();
+    let query: Vec<Value> = request
+        .query_params()
+        .iter()
+        .flat_map(|(name, values)| {
+            values
+                .iter()
+                .map(move |value| json!({ "name": name, "value": value }))
+        })
+        .collect();
+    let body = request.body().unwrap_or_default();
+
+    let mut har_request = json!({
+        "method": request.method(),
+        "url": url,
+        "httpVersion": "HTTP/1.1",
+        "cookies": [],
+        "headers": request_headers,
+        "queryString": query,
+        "headersSize": -1,
+        "bodySize": body.len(),
+    });
+    if !body.is_empty() {
+        har_request["postData"] = json!({
+            "mimeType": request.header("content-type").unwrap_or(""),
+            "text": String::from_utf8_lossy(body),
+        });
+    }
+
+    let response_headers: Vec<Value> = response
+        .headers
+        .iter()
+        .map(|(name, value)| json!({ "name": name, "value": value }))
+        .collect();
+    let mime_type = response
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .map(|(_, value)| value.as_str())
+        .unwrap_or("");
+    let wait_ms = latency.as_secs_f64() * 1000.0;
+
+    json!({
+        "startedDateTime": started.to_rfc3339(),
+        "time": wait_ms,
+        "request": har_request,
+        "response": {
+            "status": response.status,
+            "statusText": "",
+            "httpVersion": "HTTP/1.1",
+            "cookies": [],
+            "headers": response_headers,
+            "content": {
+                "size": response.body.len(),
+                "mimeType": mime_type,
+                "text": response.body,
+            },
+            "redirectURL": "",
+            "headersSize": -1,
+            "bodySize": response.body.len(),
+        },
+        "cache": {},
+        "timings": { "send": 0, "wait": wait_ms, "receive": 0 },
+        "_stubId": stub_id,
+    })
+}
+
+/// Append entries to the HAR file at `path`, creating it if needed.
+///
+/// The file is rewritten through a temporary file, so readers never see a
+/// half-written archive.
+pub async fn append(path: &Path, new_entries: Vec<Value>) -> anyhow::Result<()> {
+    let mut har = match tokio::fs::read(path).await {
+        Ok(content) => serde_json::from_slice(&content)
+            .map_err(|e| anyhow::anyhow!("Invalid HAR file {:?}: {}", path, e))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => empty_log(),
+        Err(e) => return Err(e.into()),
+    };
+    match har["log"]["entries"].as_array_mut() {
+        Some(entries) => entries.extend(new_entries),
+        None => anyhow::bail!("HAR file {:?} has no log.entries", path),
+    }
+
+    let tmp = path.with_extension("har.tmp");
+    tokio::fs::write(&tmp, serde_json::to_vec_pretty(&har)?).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+fn empty_log() -> Value {
+    json!({
+        "log": {
+            "version": "1.2",
+            "creator": {
+                "name": "zentinel-agent-mock-server",
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "entries": [],
+        }
+    })
+}
@@ -0,0 +1,210 @@
+//! Standalone HTTP listener.
+//!
+//! Serves stubs over plain HTTP, without the Zentinel proxy in front: each
+//! request goes through the same matching and response pipeline as requests
+//! from the proxy, and the resulting decision is written back as the HTTP
+//! response.
+
+use crate::agent::{MockServerAgent, REPEATED_HEADERS};
+use axum::body::{Body, Bytes};
+use axum::extract::{ConnectInfo, State};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+use zentinel_agent_sdk::{ProtocolDecision, Request, RequestHeadersEvent, RequestMetadata};
+
+/// Router answering every request from `agent`'s stubs.
+pub fn router(agent: Arc<MockServerAgent>) -> Router {
+    Router::new().fallback(handle).with_state(agent)
+}
+
+/// Serve stubs over HTTP on `listener` until the task is dropped.
+pub async fn serve(listener: TcpListener, agent: Arc<MockServerAgent>) -> anyhow::Result<()> {
+    info!(address = ?listener.local_addr()?, "HTTP listener serving stubs");
+    axum::serve(
+        listener,
+        router(agent).into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn handle(
+    State(agent): State<Arc<MockServerAgent>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let request = to_request(client, &method, &uri, &headers, body);
+    let decided = agent.respond(&request).await;
+    match decided.decision {
+        ProtocolDecision::Block {
+            status,
+            body,
+            headers,
+        } => {
+            let mut response = Response::new(Body::from(body.unwrap_or_default()));
+            *response.status_mut() =
+                StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            for (name, value) in headers.unwrap_or_default() {
+                match (
+                    HeaderName::try_from(name.as_str()),
+                    HeaderValue::try_from(value.as_str()),
+                ) {
+                    // Names differing only in case are repeated values
+                    (Ok(name), Ok(value)) => {
+                        response.headers_mut().append(name, value);
+                    }
+                    _ => warn!(header = %name, "Skipping invalid response header"),
+                }
+            }
+            // The decision holds one value per name; send each of the others too
+            let repeated = decided.audit.custom.get(REPEATED_HEADERS);
+            for (name, values) in repeated.and_then(|r| r.as_object()).into_iter().flatten() {
+                let Ok(name) = HeaderName::try_from(name.as_str()) else {
+                    continue;
+                };
+                response.headers_mut().remove(&name);
+                for value in values.as_array().into_iter().flatten() {
+                    match value.as_str().map(HeaderValue::try_from) {
+                        Some(Ok(value)) => {
+                            response.headers_mut().append(&name, value);
+                        }
+                        _ => warn!(header = %name, "Skipping invalid response header"),
+                    }
+                }
+            }
+            response
+        }
+        ProtocolDecision::Redirect { url, status } => {
+            let status = StatusCode::from_u16(status).unwrap_or(StatusCode::FOUND);
+            (status, [("location", url)]).into_response()
+        }
+        // Nothing upstream to pass the request to
+        _ => {
+            let body = serde_json::json!({
+                "error": "no_upstream",
+                "message": "Request was not mocked and there is no upstream in standalone mode",
+            });
+            (StatusCode::BAD_GATEWAY, axum::Json(body)).into_response()
+        }
+    }
+}
+
+/// Translate an HTTP request into the SDK request the agent works on.
+fn to_request(
+    client: SocketAddr,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Request {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let id = format!("http-{}", NEXT_ID.fetch_add(1, Ordering::Relaxed));
+
+    let mut header_map: HashMap<String, Vec<String>> = HashMap::new();
+    for (name, value) in headers {
+        if let Ok(value) = value.to_str() {
+            header_map
+                .entry(name.as_str().to_string())
+                .or_default()
+                .push(value.to_string());
+        }
+    }
+
+    let event = RequestHeadersEvent {
+        metadata: RequestMetadata {
+            correlation_id: id.clone(),
+            request_id: id,
+            client_ip: client.ip().to_string(),
+            client_port: client.port(),
+            server_name: headers
+                .get("host")
+                .and_then(|h| h.to_str().ok())
+                .map(String::from),
+            protocol: "HTTP/1.1".to_string(),
+            tls_version: None,
+            tls_cipher: None,
+            route_id: None,
+            upstream_id: None,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            traceparent: None,
+        },
+        method: method.as_str().to_string(),
+        uri: uri
+            .path_and_query()
+            .map(|pq| pq.as_str().to_string())
+            .unwrap_or_else(|| uri.path().to_string()),
+        headers: header_map,
+    };
+
+    let request = Request::from_headers_event(&event);
+    if body.is_empty() {
+        request
+    } else {
+        request.with_body(body.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MockServerConfig;
+
+    #[tokio::test]
+    async fn test_http_listener() {
+        let config: MockServerConfig = serde_yaml::from_str(
+            r#"
+stubs:
+  - id: hello
+    request:
+      method: [POST]
+      path: { type: exact, value: /hello }
+      body: { type: contains, value: world }
+    response:
+      status: 201
+      headers:
+        Vary: [Accept, Origin]
+        Set-Cookie: [session=abc, "theme=dark; Expires=Wed, 21 Oct 2026 07:28:00 GMT"]
+      body: { type: text, content: "Hello, World!" }
+"#,
+        )
+        .unwrap();
+        let agent = Arc::new(MockServerAgent::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, agent.clone()));
+
+        let client = reqwest::Client::new();
+        let response = client
+            .post(format!("{}/hello", base))
+            .body("hello world")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.headers()["content-type"], "text/plain");
+        assert_eq!(response.headers()["vary"], "Accept, Origin");
+        let cookies: Vec<_> = response.headers().get_all("set-cookie").iter().collect();
+        assert_eq!(
+            cookies,
+            [
+                "session=abc",
+                "theme=dark; Expires=Wed, 21 Oct 2026 07:28:00 GMT"
+            ]
+        );
+        assert_eq!(response.text().await.unwrap(), "Hello, World!");
+        assert_eq!(agent.match_count("hello").await, Some(1));
+
+        let missing = client.get(format!("{}/missing", base)).send();
+        assert_eq!(missing.await.unwrap().status(), 404);
+    }
+}
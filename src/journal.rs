@@ -0,0 +1,196 @@
+//! Request journal.
+//!
+//! Keeps the most recent requests seen by the agent so tests can check what
+//! actually hit the mock.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A request recorded in the journal.
+#[derive(Debug, Clone, Serialize)]
+pub struct JournalEntry {
+    /// When the request arrived
+    pub timestamp: DateTime<Utc>,
+    /// Request method
+    pub method: String,
+    /// Request path, without the query string
+    pub path: String,
+    /// Raw query string, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    /// Request headers
+    pub headers: HashMap<String, Vec<String>>,
+    /// Start of the request body, decoded lossily as UTF-8
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// Whether `body` was cut short
+    pub body_truncated: bool,
+    /// ID of the stub that answered, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stub_id: Option<String>,
+    /// For requests no stub answered, the enabled stubs whose path fits, so
+    /// their other matchers are what turned the request away
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub considered: Vec<String>,
+    /// Status returned by the agent (`None` when the request was passed upstream)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Whether the agent failed to build the response it was configured to
+    /// send (a template error), rather than serving a status it was set up to
+    pub agent_error: bool,
+    /// Time spent producing the decision, in milliseconds
+    pub latency_ms: f64,
+}
+
+impl JournalEntry {
+    /// Keep at most `max_bytes` of `body`.
+    pub fn with_body(mut self, body: Option<&[u8]>, max_bytes: usize) -> Self {
+        if let Some(body) = body.filter(|b| !b.is_empty()) {
+            let end = body.len().min(max_bytes);
+            self.body = Some(String::from_utf8_lossy(&body[..end]).into_owned());
+            self.body_truncated = end < body.len();
+        }
+        self
+    }
+
+    /// Record how long the request took.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency_ms = latency.as_secs_f64() * 1000.0;
+        self
+    }
+}
+
+/// Bounded ring buffer of recent requests.
+///
+/// The lock is only held to push or pop an entry that has already been
+/// built, so recording stays cheap on the request path.
+#[derive(Debug, Default)]
+pub struct Journal {
+    entries: Mutex<VecDeque<JournalEntry>>,
+    evicted: AtomicU64,
+}
+
+impl Journal {
+    /// Create an empty journal.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append an entry, dropping the oldest ones beyond `capacity`.
+    pub fn record(&self, entry: JournalEntry, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let mut evicted = 0;
+        while entries.len() >= capacity {
+            entries.pop_front();
+            evicted += 1;
+        }
+        entries.push_back(entry);
+        drop(entries);
+        if evicted > 0 {
+            self.evicted.fetch_add(evicted, Ordering::Relaxed);
+        }
+    }
+
+    /// Recorded entries, oldest first.
+    pub fn entries(&self) -> Vec<JournalEntry> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Number of entries currently held.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// Whether the journal holds no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Fraction of held entries the agent failed to answer as configured (0
+    /// when empty). A 5xx a stub is set up to return doesn't count.
+    pub fn error_rate(&self) -> f64 {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.is_empty() {
+            return 0.0;
+        }
+        let errors = entries.iter().filter(|e| e.agent_error).count();
+        errors as f64 / entries.len() as f64
+    }
+
+    /// Number of entries dropped to make room for newer ones.
+    pub fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// Remove every entry.
+    pub fn clear(&self) {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str) -> JournalEntry {
+        JournalEntry {
+            timestamp: Utc::now(),
+            method: "GET".to_string(),
+            path: path.to_string(),
+            query: None,
+            headers: HashMap::new(),
+            body: None,
+            body_truncated: false,
+            stub_id: None,
+            considered: Vec::new(),
+            status: None,
+            agent_error: false,
+            latency_ms: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let journal = Journal::new();
+        for i in 0..5 {
+            journal.record(entry(&format!("/{}", i)), 3);
+        }
+        let paths: Vec<String> = journal.entries().into_iter().map(|e| e.path).collect();
+        assert_eq!(paths, vec!["/2", "/3", "/4"]);
+        assert_eq!(journal.evicted(), 2);
+
+        journal.record(entry("/off"), 0);
+        assert_eq!(journal.len(), 3);
+
+        journal.clear();
+        assert!(journal.is_empty());
+    }
+
+    #[test]
+    fn test_body_truncation() {
+        let short = entry("/").with_body(Some(b"hello"), 16);
+        assert_eq!(short.body.as_deref(), Some("hello"));
+        assert!(!short.body_truncated);
+
+        let long = entry("/").with_body(Some(b"hello world"), 5);
+        assert_eq!(long.body.as_deref(), Some("hello"));
+        assert!(long.body_truncated);
+
+        assert!(entry("/").with_body(Some(b""), 5).body.is_none());
+    }
+}
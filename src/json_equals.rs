@@ -0,0 +1,236 @@
+//! Structural JSON comparison for `type: json_equals` body matching.
+//!
+//! Key order and whitespace never matter. Numbers compare numerically, so
+//! `1` equals `1.0`. Ignored paths use a small JSONPath subset: `$`, then
+//! `.name`, `['name']`, `[0]`, `[*]` or `.*` steps.
+
+use serde_json::Value;
+
+/// How lenient the comparison is.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+    /// Objects in the body may have fields the expected value lacks
+    pub ignore_extra_fields: bool,
+    /// Arrays may list the same elements in any order
+    pub ignore_array_order: bool,
+}
+
+/// One step of an ignored path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// Object field
+    Key(String),
+    /// Array element
+    Index(usize),
+    /// Every field or element
+    Wildcard,
+}
+
+/// Parse an ignored path like `$.items[*].id`.
+pub fn parse_path(path: &str) -> anyhow::Result<Vec<Step>> {
+    let invalid = || anyhow::anyhow!("Invalid ignore path '{}'", path);
+    let mut rest = path.trim().strip_prefix('$').ok_or_else(invalid)?;
+    let mut steps = Vec::new();
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('.') {
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            let name = &after[..end];
+            steps.push(match name {
+                "" => return Err(invalid()),
+                "*" => Step::Wildcard,
+                _ => Step::Key(name.to_string()),
+            });
+            rest = &after[end..];
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']').ok_or_else(invalid)?;
+            let inner = after[..end].trim();
+            steps.push(if inner == "*" {
+                Step::Wildcard
+            } else if let Some(name) = inner
+                .strip_prefix('\'')
+                .and_then(|s| s.strip_suffix('\''))
+                .or_else(|| inner.strip_prefix('"').and_then(|s| s.strip_suffix('"')))
+            {
+                Step::Key(name.to_string())
+            } else {
+                Step::Index(inner.parse().map_err(|_| invalid())?)
+            });
+            rest = &after[end + 1..];
+        } else {
+            return Err(invalid());
+        }
+    }
+
+    if steps.is_empty() {
+        return Err(invalid());
+    }
+    Ok(steps)
+}
+
+/// Remove whatever `steps` point at from `value`.
+pub fn remove_path(value: &mut Value, steps: &[Step]) {
+    let Some((step, rest)) = steps.split_first() else {
+        return;
+    };
+    match (value, step) {
+        (Value::Object(map), Step::Key(key)) => {
+            if rest.is_empty() {
+                map.remove(key);
+            } else if let Some(child) = map.get_mut(key) {
+                remove_path(child, rest);
+            }
+        }
+        (Value::Object(map), Step::Wildcard) => {
+            if rest.is_empty() {
+                map.clear();
+            } else {
+                map.values_mut().for_each(|child| remove_path(child, rest));
+            }
+        }
+        (Value::Array(items), Step::Index(index)) => {
+            if rest.is_empty() {
+                if *index < items.len() {
+                    items.remove(*index);
+                }
+            } else if let Some(child) = items.get_mut(*index) {
+                remove_path(child, rest);
+            }
+        }
+        (Value::Array(items), Step::Wildcard) => {
+            if rest.is_empty() {
+                items.clear();
+            } else {
+                items.iter_mut().for_each(|child| remove_path(child, rest));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Whether `actual` structurally equals `expected`.
+pub fn equals(expected: &Value, actual: &Value, options: Options) -> bool {
+    match (expected, actual) {
+        // Integers compare exactly; `as_f64` would merge those beyond 2^53
+        (Value::Number(e), Value::Number(a)) => {
+            if let (Some(e), Some(a)) = (e.as_i64(), a.as_i64()) {
+                e == a
+            } else if let (Some(e), Some(a)) = (e.as_u64(), a.as_u64()) {
+                e == a
+            } else {
+                match (e.as_f64(), a.as_f64()) {
+                    (Some(e), Some(a)) => e == a,
+                    _ => e == a,
+                }
+            }
+        }
+        (Value::Object(e), Value::Object(a)) => {
+            (options.ignore_extra_fields || e.len() == a.len())
+                && e.iter().all(|(key, value)| {
+                    a.get(key)
+                        .is_some_and(|other| equals(value, other, options))
+                })
+        }
+        (Value::Array(e), Value::Array(a)) => {
+            if e.len() != a.len() {
+                false
+            } else if options.ignore_array_order {
+                unordered_equals(e, a, options)
+            } else {
+                e.iter().zip(a).all(|(e, a)| equals(e, a, options))
+            }
+        }
+        _ => expected == actual,
+    }
+}
+
+/// Pair every expected element with a distinct equal actual element.
+fn unordered_equals(expected: &[Value], actual: &[Value], options: Options) -> bool {
+    // Expected element each actual element is paired with
+    let mut paired = vec![None; actual.len()];
+    (0..expected.len()).all(|e| {
+        let mut seen = vec![false; actual.len()];
+        pair(e, expected, actual, options, &mut paired, &mut seen)
+    })
+}
+
+/// Find a partner for expected element `e`, re-pairing others as needed.
+fn pair(
+    e: usize,
+    expected: &[Value],
+    actual: &[Value],
+    options: Options,
+    paired: &mut [Option<usize>],
+    seen: &mut [bool],
+) -> bool {
+    for a in 0..actual.len() {
+        if seen[a] || !equals(&expected[e], &actual[a], options) {
+            continue;
+        }
+        seen[a] = true;
+        let free = match paired[a] {
+            Some(other) => pair(other, expected, actual, options, paired, seen),
+            None => true,
+        };
+        if free {
+            paired[a] = Some(e);
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(
+            parse_path("$.items[*].id").unwrap(),
+            vec![
+                Step::Key("items".to_string()),
+                Step::Wildcard,
+                Step::Key("id".to_string()),
+            ]
+        );
+        assert_eq!(
+            parse_path("$['a b'][2]").unwrap(),
+            vec![Step::Key("a b".to_string()), Step::Index(2)]
+        );
+        assert!(parse_path("items").is_err());
+        assert!(parse_path("$").is_err());
+        assert!(parse_path("$.a[x]").is_err());
+    }
+
+    #[test]
+    fn test_unordered_arrays_need_distinct_partners() {
+        let options = Options {
+            ignore_array_order: true,
+            ignore_extra_fields: true,
+        };
+        // A greedy pairing of {a:1} with the first element would fail here
+        let expected = json!([{ "a": 1 }, { "a": 1, "b": 2 }]);
+        let actual = json!([{ "a": 1, "b": 2 }, { "a": 1, "c": 3 }]);
+        assert!(equals(&expected, &actual, options));
+
+        let actual = json!([{ "a": 1, "b": 2 }, { "a": 2 }]);
+        assert!(!equals(&expected, &actual, options));
+    }
+
+    #[test]
+    fn test_large_integers_compare_exactly() {
+        let options = Options::default();
+        assert!(equals(&json!(1), &json!(1.0), options));
+        assert!(equals(&json!(u64::MAX), &json!(u64::MAX), options));
+        // Both round to the same f64
+        assert!(!equals(
+            &json!(9_007_199_254_740_993_i64),
+            &json!(9_007_199_254_740_992_i64),
+            options
+        ));
+        assert!(!equals(&json!(u64::MAX), &json!(u64::MAX - 1), options));
+        assert!(!equals(&json!(-1), &json!(u64::MAX), options));
+    }
+}
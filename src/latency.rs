@@ -0,0 +1,96 @@
+//! Response latency tracking.
+//!
+//! Keeps the time spent answering the most recent requests, delays and
+//! fault sleeps included, so the metrics report can show percentiles and
+//! configured delays can be checked end to end.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Number of recent requests percentiles are computed over.
+pub const LATENCY_WINDOW: usize = 1024;
+
+/// Rolling window of request latencies.
+///
+/// Samples are plain durations, so the lock is only held to push one.
+#[derive(Debug, Default)]
+pub struct LatencyWindow {
+    samples: Mutex<VecDeque<Duration>>,
+}
+
+impl LatencyWindow {
+    /// Create an empty window.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a sample, dropping the oldest beyond [`LATENCY_WINDOW`].
+    pub fn record(&self, latency: Duration) {
+        let mut samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+        if samples.len() >= LATENCY_WINDOW {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+    }
+
+    /// Nearest-rank percentile of each `quantile` (0.0 - 1.0), in
+    /// milliseconds, or `None` while the window is empty.
+    pub fn percentiles_ms(&self, quantiles: &[f64]) -> Option<Vec<f64>> {
+        let mut sorted: Vec<Duration> = self
+            .samples
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .copied()
+            .collect();
+        if sorted.is_empty() {
+            return None;
+        }
+        sorted.sort_unstable();
+        let percentiles = quantiles
+            .iter()
+            .map(|q| {
+                let rank = (q.clamp(0.0, 1.0) * sorted.len() as f64).ceil() as usize;
+                sorted[rank.clamp(1, sorted.len()) - 1].as_secs_f64() * 1000.0
+            })
+            .collect();
+        Some(percentiles)
+    }
+
+    /// Remove every sample.
+    pub fn clear(&self) {
+        self.samples
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let window = LatencyWindow::new();
+        assert!(window.percentiles_ms(&[0.5]).is_none());
+
+        for ms in (1..=100).rev() {
+            window.record(Duration::from_millis(ms));
+        }
+        assert_eq!(
+            window.percentiles_ms(&[0.5, 0.95, 0.99, 1.0]).unwrap(),
+            vec![50.0, 95.0, 99.0, 100.0]
+        );
+
+        // Only the most recent samples count
+        for _ in 0..LATENCY_WINDOW {
+            window.record(Duration::from_millis(5));
+        }
+        assert_eq!(window.percentiles_ms(&[0.99]).unwrap(), vec![5.0]);
+
+        window.clear();
+        assert!(window.percentiles_ms(&[0.5]).is_none());
+    }
+}
@@ -30,10 +30,23 @@
 //!           message: "Hello, World!"
 //! ```
 
+pub mod admin;
 pub mod agent;
 pub mod config;
+pub mod har;
+pub mod http;
+pub mod journal;
+pub mod json_equals;
+pub mod latency;
 pub mod matcher;
+pub mod multipart;
+pub mod record;
+pub mod repl;
+pub mod seed;
+pub mod session;
 pub mod template;
+pub mod verify;
+pub mod watch;
 
 pub use agent::MockServerAgent;
 pub use config::MockServerConfig;
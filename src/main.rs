@@ -1,12 +1,29 @@
 //! Zentinel Mock Server Agent - CLI Entry Point
 
 use anyhow::Result;
+use async_trait::async_trait;
 use clap::Parser;
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::path::PathBuf;
-use tracing::{info, Level};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
+use zentinel_agent_mock_server::admin;
+use zentinel_agent_mock_server::config::{
+    json_path_expectation, BodyMatcher, HeaderMatcher, HeaderValueMode, HostMatcher, JsonPathOp,
+    PathMatcher, QueryMatcher, RequestMatcher, StubDefinition,
+};
+use zentinel_agent_mock_server::http;
+use zentinel_agent_mock_server::record;
+use zentinel_agent_mock_server::repl;
+use zentinel_agent_mock_server::seed::SeedRequest;
+use zentinel_agent_mock_server::watch::watch_config;
 use zentinel_agent_mock_server::{MockServerAgent, MockServerConfig};
+use zentinel_agent_protocol::v2::{
+    AgentCapabilities, AgentHandlerV2, DrainReason, HealthStatus, MetricsReport, ShutdownReason,
+};
+use zentinel_agent_sdk::prelude::*;
 use zentinel_agent_sdk::v2::{AgentRunnerV2, TransportConfig};
 
 #[derive(Parser, Debug)]
@@ -16,7 +33,7 @@ use zentinel_agent_sdk::v2::{AgentRunnerV2, TransportConfig};
     version
 )]
 struct Args {
-    /// Path to configuration file
+    /// Path or http(s):// URL of the configuration file
     #[arg(short, long, default_value = "mock-server.yaml")]
     config: PathBuf,
 
@@ -36,9 +53,447 @@ struct Args {
     #[arg(long)]
     print_config: bool,
 
+    /// Print a starter configuration with a stub per path in an access
+    /// log, and exit
+    #[arg(long, value_name = "ACCESS_LOG")]
+    generate_config: Option<PathBuf>,
+
     /// Validate configuration and exit
     #[arg(long)]
     validate: bool,
+
+    /// With --validate, describe how each stub's matchers are interpreted
+    #[arg(long, requires = "validate")]
+    explain: bool,
+
+    /// Load stubs recorded with `settings.record` from a YAML file
+    #[arg(long, value_name = "FILE")]
+    replay: Option<PathBuf>,
+
+    /// Replay synthetic requests from a YAML/JSON file before serving
+    #[arg(long, value_name = "FILE")]
+    seed_requests: Option<PathBuf>,
+
+    /// Reload the configuration file whenever it changes
+    #[arg(long)]
+    watch: bool,
+
+    /// Serve the admin HTTP API (e.g., "127.0.0.1:9090")
+    #[arg(long, value_name = "ADDR")]
+    admin_address: Option<SocketAddr>,
+
+    /// Serve stubs as a plain HTTP server instead of running as a proxy
+    /// agent (e.g., "127.0.0.1:8080")
+    #[arg(long, value_name = "ADDR")]
+    http_listen: Option<SocketAddr>,
+
+    /// Read requests like `GET /users/5` from stdin and print the matching
+    /// stub and response, then exit
+    #[arg(long, conflicts_with_all = ["validate", "http_listen"])]
+    repl: bool,
+}
+
+/// How often buffered HAR entries are written to `settings.har_output`.
+const HAR_FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Lets the runner own the agent while other tasks (e.g. the config
+/// watcher) keep a handle to it.
+struct SharedAgent(Arc<MockServerAgent>);
+
+#[async_trait]
+impl Agent for SharedAgent {
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    async fn on_configure(&self, config: serde_json::Value) -> Result<(), String> {
+        Agent::on_configure(&*self.0, config).await
+    }
+
+    async fn on_request(&self, request: &Request) -> Decision {
+        self.0.on_request(request).await
+    }
+
+    async fn on_request_body(&self, request: &Request) -> Decision {
+        self.0.on_request_body(request).await
+    }
+
+    async fn on_response(&self, request: &Request, response: &Response) -> Decision {
+        self.0.on_response(request, response).await
+    }
+
+    async fn on_response_body(&self, request: &Request, response: &Response) -> Decision {
+        self.0.on_response_body(request, response).await
+    }
+
+    async fn on_request_complete(&self, request: &Request, status: u16, duration_ms: u64) {
+        self.0
+            .on_request_complete(request, status, duration_ms)
+            .await
+    }
+}
+
+/// The runner talks to the agent through the v2 handler, so capabilities,
+/// health, metrics and lifecycle events have to reach it too.
+#[async_trait]
+impl AgentHandlerV2 for SharedAgent {
+    fn capabilities(&self) -> AgentCapabilities {
+        self.0.capabilities()
+    }
+
+    fn health_status(&self) -> HealthStatus {
+        self.0.health_status()
+    }
+
+    fn metrics_report(&self) -> Option<MetricsReport> {
+        self.0.metrics_report()
+    }
+
+    async fn on_configure(&self, config: serde_json::Value, version: Option<String>) -> bool {
+        AgentHandlerV2::on_configure(&*self.0, config, version).await
+    }
+
+    async fn on_shutdown(&self, reason: ShutdownReason, grace_period_ms: u64) {
+        self.0.on_shutdown(reason, grace_period_ms).await
+    }
+
+    async fn on_drain(&self, duration_ms: u64, reason: DrainReason) {
+        self.0.on_drain(duration_ms, reason).await
+    }
+
+    async fn on_stream_closed(&self) {
+        self.0.on_stream_closed().await
+    }
+}
+
+/// Return the config argument as a URL if it uses an http(s) scheme.
+fn config_url(config: &Path) -> Option<&str> {
+    config
+        .to_str()
+        .filter(|s| s.starts_with("http://") || s.starts_with("https://"))
+}
+
+/// Describe a stub's matchers in plain words.
+fn explain_stub(stub: &StubDefinition) -> String {
+    let request = &stub.request;
+    let methods = if request.method.is_empty() {
+        "any method".to_string()
+    } else {
+        explain_methods(&request.method)
+    };
+
+    let conditions = explain_conditions(request);
+
+    let mut explanation = format!("{}: matches {}", stub.id, methods);
+    if !conditions.is_empty() {
+        explanation.push_str(" where ");
+        explanation.push_str(&conditions.join(" and "));
+    }
+
+    let mut notes = Vec::new();
+    if !stub.enabled {
+        notes.push("disabled".to_string());
+    }
+    if stub.priority != 0 {
+        notes.push(format!("priority {}", stub.priority));
+    }
+    if stub.max_matches > 0 {
+        notes.push(format!("at most {} matches", stub.max_matches));
+    }
+    if !stub.requires_matched.is_empty() {
+        notes.push(format!("after {}", stub.requires_matched.join(", ")));
+    }
+    if let Some(response_match) = &stub.response_match {
+        notes.push(format!("only if upstream {}", explain_body(response_match)));
+    }
+    if let Some(scenario) = &stub.scenario {
+        let mut note = format!("scenario {}", scenario);
+        if let Some(required) = &stub.required_state {
+            note.push_str(&format!(" in state {}", required));
+        }
+        if let Some(new_state) = &stub.new_state {
+            note.push_str(&format!(", moves to {}", new_state));
+        }
+        notes.push(note);
+    }
+    if !notes.is_empty() {
+        explanation.push_str(&format!(" ({})", notes.join(", ")));
+    }
+
+    explanation
+}
+
+fn explain_methods(methods: &[String]) -> String {
+    methods
+        .iter()
+        .map(|m| m.to_uppercase())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Describe everything a request matcher checks besides the method.
+fn explain_conditions(request: &RequestMatcher) -> Vec<String> {
+    let mut conditions = Vec::new();
+    if let Some(path) = &request.path {
+        conditions.push(explain_path(path));
+    }
+
+    if let Some(host) = &request.host {
+        conditions.push(match host {
+            HostMatcher::Exact { value } => format!("host is {}", value),
+            HostMatcher::Regex { pattern } => format!("host matches regex {}", pattern),
+            HostMatcher::Wildcard { pattern } => format!("host matches {}", pattern),
+        });
+    }
+
+    if let Some(client_ip) = &request.client_ip {
+        let source = match &client_ip.from_header {
+            Some(header) => format!("{}[{}]", header, client_ip.index),
+            None => "client IP".to_string(),
+        };
+        conditions.push(format!("{} is in {}", source, client_ip.ranges.join(", ")));
+    }
+
+    if let Some(cert) = &request.client_cert {
+        if let Some(cn) = &cert.subject_cn {
+            conditions.push(format!("client cert CN matches regex {}", cn));
+        }
+        if let Some(san) = &cert.san_contains {
+            conditions.push(format!("client cert SAN contains {}", san));
+        }
+    }
+
+    let mut query: Vec<_> = request.query.iter().collect();
+    query.sort_by_key(|(name, _)| name.as_str());
+    for (name, matcher) in query {
+        conditions.push(explain_query(name, matcher));
+    }
+
+    let mut headers: Vec<_> = request.headers.iter().collect();
+    headers.sort_by_key(|(name, _)| name.as_str());
+    for (name, matcher) in headers {
+        conditions.push(explain_header(name, matcher));
+    }
+
+    if let Some(body) = &request.body {
+        conditions.push(explain_body(body));
+    }
+
+    if let Some(jwt) = &request.jwt {
+        let mut claims: Vec<_> = jwt.claims.keys().map(String::as_str).collect();
+        claims.sort();
+        conditions.push(if claims.is_empty() {
+            format!("{} carries a JWT", jwt.header)
+        } else {
+            format!(
+                "{} carries a JWT with claims {}",
+                jwt.header,
+                claims.join(", ")
+            )
+        });
+    }
+
+    if let Some(auth) = &request.basic_auth {
+        conditions.push(match &auth.password {
+            Some(_) => format!(
+                "basic auth as {} with the configured password",
+                auth.username
+            ),
+            None => format!("basic auth as {}", auth.username),
+        });
+    }
+
+    match (request.max_headers, request.max_header_bytes) {
+        (Some(count), Some(bytes)) => conditions.push(format!(
+            "more than {} headers or {} header bytes",
+            count, bytes
+        )),
+        (Some(count), None) => conditions.push(format!("more than {} headers", count)),
+        (None, Some(bytes)) => conditions.push(format!("more than {} header bytes", bytes)),
+        (None, None) => {}
+    }
+
+    if let Some(status) = request.after_status {
+        conditions.push(format!("the session was last served {}", status));
+    }
+
+    for sub in &request.all_of {
+        conditions.push(format!("({})", explain_sub_matcher(sub)));
+    }
+    if !request.any_of.is_empty() {
+        let branches: Vec<_> = request.any_of.iter().map(explain_sub_matcher).collect();
+        conditions.push(format!("({})", branches.join(" or ")));
+    }
+    if let Some(sub) = &request.not {
+        conditions.push(format!("not ({})", explain_sub_matcher(sub)));
+    }
+    conditions
+}
+
+/// Describe an `any_of`/`all_of`/`not` entry, whose method is one more condition.
+fn explain_sub_matcher(request: &RequestMatcher) -> String {
+    let mut conditions = Vec::new();
+    if !request.method.is_empty() {
+        conditions.push(format!("method is {}", explain_methods(&request.method)));
+    }
+    conditions.extend(explain_conditions(request));
+    if conditions.is_empty() {
+        "anything".to_string()
+    } else {
+        conditions.join(" and ")
+    }
+}
+
+fn explain_path(path: &PathMatcher) -> String {
+    match path {
+        PathMatcher::Exact { value } => format!("path exactly equals {}", value),
+        PathMatcher::Prefix {
+            value,
+            segment_boundary: false,
+        } => format!("path starts with {}", value),
+        PathMatcher::Prefix {
+            value,
+            segment_boundary: true,
+        } => format!("path is {} or below it", value),
+        PathMatcher::Regex { pattern } => format!("path matches regex {}", pattern),
+        PathMatcher::Glob { pattern } => format!("path matches glob {}", pattern),
+        PathMatcher::Template { template } => format!("path fits template {}", template),
+        PathMatcher::Not { matcher } => format!("not ({})", explain_path(matcher)),
+    }
+}
+
+fn explain_query(name: &str, matcher: &QueryMatcher) -> String {
+    match matcher {
+        QueryMatcher::Exact { value } => format!("query {} equals {}", name, value),
+        QueryMatcher::Regex { pattern } => format!("query {} matches regex {}", name, pattern),
+        QueryMatcher::Present => format!("query {} present", name),
+        QueryMatcher::Absent => format!("query {} absent", name),
+        QueryMatcher::Contains { value } => format!("query {} contains {}", name, value),
+        QueryMatcher::CsvContains { value } => format!("query {} lists {}", name, value),
+        QueryMatcher::AnyValue { value } => format!("query {} has value {}", name, value),
+        QueryMatcher::Count { count } => format!("query {} repeated {} times", name, count),
+        QueryMatcher::All { values } => {
+            format!("query {} has values {}", name, values.join(", "))
+        }
+        QueryMatcher::JsonPath { expressions } => {
+            format!(
+                "query {} is JSON where {}",
+                name,
+                explain_json_paths(expressions)
+            )
+        }
+        QueryMatcher::Not { matcher } => format!("not ({})", explain_query(name, matcher)),
+    }
+}
+
+fn explain_header(name: &str, matcher: &HeaderMatcher) -> String {
+    match matcher {
+        HeaderMatcher::Exact { value, values } => {
+            format!("{} equals {}", header_values(name, *values), value)
+        }
+        HeaderMatcher::Regex { pattern, values } => {
+            format!("{} matches regex {}", header_values(name, *values), pattern)
+        }
+        HeaderMatcher::Present => format!("header {} present", name),
+        HeaderMatcher::Absent => format!("header {} absent", name),
+        HeaderMatcher::Contains { value, values } => {
+            format!("{} contains {}", header_values(name, *values), value)
+        }
+        HeaderMatcher::AnyValue { value } => {
+            format!("any header {} value equals {}", name, value)
+        }
+        HeaderMatcher::AllValues { value } => {
+            format!("every header {} value equals {}", name, value)
+        }
+        HeaderMatcher::Count { count } => format!("header {} sent {} times", name, count),
+        HeaderMatcher::Not { matcher } => format!("not ({})", explain_header(name, matcher)),
+    }
+}
+
+/// Which values of a header a matcher describes.
+fn header_values(name: &str, values: HeaderValueMode) -> String {
+    match values {
+        HeaderValueMode::First => format!("header {}", name),
+        HeaderValueMode::Any => format!("any header {} value", name),
+        HeaderValueMode::All => format!("every header {} value", name),
+    }
+}
+
+fn explain_body(body: &BodyMatcher) -> String {
+    match body {
+        BodyMatcher::Exact { value } => format!("body exactly equals {}", value),
+        BodyMatcher::Regex { pattern } => format!("body matches regex {}", pattern),
+        BodyMatcher::JsonPath { expressions } => {
+            format!("body is JSON where {}", explain_json_paths(expressions))
+        }
+        BodyMatcher::Contains { value } => format!("body contains {}", value),
+        BodyMatcher::Json => "body is valid JSON".to_string(),
+        BodyMatcher::JsonEquals { value, .. } => format!("body is JSON equal to {}", value),
+        BodyMatcher::YamlPath { expressions } => {
+            format!("body is YAML where {}", explain_json_paths(expressions))
+        }
+        BodyMatcher::Yaml => "body is valid YAML".to_string(),
+        BodyMatcher::Hash { algorithm, value } => format!("body {} is {}", algorithm, value),
+        BodyMatcher::Empty => "body is empty".to_string(),
+        BodyMatcher::Multipart { parts } => {
+            let mut names: Vec<_> = parts.keys().map(String::as_str).collect();
+            names.sort();
+            format!("body is multipart with parts {}", names.join(", "))
+        }
+        BodyMatcher::GraphQl {
+            operation_name,
+            query_contains,
+            variables,
+        } => {
+            let mut conditions = Vec::new();
+            if let Some(name) = operation_name {
+                conditions.push(format!("operation is {}", name));
+            }
+            if let Some(text) = query_contains {
+                conditions.push(format!("query contains {}", text));
+            }
+            if !variables.is_empty() {
+                let mut names: Vec<_> = variables.keys().map(String::as_str).collect();
+                names.sort();
+                conditions.push(format!("variables {} match", names.join(", ")));
+            }
+            if conditions.is_empty() {
+                "body is a GraphQL request".to_string()
+            } else {
+                format!(
+                    "body is a GraphQL request where {}",
+                    conditions.join(" and ")
+                )
+            }
+        }
+        BodyMatcher::Not { matcher } => format!("not ({})", explain_body(matcher)),
+    }
+}
+
+/// Describe JSON path expressions; a null expected value only checks existence.
+fn explain_json_paths(expressions: &HashMap<String, serde_json::Value>) -> String {
+    let mut paths: Vec<_> = expressions.iter().collect();
+    paths.sort_by_key(|(path, _)| path.as_str());
+    paths
+        .into_iter()
+        .map(|(path, expected)| match json_path_expectation(expected) {
+            Ok((JsonPathOp::Eq, serde_json::Value::Null)) => format!("{} exists", path),
+            Ok((op, value)) => {
+                let op = match op {
+                    JsonPathOp::Eq => "equals",
+                    JsonPathOp::Gt => ">",
+                    JsonPathOp::Gte => ">=",
+                    JsonPathOp::Lt => "<",
+                    JsonPathOp::Lte => "<=",
+                    JsonPathOp::Contains => "contains",
+                    JsonPathOp::Regex => "matches",
+                };
+                format!("{} {} {}", path, op, value)
+            }
+            Err(e) => format!("{} is invalid ({})", path, e),
+        })
+        .collect::<Vec<_>>()
+        .join(" and ")
 }
 
 #[tokio::main]
@@ -59,8 +514,18 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
+    if let Some(log) = &args.generate_config {
+        let log = std::fs::read_to_string(log)
+            .map_err(|e| anyhow::anyhow!("Failed to read access log {:?}: {}", log, e))?;
+        print!("{}", record::config_from_access_log(&log)?);
+        return Ok(());
+    }
+
     // Load configuration
-    let config = if args.config.exists() {
+    let mut config = if let Some(url) = config_url(&args.config) {
+        info!(url = %url, "Loading configuration from URL");
+        MockServerConfig::from_url(url).await?
+    } else if args.config.exists() {
         info!(path = ?args.config, "Loading configuration");
         MockServerConfig::from_file(&args.config)?
     } else if args.validate {
@@ -70,6 +535,13 @@ async fn main() -> Result<()> {
         MockServerConfig::default()
     };
 
+    // Recorded stubs serve alongside the configured ones
+    if let Some(replay_path) = &args.replay {
+        let recorded = record::load(replay_path)?;
+        info!(path = ?replay_path, stubs = recorded.len(), "Loaded recorded stubs");
+        config.stubs.extend(recorded);
+    }
+
     // Validate and exit if requested
     if args.validate {
         config.validate()?;
@@ -77,11 +549,68 @@ async fn main() -> Result<()> {
             "Configuration is valid ({} stubs defined)",
             config.stubs.len()
         );
+        if args.explain {
+            for stub in &config.stubs {
+                println!("  {}", explain_stub(stub));
+            }
+        }
         return Ok(());
     }
 
+    let watch = args.watch || config.settings.watch_config;
+
     // Create agent
-    let agent = MockServerAgent::new(config);
+    let agent = Arc::new(MockServerAgent::new(config));
+
+    // Pre-warm match counts from seed requests
+    if let Some(seed_path) = &args.seed_requests {
+        let seeds = SeedRequest::load_file(seed_path)?;
+        info!(path = ?seed_path, requests = seeds.len(), "Replaying seed requests");
+        agent.seed(&seeds).await;
+    }
+
+    if args.repl {
+        let stdin = tokio::io::BufReader::new(tokio::io::stdin());
+        return repl::run(&agent, stdin, tokio::io::stdout()).await;
+    }
+
+    // Keep the watcher alive for as long as the agent runs
+    let _watcher = if !watch {
+        None
+    } else if config_url(&args.config).is_some() {
+        warn!("Ignoring --watch for a configuration loaded from a URL");
+        None
+    } else {
+        Some(watch_config(&args.config, agent.clone())?)
+    };
+
+    // Captured HAR entries are buffered; write them out every few seconds
+    {
+        let agent = agent.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(HAR_FLUSH_INTERVAL);
+            loop {
+                ticker.tick().await;
+                agent.flush_har().await;
+            }
+        });
+    }
+
+    if let Some(admin_addr) = args.admin_address {
+        let listener = tokio::net::TcpListener::bind(admin_addr).await?;
+        let agent = agent.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(listener, agent).await {
+                error!(error = %e, "Admin API stopped");
+            }
+        });
+    }
+
+    // Standalone mode: answer HTTP directly, without the proxy
+    if let Some(http_addr) = args.http_listen {
+        let listener = tokio::net::TcpListener::bind(http_addr).await?;
+        return http::serve(listener, agent).await;
+    }
 
     // Configure transport based on CLI options
     let transport = match args.grpc_address {
@@ -103,7 +632,7 @@ async fn main() -> Result<()> {
     };
 
     // Run agent with v2 runner
-    let mut runner = AgentRunnerV2::new(agent).with_name("mock-server");
+    let mut runner = AgentRunnerV2::new(SharedAgent(agent.clone())).with_name("mock-server");
 
     runner = match transport {
         TransportConfig::Grpc { address } => runner.with_grpc(address),
@@ -115,6 +644,149 @@ async fn main() -> Result<()> {
     };
 
     runner.run().await?;
+    agent.flush_har().await;
+
+    check_coverage(&agent).await
+}
 
+/// Fail the run if `require_all_matched` found unmatched stubs on drain or
+/// shutdown.
+async fn check_coverage(agent: &MockServerAgent) -> Result<()> {
+    if agent.coverage_failed() {
+        anyhow::bail!(
+            "Stubs never matched: {}",
+            agent.unmatched_stubs().await.join(", ")
+        );
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zentinel_agent_protocol::EventType;
+
+    #[tokio::test]
+    async fn test_shared_agent_forwards_v2_handler() {
+        let config: MockServerConfig = serde_yaml::from_str(
+            r#"
+settings:
+  contract_verification: true
+stubs:
+  - id: hello
+    request:
+      path: { type: exact, value: /hello }
+"#,
+        )
+        .unwrap();
+        let agent = Arc::new(MockServerAgent::new(config));
+        let shared = SharedAgent(agent.clone());
+
+        assert!(shared
+            .capabilities()
+            .supported_events
+            .contains(&EventType::ResponseHeaders));
+        let report = shared.metrics_report().unwrap();
+        assert!(report
+            .gauges
+            .iter()
+            .any(|g| g.name == "mock_server_stub_matches"));
+        assert!(shared.health_status().message.is_some());
+
+        let pushed = serde_json::json!({
+            "stubs": [{ "id": "pushed", "request": { "path": { "type": "exact", "value": "/p" } } }]
+        });
+        assert!(AgentHandlerV2::on_configure(&shared, pushed, None).await);
+        assert_eq!(agent.stubs()[0].id, "pushed");
+
+        shared.on_drain(1000, DrainReason::Maintenance).await;
+        assert!(agent.is_draining());
+    }
+
+    #[tokio::test]
+    async fn test_coverage_fails_run_through_runner() {
+        let config: MockServerConfig = serde_yaml::from_str(
+            r#"
+settings:
+  require_all_matched: true
+stubs:
+  - id: hit
+    request:
+      path: { type: exact, value: /hit }
+  - id: missed
+    request:
+      path: { type: exact, value: /missed }
+"#,
+        )
+        .unwrap();
+        let agent = Arc::new(MockServerAgent::new(config));
+        let shared = SharedAgent(agent.clone());
+
+        let request = SeedRequest {
+            method: "GET".to_string(),
+            path: "/hit".to_string(),
+            headers: HashMap::new(),
+            body: None,
+        };
+        shared.on_request(&request.to_request("seed-1")).await;
+        assert!(check_coverage(&agent).await.is_ok());
+
+        shared.on_shutdown(ShutdownReason::Graceful, 1000).await;
+        let error = check_coverage(&agent).await.unwrap_err();
+        assert_eq!(error.to_string(), "Stubs never matched: missed");
+    }
+
+    #[test]
+    fn test_explain_stub() {
+        let stub: StubDefinition = serde_yaml::from_str(
+            r#"
+id: create-user
+request:
+  method: [get, POST]
+  path:
+    type: exact
+    value: /x
+  headers:
+    auth:
+      type: present
+  body:
+    type: json_path
+    expressions:
+      $.name: null
+      $.role: "admin"
+      $.age: { op: gt, value: 18 }
+response:
+  status: 201
+priority: 5
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            explain_stub(&stub),
+            "create-user: matches GET/POST where path exactly equals /x and header auth present \
+             and body is JSON where $.age > 18 and $.name exists and $.role equals \"admin\" (priority 5)"
+        );
+    }
+
+    #[test]
+    fn test_explain_combinators() {
+        let stub: StubDefinition = serde_yaml::from_str(
+            r#"
+id: either
+request:
+  any_of:
+    - path: { type: exact, value: /a }
+    - method: [post]
+      path: { type: exact, value: /b }
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            explain_stub(&stub),
+            "either: matches any method where (path exactly equals /a or method is POST and \
+             path exactly equals /b)"
+        );
+    }
+}
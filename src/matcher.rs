@@ -3,20 +3,184 @@
 //! Matches incoming requests against stub definitions.
 
 use crate::config::{
-    BodyMatcher, HeaderMatcher, PathMatcher, QueryMatcher, RequestMatcher, StubDefinition,
+    digest_algorithm, json_path_expectation, BodyMatcher, ClientCertMatcher, ClientIpMatcher,
+    GlobalSettings, HeaderMatcher, HeaderValueMode, HostMatcher, JsonPathOp, PartMatcher,
+    PathMatcher, QueryMatcher, RequestMatcher, StubDefinition,
 };
+use crate::json_equals;
+use crate::multipart;
 use regex::Regex;
+use serde::Serialize;
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::net::IpAddr;
 
 /// Context captured during matching (for template variables).
 #[derive(Debug, Clone, Default)]
 pub struct MatchContext {
     /// Path parameters extracted from template matching
     pub path_params: HashMap<String, String>,
-    /// Query parameters
+    /// Request host, normalized as for `host` matching
+    pub host: Option<String>,
+    /// Client address, as resolved by a `client_ip` matcher or else the peer's
+    pub client_ip: Option<String>,
+    /// Query parameters (last value of repeated ones)
     pub query_params: HashMap<String, String>,
+    /// Every value of every query parameter
+    pub query_all: HashMap<String, Vec<String>>,
+    /// Query string as received, without the leading `?`
+    pub raw_query: String,
+    /// Every value of every request header
+    pub headers_all: HashMap<String, Vec<String>>,
     /// Regex capture groups
     pub captures: HashMap<String, String>,
+    /// Text fields of a multipart body matched by `type: multipart`
+    pub form: HashMap<String, String>,
+    /// GraphQL request matched by `type: graphql`
+    pub graphql: Option<GraphQlRequest>,
+    /// Claims of a JWT matched by `jwt`
+    pub jwt: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Credentials matched by `basic_auth`
+    pub auth: Option<BasicAuth>,
+}
+
+/// Identity from a matched `Authorization: Basic` header. The password is
+/// left out so templates can't echo it.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BasicAuth {
+    /// Decoded username
+    pub username: String,
+}
+
+/// A GraphQL request, from its JSON envelope or a raw query body.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GraphQlRequest {
+    /// Query text
+    pub query: String,
+    /// `operationName`, or the name of the query's first operation
+    pub operation_name: Option<String>,
+    /// Variables from the envelope (none for raw query bodies)
+    pub variables: serde_json::Map<String, serde_json::Value>,
+}
+
+impl GraphQlRequest {
+    /// Parse a GraphQL body. `application/graphql` bodies, and bodies that
+    /// aren't a JSON envelope, are taken as the bare query.
+    fn parse(body: &str, content_type: Option<&str>) -> Option<Self> {
+        let raw = content_type.is_some_and(|ct| {
+            ct.trim()
+                .to_ascii_lowercase()
+                .starts_with("application/graphql")
+        });
+        let envelope = (!raw)
+            .then(|| serde_json::from_str::<serde_json::Value>(body).ok())
+            .flatten();
+
+        let (query, operation_name, variables) = match envelope {
+            Some(serde_json::Value::Object(mut envelope)) => {
+                let query = envelope.get("query")?.as_str()?.to_string();
+                let operation_name = envelope
+                    .get("operationName")
+                    .and_then(|name| name.as_str())
+                    .map(String::from);
+                let variables = match envelope.remove("variables") {
+                    Some(serde_json::Value::Object(variables)) => variables,
+                    _ => serde_json::Map::new(),
+                };
+                (query, operation_name, variables)
+            }
+            Some(_) => return None,
+            None => (body.to_string(), None, serde_json::Map::new()),
+        };
+        if query.trim().is_empty() {
+            return None;
+        }
+
+        let operation_name = operation_name.or_else(|| first_operation_name(&query));
+        Some(Self {
+            query,
+            operation_name,
+            variables,
+        })
+    }
+}
+
+/// Name of the first named operation in a GraphQL document.
+fn first_operation_name(query: &str) -> Option<String> {
+    static OPERATION: std::sync::OnceLock<Regex> = std::sync::OnceLock::new();
+    let regex = OPERATION.get_or_init(|| {
+        Regex::new(r"\b(?:query|mutation|subscription)\s+([_A-Za-z][_0-9A-Za-z]*)").unwrap()
+    });
+    regex
+        .captures(query)
+        .map(|captures| captures[1].to_string())
+}
+
+impl MatchContext {
+    /// Take over path parameters and captures from a sub-matcher's context.
+    fn merge(&mut self, other: MatchContext) {
+        self.path_params.extend(other.path_params);
+        self.captures.extend(other.captures);
+        self.form.extend(other.form);
+        if other.graphql.is_some() {
+            self.graphql = other.graphql;
+        }
+        if other.jwt.is_some() {
+            self.jwt = other.jwt;
+        }
+        if other.auth.is_some() {
+            self.auth = other.auth;
+        }
+    }
+}
+
+/// The parts of a request that stubs match on.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestParts<'a> {
+    /// HTTP method
+    pub method: &'a str,
+    /// Path, without the query string
+    pub path: &'a str,
+    /// Raw query string, without the `?`
+    pub query: Option<&'a str>,
+    /// Request headers, with every value of repeated ones
+    pub headers: &'a HashMap<String, Vec<String>>,
+    /// Request body, if it has been received
+    pub body: Option<&'a [u8]>,
+    /// Address of the connection's peer, if known
+    pub client_ip: Option<&'a str>,
+}
+
+impl<'a> RequestParts<'a> {
+    /// A request with no query string, body or known peer address.
+    pub fn new(method: &'a str, path: &'a str, headers: &'a HashMap<String, Vec<String>>) -> Self {
+        Self {
+            method,
+            path,
+            query: None,
+            headers,
+            body: None,
+            client_ip: None,
+        }
+    }
+
+    /// Set the query string.
+    pub fn with_query(mut self, query: &'a str) -> Self {
+        self.query = Some(query);
+        self
+    }
+
+    /// Set the request body.
+    pub fn with_body(mut self, body: &'a [u8]) -> Self {
+        self.body = Some(body);
+        self
+    }
+
+    /// Set the peer address.
+    pub fn with_client_ip(mut self, client_ip: &'a str) -> Self {
+        self.client_ip = Some(client_ip);
+        self
+    }
 }
 
 /// Result of matching a request against stubs.
@@ -30,16 +194,60 @@ pub struct MatchResult<'a> {
 
 /// Request matcher engine.
 pub struct Matcher {
-    /// Compiled path matchers (Option because path matcher is optional per stub)
-    path_matchers: Vec<Option<CompiledPathMatcher>>,
+    /// Compiled path and body matchers, one tree per stub
+    requests: Vec<CompiledRequest>,
+    /// Normalization the compiled matchers expect of request paths
+    path_options: PathOptions,
+    /// Each stub's criteria, compiled on their own the first time a closest
+    /// match is looked for
+    criteria: std::sync::OnceLock<Vec<Vec<Criterion>>>,
+}
+
+/// One criterion of a stub's request matcher, as a matcher of its own.
+struct Criterion {
+    label: String,
+    matcher: RequestMatcher,
+    compiled: CompiledRequest,
+}
+
+/// Path and body matchers of a request matcher and its
+/// `any_of`/`all_of`/`not` sub-matchers (Option because both are optional).
+struct CompiledRequest {
+    path: Option<CompiledPathMatcher>,
+    body: Option<CompiledBody>,
+    any_of: Vec<CompiledRequest>,
+    all_of: Vec<CompiledRequest>,
+    not: Option<Box<CompiledRequest>>,
+}
+
+/// What a body matcher needs prepared before it sees a request.
+enum CompiledBody {
+    /// `json_equals`, or `None` if one of its ignored paths doesn't parse
+    JsonEquals(Option<PreparedJsonEquals>),
+    Not(Box<CompiledBody>),
+    /// Matchers with nothing to prepare
+    Plain,
+}
+
+/// A `json_equals` expected value with its ignored paths parsed and already
+/// removed from it.
+struct PreparedJsonEquals {
+    expected: serde_json::Value,
+    ignore: Vec<Vec<json_equals::Step>>,
 }
 
 enum CompiledPathMatcher {
     Exact(String),
-    Prefix(String),
+    Prefix {
+        value: String,
+        segment_boundary: bool,
+    },
     Regex(Regex),
     Glob(globset::GlobMatcher),
     Template(PathTemplate),
+    Not(Box<CompiledPathMatcher>),
+    /// A pattern that doesn't compile, which never matches
+    Invalid,
 }
 
 struct PathTemplate {
@@ -48,85 +256,373 @@ struct PathTemplate {
 
 enum TemplateSegment {
     Literal(String),
-    Param(String),
+    /// Named parameter, optionally constrained (`{id:int}`, `{id:\d+}`)
+    Param(String, Option<Constraint>),
+    /// Parameter that may span segments, slashes included (`{path*}` or
+    /// `{path:*}`)
+    Greedy(String, Option<Constraint>),
+}
+
+/// What a path parameter's value must look like.
+enum Constraint {
+    /// Digits, optionally after a `-`
+    Int,
+    /// A hyphenated UUID (`8-4-4-4-12` hex digits)
+    Uuid,
+    /// Anchored regex covering the whole value
+    Regex(Regex),
+}
+
+impl Constraint {
+    fn parse(name: &str, pattern: &str) -> anyhow::Result<Self> {
+        Ok(match pattern {
+            "int" => Constraint::Int,
+            "uuid" => Constraint::Uuid,
+            _ => Constraint::Regex(Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| {
+                anyhow::anyhow!("Invalid regex for path parameter '{}': {}", name, e)
+            })?),
+        })
+    }
+
+    fn accepts(&self, value: &str) -> bool {
+        match self {
+            Constraint::Int => {
+                let digits = value.strip_prefix('-').unwrap_or(value);
+                !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+            }
+            Constraint::Uuid => {
+                value.len() == 36
+                    && value.bytes().enumerate().all(|(i, b)| match i {
+                        8 | 13 | 18 | 23 => b == b'-',
+                        _ => b.is_ascii_hexdigit(),
+                    })
+            }
+            Constraint::Regex(re) => re.is_match(value),
+        }
+    }
+}
+
+/// The stub that came closest to matching a request, from
+/// [`Matcher::closest_match`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClosestMatch {
+    /// ID of the stub
+    pub stub_id: String,
+    /// Criteria the request met, e.g. `path`, `header accept`
+    pub matched: Vec<String>,
+    /// Criteria the request failed
+    pub failed: Vec<String>,
+}
+
+/// Each criterion of `matcher` as a matcher of its own, labelled for
+/// diagnostics.
+fn criteria(matcher: &RequestMatcher) -> Vec<(String, RequestMatcher)> {
+    let mut criteria = Vec::new();
+    let mut add = |label: String, criterion: RequestMatcher| criteria.push((label, criterion));
+
+    if !matcher.method.is_empty() {
+        add(
+            "method".to_string(),
+            RequestMatcher {
+                method: matcher.method.clone(),
+                ..Default::default()
+            },
+        );
+    }
+    if matcher.path.is_some() {
+        add(
+            "path".to_string(),
+            RequestMatcher {
+                path: matcher.path.clone(),
+                ..Default::default()
+            },
+        );
+    }
+    if matcher.host.is_some() {
+        add(
+            "host".to_string(),
+            RequestMatcher {
+                host: matcher.host.clone(),
+                ..Default::default()
+            },
+        );
+    }
+    if matcher.client_ip.is_some() {
+        add(
+            "client_ip".to_string(),
+            RequestMatcher {
+                client_ip: matcher.client_ip.clone(),
+                ..Default::default()
+            },
+        );
+    }
+    if matcher.client_cert.is_some() {
+        add(
+            "client_cert".to_string(),
+            RequestMatcher {
+                client_cert: matcher.client_cert.clone(),
+                ..Default::default()
+            },
+        );
+    }
+    let mut query: Vec<_> = matcher.query.iter().collect();
+    query.sort_by_key(|(name, _)| name.as_str());
+    for (name, qm) in query {
+        add(
+            format!("query {}", name),
+            RequestMatcher {
+                query: HashMap::from([(name.clone(), qm.clone())]),
+                ..Default::default()
+            },
+        );
+    }
+    let mut headers: Vec<_> = matcher.headers.iter().collect();
+    headers.sort_by_key(|(name, _)| name.as_str());
+    for (name, hm) in headers {
+        add(
+            format!("header {}", name),
+            RequestMatcher {
+                headers: HashMap::from([(name.clone(), hm.clone())]),
+                ..Default::default()
+            },
+        );
+    }
+    if matcher.jwt.is_some() {
+        add(
+            "jwt".to_string(),
+            RequestMatcher {
+                jwt: matcher.jwt.clone(),
+                ..Default::default()
+            },
+        );
+    }
+    if matcher.basic_auth.is_some() {
+        add(
+            "basic_auth".to_string(),
+            RequestMatcher {
+                basic_auth: matcher.basic_auth.clone(),
+                ..Default::default()
+            },
+        );
+    }
+    if matcher.max_headers.is_some() || matcher.max_header_bytes.is_some() {
+        add(
+            "header limits".to_string(),
+            RequestMatcher {
+                max_headers: matcher.max_headers,
+                max_header_bytes: matcher.max_header_bytes,
+                ..Default::default()
+            },
+        );
+    }
+    if matcher.body.is_some() {
+        add(
+            "body".to_string(),
+            RequestMatcher {
+                body: matcher.body.clone(),
+                ..Default::default()
+            },
+        );
+    }
+    if !matcher.any_of.is_empty() {
+        add(
+            "any_of".to_string(),
+            RequestMatcher {
+                any_of: matcher.any_of.clone(),
+                ..Default::default()
+            },
+        );
+    }
+    if !matcher.all_of.is_empty() {
+        add(
+            "all_of".to_string(),
+            RequestMatcher {
+                all_of: matcher.all_of.clone(),
+                ..Default::default()
+            },
+        );
+    }
+    if matcher.not.is_some() {
+        add(
+            "not".to_string(),
+            RequestMatcher {
+                not: matcher.not.clone(),
+                ..Default::default()
+            },
+        );
+    }
+    criteria
+}
+
+/// Check that a path template's parameter regexes compile and that it has at
+/// most one greedy parameter.
+pub fn validate_path_template(template: &str) -> anyhow::Result<()> {
+    PathTemplate::parse(template).map(|_| ())
 }
 
 impl PathTemplate {
-    fn parse(template: &str) -> Self {
+    fn parse(template: &str) -> anyhow::Result<Self> {
         let mut segments = Vec::new();
         let mut current = String::new();
-        let mut in_param = false;
-        let mut param_name = String::new();
+        // Brace nesting inside a parameter, so regexes can use `{n}`
+        let mut depth = 0;
+        let mut param = String::new();
 
         for ch in template.chars() {
-            if ch == '{' && !in_param {
-                if !current.is_empty() {
-                    segments.push(TemplateSegment::Literal(current.clone()));
-                    current.clear();
+            if depth == 0 {
+                if ch == '{' {
+                    if !current.is_empty() {
+                        segments.push(TemplateSegment::Literal(current.clone()));
+                        current.clear();
+                    }
+                    depth = 1;
+                    param.clear();
+                } else {
+                    current.push(ch);
                 }
-                in_param = true;
-                param_name.clear();
-            } else if ch == '}' && in_param {
-                segments.push(TemplateSegment::Param(param_name.clone()));
-                in_param = false;
-                param_name.clear();
-            } else if in_param {
-                param_name.push(ch);
+                continue;
+            }
+            match ch {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                segments.push(Self::param(&param)?);
             } else {
-                current.push(ch);
+                param.push(ch);
             }
         }
 
+        if depth > 0 {
+            anyhow::bail!("Path template '{}' has an unterminated parameter", template);
+        }
         if !current.is_empty() {
             segments.push(TemplateSegment::Literal(current));
         }
 
-        Self { segments }
+        let greedy = segments
+            .iter()
+            .filter(|s| matches!(s, TemplateSegment::Greedy(..)))
+            .count();
+        if greedy > 1 {
+            anyhow::bail!(
+                "Path template '{}' has more than one greedy parameter",
+                template
+            );
+        }
+
+        Ok(Self { segments })
+    }
+
+    /// Parse the inside of `{name}`, `{name:int}`, `{name:uuid}` or
+    /// `{name:regex}`, either with a `*` after the name to make it greedy.
+    /// `{name:*}` is greedy too.
+    fn param(param: &str) -> anyhow::Result<TemplateSegment> {
+        let (name, pattern) = match param.split_once(':') {
+            Some((name, "*")) => (format!("{}*", name), None),
+            Some((name, pattern)) => (name.to_string(), Some(pattern)),
+            None => (param.to_string(), None),
+        };
+        let constraint = pattern
+            .map(|pattern| Constraint::parse(&name, pattern))
+            .transpose()?;
+        Ok(match name.strip_suffix('*') {
+            Some(name) => TemplateSegment::Greedy(name.to_string(), constraint),
+            None => TemplateSegment::Param(name, constraint),
+        })
+    }
+
+    /// Fold the case of the literals, for matching against folded paths.
+    fn fold_case(mut self) -> Self {
+        for segment in &mut self.segments {
+            if let TemplateSegment::Literal(lit) = segment {
+                lit.make_ascii_lowercase();
+            }
+        }
+        self
+    }
+
+    /// Drop a trailing slash from the last literal.
+    fn trim_trailing_slash(mut self) -> Self {
+        // The root template keeps its slash
+        let root =
+            matches!(self.segments.as_slice(), [TemplateSegment::Literal(lit)] if lit == "/");
+        if let Some(TemplateSegment::Literal(lit)) = self.segments.last_mut().filter(|_| !root) {
+            if lit.ends_with('/') {
+                lit.pop();
+            }
+            if lit.is_empty() {
+                self.segments.pop();
+            }
+        }
+        self
     }
 
-    fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+    /// Match `folded` (which `fold_case` literals compare against) and
+    /// capture parameters from `path` at the same offsets.
+    fn matches(&self, path: &str, folded: &str) -> Option<HashMap<String, String>> {
         let mut params = HashMap::new();
-        let mut remaining = path;
+        let mut offset = 0;
 
-        for segment in &self.segments {
+        for (i, segment) in self.segments.iter().enumerate() {
+            let remaining = &folded[offset..];
             match segment {
                 TemplateSegment::Literal(lit) => {
                     if remaining.starts_with(lit) {
-                        remaining = &remaining[lit.len()..];
+                        offset += lit.len();
                     } else {
                         return None;
                     }
                 }
-                TemplateSegment::Param(name) => {
-                    // Find the next literal or end of string
-                    let end_pos = if let Some(next_segment) = self
-                        .segments
-                        .iter()
-                        .find(|s| matches!(s, TemplateSegment::Literal(_)))
-                    {
-                        if let TemplateSegment::Literal(next_lit) = next_segment {
-                            remaining.find(next_lit.as_str()).unwrap_or(remaining.len())
-                        } else {
-                            remaining.len()
+                TemplateSegment::Param(name, constraint) => {
+                    // Stay within the current segment, up to the literal
+                    // that follows, if any
+                    let segment_end = remaining.find('/').unwrap_or(remaining.len());
+                    let end_pos = match self.segments.get(i + 1) {
+                        Some(TemplateSegment::Literal(next_lit)) => remaining
+                            .find(next_lit.as_str())
+                            .filter(|pos| *pos <= segment_end)?,
+                        _ => segment_end,
+                    };
+
+                    if end_pos == 0 {
+                        return None;
+                    }
+
+                    let value = &path[offset..offset + end_pos];
+                    if constraint.as_ref().is_some_and(|c| !c.accepts(value)) {
+                        return None;
+                    }
+                    params.insert(name.clone(), value.to_string());
+                    offset += end_pos;
+                }
+                TemplateSegment::Greedy(name, constraint) => {
+                    // Run to the last occurrence of the following literal,
+                    // or to the end when nothing follows
+                    let end_pos = match self.segments.get(i + 1) {
+                        Some(TemplateSegment::Literal(next_lit)) => {
+                            remaining.rfind(next_lit.as_str())?
                         }
-                    } else {
-                        // Find next slash or end
-                        remaining.find('/').unwrap_or(remaining.len())
+                        _ => remaining.len(),
                     };
 
                     if end_pos == 0 {
                         return None;
                     }
 
-                    let value = &remaining[..end_pos];
+                    let value = &path[offset..offset + end_pos];
+                    if constraint.as_ref().is_some_and(|c| !c.accepts(value)) {
+                        return None;
+                    }
                     params.insert(name.clone(), value.to_string());
-                    remaining = &remaining[end_pos..];
+                    offset += end_pos;
                 }
             }
         }
 
         // Must consume entire path
-        if remaining.is_empty() {
+        if offset == folded.len() {
             Some(params)
         } else {
             None
@@ -134,60 +630,175 @@ impl PathTemplate {
     }
 }
 
+/// How paths are normalized before exact, prefix and template matching.
+/// Regex and glob matchers always see the path as received.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PathOptions {
+    /// Compare paths ignoring ASCII case
+    pub case_insensitive: bool,
+    /// Treat `/hello/` as `/hello` (not applied to prefixes)
+    pub ignore_trailing_slash: bool,
+}
+
+impl From<&GlobalSettings> for PathOptions {
+    fn from(settings: &GlobalSettings) -> Self {
+        Self {
+            case_insensitive: settings.case_insensitive_paths,
+            ignore_trailing_slash: settings.ignore_trailing_slash,
+        }
+    }
+}
+
+impl PathOptions {
+    /// Drop a trailing slash, if configured. The root path keeps its slash.
+    fn trim<'a>(&self, path: &'a str) -> &'a str {
+        match path.strip_suffix('/') {
+            Some(trimmed) if self.ignore_trailing_slash && !trimmed.is_empty() => trimmed,
+            _ => path,
+        }
+    }
+
+    /// Fold the case, if configured. ASCII only, so byte offsets into the
+    /// folded path are valid in the original.
+    fn fold<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        if self.case_insensitive {
+            Cow::Owned(path.to_ascii_lowercase())
+        } else {
+            Cow::Borrowed(path)
+        }
+    }
+}
+
+fn compile_request(matcher: &RequestMatcher, options: PathOptions) -> CompiledRequest {
+    let compile = |sub: &RequestMatcher| compile_request(sub, options);
+    CompiledRequest {
+        path: matcher.path.as_ref().map(|path| {
+            compile_path(path, options).unwrap_or_else(|e| {
+                tracing::warn!(error = %e, "Invalid path matcher, never matching");
+                CompiledPathMatcher::Invalid
+            })
+        }),
+        body: matcher.body.as_ref().map(compile_body),
+        any_of: matcher.any_of.iter().map(compile).collect(),
+        all_of: matcher.all_of.iter().map(compile).collect(),
+        not: matcher.not.as_deref().map(|sub| Box::new(compile(sub))),
+    }
+}
+
+fn compile_body(matcher: &BodyMatcher) -> CompiledBody {
+    match matcher {
+        BodyMatcher::JsonEquals {
+            value,
+            ignore_paths,
+            ..
+        } => {
+            let ignore: Result<Vec<_>, _> = ignore_paths
+                .iter()
+                .map(|path| json_equals::parse_path(path))
+                .collect();
+            CompiledBody::JsonEquals(ignore.ok().map(|ignore| {
+                let mut expected = value.clone();
+                for steps in &ignore {
+                    json_equals::remove_path(&mut expected, steps);
+                }
+                PreparedJsonEquals { expected, ignore }
+            }))
+        }
+        BodyMatcher::Not { matcher } => CompiledBody::Not(Box::new(compile_body(matcher))),
+        _ => CompiledBody::Plain,
+    }
+}
+
+fn compile_path(
+    matcher: &PathMatcher,
+    options: PathOptions,
+) -> anyhow::Result<CompiledPathMatcher> {
+    Ok(match matcher {
+        PathMatcher::Exact { value } => {
+            CompiledPathMatcher::Exact(options.fold(options.trim(value)).into_owned())
+        }
+        PathMatcher::Prefix {
+            value,
+            segment_boundary,
+        } => CompiledPathMatcher::Prefix {
+            value: options.fold(value).into_owned(),
+            segment_boundary: *segment_boundary,
+        },
+        PathMatcher::Regex { pattern } => CompiledPathMatcher::Regex(Regex::new(pattern)?),
+        PathMatcher::Glob { pattern } => {
+            let glob = globset::Glob::new(pattern)?;
+            CompiledPathMatcher::Glob(glob.compile_matcher())
+        }
+        PathMatcher::Template { template } => {
+            let mut template = PathTemplate::parse(template)?;
+            if options.case_insensitive {
+                template = template.fold_case();
+            }
+            if options.ignore_trailing_slash {
+                template = template.trim_trailing_slash();
+            }
+            CompiledPathMatcher::Template(template)
+        }
+        PathMatcher::Not { matcher } => {
+            CompiledPathMatcher::Not(Box::new(compile_path(matcher, options)?))
+        }
+    })
+}
+
 impl Matcher {
     /// Create a new matcher from stub definitions.
     pub fn new(stubs: &[StubDefinition]) -> Self {
-        let path_matchers = stubs
+        Self::with_path_options(stubs, PathOptions::default())
+    }
+
+    /// Create a new matcher that normalizes paths as `options` says.
+    pub fn with_path_options(stubs: &[StubDefinition], options: PathOptions) -> Self {
+        let requests = stubs
             .iter()
-            .map(|stub| {
-                stub.request.path.as_ref().map(|p| match p {
-                    PathMatcher::Exact { value } => CompiledPathMatcher::Exact(value.clone()),
-                    PathMatcher::Prefix { value } => CompiledPathMatcher::Prefix(value.clone()),
-                    PathMatcher::Regex { pattern } => {
-                        CompiledPathMatcher::Regex(Regex::new(pattern).unwrap())
-                    }
-                    PathMatcher::Glob { pattern } => {
-                        let glob = globset::Glob::new(pattern).unwrap();
-                        CompiledPathMatcher::Glob(glob.compile_matcher())
-                    }
-                    PathMatcher::Template { template } => {
-                        CompiledPathMatcher::Template(PathTemplate::parse(template))
-                    }
-                })
-            })
+            .map(|stub| compile_request(&stub.request, options))
             .collect();
 
-        Self { path_matchers }
+        Self {
+            requests,
+            path_options: options,
+            criteria: std::sync::OnceLock::new(),
+        }
     }
 
     /// Find the first matching stub for a request.
     pub fn find_match<'a>(
         &self,
         stubs: &'a [StubDefinition],
-        method: &str,
-        path: &str,
-        query_string: Option<&str>,
-        headers: &HashMap<String, String>,
-        body: Option<&[u8]>,
+        request: &RequestParts,
     ) -> Option<MatchResult<'a>> {
+        self.find_match_filtered(stubs, request, |_| true)
+    }
+
+    /// Find the first matching stub for a request, skipping stubs rejected by `filter`.
+    ///
+    /// Used by the agent to apply runtime state (e.g. stub dependencies) that
+    /// the matcher itself doesn't track.
+    pub fn find_match_filtered<'a, F>(
+        &self,
+        stubs: &'a [StubDefinition],
+        request: &RequestParts,
+        filter: F,
+    ) -> Option<MatchResult<'a>>
+    where
+        F: Fn(&StubDefinition) -> bool,
+    {
         // Sort by priority (highest first)
         let mut indexed_stubs: Vec<_> = stubs.iter().enumerate().collect();
-        indexed_stubs.sort_by(|a, b| b.1.priority.cmp(&a.1.priority));
+        indexed_stubs.sort_by_key(|(_, stub)| std::cmp::Reverse(stub.priority));
 
         for (idx, stub) in indexed_stubs {
-            if !stub.enabled {
+            if !stub.enabled || !filter(stub) {
                 continue;
             }
 
-            if let Some(context) = self.matches_request(
-                idx,
-                &stub.request,
-                method,
-                path,
-                query_string,
-                headers,
-                body,
-            ) {
+            if let Some(context) =
+                self.matches_request(self.requests.get(idx), &stub.request, request)
+            {
                 return Some(MatchResult { stub, context });
             }
         }
@@ -195,17 +806,125 @@ impl Matcher {
         None
     }
 
-    #[allow(clippy::too_many_arguments)]
+    /// Find the first disabled stub that would match a request, for telling
+    /// disabled routes apart from unknown ones.
+    pub fn find_disabled_match<'a>(
+        &self,
+        stubs: &'a [StubDefinition],
+        request: &RequestParts,
+    ) -> Option<&'a StubDefinition> {
+        stubs.iter().enumerate().find_map(|(idx, stub)| {
+            let matched = !stub.enabled
+                && self
+                    .matches_request(self.requests.get(idx), &stub.request, request)
+                    .is_some();
+            matched.then_some(stub)
+        })
+    }
+
+    /// For a request no stub matched, the enabled stub that met the most of
+    /// its criteria, with which criteria passed and failed. `None` when no
+    /// stub met any.
+    ///
+    /// Each criterion is checked on its own, so this is slower than
+    /// matching and only meant for diagnostics. The criteria are compiled
+    /// on the first call.
+    pub fn closest_match(
+        &self,
+        stubs: &[StubDefinition],
+        request: &RequestParts,
+    ) -> Option<ClosestMatch> {
+        let criteria = self.criteria.get_or_init(|| {
+            stubs
+                .iter()
+                .map(|stub| {
+                    criteria(&stub.request)
+                        .into_iter()
+                        .map(|(label, matcher)| Criterion {
+                            compiled: compile_request(&matcher, self.path_options),
+                            label,
+                            matcher,
+                        })
+                        .collect()
+                })
+                .collect()
+        });
+        let mut ordered: Vec<(usize, &StubDefinition)> = stubs
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.enabled)
+            .collect();
+        ordered.sort_by_key(|(_, stub)| std::cmp::Reverse(stub.priority));
+
+        let mut closest: Option<ClosestMatch> = None;
+        for (idx, stub) in ordered {
+            let mut candidate = ClosestMatch {
+                stub_id: stub.id.clone(),
+                matched: Vec::new(),
+                failed: Vec::new(),
+            };
+            for criterion in criteria.get(idx).into_iter().flatten() {
+                let passed = self
+                    .matches_request(Some(&criterion.compiled), &criterion.matcher, request)
+                    .is_some();
+                let label = criterion.label.clone();
+                if passed {
+                    candidate.matched.push(label);
+                } else {
+                    candidate.failed.push(label);
+                }
+            }
+            // Ties go to the stub that would have been tried first
+            let better = closest.as_ref().is_none_or(|best| {
+                (
+                    candidate.matched.len(),
+                    std::cmp::Reverse(candidate.failed.len()),
+                ) > (best.matched.len(), std::cmp::Reverse(best.failed.len()))
+            });
+            if !candidate.matched.is_empty() && better {
+                closest = Some(candidate);
+            }
+        }
+        closest
+    }
+
+    /// Whether the path matchers of the stub with the given ID accept `path`,
+    /// including those nested in `all_of` (all must) and `any_of` (one
+    /// must).
+    ///
+    /// Stubs without a path matcher accept any path. A path under `not`
+    /// never rules a path out, as the criteria beside it may be what fails.
+    pub fn matches_stub_path(&self, stubs: &[StubDefinition], id: &str, path: &str) -> bool {
+        let Some(idx) = stubs.iter().position(|s| s.id == id) else {
+            return false;
+        };
+        self.requests
+            .get(idx)
+            .is_none_or(|compiled| self.path_fits(compiled, path))
+    }
+
+    fn path_fits(&self, compiled: &CompiledRequest, path: &str) -> bool {
+        compiled.path.as_ref().is_none_or(|path_matcher| {
+            self.matches_path(path_matcher, path, &mut MatchContext::default())
+        }) && compiled.all_of.iter().all(|sub| self.path_fits(sub, path))
+            && (compiled.any_of.is_empty()
+                || compiled.any_of.iter().any(|sub| self.path_fits(sub, path)))
+    }
+
     fn matches_request(
         &self,
-        stub_idx: usize,
+        compiled: Option<&CompiledRequest>,
         matcher: &RequestMatcher,
-        method: &str,
-        path: &str,
-        query_string: Option<&str>,
-        headers: &HashMap<String, String>,
-        body: Option<&[u8]>,
+        request: &RequestParts,
     ) -> Option<MatchContext> {
+        let RequestParts {
+            method,
+            path,
+            query: query_string,
+            headers,
+            body,
+            client_ip,
+        } = *request;
         let mut context = MatchContext::default();
 
         // Check method
@@ -221,15 +940,53 @@ impl Matcher {
         }
 
         // Check path
-        if let Some(Some(path_matcher)) = self.path_matchers.get(stub_idx) {
+        if let Some(path_matcher) = compiled.and_then(|c| c.path.as_ref()) {
             if !self.matches_path(path_matcher, path, &mut context) {
                 return None;
             }
         }
 
+        // Check host
+        context.host = request_host(headers);
+        if let Some(hm) = &matcher.host {
+            if !context
+                .host
+                .as_deref()
+                .is_some_and(|host| matches_host(host, hm))
+            {
+                return None;
+            }
+        }
+
+        // Check client address
+        context.client_ip = client_ip.map(str::to_string);
+        if let Some(cm) = &matcher.client_ip {
+            let address = resolve_client_ip(cm, headers, client_ip)?;
+            if !cm
+                .ranges
+                .iter()
+                .filter_map(|range| parse_ip_range(range).ok())
+                .any(|(network, prefix)| ip_in_range(address, network, prefix))
+            {
+                return None;
+            }
+            context.client_ip = Some(address.to_string());
+        }
+
+        // Check client certificate
+        if let Some(cm) = &matcher.client_cert {
+            if !matches_client_cert(cm, headers) {
+                return None;
+            }
+        }
+
         // Parse query string
         let query_params = parse_query_string(query_string.unwrap_or(""));
-        context.query_params = query_params.clone();
+        context.query_params = query_params
+            .iter()
+            .map(|(name, values)| (name.clone(), values.last().cloned().unwrap_or_default()))
+            .collect();
+        context.raw_query = query_string.unwrap_or("").to_string();
 
         // Check query parameters
         for (name, qm) in &matcher.query {
@@ -245,13 +1002,85 @@ impl Matcher {
             }
         }
 
+        // Check JWT claims
+        if let Some(jwt) = &matcher.jwt {
+            let claims = header_value(headers, &jwt.header).and_then(jwt_claims)?;
+            if !jwt
+                .claims
+                .iter()
+                .all(|(name, expected)| matches_json_value(claims.get(name), expected))
+            {
+                return None;
+            }
+            context.jwt = Some(claims);
+        }
+
+        // Check basic auth credentials
+        if let Some(expected) = &matcher.basic_auth {
+            let (username, password) =
+                header_value(headers, "authorization").and_then(basic_credentials)?;
+            if username != expected.username
+                || expected
+                    .password
+                    .as_ref()
+                    .is_some_and(|expected| *expected != password)
+            {
+                return None;
+            }
+            context.auth = Some(BasicAuth { username });
+        }
+
+        // Header volume limits match when either is exceeded
+        if matcher.max_headers.is_some() || matcher.max_header_bytes.is_some() {
+            let (count, bytes) = header_volume(headers);
+            let too_many = matcher.max_headers.is_some_and(|max| count > max);
+            let too_large = matcher.max_header_bytes.is_some_and(|max| bytes > max);
+            if !too_many && !too_large {
+                return None;
+            }
+        }
+
         // Check body
         if let Some(bm) = &matcher.body {
-            if !self.matches_body(body, bm) {
+            let content_type = header_value(headers, "content-type");
+            let uncompiled;
+            let compiled_body = match compiled.and_then(|c| c.body.as_ref()) {
+                Some(compiled_body) => compiled_body,
+                None => {
+                    uncompiled = compile_body(bm);
+                    &uncompiled
+                }
+            };
+            if !self.body_matches(body, content_type, bm, compiled_body, &mut context) {
+                return None;
+            }
+        }
+
+        // Sub-matchers see the same request; what they capture adds to ours
+        let sub_match = |compiled: Option<&CompiledRequest>, sub: &RequestMatcher| {
+            self.matches_request(compiled, sub, request)
+        };
+        for (i, sub) in matcher.all_of.iter().enumerate() {
+            let sub_context = sub_match(compiled.and_then(|c| c.all_of.get(i)), sub)?;
+            context.merge(sub_context);
+        }
+        if !matcher.any_of.is_empty() {
+            let sub_context = matcher
+                .any_of
+                .iter()
+                .enumerate()
+                .find_map(|(i, sub)| sub_match(compiled.and_then(|c| c.any_of.get(i)), sub))?;
+            context.merge(sub_context);
+        }
+        // A negated sub-matcher captures nothing, having not matched
+        if let Some(sub) = &matcher.not {
+            if sub_match(compiled.and_then(|c| c.not.as_deref()), sub).is_some() {
                 return None;
             }
         }
 
+        context.query_all = query_params;
+        context.headers_all = headers.clone();
         Some(context)
     }
 
@@ -261,9 +1090,19 @@ impl Matcher {
         path: &str,
         context: &mut MatchContext,
     ) -> bool {
+        let options = self.path_options;
         match matcher {
-            CompiledPathMatcher::Exact(value) => path == value,
-            CompiledPathMatcher::Prefix(value) => path.starts_with(value),
+            CompiledPathMatcher::Exact(value) => options.fold(options.trim(path)) == value.as_str(),
+            CompiledPathMatcher::Prefix {
+                value,
+                segment_boundary,
+            } => match options.fold(path).strip_prefix(value.as_str()) {
+                Some(rest) if *segment_boundary => {
+                    rest.is_empty() || rest.starts_with('/') || value.ends_with('/')
+                }
+                Some(_) => true,
+                None => false,
+            },
             CompiledPathMatcher::Regex(regex) => {
                 if let Some(captures) = regex.captures(path) {
                     for (i, cap) in captures.iter().enumerate().skip(1) {
@@ -287,69 +1126,132 @@ impl Matcher {
                 }
             }
             CompiledPathMatcher::Glob(glob) => glob.is_match(path),
+            // Whatever the inner matcher captured belongs to a match that failed
+            CompiledPathMatcher::Not(inner) => {
+                !self.matches_path(inner, path, &mut MatchContext::default())
+            }
             CompiledPathMatcher::Template(template) => {
-                if let Some(params) = template.matches(path) {
+                let path = options.trim(path);
+                if let Some(params) = template.matches(path, &options.fold(path)) {
                     context.path_params = params;
                     true
                 } else {
                     false
                 }
             }
+            CompiledPathMatcher::Invalid => false,
         }
     }
 
     fn matches_query(
         &self,
-        query_params: &HashMap<String, String>,
+        query_params: &HashMap<String, Vec<String>>,
         name: &str,
         matcher: &QueryMatcher,
     ) -> bool {
+        let values = query_params
+            .get(name)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        // Single-value matchers look at the last value, as they always have
+        let last = values.last();
         match matcher {
-            QueryMatcher::Exact { value } => query_params.get(name) == Some(value),
+            QueryMatcher::Exact { value } => last == Some(value),
             QueryMatcher::Regex { pattern } => {
-                if let Some(val) = query_params.get(name) {
+                if let Some(val) = last {
                     if let Ok(regex) = Regex::new(pattern) {
                         return regex.is_match(val);
                     }
                 }
                 false
             }
-            QueryMatcher::Present => query_params.contains_key(name),
-            QueryMatcher::Absent => !query_params.contains_key(name),
+            QueryMatcher::Present => !values.is_empty(),
+            QueryMatcher::Absent => values.is_empty(),
+            QueryMatcher::Contains { value } => {
+                last.map(|v| v.contains(value.as_str())).unwrap_or(false)
+            }
+            QueryMatcher::CsvContains { value } => last
+                .map(|v| v.split(',').any(|item| item.trim() == value))
+                .unwrap_or(false),
+            QueryMatcher::AnyValue { value } => values.contains(value),
+            QueryMatcher::Count { count } => values.len() == *count,
+            QueryMatcher::All { values: expected } => {
+                let mut actual = values.to_vec();
+                let mut expected = expected.clone();
+                actual.sort();
+                expected.sort();
+                actual == expected
+            }
+            QueryMatcher::Not { matcher } => !self.matches_query(query_params, name, matcher),
+            QueryMatcher::JsonPath { expressions } => {
+                // Values are already URL-decoded by parse_query_string
+                if let Some(val) = last {
+                    if let Ok(json) = serde_json::from_str::<serde_json::Value>(val) {
+                        return self.matches_json_paths(&json, expressions);
+                    }
+                }
+                false
+            }
         }
     }
 
     fn matches_header(
         &self,
-        headers: &HashMap<String, String>,
+        headers: &HashMap<String, Vec<String>>,
         name: &str,
         matcher: &HeaderMatcher,
     ) -> bool {
         // Case-insensitive header lookup
-        let header_value = headers
+        let values = headers
             .iter()
             .find(|(k, _)| k.to_lowercase() == name.to_lowercase())
-            .map(|(_, v)| v);
+            .map(|(_, v)| v.as_slice());
+        // Value matchers look at the first value unless told otherwise
+        let check = |mode: HeaderValueMode, matches: &dyn Fn(&str) -> bool| {
+            let values = values.unwrap_or_default();
+            match mode {
+                HeaderValueMode::First => values.first().is_some_and(|v| matches(v)),
+                HeaderValueMode::Any => values.iter().any(|v| matches(v)),
+                HeaderValueMode::All => !values.is_empty() && values.iter().all(|v| matches(v)),
+            }
+        };
 
         match matcher {
-            HeaderMatcher::Exact { value } => header_value == Some(value),
-            HeaderMatcher::Regex { pattern } => {
-                if let Some(val) = header_value {
-                    if let Ok(regex) = Regex::new(pattern) {
-                        return regex.is_match(val);
-                    }
-                }
-                false
-            }
-            HeaderMatcher::Present => header_value.is_some(),
-            HeaderMatcher::Absent => header_value.is_none(),
-            HeaderMatcher::Contains { value } => {
-                header_value.map(|v| v.contains(value)).unwrap_or(false)
+            HeaderMatcher::Exact { value, values } => check(*values, &|v| v == value),
+            HeaderMatcher::Regex { pattern, values } => match Regex::new(pattern) {
+                Ok(regex) => check(*values, &|v| regex.is_match(v)),
+                Err(_) => false,
+            },
+            HeaderMatcher::Present => values.is_some_and(|v| !v.is_empty()),
+            HeaderMatcher::Absent => values.is_none_or(|v| v.is_empty()),
+            HeaderMatcher::Contains { value, values } => {
+                check(*values, &|v| v.contains(value.as_str()))
             }
+            HeaderMatcher::AnyValue { value } => check(HeaderValueMode::Any, &|v| v == value),
+            HeaderMatcher::AllValues { value } => check(HeaderValueMode::All, &|v| v == value),
+            HeaderMatcher::Count { count } => values.map_or(0, |v| v.len()) == *count,
+            HeaderMatcher::Not { matcher } => !self.matches_header(headers, name, matcher),
         }
     }
 
-    fn matches_body(&self, body: Option<&[u8]>, matcher: &BodyMatcher) -> bool {
+    /// Check a body against a body matcher.
+    ///
+    /// Multipart bodies are split on the boundary their first line starts.
+    pub fn matches_body(&self, body: Option<&[u8]>, matcher: &BodyMatcher) -> bool {
+        let compiled = compile_body(matcher);
+        self.body_matches(body, None, matcher, &compiled, &mut MatchContext::default())
+    }
+
+    /// Check a body, recording the text fields of matched multipart bodies
+    /// in `context`.
+    fn body_matches(
+        &self,
+        body: Option<&[u8]>,
+        content_type: Option<&str>,
+        matcher: &BodyMatcher,
+        compiled: &CompiledBody,
+        context: &mut MatchContext,
+    ) -> bool {
         let body_str = body.and_then(|b| std::str::from_utf8(b).ok());
 
         match matcher {
@@ -376,7 +1278,108 @@ impl Matcher {
             BodyMatcher::Json => body_str
                 .map(|bs| serde_json::from_str::<serde_json::Value>(bs).is_ok())
                 .unwrap_or(false),
+            BodyMatcher::JsonEquals {
+                ignore_extra_fields,
+                ignore_array_order,
+                ..
+            } => {
+                let CompiledBody::JsonEquals(Some(prepared)) = compiled else {
+                    return false;
+                };
+                let Some(mut actual) =
+                    body_str.and_then(|bs| serde_json::from_str::<serde_json::Value>(bs).ok())
+                else {
+                    return false;
+                };
+                for steps in &prepared.ignore {
+                    json_equals::remove_path(&mut actual, steps);
+                }
+                let options = json_equals::Options {
+                    ignore_extra_fields: *ignore_extra_fields,
+                    ignore_array_order: *ignore_array_order,
+                };
+                json_equals::equals(&prepared.expected, &actual, options)
+            }
+            BodyMatcher::YamlPath { expressions } => body_str
+                .and_then(|bs| serde_yaml::from_str::<serde_json::Value>(bs).ok())
+                .is_some_and(|yaml| self.matches_json_paths(&yaml, expressions)),
+            BodyMatcher::Yaml => body_str
+                .map(|bs| serde_yaml::from_str::<serde_yaml::Value>(bs).is_ok())
+                .unwrap_or(false),
+            BodyMatcher::Hash { algorithm, value } => {
+                let Ok(algorithm) = digest_algorithm(algorithm) else {
+                    return false;
+                };
+                let digest = ring::digest::digest(algorithm, body.unwrap_or_default());
+                let hex: String = digest
+                    .as_ref()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect();
+                hex.eq_ignore_ascii_case(value.trim())
+            }
             BodyMatcher::Empty => body.map(|b| b.is_empty()).unwrap_or(true),
+            BodyMatcher::Multipart { parts } => {
+                let body = body.unwrap_or_default();
+                let Some(boundary) = content_type
+                    .and_then(multipart::boundary)
+                    .or_else(|| multipart::sniff_boundary(body))
+                else {
+                    return false;
+                };
+                let received = multipart::parse(body, &boundary);
+                let matched = parts.iter().all(|(name, expected)| {
+                    received
+                        .iter()
+                        .any(|part| part.name == *name && matches_part(part, expected))
+                });
+                if matched {
+                    context.form = received
+                        .iter()
+                        .filter(|part| !part.is_file())
+                        .map(|part| {
+                            let value = String::from_utf8_lossy(&part.data).into_owned();
+                            (part.name.clone(), value)
+                        })
+                        .collect();
+                }
+                matched
+            }
+            BodyMatcher::GraphQl {
+                operation_name,
+                query_contains,
+                variables,
+            } => {
+                let Some(request) = body_str.and_then(|bs| GraphQlRequest::parse(bs, content_type))
+                else {
+                    return false;
+                };
+                let matched = operation_name
+                    .as_ref()
+                    .is_none_or(|name| request.operation_name.as_ref() == Some(name))
+                    && query_contains
+                        .as_ref()
+                        .is_none_or(|text| request.query.contains(text.as_str()))
+                    && variables.iter().all(|(name, expected)| {
+                        matches_json_value(request.variables.get(name), expected)
+                    });
+                if matched {
+                    context.graphql = Some(request);
+                }
+                matched
+            }
+            BodyMatcher::Not { matcher } => {
+                let CompiledBody::Not(compiled) = compiled else {
+                    return false;
+                };
+                !self.body_matches(
+                    body,
+                    content_type,
+                    matcher,
+                    compiled,
+                    &mut MatchContext::default(),
+                )
+            }
         }
     }
 
@@ -387,22 +1390,32 @@ impl Matcher {
     ) -> bool {
         use jsonpath_rust::JsonPath;
 
-        for (path_expr, expected) in expressions {
+        for (path_expr, raw_expected) in expressions {
             let path = match JsonPath::try_from(path_expr.as_str()) {
                 Ok(p) => p,
                 Err(_) => return false,
             };
+            let Ok((op, expected)) = json_path_expectation(raw_expected) else {
+                return false;
+            };
 
             let results = path.find(json);
 
             // Check if any result matches the expected value
             // If expected is null, just check that the path exists (returns non-null)
-            let matches = if expected.is_null() {
+            let matches = if op == JsonPathOp::Eq && expected.is_null() {
                 // Check if the path resolved to something
                 !results.is_null()
             } else {
-                // Compare results to expected
-                results == *expected
+                // Paths resolve to an array of results; compare each of them
+                // as well as the array as a whole
+                match &results {
+                    serde_json::Value::Array(values) => {
+                        values.iter().any(|v| compare_json(op, v, expected))
+                            || (op == JsonPathOp::Eq && results == *expected)
+                    }
+                    _ => compare_json(op, &results, expected),
+                }
             };
             if !matches {
                 return false;
@@ -412,19 +1425,279 @@ impl Matcher {
     }
 }
 
-/// Parse a query string into key-value pairs.
-fn parse_query_string(query: &str) -> HashMap<String, String> {
-    let mut params = HashMap::new();
+/// Check a named value (a GraphQL variable, a JWT claim) against a JSON path
+/// style expectation. `null` only requires the value to be present.
+fn matches_json_value(
+    actual: Option<&serde_json::Value>,
+    raw_expected: &serde_json::Value,
+) -> bool {
+    let Ok((op, expected)) = json_path_expectation(raw_expected) else {
+        return false;
+    };
+    match actual {
+        Some(actual) if expected.is_null() && op == JsonPathOp::Eq => !actual.is_null(),
+        Some(actual) => compare_json(op, actual, expected),
+        None => false,
+    }
+}
+
+/// Apply a JSON path comparison operator.
+///
+/// Numbers compare numerically (integers and floats alike), strings
+/// lexically; values of different types never satisfy an ordering.
+fn compare_json(op: JsonPathOp, actual: &serde_json::Value, expected: &serde_json::Value) -> bool {
+    use serde_json::Value;
+
+    let ordering = match (actual, expected) {
+        (Value::Number(a), Value::Number(b)) => match (a.as_f64(), b.as_f64()) {
+            (Some(a), Some(b)) => a.partial_cmp(&b),
+            _ => None,
+        },
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    };
+
+    match op {
+        JsonPathOp::Eq => match ordering {
+            Some(ordering) => ordering.is_eq(),
+            None => actual == expected,
+        },
+        JsonPathOp::Gt => ordering.is_some_and(|o| o.is_gt()),
+        JsonPathOp::Gte => ordering.is_some_and(|o| o.is_ge()),
+        JsonPathOp::Lt => ordering.is_some_and(|o| o.is_lt()),
+        JsonPathOp::Lte => ordering.is_some_and(|o| o.is_le()),
+        JsonPathOp::Contains => match (actual, expected) {
+            (Value::String(a), Value::String(b)) => a.contains(b.as_str()),
+            (Value::Array(items), _) => items.contains(expected),
+            _ => false,
+        },
+        JsonPathOp::Regex => match (actual, expected) {
+            (Value::String(a), Value::String(pattern)) => {
+                Regex::new(pattern).is_ok_and(|regex| regex.is_match(a))
+            }
+            _ => false,
+        },
+    }
+}
+
+/// Claims from the payload of a JWT, with or without a `Bearer ` prefix.
+/// Malformed tokens have none.
+fn jwt_claims(value: &str) -> Option<serde_json::Map<String, serde_json::Value>> {
+    use base64::Engine;
+
+    let value = value.trim();
+    let token = match value.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => token.trim(),
+        _ => value,
+    };
+    let mut segments = token.split('.');
+    let (Some(_header), Some(payload), Some(_signature)) =
+        (segments.next(), segments.next(), segments.next())
+    else {
+        return None;
+    };
+    let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
+    match serde_json::from_slice(&payload).ok()? {
+        serde_json::Value::Object(claims) => Some(claims),
+        _ => None,
+    }
+}
+
+/// Username and password of an `Authorization: Basic` header value.
+fn basic_credentials(value: &str) -> Option<(String, String)> {
+    use base64::Engine;
+
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let (username, password) = String::from_utf8(decoded)
+        .ok()?
+        .split_once(':')
+        .map(|(username, password)| (username.to_string(), password.to_string()))?;
+    Some((username, password))
+}
+
+/// Number of header values, and their size as `name: value\r\n` lines.
+fn header_volume(headers: &HashMap<String, Vec<String>>) -> (usize, usize) {
+    headers
+        .iter()
+        .flat_map(|(name, values)| values.iter().map(move |value| name.len() + value.len() + 4))
+        .fold((0, 0), |(count, bytes), size| (count + 1, bytes + size))
+}
+
+fn matches_part(part: &multipart::Part, expected: &PartMatcher) -> bool {
+    if expected.file && !part.is_file() {
+        return false;
+    }
+    if let Some(content_type) = &expected.content_type {
+        let actual = part.content_type.as_deref().unwrap_or("text/plain");
+        let mime = actual.split(';').next().unwrap_or_default().trim();
+        if !mime.eq_ignore_ascii_case(content_type.trim()) {
+            return false;
+        }
+    }
+    if let Some(value) = &expected.value {
+        match std::str::from_utf8(&part.data) {
+            Ok(text) if value.matches(text) => {}
+            _ => return false,
+        }
+    }
+    let size = part.data.len();
+    expected.min_size.is_none_or(|min| size >= min)
+        && expected.max_size.is_none_or(|max| size <= max)
+}
+
+/// Parse an address or CIDR block into its network and prefix length.
+pub fn parse_ip_range(range: &str) -> anyhow::Result<(IpAddr, u8)> {
+    let invalid = || anyhow::anyhow!("Invalid IP address or CIDR block '{}'", range);
+    let (address, prefix) = match range.trim().split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (range.trim(), None),
+    };
+    let address: IpAddr = address.parse().map_err(|_| invalid())?;
+    let max = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix
+            .parse()
+            .ok()
+            .filter(|p| *p <= max)
+            .ok_or_else(invalid)?,
+        None => max,
+    };
+    Ok((address, prefix))
+}
+
+/// Whether `address` lies in the block of `prefix` leading bits of `network`.
+///
+/// Both sides are compared as IPv6, with IPv4 in its mapped form
+/// (`::ffff:a.b.c.d`), so IPv4 addresses and ranges written either way agree.
+fn ip_in_range(address: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    let (network, prefix) = match network {
+        IpAddr::V4(v4) => (v4.to_ipv6_mapped(), prefix + 96),
+        IpAddr::V6(v6) => (v6, prefix),
+    };
+    let address = match address {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    };
+    let shift = 128 - u32::from(prefix);
+    shift >= 128 || (u128::from(address) >> shift) == (u128::from(network) >> shift)
+}
+
+/// Client address a `client_ip` matcher looks at: an entry of its header's
+/// list, or the connection's peer.
+fn resolve_client_ip(
+    matcher: &ClientIpMatcher,
+    headers: &HashMap<String, Vec<String>>,
+    peer: Option<&str>,
+) -> Option<IpAddr> {
+    let raw = match &matcher.from_header {
+        Some(name) => {
+            let entries: Vec<&str> = header_value(headers, name)?.split(',').collect();
+            let index = if matcher.index < 0 {
+                entries.len().checked_sub(matcher.index.unsigned_abs())?
+            } else {
+                matcher.index as usize
+            };
+            entries.get(index)?.trim()
+        }
+        None => peer?,
+    };
+    raw.parse().ok()
+}
+
+/// Host a request was sent to, from `Host` or `:authority`, lowercased and
+/// without its port or a trailing dot.
+fn request_host(headers: &HashMap<String, Vec<String>>) -> Option<String> {
+    let authority = header_value(headers, "host")
+        .or_else(|| header_value(headers, ":authority"))?
+        .trim();
+    let host = match authority.strip_prefix('[') {
+        // IPv6 literal, keeping its brackets
+        Some(rest) => &authority[..rest.find(']')? + 2],
+        None => authority.split(':').next().unwrap_or_default(),
+    };
+    let host = normalize_host(host);
+    (!host.is_empty()).then_some(host)
+}
+
+fn normalize_host(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn matches_host(host: &str, matcher: &HostMatcher) -> bool {
+    match matcher {
+        HostMatcher::Exact { value } => host == normalize_host(value),
+        HostMatcher::Regex { pattern } => Regex::new(pattern).is_ok_and(|re| re.is_match(host)),
+        HostMatcher::Wildcard { pattern } => {
+            let domain = normalize_host(pattern.trim_start_matches('*'));
+            host.len() > domain.len() && host.ends_with(&domain)
+        }
+    }
+}
+
+/// Whether the certificate described by the request's headers fits `matcher`.
+/// A missing header fails whichever check needs it.
+fn matches_client_cert(
+    matcher: &ClientCertMatcher,
+    headers: &HashMap<String, Vec<String>>,
+) -> bool {
+    if let Some(pattern) = &matcher.subject_cn {
+        let cn = header_value(headers, &matcher.subject_header).and_then(subject_cn);
+        if !cn.is_some_and(|cn| Regex::new(pattern).is_ok_and(|re| re.is_match(cn))) {
+            return false;
+        }
+    }
+    if let Some(expected) = &matcher.san_contains {
+        let sans = header_value(headers, &matcher.san_header).unwrap_or("");
+        if !sans
+            .split(',')
+            .any(|san| san.trim().contains(expected.as_str()))
+        {
+            return false;
+        }
+    }
+    true
+}
+
+/// Common name in a certificate subject, written either as RFC 4514
+/// (`CN=client,O=Example`) or OpenSSL's one-line form (`/O=Example/CN=client`).
+fn subject_cn(subject: &str) -> Option<&str> {
+    subject.split([',', '/']).find_map(|part| {
+        let (name, value) = part.split_once('=')?;
+        name.trim().eq_ignore_ascii_case("cn").then(|| value.trim())
+    })
+}
+
+/// First value of a header, looked up case-insensitively.
+fn header_value<'a>(headers: &'a HashMap<String, Vec<String>>, name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .and_then(|(_, values)| values.first())
+        .map(String::as_str)
+}
+
+/// Parse a query string into its parameters, keeping every value of
+/// repeated keys in order.
+fn parse_query_string(query: &str) -> HashMap<String, Vec<String>> {
+    let mut params: HashMap<String, Vec<String>> = HashMap::new();
 
     for part in query.split('&') {
         if part.is_empty() {
             continue;
         }
-        if let Some((key, value)) = part.split_once('=') {
-            params.insert(urlencoding_decode(key), urlencoding_decode(value));
-        } else {
-            params.insert(urlencoding_decode(part), String::new());
-        }
+        let (key, value) = part.split_once('=').unwrap_or((part, ""));
+        params
+            .entry(urlencoding_decode(key))
+            .or_default()
+            .push(urlencoding_decode(value));
     }
 
     params
@@ -467,21 +1740,52 @@ mod tests {
             name: None,
             request: RequestMatcher {
                 method: vec![],
+                host: None,
+                client_ip: None,
+                client_cert: None,
                 path: Some(path),
                 query: HashMap::new(),
                 headers: HashMap::new(),
                 body: None,
+                jwt: None,
+                basic_auth: None,
+                max_headers: None,
+                max_header_bytes: None,
+                after_status: None,
+                any_of: vec![],
+                all_of: vec![],
+                not: None,
             },
+            response_match: None,
             response: ResponseDefinition {
-                status: 200,
+                status: 200.into(),
                 headers: HashMap::new(),
                 body: None,
                 template: false,
+                auto_etag: false,
+                throughput_bps: None,
+                delay: None,
+                fault: None,
+                weight: 1,
             },
+            responses: vec![],
+            sequence_mode: None,
+            selection: None,
+            weighted_responses: vec![],
+            ab_key: None,
+            ab_buckets: vec![],
+            switch: None,
+            match_probability: None,
+            long_poll: None,
             priority: 0,
             enabled: true,
             max_matches: 0,
+            requires_matched: vec![],
+            scenario: None,
+            required_state: None,
+            new_state: None,
             delay: None,
+            ramp: None,
             fault: None,
         }
     }
@@ -496,10 +1800,16 @@ mod tests {
         )];
         let matcher = Matcher::new(&stubs);
 
-        let result = matcher.find_match(&stubs, "GET", "/api/users", None, &HashMap::new(), None);
+        let result = matcher.find_match(
+            &stubs,
+            &RequestParts::new("GET", "/api/users", &HashMap::new()),
+        );
         assert!(result.is_some());
 
-        let result = matcher.find_match(&stubs, "GET", "/api/posts", None, &HashMap::new(), None);
+        let result = matcher.find_match(
+            &stubs,
+            &RequestParts::new("GET", "/api/posts", &HashMap::new()),
+        );
         assert!(result.is_none());
     }
 
@@ -509,21 +1819,50 @@ mod tests {
             "prefix",
             PathMatcher::Prefix {
                 value: "/api/".to_string(),
+                segment_boundary: false,
             },
         )];
         let matcher = Matcher::new(&stubs);
 
-        let result = matcher.find_match(&stubs, "GET", "/api/users", None, &HashMap::new(), None);
+        let result = matcher.find_match(
+            &stubs,
+            &RequestParts::new("GET", "/api/users", &HashMap::new()),
+        );
         assert!(result.is_some());
 
-        let result =
-            matcher.find_match(&stubs, "GET", "/api/posts/123", None, &HashMap::new(), None);
+        let result = matcher.find_match(
+            &stubs,
+            &RequestParts::new("GET", "/api/posts/123", &HashMap::new()),
+        );
         assert!(result.is_some());
 
-        let result = matcher.find_match(&stubs, "GET", "/other", None, &HashMap::new(), None);
+        let result =
+            matcher.find_match(&stubs, &RequestParts::new("GET", "/other", &HashMap::new()));
         assert!(result.is_none());
     }
 
+    #[test]
+    fn test_segment_boundary_prefix_matching() {
+        let stubs = vec![make_stub(
+            "api",
+            PathMatcher::Prefix {
+                value: "/api".to_string(),
+                segment_boundary: true,
+            },
+        )];
+        let matcher = Matcher::new(&stubs);
+        let matches = |path: &str| {
+            matcher
+                .find_match(&stubs, &RequestParts::new("GET", path, &HashMap::new()))
+                .is_some()
+        };
+
+        assert!(matches("/api"));
+        assert!(matches("/api/x"));
+        assert!(!matches("/apiv2"));
+        assert!(!matches("/other"));
+    }
+
     #[test]
     fn test_template_path_matching() {
         let stubs = vec![make_stub(
@@ -534,15 +1873,240 @@ mod tests {
         )];
         let matcher = Matcher::new(&stubs);
 
-        let result = matcher.find_match(&stubs, "GET", "/users/123", None, &HashMap::new(), None);
+        let result = matcher.find_match(
+            &stubs,
+            &RequestParts::new("GET", "/users/123", &HashMap::new()),
+        );
         assert!(result.is_some());
         let ctx = result.unwrap().context;
         assert_eq!(ctx.path_params.get("id"), Some(&"123".to_string()));
 
-        let result = matcher.find_match(&stubs, "GET", "/users/", None, &HashMap::new(), None);
+        let result = matcher.find_match(
+            &stubs,
+            &RequestParts::new("GET", "/users/", &HashMap::new()),
+        );
         assert!(result.is_none());
     }
 
+    #[test]
+    fn test_multi_param_templates() {
+        let template = |id: &str, template: &str| {
+            make_stub(
+                id,
+                PathMatcher::Template {
+                    template: template.to_string(),
+                },
+            )
+        };
+        let stubs = vec![
+            template("repo", "/orgs/{org}/repos/{repo}"),
+            template("pair", "/a/{x}/b/{y}"),
+            template("file", "/file-{name}.json"),
+            template("user-posts", "/users/{id}/posts"),
+        ];
+        let matcher = Matcher::new(&stubs);
+        let find = |path: &str| {
+            matcher
+                .find_match(&stubs, &RequestParts::new("GET", path, &HashMap::new()))
+                .map(|result| (result.stub.id.clone(), result.context.path_params))
+        };
+
+        let (id, params) = find("/orgs/acme/repos/widgets").unwrap();
+        assert_eq!(id, "repo");
+        assert_eq!(params["org"], "acme");
+        assert_eq!(params["repo"], "widgets");
+
+        let (id, params) = find("/a/1/b/2").unwrap();
+        assert_eq!(id, "pair");
+        assert_eq!((params["x"].as_str(), params["y"].as_str()), ("1", "2"));
+        // A parameter never spans a slash
+        assert!(find("/a/1/2/b/3").is_none());
+        assert!(find("/a/1/b/2/3").is_none());
+
+        // A parameter next to literals within one segment
+        let (id, params) = find("/file-report.v2.json").unwrap();
+        assert_eq!(id, "file");
+        assert_eq!(params["name"], "report.v2");
+        assert!(find("/file-.json").is_none());
+        assert!(find("/file-a/b.json").is_none());
+
+        // A parameter followed by a literal, and empty segments
+        assert_eq!(find("/users/7/posts").unwrap().1["id"], "7");
+        assert!(find("/users//posts").is_none());
+        assert!(find("/orgs//repos/widgets").is_none());
+        assert!(find("/orgs/acme/repos/").is_none());
+    }
+
+    #[test]
+    fn test_constrained_template_params() {
+        let template = |id: &str, template: &str| {
+            make_stub(
+                id,
+                PathMatcher::Template {
+                    template: template.to_string(),
+                },
+            )
+        };
+        let mut by_id = template("by-id", r"/users/{id:\d+}");
+        by_id.priority = 1;
+        let stubs = vec![
+            by_id,
+            template("by-slug", "/users/{slug}"),
+            template("by-code", "/codes/{code:[A-Z]{3}}"),
+        ];
+        let matcher = Matcher::new(&stubs);
+        let find = |path: &str| {
+            matcher
+                .find_match(&stubs, &RequestParts::new("GET", path, &HashMap::new()))
+                .map(|result| (result.stub.id.clone(), result.context.path_params))
+        };
+
+        let (id, params) = find("/users/42").unwrap();
+        assert_eq!(id, "by-id");
+        assert_eq!(params["id"], "42");
+        assert_eq!(find("/users/abc").unwrap().0, "by-slug");
+        assert_eq!(find("/users/42abc").unwrap().0, "by-slug");
+        assert_eq!(find("/codes/ABC").unwrap().1["code"], "ABC");
+        assert!(find("/codes/ABCD").is_none());
+
+        let invalid = template("invalid", "/users/{id:[0-9}");
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_greedy_template_params() {
+        let template = |id: &str, template: &str| {
+            make_stub(
+                id,
+                PathMatcher::Template {
+                    template: template.to_string(),
+                },
+            )
+        };
+        let stubs = vec![
+            template("files", "/files/{path*}"),
+            template("raw", "/raw/{path*}/content"),
+        ];
+        let matcher = Matcher::new(&stubs);
+        let find = |path: &str| {
+            matcher
+                .find_match(&stubs, &RequestParts::new("GET", path, &HashMap::new()))
+                .map(|result| (result.stub.id.clone(), result.context.path_params))
+        };
+
+        let (id, params) = find("/files/docs/2024/report.pdf").unwrap();
+        assert_eq!(id, "files");
+        assert_eq!(params["path"], "docs/2024/report.pdf");
+        assert!(!params.contains_key("path*"));
+
+        let (id, params) = find("/raw/a/b/content").unwrap();
+        assert_eq!(id, "raw");
+        assert_eq!(params["path"], "a/b");
+        assert!(find("/raw/a/b").is_none());
+        assert!(find("/files/").is_none());
+    }
+
+    #[test]
+    fn test_typed_template_params() {
+        let template = |id: &str, template: &str| {
+            make_stub(
+                id,
+                PathMatcher::Template {
+                    template: template.to_string(),
+                },
+            )
+        };
+        let stubs = vec![
+            template("order", "/orders/{id:int}"),
+            template("session", "/sessions/{sid:uuid}"),
+            template("meta", "/files/{path:*}/meta"),
+        ];
+        let matcher = Matcher::new(&stubs);
+        let find = |path: &str| {
+            matcher
+                .find_match(&stubs, &RequestParts::new("GET", path, &HashMap::new()))
+                .map(|result| (result.stub.id.clone(), result.context.path_params))
+        };
+
+        assert_eq!(find("/orders/42").unwrap().1["id"], "42");
+        assert_eq!(find("/orders/-7").unwrap().1["id"], "-7");
+        assert!(find("/orders/4a").is_none());
+        assert!(find("/orders/-").is_none());
+
+        let uuid = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        assert_eq!(find(&format!("/sessions/{}", uuid)).unwrap().1["sid"], uuid);
+        assert!(find("/sessions/67e55044-10b1-426f-9247").is_none());
+        assert!(find("/sessions/67e55044x10b1-426f-9247-bb680e5fe0c8").is_none());
+
+        let (id, params) = find("/files/docs/2024/report.pdf/meta").unwrap();
+        assert_eq!(id, "meta");
+        assert_eq!(params["path"], "docs/2024/report.pdf");
+        assert!(find("/files/report.pdf").is_none());
+
+        assert!(validate_path_template("/a/{x*}/b/{y:*}").is_err());
+        assert!(validate_path_template("/users/{id").is_err());
+        assert!(validate_path_template("/users/{id:[0-9]{2}/posts").is_err());
+
+        // Templates that don't compile never match, rather than panicking
+        let stubs = vec![make_stub(
+            "broken",
+            PathMatcher::Template {
+                template: "/users/{id:(}".to_string(),
+            },
+        )];
+        let matcher = Matcher::new(&stubs);
+        let request = RequestParts::new("GET", "/users/1", &HashMap::new());
+        assert!(matcher.find_match(&stubs, &request).is_none());
+    }
+
+    #[test]
+    fn test_path_normalization_options() {
+        let stubs = vec![
+            make_stub(
+                "hello",
+                PathMatcher::Exact {
+                    value: "/hello".to_string(),
+                },
+            ),
+            make_stub(
+                "user",
+                PathMatcher::Template {
+                    template: "/Users/{name}/".to_string(),
+                },
+            ),
+            make_stub(
+                "regex",
+                PathMatcher::Regex {
+                    pattern: "^/raw$".to_string(),
+                },
+            ),
+        ];
+        let options = PathOptions {
+            case_insensitive: true,
+            ignore_trailing_slash: true,
+        };
+        let matcher = Matcher::with_path_options(&stubs, options);
+        let find = |path: &str| {
+            matcher
+                .find_match(&stubs, &RequestParts::new("GET", path, &HashMap::new()))
+                .map(|result| (result.stub.id.clone(), result.context.path_params))
+        };
+
+        assert_eq!(find("/Hello").unwrap().0, "hello");
+        assert_eq!(find("/hello/").unwrap().0, "hello");
+        let (id, params) = find("/users/Alice").unwrap();
+        assert_eq!(id, "user");
+        assert_eq!(params["name"], "Alice");
+        assert_eq!(find("/USERS/Bob/").unwrap().1["name"], "Bob");
+        assert!(find("/RAW").is_none());
+
+        // Off by default
+        let matcher = Matcher::new(&stubs);
+        assert!(matcher
+            .find_match(&stubs, &RequestParts::new("GET", "/Hello", &HashMap::new()))
+            .is_none());
+    }
+
     #[test]
     fn test_method_matching() {
         let mut stub = make_stub(
@@ -556,11 +2120,16 @@ mod tests {
         let stubs = vec![stub];
         let matcher = Matcher::new(&stubs);
 
-        let result = matcher.find_match(&stubs, "GET", "/api/users", None, &HashMap::new(), None);
+        let result = matcher.find_match(
+            &stubs,
+            &RequestParts::new("GET", "/api/users", &HashMap::new()),
+        );
         assert!(result.is_some());
 
-        let result =
-            matcher.find_match(&stubs, "DELETE", "/api/users", None, &HashMap::new(), None);
+        let result = matcher.find_match(
+            &stubs,
+            &RequestParts::new("DELETE", "/api/users", &HashMap::new()),
+        );
         assert!(result.is_none());
     }
 
@@ -584,21 +2153,13 @@ mod tests {
 
         let result = matcher.find_match(
             &stubs,
-            "GET",
-            "/api/users",
-            Some("page=1"),
-            &HashMap::new(),
-            None,
+            &RequestParts::new("GET", "/api/users", &HashMap::new()).with_query("page=1"),
         );
         assert!(result.is_some());
 
         let result = matcher.find_match(
             &stubs,
-            "GET",
-            "/api/users",
-            Some("page=2"),
-            &HashMap::new(),
-            None,
+            &RequestParts::new("GET", "/api/users", &HashMap::new()).with_query("page=2"),
         );
         assert!(result.is_none());
     }
@@ -619,21 +2180,190 @@ mod tests {
         let matcher = Matcher::new(&stubs);
 
         let mut headers = HashMap::new();
-        headers.insert("Authorization".to_string(), "Bearer token".to_string());
+        headers.insert(
+            "Authorization".to_string(),
+            vec!["Bearer token".to_string()],
+        );
 
-        let result = matcher.find_match(&stubs, "GET", "/api/users", None, &headers, None);
+        let result = matcher.find_match(&stubs, &RequestParts::new("GET", "/api/users", &headers));
         assert!(result.is_some());
 
-        let result = matcher.find_match(&stubs, "GET", "/api/users", None, &HashMap::new(), None);
+        let result = matcher.find_match(
+            &stubs,
+            &RequestParts::new("GET", "/api/users", &HashMap::new()),
+        );
         assert!(result.is_none());
     }
 
+    #[test]
+    fn test_multi_value_header_matching() {
+        let mut any = make_stub(
+            "any",
+            PathMatcher::Exact {
+                value: "/any".to_string(),
+            },
+        );
+        any.request.headers.insert(
+            "x-forwarded-for".to_string(),
+            HeaderMatcher::AnyValue {
+                value: "10.0.0.2".to_string(),
+            },
+        );
+        let mut all = make_stub(
+            "all",
+            PathMatcher::Exact {
+                value: "/all".to_string(),
+            },
+        );
+        all.request.headers.insert(
+            "accept".to_string(),
+            HeaderMatcher::AllValues {
+                value: "application/json".to_string(),
+            },
+        );
+        let mut first = make_stub(
+            "first",
+            PathMatcher::Exact {
+                value: "/first".to_string(),
+            },
+        );
+        first.request.headers.insert(
+            "x-forwarded-for".to_string(),
+            HeaderMatcher::Exact {
+                value: "10.0.0.2".to_string(),
+                values: HeaderValueMode::First,
+            },
+        );
+
+        let stubs = vec![any, all, first];
+        let matcher = Matcher::new(&stubs);
+
+        let mut headers = HashMap::new();
+        headers.insert(
+            "x-forwarded-for".to_string(),
+            vec!["10.0.0.1".to_string(), "10.0.0.2".to_string()],
+        );
+        headers.insert(
+            "accept".to_string(),
+            vec!["application/json".to_string(), "text/html".to_string()],
+        );
+
+        assert!(matcher
+            .find_match(&stubs, &RequestParts::new("GET", "/any", &headers))
+            .is_some());
+        // Single-value matchers only see the first value
+        assert!(matcher
+            .find_match(&stubs, &RequestParts::new("GET", "/first", &headers))
+            .is_none());
+        assert!(matcher
+            .find_match(&stubs, &RequestParts::new("GET", "/all", &headers))
+            .is_none());
+
+        headers.insert(
+            "accept".to_string(),
+            vec![
+                "application/json".to_string(),
+                "application/json".to_string(),
+            ],
+        );
+        assert!(matcher
+            .find_match(&stubs, &RequestParts::new("GET", "/all", &headers))
+            .is_some());
+    }
+
+    #[test]
+    fn test_repeated_header_value_modes() {
+        let stub = |id: &str, name: &str, matcher: HeaderMatcher| {
+            let mut stub = make_stub(
+                id,
+                PathMatcher::Exact {
+                    value: format!("/{}", id),
+                },
+            );
+            stub.request.headers.insert(name.to_string(), matcher);
+            stub
+        };
+        let stubs = vec![
+            stub(
+                "exact-any",
+                "x-forwarded-for",
+                HeaderMatcher::Exact {
+                    value: "10.0.0.2".to_string(),
+                    values: HeaderValueMode::Any,
+                },
+            ),
+            stub(
+                "regex-all",
+                "x-forwarded-for",
+                HeaderMatcher::Regex {
+                    pattern: r"^10\.".to_string(),
+                    values: HeaderValueMode::All,
+                },
+            ),
+            stub(
+                "contains-any",
+                "accept",
+                HeaderMatcher::Contains {
+                    value: "json".to_string(),
+                    values: HeaderValueMode::Any,
+                },
+            ),
+            stub(
+                "contains-first",
+                "accept",
+                HeaderMatcher::Contains {
+                    value: "json".to_string(),
+                    values: HeaderValueMode::First,
+                },
+            ),
+            stub(
+                "count",
+                "x-forwarded-for",
+                HeaderMatcher::Count { count: 2 },
+            ),
+        ];
+        let matcher = Matcher::new(&stubs);
+        let matches = |path: &str, headers: &HashMap<String, Vec<String>>| {
+            matcher
+                .find_match(&stubs, &RequestParts::new("GET", path, headers))
+                .is_some()
+        };
+
+        let mut headers = HashMap::new();
+        headers.insert(
+            "x-forwarded-for".to_string(),
+            vec!["10.0.0.1".to_string(), "10.0.0.2".to_string()],
+        );
+        headers.insert(
+            "accept".to_string(),
+            vec!["text/html".to_string(), "application/json".to_string()],
+        );
+        assert!(matches("/exact-any", &headers));
+        assert!(matches("/regex-all", &headers));
+        assert!(matches("/contains-any", &headers));
+        assert!(!matches("/contains-first", &headers));
+        assert!(matches("/count", &headers));
+
+        headers.insert(
+            "x-forwarded-for".to_string(),
+            vec![
+                "10.0.0.1".to_string(),
+                "192.168.0.1".to_string(),
+                "10.0.0.3".to_string(),
+            ],
+        );
+        assert!(!matches("/exact-any", &headers));
+        assert!(!matches("/regex-all", &headers));
+        assert!(!matches("/count", &headers));
+    }
+
     #[test]
     fn test_priority_matching() {
         let mut stub1 = make_stub(
             "low-priority",
             PathMatcher::Prefix {
                 value: "/api/".to_string(),
+                segment_boundary: false,
             },
         );
         stub1.priority = 0;
@@ -649,7 +2379,10 @@ mod tests {
         let stubs = vec![stub1, stub2];
         let matcher = Matcher::new(&stubs);
 
-        let result = matcher.find_match(&stubs, "GET", "/api/users", None, &HashMap::new(), None);
+        let result = matcher.find_match(
+            &stubs,
+            &RequestParts::new("GET", "/api/users", &HashMap::new()),
+        );
         assert!(result.is_some());
         assert_eq!(result.unwrap().stub.id, "high-priority");
     }
@@ -670,22 +2403,838 @@ mod tests {
         let body = br#"{"name": "John"}"#;
         let result = matcher.find_match(
             &stubs,
-            "POST",
-            "/api/users",
-            None,
-            &HashMap::new(),
-            Some(body),
+            &RequestParts::new("POST", "/api/users", &HashMap::new()).with_body(body),
         );
         assert!(result.is_some());
 
         let body = b"not json";
         let result = matcher.find_match(
             &stubs,
-            "POST",
-            "/api/users",
-            None,
-            &HashMap::new(),
-            Some(body),
+            &RequestParts::new("POST", "/api/users", &HashMap::new()).with_body(body),
+        );
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_body_json_equals_matching() {
+        let matcher = Matcher::new(&[]);
+        let json_equals = |value: serde_json::Value, extra: bool, order: bool, ignore: &[&str]| {
+            BodyMatcher::JsonEquals {
+                value,
+                ignore_extra_fields: extra,
+                ignore_array_order: order,
+                ignore_paths: ignore.iter().map(|p| p.to_string()).collect(),
+            }
+        };
+
+        // Nested objects, regardless of key order and whitespace
+        let nested = json_equals(
+            serde_json::json!({ "user": { "name": "Alice", "age": 30 } }),
+            false,
+            false,
+            &[],
+        );
+        let body = br#"{ "user": {"age": 30.0, "name": "Alice"} }"#;
+        assert!(matcher.matches_body(Some(body), &nested));
+        let body = br#"{"user": {"name": "Alice", "age": 30, "admin": true}}"#;
+        assert!(!matcher.matches_body(Some(body), &nested));
+        let lenient = json_equals(
+            serde_json::json!({ "user": { "name": "Alice" } }),
+            true,
+            false,
+            &[],
+        );
+        assert!(matcher.matches_body(Some(body), &lenient));
+
+        // Arrays, with and without order
+        let roles = serde_json::json!({ "roles": ["admin", "dev"] });
+        let body = br#"{"roles": ["dev", "admin"]}"#;
+        assert!(!matcher.matches_body(Some(body), &json_equals(roles.clone(), false, false, &[])));
+        assert!(matcher.matches_body(Some(body), &json_equals(roles.clone(), false, true, &[])));
+        let body = br#"{"roles": ["dev", "dev"]}"#;
+        assert!(!matcher.matches_body(Some(body), &json_equals(roles, false, true, &[])));
+
+        // Ignored paths deep in the tree, on both sides
+        let order = json_equals(
+            serde_json::json!({
+                "timestamp": "2024-01-01T00:00:00Z",
+                "order": { "items": [{ "id": "x", "sku": "A" }, { "id": "y", "sku": "B" }] }
+            }),
+            false,
+            false,
+            &["$.timestamp", "$.order.items[*].id"],
+        );
+        let body = br#"{
+            "timestamp": "2026-10-15T12:00:00Z",
+            "order": { "items": [{ "id": "1", "sku": "A" }, { "id": "2", "sku": "B" }] }
+        }"#;
+        assert!(matcher.matches_body(Some(body), &order));
+        let body = br#"{"order": {"items": [{"sku": "A"}, {"sku": "C"}]}}"#;
+        assert!(!matcher.matches_body(Some(body), &order));
+
+        assert!(!matcher.matches_body(Some(b"not json"), &lenient));
+    }
+
+    #[test]
+    fn test_body_graphql_matching() {
+        let mut stub = make_stub(
+            "get-user",
+            PathMatcher::Exact {
+                value: "/graphql".to_string(),
+            },
+        );
+        let mut variables = HashMap::new();
+        variables.insert("id".to_string(), serde_json::json!("42"));
+        stub.request.body = Some(BodyMatcher::GraphQl {
+            operation_name: Some("GetUser".to_string()),
+            query_contains: None,
+            variables,
+        });
+        let mut raw = make_stub(
+            "raw",
+            PathMatcher::Exact {
+                value: "/graphql".to_string(),
+            },
+        );
+        raw.request.body = Some(BodyMatcher::GraphQl {
+            operation_name: Some("ListUsers".to_string()),
+            query_contains: Some("email".to_string()),
+            variables: HashMap::new(),
+        });
+
+        let stubs = vec![stub, raw];
+        let matcher = Matcher::new(&stubs);
+        let find = |content_type: &str, body: &str| {
+            let mut headers = HashMap::new();
+            headers.insert("content-type".to_string(), vec![content_type.to_string()]);
+            matcher
+                .find_match(
+                    &stubs,
+                    &RequestParts::new("POST", "/graphql", &headers).with_body(body.as_bytes()),
+                )
+                .map(|result| (result.stub.id.clone(), result.context.graphql))
+        };
+
+        let envelope = r#"{"query": "query GetUser($id: ID!) { user(id: $id) { name } }",
+            "operationName": "GetUser", "variables": {"id": "42"}}"#;
+        let (id, graphql) = find("application/json", envelope).unwrap();
+        assert_eq!(id, "get-user");
+        assert_eq!(graphql.unwrap().variables["id"], "42");
+
+        // The operation name can come from the query itself
+        let unnamed = r#"{"query": "query GetUser { user(id: 42) { name } }",
+            "variables": {"id": "42"}}"#;
+        assert_eq!(find("application/json", unnamed).unwrap().0, "get-user");
+        let other = r#"{"query": "query GetUser { user { name } }", "variables": {"id": "7"}}"#;
+        assert!(find("application/json", other).is_none());
+
+        let raw = "query ListUsers { users { name email } }";
+        let (id, graphql) = find("application/graphql", raw).unwrap();
+        assert_eq!(id, "raw");
+        assert!(graphql.unwrap().variables.is_empty());
+    }
+
+    fn make_jwt(claims: serde_json::Value) -> String {
+        use base64::Engine;
+        let encode = |value: serde_json::Value| {
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(value.to_string())
+        };
+        format!(
+            "{}.{}.signature",
+            encode(serde_json::json!({ "alg": "HS256" })),
+            encode(claims)
+        )
+    }
+
+    #[test]
+    fn test_jwt_claims_matching() {
+        let mut stub = make_stub(
+            "admin",
+            PathMatcher::Exact {
+                value: "/admin".to_string(),
+            },
+        );
+        let mut claims = HashMap::new();
+        claims.insert("sub".to_string(), serde_json::Value::Null);
+        claims.insert("role".to_string(), serde_json::json!("admin"));
+        claims.insert(
+            "email".to_string(),
+            serde_json::json!({ "op": "regex", "value": "@example\\.com$" }),
+        );
+        stub.request.jwt = Some(crate::config::JwtMatcher {
+            header: "Authorization".to_string(),
+            claims,
+        });
+        assert!(stub.validate().is_ok());
+
+        let stubs = vec![stub];
+        let matcher = Matcher::new(&stubs);
+        let find = |authorization: &str| {
+            let mut headers = HashMap::new();
+            headers.insert("authorization".to_string(), vec![authorization.to_string()]);
+            matcher
+                .find_match(&stubs, &RequestParts::new("GET", "/admin", &headers))
+                .map(|result| result.context.jwt.unwrap())
+        };
+
+        let token = make_jwt(serde_json::json!({
+            "sub": "alice", "role": "admin", "email": "alice@example.com"
+        }));
+        let claims = find(&format!("Bearer {}", token)).unwrap();
+        assert_eq!(claims["sub"], "alice");
+        assert!(find(&token).is_some());
+
+        let token = make_jwt(serde_json::json!({
+            "sub": "bob", "role": "user", "email": "bob@example.com"
+        }));
+        assert!(find(&format!("Bearer {}", token)).is_none());
+        let token = make_jwt(serde_json::json!({ "role": "admin", "email": "x@example.com" }));
+        assert!(find(&format!("Bearer {}", token)).is_none());
+
+        // Malformed tokens fail the match rather than the request
+        assert!(find("Bearer not-a-jwt").is_none());
+        assert!(find("Bearer a.%%%.c").is_none());
+    }
+
+    #[test]
+    fn test_body_yaml_path_matching() {
+        let mut stub = make_stub(
+            "yaml-body",
+            PathMatcher::Exact {
+                value: "/deployments".to_string(),
+            },
+        );
+        let mut expressions = HashMap::new();
+        expressions.insert("$.spec.image".to_string(), serde_json::json!("web:1.2"));
+        expressions.insert(
+            "$.spec.replicas".to_string(),
+            serde_json::json!({ "op": "gte", "value": 2 }),
+        );
+        stub.request.body = Some(BodyMatcher::YamlPath { expressions });
+
+        let stubs = vec![stub];
+        let matcher = Matcher::new(&stubs);
+        let matches = |body: &[u8]| {
+            matcher
+                .find_match(
+                    &stubs,
+                    &RequestParts::new("POST", "/deployments", &HashMap::new()).with_body(body),
+                )
+                .is_some()
+        };
+
+        assert!(matches(b"spec:\n  image: web:1.2\n  replicas: 3\n"));
+        assert!(!matches(b"spec:\n  image: web:1.2\n  replicas: 1\n"));
+        assert!(!matches(b"spec: [unclosed"));
+
+        assert!(matcher.matches_body(Some(b"a: 1\nb: [2, 3]\n"), &BodyMatcher::Yaml));
+        assert!(!matcher.matches_body(Some(b"a: [unclosed"), &BodyMatcher::Yaml));
+    }
+
+    #[test]
+    fn test_body_hash_matching() {
+        let matcher = Matcher::new(&[]);
+        let sha256 = |value: &str| BodyMatcher::Hash {
+            algorithm: "sha256".to_string(),
+            value: value.to_string(),
+        };
+        // sha256("hello world")
+        let digest = "B94D27B9934D3E08A52E52D7DA7DABFAC484EFE37A5380EE9088F7ACE2EFCDE9";
+
+        assert!(matcher.matches_body(Some(b"hello world"), &sha256(digest)));
+        assert!(!matcher.matches_body(Some(b"hello world!"), &sha256(digest)));
+        assert!(!matcher.matches_body(None, &sha256(digest)));
+
+        let unknown = BodyMatcher::Hash {
+            algorithm: "crc32".to_string(),
+            value: digest.to_string(),
+        };
+        assert!(!matcher.matches_body(Some(b"hello world"), &unknown));
+        let mut stub = make_stub("hash", PathMatcher::Exact { value: "/".into() });
+        stub.request.body = Some(unknown);
+        assert!(stub.validate().is_err());
+    }
+
+    #[test]
+    fn test_negated_matchers() {
+        let yaml = r#"
+- id: not-health
+  request:
+    path:
+      type: not
+      matcher: { type: regex, pattern: "^/(?P<name>health|ready)$" }
+    query:
+      debug:
+        type: not
+        matcher: { type: present }
+    headers:
+      x-env:
+        type: not
+        matcher: { type: exact, value: prod }
+    body:
+      type: not
+      matcher: { type: contains, value: secret }
+"#;
+        let stubs: Vec<StubDefinition> = serde_yaml::from_str(yaml).unwrap();
+        assert!(stubs[0].validate().is_ok());
+        let matcher = Matcher::new(&stubs);
+        let find = |path: &str, query: Option<&str>, env: &str, body: &[u8]| {
+            let headers = HashMap::from([("x-env".to_string(), vec![env.to_string()])]);
+            matcher.find_match(
+                &stubs,
+                &RequestParts {
+                    method: "GET",
+                    path,
+                    query,
+                    headers: &headers,
+                    body: Some(body),
+                    client_ip: None,
+                },
+            )
+        };
+
+        let result = find("/users", None, "dev", b"{}").unwrap();
+        assert!(result.context.captures.is_empty());
+        assert!(find("/health", None, "dev", b"{}").is_none());
+        assert!(find("/users", Some("debug=1"), "dev", b"{}").is_none());
+        assert!(find("/users", None, "prod", b"{}").is_none());
+        assert!(find("/users", None, "dev", b"a secret").is_none());
+
+        let mut invalid = stubs[0].clone();
+        invalid.request.path = Some(PathMatcher::Not {
+            matcher: Box::new(PathMatcher::Regex {
+                pattern: "(".to_string(),
+            }),
+        });
+        assert!(invalid.validate().is_err());
+        invalid.request.path = None;
+        invalid.request.headers.insert(
+            "x-env".to_string(),
+            HeaderMatcher::Not {
+                matcher: Box::new(HeaderMatcher::Regex {
+                    pattern: "[".to_string(),
+                    values: HeaderValueMode::First,
+                }),
+            },
+        );
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_any_of_all_of() {
+        let yaml = r#"
+- id: combined
+  request:
+    method: [GET]
+    any_of:
+      - path: { type: exact, value: /a }
+      - path: { type: template, template: "/b/{id}" }
+    all_of:
+      - headers:
+          x-tenant: { type: present }
+      - path: { type: regex, pattern: "^/(?P<section>[ab])" }
+"#;
+        let stubs: Vec<StubDefinition> = serde_yaml::from_str(yaml).unwrap();
+        assert!(stubs[0].validate().is_ok());
+        let matcher = Matcher::new(&stubs);
+        let tenant = HashMap::from([("x-tenant".to_string(), vec!["acme".to_string()])]);
+        let find = |method: &str, path: &str, headers: &HashMap<String, Vec<String>>| {
+            matcher.find_match(&stubs, &RequestParts::new(method, path, headers))
+        };
+
+        let result = find("GET", "/b/7", &tenant).unwrap();
+        assert_eq!(result.context.path_params["id"], "7");
+        assert_eq!(result.context.captures["section"], "b");
+        assert!(find("GET", "/a", &tenant).is_some());
+        assert!(find("GET", "/c", &tenant).is_none());
+        assert!(find("GET", "/a", &HashMap::new()).is_none());
+        // Top-level fields still apply alongside the sub-matchers
+        assert!(find("POST", "/a", &tenant).is_none());
+    }
+
+    #[test]
+    fn test_host_matching() {
+        let yaml = r#"
+- id: exact
+  request:
+    host: { type: exact, value: API.staging.example.com }
+- id: wildcard
+  request:
+    host: { type: wildcard, pattern: "*.example.com" }
+- id: regex
+  request:
+    host: { type: regex, pattern: "^\\[::1\\]$|^localhost$" }
+"#;
+        let stubs: Vec<StubDefinition> = serde_yaml::from_str(yaml).unwrap();
+        assert!(stubs.iter().all(|stub| stub.validate().is_ok()));
+        let matcher = Matcher::new(&stubs);
+        let find = |name: &str, value: &str| {
+            let headers = HashMap::from([(name.to_string(), vec![value.to_string()])]);
+            matcher
+                .find_match(&stubs, &RequestParts::new("GET", "/", &headers))
+                .map(|result| (result.stub.id.as_str(), result.context.host))
+        };
+
+        assert_eq!(
+            find("Host", "api.STAGING.example.com:8443"),
+            Some(("exact", Some("api.staging.example.com".to_string())))
+        );
+        assert_eq!(find("host", "api.staging.example.com.").unwrap().0, "exact");
+        assert_eq!(find(":authority", "a.b.example.com").unwrap().0, "wildcard");
+        assert!(find("host", "example.com").is_none());
+        assert!(find("host", "notexample.com").is_none());
+        assert_eq!(find("host", "[::1]:8080").unwrap().0, "regex");
+        assert_eq!(find("host", "localhost:3000").unwrap().0, "regex");
+
+        let invalid: StubDefinition = serde_yaml::from_str(
+            r#"
+id: bad
+request:
+  host: { type: wildcard, pattern: "example.*" }
+"#,
+        )
+        .unwrap();
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_matches_stub_path_walks_combinators() {
+        let yaml = r#"
+- id: plain
+  request:
+    path: { type: exact, value: /plain }
+- id: either
+  request:
+    any_of:
+      - path: { type: exact, value: /a }
+      - path: { type: exact, value: /b }
+- id: both
+  request:
+    all_of:
+      - path: { type: prefix, value: /api }
+      - path: { type: regex, pattern: "/v2/" }
+- id: negated
+  request:
+    not:
+      path: { type: exact, value: /admin }
+      method: [DELETE]
+"#;
+        let stubs: Vec<StubDefinition> = serde_yaml::from_str(yaml).unwrap();
+        let matcher = Matcher::new(&stubs);
+        let fits = |id: &str, path: &str| matcher.matches_stub_path(&stubs, id, path);
+
+        assert!(fits("plain", "/plain"));
+        assert!(!fits("plain", "/other"));
+        assert!(fits("either", "/b"));
+        assert!(!fits("either", "/c"));
+        assert!(fits("both", "/api/v2/users"));
+        assert!(!fits("both", "/api/v1/users"));
+        assert!(fits("negated", "/admin"));
+        assert!(!fits("missing", "/plain"));
+    }
+
+    #[test]
+    fn test_client_ip_matching() {
+        let yaml = r#"
+- id: office
+  request:
+    client_ip: { ranges: [10.1.0.0/16, "2001:db8::/32", 192.168.0.7] }
+- id: forwarded
+  request:
+    path: { type: exact, value: /fwd }
+    client_ip:
+      ranges: [203.0.113.0/24]
+      from_header: X-Forwarded-For
+      index: -2
+"#;
+        let stubs: Vec<StubDefinition> = serde_yaml::from_str(yaml).unwrap();
+        assert!(stubs.iter().all(|stub| stub.validate().is_ok()));
+        let matcher = Matcher::new(&stubs);
+        let find = |path: &str, peer: &str, forwarded: Option<&str>| {
+            let mut headers = HashMap::new();
+            if let Some(forwarded) = forwarded {
+                headers.insert("x-forwarded-for".to_string(), vec![forwarded.to_string()]);
+            }
+            matcher
+                .find_match(
+                    &stubs,
+                    &RequestParts::new("GET", path, &headers).with_client_ip(peer),
+                )
+                .map(|result| (result.stub.id.as_str(), result.context.client_ip.unwrap()))
+        };
+
+        assert_eq!(
+            find("/", "10.1.200.3", None),
+            Some(("office", "10.1.200.3".to_string()))
+        );
+        assert!(find("/", "10.2.0.1", None).is_none());
+        assert!(find("/", "192.168.0.7", None).is_some());
+        assert!(find("/", "::ffff:192.168.0.7", None).is_some());
+        assert!(find("/", "2001:db8:1::1", None).is_some());
+        assert!(find("/", "2001:db9::1", None).is_none());
+
+        // The second-to-last hop, not the peer, is checked
+        assert_eq!(
+            find(
+                "/fwd",
+                "10.9.9.9",
+                Some("198.51.100.1, 203.0.113.9, 10.0.0.1")
+            ),
+            Some(("forwarded", "203.0.113.9".to_string()))
+        );
+        assert!(find("/fwd", "203.0.113.9", Some("203.0.113.9")).is_none());
+        assert!(find("/fwd", "203.0.113.9", None).is_none());
+
+        for range in ["10.0.0.0/33", "10.0.0/8", "2001:db8::/129", "host"] {
+            assert!(
+                parse_ip_range(range).is_err(),
+                "{} should be rejected",
+                range
+            );
+        }
+        assert_eq!(
+            parse_ip_range("0.0.0.0/0").unwrap(),
+            ("0.0.0.0".parse().unwrap(), 0)
+        );
+
+        // IPv4 ranges written in IPv4-mapped IPv6 form cover plain IPv4 too
+        let (network, prefix) = parse_ip_range("::ffff:10.0.0.0/104").unwrap();
+        for address in ["10.20.30.40", "::ffff:10.20.30.40"] {
+            assert!(ip_in_range(address.parse().unwrap(), network, prefix));
+        }
+        assert!(!ip_in_range("11.0.0.1".parse().unwrap(), network, prefix));
+    }
+
+    #[test]
+    fn test_client_cert_matching() {
+        let yaml = r#"
+- id: billing-client
+  request:
+    client_cert:
+      subject_cn: "^billing-[0-9]+$"
+      san_contains: spiffe://example/billing
+- id: any-cert
+  request:
+    client_cert: { subject_cn: ".+", subject_header: ssl-client-subject-dn }
+"#;
+        let stubs: Vec<StubDefinition> = serde_yaml::from_str(yaml).unwrap();
+        assert!(stubs.iter().all(|stub| stub.validate().is_ok()));
+        let matcher = Matcher::new(&stubs);
+        let find = |headers: &[(&str, &str)]| {
+            let headers: HashMap<String, Vec<String>> = headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), vec![value.to_string()]))
+                .collect();
+            matcher
+                .find_match(&stubs, &RequestParts::new("GET", "/", &headers))
+                .map(|result| result.stub.id.as_str())
+        };
+
+        let sans = "DNS:billing.example.com, URI:spiffe://example/billing";
+        assert_eq!(
+            find(&[
+                ("x-client-cert-subject", "CN=billing-7,O=Example"),
+                ("x-client-cert-san", sans),
+            ]),
+            Some("billing-client")
+        );
+        // OpenSSL's one-line subject form works too
+        assert_eq!(
+            find(&[
+                ("x-client-cert-subject", "/C=US/O=Example/CN=billing-12"),
+                ("x-client-cert-san", sans),
+            ]),
+            Some("billing-client")
+        );
+        assert!(find(&[
+            ("x-client-cert-subject", "CN=orders-1"),
+            ("x-client-cert-san", sans)
+        ])
+        .is_none());
+        assert!(find(&[("x-client-cert-subject", "CN=billing-7")]).is_none());
+        assert_eq!(
+            find(&[("ssl-client-subject-dn", "O=Example, CN=anyone")]),
+            Some("any-cert")
+        );
+        assert!(find(&[("ssl-client-subject-dn", "O=Example")]).is_none());
+
+        let invalid: StubDefinition =
+            serde_yaml::from_str("id: x\nrequest:\n  client_cert: { subject_cn: \"(\" }").unwrap();
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_not_request_matcher() {
+        // /api/* and (no X-Debug header or a legacy body), except internal paths
+        let yaml = r#"
+- id: legacy-api
+  request:
+    path: { type: regex, pattern: "^/api/(?P<resource>[a-z]+)" }
+    any_of:
+      - headers:
+          x-debug: { type: absent }
+      - body: { type: contains, value: legacy }
+    not:
+      path: { type: template, template: "/api/internal/{rest*}" }
+"#;
+        let stubs: Vec<StubDefinition> = serde_yaml::from_str(yaml).unwrap();
+        assert!(stubs[0].validate().is_ok());
+        let matcher = Matcher::new(&stubs);
+        let debug = HashMap::from([("x-debug".to_string(), vec!["1".to_string()])]);
+        let find = |path: &str, headers: &HashMap<String, Vec<String>>, body: &[u8]| {
+            matcher.find_match(
+                &stubs,
+                &RequestParts::new("POST", path, headers).with_body(body),
+            )
+        };
+
+        let result = find("/api/users", &HashMap::new(), b"{}").unwrap();
+        assert_eq!(result.context.captures["resource"], "users");
+        // The negated template's parameters are not captured
+        assert!(result.context.path_params.is_empty());
+        assert!(find("/api/users", &debug, b"{}").is_none());
+        assert!(find("/api/users", &debug, b"legacy client").is_some());
+        assert!(find("/api/internal/jobs", &HashMap::new(), b"{}").is_none());
+        assert!(find("/web/users", &HashMap::new(), b"{}").is_none());
+    }
+
+    #[test]
+    fn test_matcher_nesting_depth() {
+        use crate::config::MAX_MATCHER_DEPTH;
+
+        // `depth` levels of `not` below the top-level matcher
+        let nested = |depth: usize| {
+            let mut request = serde_json::json!({});
+            for _ in 0..depth {
+                request = serde_json::json!({ "not": request });
+            }
+            serde_json::from_value::<RequestMatcher>(request).unwrap()
+        };
+        assert!(nested(MAX_MATCHER_DEPTH - 1).validate().is_ok());
+        assert!(nested(MAX_MATCHER_DEPTH).validate().is_err());
+    }
+
+    #[test]
+    fn test_body_json_path_operators() {
+        let mut stub = make_stub(
+            "adult",
+            PathMatcher::Exact {
+                value: "/api/users".to_string(),
+            },
+        );
+        let mut expressions = HashMap::new();
+        expressions.insert(
+            "$.age".to_string(),
+            serde_json::json!({ "op": "gte", "value": 18 }),
+        );
+        expressions.insert(
+            "$.name".to_string(),
+            serde_json::json!({ "op": "lt", "value": "M" }),
+        );
+        expressions.insert(
+            "$.tags".to_string(),
+            serde_json::json!({ "op": "contains", "value": "vip" }),
+        );
+        stub.request.body = Some(BodyMatcher::JsonPath { expressions });
+
+        let stubs = vec![stub];
+        let matcher = Matcher::new(&stubs);
+        let matches = |body: &[u8]| {
+            matcher
+                .find_match(
+                    &stubs,
+                    &RequestParts::new("POST", "/api/users", &HashMap::new()).with_body(body),
+                )
+                .is_some()
+        };
+
+        assert!(matches(
+            br#"{"age": 18.5, "name": "John", "tags": ["vip"]}"#
+        ));
+        assert!(matches(
+            br#"{"age": 18, "name": "Ann", "tags": ["new", "vip"]}"#
+        ));
+        assert!(!matches(br#"{"age": 17, "name": "John", "tags": ["vip"]}"#));
+        assert!(!matches(br#"{"age": 30, "name": "Zed", "tags": ["vip"]}"#));
+        assert!(!matches(
+            br#"{"age": "30", "name": "John", "tags": ["vip"]}"#
+        ));
+        assert!(!matches(br#"{"age": 30, "name": "John", "tags": []}"#));
+    }
+
+    #[test]
+    fn test_query_csv_contains_matching() {
+        let mut stub = make_stub(
+            "query-csv",
+            PathMatcher::Exact {
+                value: "/api/items".to_string(),
+            },
+        );
+        stub.request.query.insert(
+            "ids".to_string(),
+            QueryMatcher::CsvContains {
+                value: "2".to_string(),
+            },
+        );
+
+        let stubs = vec![stub];
+        let matcher = Matcher::new(&stubs);
+
+        let result = matcher.find_match(
+            &stubs,
+            &RequestParts::new("GET", "/api/items", &HashMap::new()).with_query("ids=1,2,3"),
+        );
+        assert!(result.is_some());
+
+        // "12" contains "2" as a substring but not as a CSV item
+        let result = matcher.find_match(
+            &stubs,
+            &RequestParts::new("GET", "/api/items", &HashMap::new()).with_query("ids=1,12,3"),
+        );
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_query_contains_matching() {
+        let mut stub = make_stub(
+            "query-contains",
+            PathMatcher::Exact {
+                value: "/api/items".to_string(),
+            },
+        );
+        stub.request.query.insert(
+            "ids".to_string(),
+            QueryMatcher::Contains {
+                value: "2".to_string(),
+            },
+        );
+
+        let stubs = vec![stub];
+        let matcher = Matcher::new(&stubs);
+
+        let result = matcher.find_match(
+            &stubs,
+            &RequestParts::new("GET", "/api/items", &HashMap::new()).with_query("ids=1,12,3"),
+        );
+        assert!(result.is_some());
+    }
+
+    #[test]
+    fn test_repeated_query_params() {
+        let stub = |id: &str, matcher: QueryMatcher| {
+            let mut stub = make_stub(
+                id,
+                PathMatcher::Exact {
+                    value: format!("/{}", id),
+                },
+            );
+            stub.request.query.insert("tag".to_string(), matcher);
+            stub
+        };
+        let stubs = vec![
+            stub(
+                "exact",
+                QueryMatcher::Exact {
+                    value: "a".to_string(),
+                },
+            ),
+            stub(
+                "any",
+                QueryMatcher::AnyValue {
+                    value: "b".to_string(),
+                },
+            ),
+            stub("count", QueryMatcher::Count { count: 2 }),
+            stub(
+                "all",
+                QueryMatcher::All {
+                    values: vec!["b".to_string(), "a".to_string()],
+                },
+            ),
+        ];
+        let matcher = Matcher::new(&stubs);
+        let find = |path: &str, query: &str| {
+            matcher.find_match(
+                &stubs,
+                &RequestParts::new("GET", path, &HashMap::new()).with_query(query),
+            )
+        };
+
+        // Single-value matchers and `query.x` keep the last value
+        let result = find("/exact", "tag=b&tag=a").unwrap();
+        assert_eq!(result.context.query_params["tag"], "a");
+        assert_eq!(result.context.query_all["tag"], vec!["b", "a"]);
+        assert!(find("/exact", "tag=a&tag=b").is_none());
+        let result = find("/count", "tag=a&tag=b").unwrap();
+        assert_eq!(result.context.query_params["tag"], "b");
+        assert!(find("/any", "tag=a&tag=b").is_some());
+        assert!(find("/any", "tag=a&tag=c").is_none());
+        assert!(find("/count", "tag=a&tag=b").is_some());
+        assert!(find("/count", "tag=a").is_none());
+        assert!(find("/all", "tag=a&tag=b").is_some());
+        assert!(find("/all", "tag=a&tag=b&tag=c").is_none());
+    }
+
+    #[test]
+    fn test_json_path_result_comparison() {
+        let matcher = Matcher::new(&[]);
+        let body = serde_json::json!({
+            "email": "ann@example.com",
+            "role": "admin",
+            "roles": ["admin", "dev"]
+        });
+        let matches = |path: &str, expected: serde_json::Value| {
+            let expressions = HashMap::from([(path.to_string(), expected)]);
+            matcher.matches_json_paths(&body, &expressions)
+        };
+
+        // Unchanged: presence checks, and the results compared as a whole
+        assert!(matches("$.email", serde_json::Value::Null));
+        assert!(!matches("$.phone", serde_json::Value::Null));
+        assert!(matches("$.roles[*]", serde_json::json!(["admin", "dev"])));
+        assert!(!matches("$.role", serde_json::json!("user")));
+        assert!(!matches("$.roles[*]", serde_json::json!(["dev"])));
+
+        // New: a single result, or any one of several, compares on its own
+        assert!(matches("$.role", serde_json::json!("admin")));
+        assert!(matches("$.roles[*]", serde_json::json!("dev")));
+        assert!(!matches("$.roles[*]", serde_json::json!("ops")));
+    }
+
+    #[test]
+    fn test_query_json_path_matching() {
+        let mut stub = make_stub(
+            "query-json",
+            PathMatcher::Exact {
+                value: "/api/items".to_string(),
+            },
+        );
+        let mut expressions = HashMap::new();
+        expressions.insert("$.a".to_string(), serde_json::json!(1));
+        stub.request
+            .query
+            .insert("filter".to_string(), QueryMatcher::JsonPath { expressions });
+
+        let stubs = vec![stub];
+        let matcher = Matcher::new(&stubs);
+
+        let result = matcher.find_match(
+            &stubs,
+            &RequestParts::new("GET", "/api/items", &HashMap::new())
+                .with_query("filter=%7B%22a%22%3A1%7D"),
+        );
+        assert!(result.is_some());
+
+        let result = matcher.find_match(
+            &stubs,
+            &RequestParts::new("GET", "/api/items", &HashMap::new())
+                .with_query("filter=%7B%22a%22%3A2%7D"),
+        );
+        assert!(result.is_none());
+
+        let result = matcher.find_match(
+            &stubs,
+            &RequestParts::new("GET", "/api/items", &HashMap::new()).with_query("filter=not-json"),
         );
         assert!(result.is_none());
     }
@@ -693,10 +3242,14 @@ mod tests {
     #[test]
     fn test_parse_query_string() {
         let params = parse_query_string("foo=bar&baz=qux");
-        assert_eq!(params.get("foo"), Some(&"bar".to_string()));
-        assert_eq!(params.get("baz"), Some(&"qux".to_string()));
+        assert_eq!(params["foo"], vec!["bar"]);
+        assert_eq!(params["baz"], vec!["qux"]);
 
         let params = parse_query_string("name=John%20Doe");
-        assert_eq!(params.get("name"), Some(&"John Doe".to_string()));
+        assert_eq!(params["name"], vec!["John Doe"]);
+
+        let params = parse_query_string("tag=a&flag&tag=b");
+        assert_eq!(params["tag"], vec!["a", "b"]);
+        assert_eq!(params["flag"], vec![""]);
     }
 }
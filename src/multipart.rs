@@ -0,0 +1,222 @@
+//! Minimal `multipart/form-data` parsing for body matching.
+//!
+//! Only what matching needs: part names, filenames, content types and
+//! content. Bodies with bare LF line endings are accepted as well as CRLF,
+//! and a body cut off before its closing delimiter keeps its last part.
+
+/// One part of a multipart body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    /// Form field name from `Content-Disposition`
+    pub name: String,
+    /// Filename from `Content-Disposition`, for file parts
+    pub filename: Option<String>,
+    /// The part's `Content-Type`, if given
+    pub content_type: Option<String>,
+    /// Part content
+    pub data: Vec<u8>,
+}
+
+impl Part {
+    /// Whether the part is a file upload rather than a text field.
+    pub fn is_file(&self) -> bool {
+        self.filename.is_some()
+    }
+}
+
+/// Boundary from a `multipart/*` content type.
+pub fn boundary(content_type: &str) -> Option<String> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().to_ascii_lowercase().starts_with("multipart/") {
+        return None;
+    }
+    params.split(';').find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"').to_string())
+            .filter(|b| !b.is_empty())
+    })
+}
+
+/// Boundary taken from the first line of `body`, for bodies whose content
+/// type isn't known.
+pub fn sniff_boundary(body: &[u8]) -> Option<String> {
+    let line = body.split(|&b| b == b'\n').next()?;
+    let line = std::str::from_utf8(line).ok()?.trim_end_matches('\r');
+    line.strip_prefix("--")
+        .filter(|b| !b.is_empty() && !b.contains(char::is_whitespace))
+        .map(String::from)
+}
+
+/// Split a multipart body into its parts.
+///
+/// Parts without a `Content-Disposition` name are skipped.
+pub fn parse(body: &[u8], boundary: &str) -> Vec<Part> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut parts = Vec::new();
+
+    let Some(start) = find(body, &delimiter, 0) else {
+        return parts;
+    };
+    let mut pos = start + delimiter.len();
+    loop {
+        // Closing delimiter
+        if body[pos..].starts_with(b"--") {
+            break;
+        }
+        // Rest of the delimiter line
+        let Some(line_end) = find(body, b"\n", pos) else {
+            break;
+        };
+        let head_start = line_end + 1;
+
+        // Headers end at the first empty line
+        let mut headers = Vec::new();
+        let mut cursor = head_start;
+        let content_start = loop {
+            let Some(end) = find(body, b"\n", cursor) else {
+                return parts;
+            };
+            let line = trim_cr(&body[cursor..end]);
+            cursor = end + 1;
+            if line.is_empty() {
+                break cursor;
+            }
+            headers.push(String::from_utf8_lossy(line).into_owned());
+        };
+
+        let next = find_delimiter(body, &delimiter, content_start);
+        let content_end = match next {
+            Some(next) => strip_line_ending(body, content_start, next),
+            None => body.len(),
+        };
+        if let Some(part) = make_part(&headers, &body[content_start..content_end]) {
+            parts.push(part);
+        }
+
+        match next {
+            Some(next) => pos = next + delimiter.len(),
+            None => break,
+        }
+    }
+    parts
+}
+
+fn make_part(headers: &[String], data: &[u8]) -> Option<Part> {
+    let mut name = None;
+    let mut filename = None;
+    let mut content_type = None;
+    for header in headers {
+        let Some((key, value)) = header.split_once(':') else {
+            continue;
+        };
+        let key = key.trim();
+        if key.eq_ignore_ascii_case("content-disposition") {
+            for param in value.split(';').skip(1) {
+                let Some((k, v)) = param.split_once('=') else {
+                    continue;
+                };
+                let v = v.trim().trim_matches('"').to_string();
+                match k.trim().to_ascii_lowercase().as_str() {
+                    "name" => name = Some(v),
+                    "filename" => filename = Some(v),
+                    _ => {}
+                }
+            }
+        } else if key.eq_ignore_ascii_case("content-type") {
+            content_type = Some(value.trim().to_string());
+        }
+    }
+    Some(Part {
+        name: name?,
+        filename,
+        content_type,
+        data: data.to_vec(),
+    })
+}
+
+/// Next delimiter that starts a line at or after `from`.
+fn find_delimiter(body: &[u8], delimiter: &[u8], from: usize) -> Option<usize> {
+    let mut pos = from;
+    while let Some(found) = find(body, delimiter, pos) {
+        if found == from || body[found - 1] == b'\n' {
+            return Some(found);
+        }
+        pos = found + 1;
+    }
+    None
+}
+
+/// End of the content before a delimiter at `delimiter_pos`, without the
+/// line ending that belongs to the delimiter.
+fn strip_line_ending(body: &[u8], start: usize, delimiter_pos: usize) -> usize {
+    let mut end = delimiter_pos;
+    if end > start && body[end - 1] == b'\n' {
+        end -= 1;
+        if end > start && body[end - 1] == b'\r' {
+            end -= 1;
+        }
+    }
+    end
+}
+
+fn trim_cr(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if from > haystack.len() {
+        return None;
+    }
+    haystack[from..]
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .map(|i| i + from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_crlf_and_lf() {
+        let crlf = "--XyZ\r\n\
+            Content-Disposition: form-data; name=\"description\"\r\n\
+            \r\n\
+            invoice\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"a.pdf\"\r\n\
+            Content-Type: application/pdf\r\n\
+            \r\n\
+            %PDF-1.4\r\n\
+            --XyZ--\r\n";
+        let lf = crlf.replace("\r\n", "\n");
+
+        for body in [crlf.to_string(), lf] {
+            let parts = parse(body.as_bytes(), "XyZ");
+            assert_eq!(parts.len(), 2);
+            assert_eq!(parts[0].name, "description");
+            assert_eq!(parts[0].data, b"invoice");
+            assert!(!parts[0].is_file());
+            assert_eq!(parts[1].filename.as_deref(), Some("a.pdf"));
+            assert_eq!(parts[1].content_type.as_deref(), Some("application/pdf"));
+            assert_eq!(parts[1].data, b"%PDF-1.4");
+        }
+
+        // Cut off mid-part, as when truncated to max_body_bytes
+        let parts = parse(&crlf.as_bytes()[..crlf.find("%PDF").unwrap() + 3], "XyZ");
+        assert_eq!(parts[1].data, b"%PD");
+    }
+
+    #[test]
+    fn test_boundary() {
+        assert_eq!(
+            boundary("multipart/form-data; boundary=\"abc 1\"").as_deref(),
+            Some("abc 1")
+        );
+        assert_eq!(boundary("application/json; boundary=x"), None);
+        assert_eq!(sniff_boundary(b"--abc\r\nrest").as_deref(), Some("abc"));
+        assert_eq!(sniff_boundary(b"{\"a\": 1}"), None);
+    }
+}
@@ -0,0 +1,338 @@
+//! Record-and-replay.
+//!
+//! Turns upstream responses to unmatched requests into stubs, appended to a
+//! YAML file that can be loaded again with `--replay`. Starter configs can
+//! also be generated from an access log with `--generate-config`.
+
+use crate::config::{
+    HeaderMatcher, HeaderValueMode, HeaderValues, PathMatcher, RecordConfig, RequestMatcher,
+    ResponseBody, ResponseDefinition, StubDefinition,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+use zentinel_agent_sdk::{Request, Response};
+
+/// Response headers that describe the original transfer rather than the
+/// content, and would be wrong when the stub is served.
+const SKIPPED_RESPONSE_HEADERS: &[&str] = &[
+    "connection",
+    "content-length",
+    "date",
+    "keep-alive",
+    "transfer-encoding",
+];
+
+/// Build a stub that answers `request` the way upstream just did.
+pub fn recorded_stub(
+    id: String,
+    request: &Request,
+    response: &Response,
+    record: &RecordConfig,
+) -> StubDefinition {
+    // Start from the defaults a minimal stub gets when loaded from a file
+    let mut stub: StubDefinition =
+        serde_json::from_value(serde_json::json!({ "id": id, "request": {} }))
+            .expect("minimal stub definition is valid");
+
+    let mut headers = HashMap::new();
+    let mut all_of = Vec::new();
+    for name in &record.match_headers {
+        let name = name.to_lowercase();
+        let Some(values) = request.headers().get(&name) else {
+            continue;
+        };
+        match values.as_slice() {
+            [] => {}
+            [value] => {
+                let value = value.clone();
+                let values = Default::default();
+                headers.insert(name, HeaderMatcher::Exact { value, values });
+            }
+            // Repeated headers: sent as often, with every recorded value
+            values => {
+                headers.insert(
+                    name.clone(),
+                    HeaderMatcher::Count {
+                        count: values.len(),
+                    },
+                );
+                all_of.extend(values.iter().map(|value| RequestMatcher {
+                    headers: HashMap::from([(
+                        name.clone(),
+                        HeaderMatcher::Exact {
+                            value: value.clone(),
+                            values: HeaderValueMode::Any,
+                        },
+                    )]),
+                    ..stub.request.clone()
+                }));
+            }
+        }
+    }
+
+    stub.name = Some(format!("Recorded from {}", record.upstream));
+    stub.request = RequestMatcher {
+        method: vec![request.method().to_uppercase()],
+        path: Some(PathMatcher::Exact {
+            value: request.path_only().to_string(),
+        }),
+        headers,
+        all_of,
+        ..stub.request
+    };
+    stub.response = ResponseDefinition {
+        status: response.status_code().into(),
+        headers: response_headers(response),
+        body: response
+            .body()
+            .filter(|b| !b.is_empty())
+            .map(|body| recorded_body(body, response.is_json())),
+        ..Default::default()
+    };
+    stub
+}
+
+/// Upstream's response headers, with every value of repeated ones.
+fn response_headers(response: &Response) -> HashMap<String, HeaderValues> {
+    response
+        .headers()
+        .iter()
+        .filter(|(name, _)| !SKIPPED_RESPONSE_HEADERS.contains(&name.to_lowercase().as_str()))
+        .filter(|(_, values)| !values.is_empty())
+        .map(|(name, values)| (name.clone(), values.clone().into()))
+        .collect()
+}
+
+/// Keep JSON as JSON and text as text, so the recording stays editable.
+fn recorded_body(body: &[u8], is_json: bool) -> ResponseBody {
+    if is_json {
+        if let Ok(content) = serde_json::from_slice(body) {
+            return ResponseBody::Json { content };
+        }
+    }
+    match std::str::from_utf8(body) {
+        Ok(text) => ResponseBody::Text {
+            content: text.to_string(),
+        },
+        Err(_) => {
+            use base64::Engine;
+            ResponseBody::Base64 {
+                content: base64::engine::general_purpose::STANDARD.encode(body),
+            }
+        }
+    }
+}
+
+/// Append a stub to a recording file, creating it if needed.
+pub async fn append(path: &Path, stub: &StubDefinition) -> anyhow::Result<()> {
+    let entry = serde_yaml::to_string(&[stub])?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(entry.as_bytes()).await?;
+    // tokio buffers file writes; make sure the stub is on disk before returning
+    file.flush().await?;
+    Ok(())
+}
+
+/// Load stubs from a recording file.
+pub fn load(path: &Path) -> anyhow::Result<Vec<StubDefinition>> {
+    let content = std::fs::read_to_string(path)?;
+    let stubs: Option<Vec<StubDefinition>> = serde_yaml::from_str(&content)
+        .map_err(|e| anyhow::anyhow!("Invalid recording {:?}: {}", path, e))?;
+    Ok(stubs.unwrap_or_default())
+}
+
+/// Methods recognised when scanning access log lines.
+const LOG_METHODS: &[&str] = &[
+    "GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS", "TRACE", "CONNECT",
+];
+
+/// A starter config with one stub per distinct path in an access log, each
+/// answering 200 to the methods seen for it.
+///
+/// Any line format works as long as the method is followed by the path, so
+/// common/combined log lines (`"GET /users?page=2 HTTP/1.1"`) and bare
+/// `GET /users` lines both do. Query strings are dropped, and lines without
+/// a request are skipped. Stubs are sorted by path.
+pub fn config_from_access_log(log: &str) -> anyhow::Result<String> {
+    let mut paths: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for line in log.lines() {
+        let tokens: Vec<&str> = line
+            .split_whitespace()
+            .map(|token| token.trim_matches('"'))
+            .collect();
+        let request = tokens.windows(2).find(|pair| {
+            LOG_METHODS.contains(&pair[0].to_uppercase().as_str()) && pair[1].starts_with('/')
+        });
+        if let Some(pair) = request {
+            let path = pair[1].split(['?', '#']).next().unwrap_or("/");
+            paths
+                .entry(path.to_string())
+                .or_default()
+                .insert(pair[0].to_uppercase());
+        }
+    }
+
+    let mut ids = BTreeSet::new();
+    let stubs: Vec<serde_json::Value> = paths
+        .into_iter()
+        .map(|(path, methods)| {
+            let id = unique_id(stub_id(&path), &mut ids);
+            serde_json::json!({
+                "id": id,
+                "request": {
+                    "method": methods,
+                    "path": { "type": "exact", "value": path },
+                },
+                "response": { "status": 200 },
+            })
+        })
+        .collect();
+    Ok(serde_yaml::to_string(
+        &serde_json::json!({ "stubs": stubs }),
+    )?)
+}
+
+/// Readable stub ID for a path: `/api/users/5` becomes `api-users-5`.
+fn stub_id(path: &str) -> String {
+    let id = path
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+        .to_lowercase();
+    if id.is_empty() {
+        "root".to_string()
+    } else {
+        id
+    }
+}
+
+/// `id`, or `id-2`, `id-3`... if it's taken.
+fn unique_id(id: String, taken: &mut BTreeSet<String>) -> String {
+    let mut candidate = id.clone();
+    let mut n = 1;
+    while taken.contains(&candidate) {
+        n += 1;
+        candidate = format!("{}-{}", id, n);
+    }
+    taken.insert(candidate.clone());
+    candidate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seed::SeedRequest;
+    use std::path::PathBuf;
+    use zentinel_agent_sdk::ResponseHeadersEvent;
+
+    fn upstream_response(body: &[u8]) -> Response {
+        let event = ResponseHeadersEvent {
+            correlation_id: "test".to_string(),
+            status: 201,
+            headers: HashMap::from([
+                (
+                    "content-type".to_string(),
+                    vec!["application/json".to_string()],
+                ),
+                ("content-length".to_string(), vec![body.len().to_string()]),
+            ]),
+        };
+        Response::from_headers_event(&event).with_body(body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_record_and_load() {
+        let record = RecordConfig {
+            enabled: true,
+            upstream: "http://backend:8080".to_string(),
+            file: PathBuf::new(),
+            match_headers: vec!["X-Tenant".to_string()],
+        };
+        let seeds: Vec<SeedRequest> = serde_yaml::from_str(
+            "- { method: POST, path: /orders?page=2, headers: { X-Tenant: acme, X-Trace: abc } }",
+        )
+        .unwrap();
+        let request = seeds[0].to_request("test");
+
+        let stub = recorded_stub(
+            "recorded-1".to_string(),
+            &request,
+            &upstream_response(br#"{"id": 7}"#),
+            &record,
+        );
+        assert_eq!(stub.request.method, vec!["POST"]);
+        assert!(matches!(
+            &stub.request.path,
+            Some(PathMatcher::Exact { value }) if value == "/orders"
+        ));
+        assert_eq!(stub.request.headers.len(), 1);
+        assert!(matches!(
+            &stub.request.headers["x-tenant"],
+            HeaderMatcher::Exact { value, .. } if value == "acme"
+        ));
+        assert_eq!(stub.response.status, 201);
+        assert!(!stub.response.headers.contains_key("content-length"));
+        assert!(matches!(
+            &stub.response.body,
+            Some(ResponseBody::Json { content }) if content["id"] == 7
+        ));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recorded.yaml");
+        append(&path, &stub).await.unwrap();
+        let mut second = stub.clone();
+        second.id = "recorded-2".to_string();
+        append(&path, &second).await.unwrap();
+
+        let loaded = load(&path).unwrap();
+        let ids: Vec<&str> = loaded.iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(ids, vec!["recorded-1", "recorded-2"]);
+        assert!(loaded[0].validate().is_ok());
+    }
+
+    #[test]
+    fn test_config_from_access_log() {
+        let log = r#"127.0.0.1 - - [10/Oct/2026:13:55:36 +0000] "GET /api/users?page=2 HTTP/1.1" 200 512
+127.0.0.1 - - [10/Oct/2026:13:55:37 +0000] "POST /api/users HTTP/1.1" 201 64
+127.0.0.1 - - [10/Oct/2026:13:55:38 +0000] "GET /api/users HTTP/1.1" 200 512
+GET /
+delete /api/users/5
+GET /api/users-5
+not a request line
+"#;
+        let yaml = config_from_access_log(log).unwrap();
+        let config: crate::config::MockServerConfig = serde_yaml::from_str(&yaml).unwrap();
+        assert!(config.validate().is_ok());
+
+        let stubs: Vec<(&str, &str, Vec<&str>)> = config
+            .stubs
+            .iter()
+            .map(|stub| {
+                let Some(PathMatcher::Exact { value }) = &stub.request.path else {
+                    panic!("expected an exact path");
+                };
+                let methods = stub.request.method.iter().map(String::as_str).collect();
+                (stub.id.as_str(), value.as_str(), methods)
+            })
+            .collect();
+        assert_eq!(
+            stubs,
+            vec![
+                ("root", "/", vec!["GET"]),
+                ("api-users", "/api/users", vec!["GET", "POST"]),
+                ("api-users-5", "/api/users-5", vec!["GET"]),
+                ("api-users-5-2", "/api/users/5", vec!["DELETE"]),
+            ]
+        );
+        assert!(config
+            .stubs
+            .iter()
+            .all(|stub| stub.response.status.fixed() == Some(200)));
+    }
+}
@@ -0,0 +1,188 @@
+//! Interactive stub testing.
+//!
+//! Reads request lines such as `GET /users/5`, sends each through the same
+//! matching and response pipeline as requests from the proxy, and prints
+//! which stub matched and the response it produced. Meant for authoring
+//! configs without a proxy in front.
+
+use crate::agent::MockServerAgent;
+use crate::seed::SeedRequest;
+use std::collections::HashMap;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use zentinel_agent_sdk::ProtocolDecision;
+
+const HELP: &str = "\
+Enter requests as: METHOD PATH [name:value ...] [| BODY]
+  GET /users/5
+  POST /users content-type:application/json | {\"name\": \"Ada\"}
+Type `quit` to leave.
+";
+
+/// Answer request lines from `input` until it ends or `quit` is entered.
+pub async fn run<R, W>(agent: &MockServerAgent, input: R, mut output: W) -> anyhow::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    output.write_all(HELP.as_bytes()).await?;
+    let mut lines = input.lines();
+    let mut count = 0;
+    loop {
+        output.write_all(b"> ").await?;
+        output.flush().await?;
+        let Some(line) = lines.next_line().await? else {
+            break;
+        };
+        let line = line.trim();
+        match line {
+            "" => continue,
+            "quit" | "exit" => break,
+            "help" => {
+                output.write_all(HELP.as_bytes()).await?;
+                continue;
+            }
+            _ => {}
+        }
+
+        let text = match parse_line(line) {
+            Ok(seed) => {
+                count += 1;
+                let request = seed.to_request(&format!("repl-{}", count));
+                describe(agent.respond(&request).await)
+            }
+            Err(e) => format!("error: {}\n", e),
+        };
+        output.write_all(text.as_bytes()).await?;
+    }
+    output.write_all(b"\n").await?;
+    output.flush().await?;
+    Ok(())
+}
+
+/// Parse `METHOD PATH [name:value ...] [| BODY]`.
+fn parse_line(line: &str) -> anyhow::Result<SeedRequest> {
+    let (head, body) = match line.split_once('|') {
+        Some((head, body)) => (head, Some(body.trim().to_string())),
+        None => (line, None),
+    };
+    let mut tokens = head.split_whitespace();
+    let (Some(method), Some(path)) = (tokens.next(), tokens.next()) else {
+        anyhow::bail!("expected METHOD PATH, e.g. `GET /users/5`");
+    };
+    if !path.starts_with('/') {
+        anyhow::bail!("path must start with '/': {}", path);
+    }
+
+    let mut headers = HashMap::new();
+    for token in tokens {
+        let Some((name, value)) = token.split_once(':') else {
+            anyhow::bail!("expected a header as name:value, got '{}'", token);
+        };
+        headers.insert(name.to_string(), value.to_string());
+    }
+
+    Ok(SeedRequest {
+        method: method.to_uppercase(),
+        path: path.to_string(),
+        headers,
+        body,
+    })
+}
+
+/// The matched stub and the response, as printed after each request.
+fn describe(response: zentinel_agent_sdk::AgentResponse) -> String {
+    let stub = response
+        .audit
+        .custom
+        .get("stub_id")
+        .and_then(|id| id.as_str())
+        .unwrap_or("(none)");
+    let mut text = format!("stub: {}\n", stub);
+
+    match response.decision {
+        ProtocolDecision::Block {
+            status,
+            body,
+            headers,
+        } => {
+            text.push_str(&format!("status: {}\n", status));
+            let mut headers: Vec<_> = headers.unwrap_or_default().into_iter().collect();
+            headers.sort();
+            for (name, value) in headers {
+                text.push_str(&format!("{}: {}\n", name, value));
+            }
+            if let Some(body) = body.filter(|body| !body.is_empty()) {
+                text.push('\n');
+                text.push_str(&body);
+                text.push('\n');
+            }
+        }
+        ProtocolDecision::Redirect { url, status } => {
+            text.push_str(&format!("status: {}\nLocation: {}\n", status, url));
+        }
+        _ => text.push_str("passed through to upstream\n"),
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MockServerConfig;
+
+    #[tokio::test]
+    async fn test_scripted_session() {
+        let config: MockServerConfig = serde_yaml::from_str(
+            r#"
+stubs:
+  - id: get-user
+    request:
+      method: [GET]
+      path: { type: template, template: "/users/{id}" }
+    response:
+      template: true
+      headers: { X-User: "{{path.id}}" }
+      body: { type: json, content: { id: "{{path.id}}" } }
+  - id: create-user
+    request:
+      method: [POST]
+      path: { type: exact, value: /users }
+      headers:
+        content-type: { type: contains, value: json }
+      body: { type: contains, value: Ada }
+    response: { status: 201 }
+"#,
+        )
+        .unwrap();
+        let agent = MockServerAgent::new(config);
+        let input = b"GET /users/5\n\
+            \n\
+            post /users content-type:application/json | {\"name\": \"Ada\"}\n\
+            DELETE /nothing\n\
+            users\n\
+            quit\n\
+            GET /users/6\n";
+        let mut output = Vec::new();
+        run(&agent, &input[..], &mut output).await.unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        let expected = "\
+> stub: get-user
+status: 200
+Content-Type: application/json
+X-User: 5
+
+{\"id\":\"5\"}
+> > stub: create-user
+status: 201
+Content-Type: application/json
+> stub: (none)
+status: 404
+";
+        assert!(output.contains(expected), "unexpected output:\n{}", output);
+        assert!(output.contains("error: expected METHOD PATH"));
+        // Nothing after `quit` is answered
+        assert!(!output.contains("X-User: 6"));
+        assert_eq!(agent.match_count("get-user").await, Some(1));
+    }
+}
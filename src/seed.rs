@@ -0,0 +1,105 @@
+//! Synthetic request seeding.
+//!
+//! Loads a list of requests from a file and converts them into SDK requests
+//! so they can be replayed through the matcher before the agent starts serving.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use zentinel_agent_sdk::{Request, RequestHeadersEvent, RequestMetadata};
+
+/// A synthetic request used to pre-warm match counts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SeedRequest {
+    /// HTTP method
+    #[serde(default = "default_method")]
+    pub method: String,
+
+    /// Request path, optionally including a query string
+    pub path: String,
+
+    /// Request headers
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+
+    /// Request body
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+impl SeedRequest {
+    /// Load seed requests from a YAML or JSON file.
+    pub fn load_file(path: &Path) -> anyhow::Result<Vec<Self>> {
+        let content = std::fs::read_to_string(path)?;
+        let requests: Vec<Self> = serde_yaml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Invalid seed requests file {:?}: {}", path, e))?;
+        Ok(requests)
+    }
+
+    /// Convert into an SDK request.
+    pub fn to_request(&self, correlation_id: &str) -> Request {
+        let headers = self
+            .headers
+            .iter()
+            .map(|(k, v)| (k.to_lowercase(), vec![v.clone()]))
+            .collect();
+
+        let event = RequestHeadersEvent {
+            metadata: RequestMetadata {
+                correlation_id: correlation_id.to_string(),
+                request_id: correlation_id.to_string(),
+                client_ip: "127.0.0.1".to_string(),
+                client_port: 0,
+                server_name: None,
+                protocol: "HTTP/1.1".to_string(),
+                tls_version: None,
+                tls_cipher: None,
+                route_id: None,
+                upstream_id: None,
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                traceparent: None,
+            },
+            method: self.method.to_uppercase(),
+            uri: self.path.clone(),
+            headers,
+        };
+
+        let request = Request::from_headers_event(&event);
+        match &self.body {
+            Some(body) => request.with_body(body.clone().into_bytes()),
+            None => request,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_seed_requests() {
+        let yaml = r#"
+- path: /hello
+- method: post
+  path: /users?role=admin
+  headers:
+    Content-Type: application/json
+  body: '{"name": "John"}'
+"#;
+        let seeds: Vec<SeedRequest> = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(seeds.len(), 2);
+        assert_eq!(seeds[0].method, "GET");
+
+        let request = seeds[1].to_request("seed-1");
+        assert_eq!(request.method(), "POST");
+        assert_eq!(request.path_only(), "/users");
+        assert_eq!(request.query_string(), Some("role=admin"));
+        assert_eq!(request.header("content-type"), Some("application/json"));
+        assert_eq!(request.body_str(), Some(r#"{"name": "John"}"#));
+    }
+}
@@ -0,0 +1,123 @@
+//! Per-session state.
+//!
+//! Sessions are keyed by whatever `settings.session_key` reads from the
+//! request, so clients choose how many there are. The state kept for them
+//! is bounded: past [`MAX_SESSIONS`], the least recently used session is
+//! forgotten.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Number of sessions whose state is kept.
+pub const MAX_SESSIONS: usize = 10_000;
+
+/// Bounded map of per-session values.
+#[derive(Debug)]
+pub struct Sessions<V> {
+    inner: Mutex<Inner<V>>,
+    capacity: usize,
+}
+
+#[derive(Debug)]
+struct Inner<V> {
+    /// Value and last use of each session
+    entries: HashMap<String, (V, u64)>,
+    /// Incremented on every use, to order sessions by recency
+    clock: u64,
+}
+
+impl<V: Clone + Default> Sessions<V> {
+    /// Create an empty map holding up to [`MAX_SESSIONS`] sessions.
+    pub fn new() -> Self {
+        Self::with_capacity(MAX_SESSIONS)
+    }
+
+    /// Create an empty map holding up to `capacity` sessions.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                clock: 0,
+            }),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Value of a session, if it has one.
+    pub fn get(&self, session: &str) -> Option<V> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.clock += 1;
+        let now = inner.clock;
+        let (value, used) = inner.entries.get_mut(session)?;
+        *used = now;
+        Some(value.clone())
+    }
+
+    /// Change a session's value, starting from the default for new
+    /// sessions.
+    pub fn update(&self, session: &str, change: impl FnOnce(&mut V)) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.clock += 1;
+        let now = inner.clock;
+        if !inner.entries.contains_key(session) && inner.entries.len() >= self.capacity {
+            let oldest = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(session, _)| session.clone());
+            if let Some(oldest) = oldest {
+                inner.entries.remove(&oldest);
+            }
+        }
+        let (value, used) = inner
+            .entries
+            .entry(session.to_string())
+            .or_insert_with(|| (V::default(), now));
+        change(value);
+        *used = now;
+    }
+
+    /// Forget every session.
+    pub fn clear(&self) {
+        self.inner
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entries
+            .clear();
+    }
+}
+
+impl<V: Clone + Default> Default for Sessions<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_and_get() {
+        let sessions: Sessions<u16> = Sessions::new();
+        assert_eq!(sessions.get("a"), None);
+        sessions.update("a", |status| *status = 503);
+        assert_eq!(sessions.get("a"), Some(503));
+        sessions.clear();
+        assert_eq!(sessions.get("a"), None);
+    }
+
+    #[test]
+    fn test_least_recently_used_is_forgotten() {
+        let sessions: Sessions<u16> = Sessions::with_capacity(2);
+        sessions.update("a", |status| *status = 1);
+        sessions.update("b", |status| *status = 2);
+        // Using `a` makes `b` the oldest
+        assert_eq!(sessions.get("a"), Some(1));
+        sessions.update("c", |status| *status = 3);
+
+        assert_eq!(sessions.get("a"), Some(1));
+        assert_eq!(sessions.get("b"), None);
+        assert_eq!(sessions.get("c"), Some(3));
+    }
+}
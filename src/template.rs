@@ -2,14 +2,30 @@
 //!
 //! Uses Handlebars for template rendering with request context.
 
-use crate::matcher::MatchContext;
+use crate::matcher::{BasicAuth, GraphQlRequest, MatchContext};
 use handlebars::Handlebars;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use serde::Serialize;
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Random number generator shared by helpers that need reproducible output.
+type SharedRng = Arc<Mutex<StdRng>>;
 
 /// Template engine for rendering dynamic responses.
 pub struct TemplateEngine {
+    /// Locked so partials and precompiled templates can be swapped on reload
+    registry: RwLock<Registry>,
+    /// Constants from `settings.template_vars`, exposed as `vars`
+    vars: RwLock<HashMap<String, serde_json::Value>>,
+}
+
+/// Handlebars with the partials and precompiled stub templates it holds.
+struct Registry {
     handlebars: Handlebars<'static>,
+    /// Registered name of each precompiled template, by its source
+    compiled: HashMap<String, String>,
 }
 
 /// Context for template rendering.
@@ -19,14 +35,41 @@ pub struct TemplateContext {
     pub path: HashMap<String, String>,
     /// Query parameters
     pub query: HashMap<String, String>,
+    /// Every value of every query parameter (`query` has the first only)
+    pub query_all: HashMap<String, Vec<String>>,
     /// Request headers
     pub headers: HashMap<String, String>,
+    /// Every value of every request header (`headers` has the first only)
+    pub headers_all: HashMap<String, Vec<String>>,
+    /// Raw query string, without the leading `?`
+    pub raw_query: String,
+    /// Cookies from the `Cookie` header
+    pub cookies: HashMap<String, String>,
+    /// Text fields of a multipart body matched by `type: multipart`
+    pub form: HashMap<String, String>,
+    /// GraphQL query, operation name and variables matched by `type: graphql`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gql: Option<GraphQlRequest>,
+    /// Claims of a JWT matched by `jwt`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jwt: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Username matched by `basic_auth`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<BasicAuth>,
     /// Regex capture groups
     pub captures: HashMap<String, String>,
+    /// Constants from `settings.template_vars`
+    pub vars: HashMap<String, serde_json::Value>,
     /// Request method
     pub method: String,
     /// Request path
     pub request_path: String,
+    /// Request host, lowercased and without its port
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Client address (from a `client_ip` matcher's header, or the peer)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<String>,
     /// Request body (as string, if text)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub body: Option<String>,
@@ -35,10 +78,69 @@ pub struct TemplateContext {
     pub json: Option<serde_json::Value>,
 }
 
+impl TemplateContext {
+    fn new(
+        vars: HashMap<String, serde_json::Value>,
+        match_ctx: &MatchContext,
+        method: &str,
+        path: &str,
+        headers: &HashMap<String, String>,
+        body: Option<&[u8]>,
+    ) -> Self {
+        let body_str = body
+            .and_then(|b| std::str::from_utf8(b).ok())
+            .map(String::from);
+        let json_body = body_str.as_ref().and_then(|s| serde_json::from_str(s).ok());
+
+        Self {
+            path: match_ctx.path_params.clone(),
+            query: match_ctx.query_params.clone(),
+            query_all: match_ctx.query_all.clone(),
+            headers: headers.clone(),
+            headers_all: match_ctx.headers_all.clone(),
+            raw_query: match_ctx.raw_query.clone(),
+            cookies: parse_cookies(headers),
+            form: match_ctx.form.clone(),
+            gql: match_ctx.graphql.clone(),
+            jwt: match_ctx.jwt.clone(),
+            auth: match_ctx.auth.clone(),
+            captures: match_ctx.captures.clone(),
+            vars,
+            method: method.to_string(),
+            request_path: path.to_string(),
+            host: match_ctx.host.clone(),
+            client_ip: match_ctx.client_ip.clone(),
+            body: body_str,
+            json: json_body,
+        }
+    }
+}
+
+/// Parse `name=value` pairs out of the `Cookie` header.
+fn parse_cookies(headers: &HashMap<String, String>) -> HashMap<String, String> {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("cookie"))
+        .flat_map(|(_, value)| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
 impl TemplateEngine {
     /// Create a new template engine.
     pub fn new() -> Self {
+        Self::with_rng(StdRng::from_entropy())
+    }
+
+    /// Create a template engine whose random helpers are seeded for reproducible output.
+    pub fn with_seed(seed: u64) -> Self {
+        Self::with_rng(StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(rng: StdRng) -> Self {
         let mut handlebars = Handlebars::new();
+        let rng: SharedRng = Arc::new(Mutex::new(rng));
 
         // Register custom helpers
         handlebars.register_helper("json", Box::new(json_helper));
@@ -48,11 +150,83 @@ impl TemplateEngine {
         handlebars.register_helper("default", Box::new(default_helper));
         handlebars.register_helper("upper", Box::new(upper_helper));
         handlebars.register_helper("lower", Box::new(lower_helper));
+        handlebars.register_helper("replace", Box::new(replace_helper));
+        handlebars.register_helper("min", Box::new(min_helper));
+        handlebars.register_helper("max", Box::new(max_helper));
+        handlebars.register_helper("clamp", Box::new(clamp_helper));
+        handlebars.register_helper("add", Box::new(add_helper));
+        handlebars.register_helper("sub", Box::new(sub_helper));
+        handlebars.register_helper("mul", Box::new(mul_helper));
+        handlebars.register_helper("div", Box::new(div_helper));
+        handlebars.register_helper("concat", Box::new(concat_helper));
+        handlebars.register_helper("substr", Box::new(substr_helper));
+        handlebars.register_helper("len", Box::new(len_helper));
+        handlebars.register_helper("base64enc", Box::new(base64enc_helper));
+        handlebars.register_helper("base64dec", Box::new(base64dec_helper));
+        handlebars.register_helper("md5", Box::new(md5_helper));
+        handlebars.register_helper("sha256", Box::new(sha256_helper));
+        handlebars.register_helper("hmac_sha256", Box::new(hmac_sha256_helper));
+        handlebars.register_helper("weighted", Box::new(WeightedHelper { rng: rng.clone() }));
+        handlebars.register_helper("fake", Box::new(FakeHelper { rng }));
 
         // Don't escape HTML by default (we're not rendering HTML)
         handlebars.register_escape_fn(handlebars::no_escape);
 
-        Self { handlebars }
+        Self {
+            registry: RwLock::new(Registry {
+                handlebars,
+                compiled: HashMap::new(),
+            }),
+            vars: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Replace the constants templates see as `vars`.
+    pub fn set_vars(&self, vars: HashMap<String, serde_json::Value>) {
+        *self.vars.write().unwrap_or_else(|e| e.into_inner()) = vars;
+    }
+
+    /// Replace the partials templates can include as `{{> name}}`, and
+    /// precompile `templates` so rendering them skips parsing.
+    ///
+    /// A template that doesn't compile is left to fail when it's rendered.
+    pub fn set_templates<'a>(
+        &self,
+        partials: &HashMap<String, String>,
+        templates: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), handlebars::TemplateError> {
+        let mut registry = self.registry.write().unwrap_or_else(|e| e.into_inner());
+        registry.handlebars.clear_templates();
+        registry.compiled.clear();
+        for (name, source) in partials {
+            registry.handlebars.register_partial(name, source)?;
+        }
+        for source in templates {
+            if registry.compiled.contains_key(source) {
+                continue;
+            }
+            let name = format!("__stub_template_{}", registry.compiled.len());
+            if registry
+                .handlebars
+                .register_template_string(&name, source)
+                .is_ok()
+            {
+                registry.compiled.insert(source.to_string(), name);
+            }
+        }
+        Ok(())
+    }
+
+    fn context(
+        &self,
+        match_ctx: &MatchContext,
+        method: &str,
+        path: &str,
+        headers: &HashMap<String, String>,
+        body: Option<&[u8]>,
+    ) -> TemplateContext {
+        let vars = self.vars.read().unwrap_or_else(|e| e.into_inner()).clone();
+        TemplateContext::new(vars, match_ctx, method, path, headers, body)
     }
 
     /// Render a template string with the given context.
@@ -65,23 +239,8 @@ impl TemplateEngine {
         headers: &HashMap<String, String>,
         body: Option<&[u8]>,
     ) -> Result<String, handlebars::RenderError> {
-        let body_str = body
-            .and_then(|b| std::str::from_utf8(b).ok())
-            .map(String::from);
-        let json_body = body_str.as_ref().and_then(|s| serde_json::from_str(s).ok());
-
-        let ctx = TemplateContext {
-            path: match_ctx.path_params.clone(),
-            query: match_ctx.query_params.clone(),
-            headers: headers.clone(),
-            captures: match_ctx.captures.clone(),
-            method: method.to_string(),
-            request_path: path.to_string(),
-            body: body_str,
-            json: json_body,
-        };
-
-        self.handlebars.render_template(template, &ctx)
+        let ctx = self.context(match_ctx, method, path, headers, body);
+        self.render_with(template, &ctx)
     }
 
     /// Render a JSON value with templates in string fields.
@@ -94,25 +253,23 @@ impl TemplateEngine {
         headers: &HashMap<String, String>,
         body: Option<&[u8]>,
     ) -> Result<serde_json::Value, handlebars::RenderError> {
-        let body_str = body
-            .and_then(|b| std::str::from_utf8(b).ok())
-            .map(String::from);
-        let json_body = body_str.as_ref().and_then(|s| serde_json::from_str(s).ok());
-
-        let ctx = TemplateContext {
-            path: match_ctx.path_params.clone(),
-            query: match_ctx.query_params.clone(),
-            headers: headers.clone(),
-            captures: match_ctx.captures.clone(),
-            method: method.to_string(),
-            request_path: path.to_string(),
-            body: body_str,
-            json: json_body,
-        };
-
+        let ctx = self.context(match_ctx, method, path, headers, body);
         self.render_json_value(json, &ctx)
     }
 
+    /// Render a precompiled template, or parse `template` if it isn't one.
+    fn render_with(
+        &self,
+        template: &str,
+        ctx: &TemplateContext,
+    ) -> Result<String, handlebars::RenderError> {
+        let registry = self.registry.read().unwrap_or_else(|e| e.into_inner());
+        match registry.compiled.get(template) {
+            Some(name) => registry.handlebars.render(name, ctx),
+            None => registry.handlebars.render_template(template, ctx),
+        }
+    }
+
     fn render_json_value(
         &self,
         value: &serde_json::Value,
@@ -122,7 +279,7 @@ impl TemplateEngine {
             serde_json::Value::String(s) => {
                 // Check if it contains template syntax
                 if s.contains("{{") {
-                    let rendered = self.handlebars.render_template(s, ctx)?;
+                    let rendered = self.render_with(s, ctx)?;
                     Ok(serde_json::Value::String(rendered))
                 } else {
                     Ok(value.clone())
@@ -177,18 +334,20 @@ fn uuid_helper(
     _: &mut handlebars::RenderContext,
     out: &mut dyn handlebars::Output,
 ) -> handlebars::HelperResult {
-    use rand::Rng;
-    let mut rng = rand::thread_rng();
-    let uuid = format!(
+    out.write(&random_uuid(&mut rand::thread_rng()))?;
+    Ok(())
+}
+
+/// Format a random version 4 UUID.
+fn random_uuid<R: rand::Rng>(rng: &mut R) -> String {
+    format!(
         "{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}",
         rng.gen::<u32>(),
         rng.gen::<u16>(),
         rng.gen::<u16>() & 0x0fff,
         (rng.gen::<u16>() & 0x3fff) | 0x8000,
         rng.gen::<u64>() & 0xffffffffffff,
-    );
-    out.write(&uuid)?;
-    Ok(())
+    )
 }
 
 fn now_helper(
@@ -204,12 +363,42 @@ fn now_helper(
         .param(0)
         .and_then(|v| v.value().as_str())
         .unwrap_or("%Y-%m-%dT%H:%M:%S%.3fZ");
-
     let now = Utc::now();
+    let now = match h.param(1).and_then(|v| v.value().as_str()) {
+        // Offsets can come from the request, so stay within chrono's range
+        Some(offset) => parse_offset(offset)
+            .and_then(|offset| now.checked_add_signed(offset))
+            .unwrap_or_else(|| {
+                tracing::warn!(offset = %offset, "Invalid now offset, ignoring it");
+                now
+            }),
+        None => now,
+    };
+
     out.write(&now.format(format).to_string())?;
     Ok(())
 }
 
+/// Parse an offset like `+1d`, `-2h`, `30m` or `+45s` (`w` for weeks too).
+fn parse_offset(offset: &str) -> Option<chrono::TimeDelta> {
+    let offset = offset.trim();
+    let (sign, rest) = match offset.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, offset.strip_prefix('+').unwrap_or(offset)),
+    };
+    let unit = rest.chars().last()?;
+    let amount: i64 = rest[..rest.len() - unit.len_utf8()].parse().ok()?;
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    chrono::TimeDelta::try_seconds(amount.checked_mul(seconds)?.checked_mul(sign)?)
+}
+
 fn random_helper(
     h: &handlebars::Helper,
     _: &Handlebars,
@@ -281,6 +470,430 @@ fn lower_helper(
     Ok(())
 }
 
+/// Replace every match of a regex; an invalid pattern leaves the input as is.
+fn replace_helper(
+    h: &handlebars::Helper,
+    _: &Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    let value = text_param(h, 0);
+    let pattern = h.param(1).and_then(|v| v.value().as_str()).unwrap_or("");
+    let with = h.param(2).and_then(|v| v.value().as_str()).unwrap_or("");
+
+    match regex::Regex::new(pattern) {
+        Ok(re) => out.write(&re.replace_all(&value, with))?,
+        Err(_) => out.write(&value)?,
+    }
+    Ok(())
+}
+
+/// Read a helper param as text: strings as they are, other values as JSON,
+/// and missing or null values as empty.
+fn text_param(h: &handlebars::Helper, index: usize) -> String {
+    match h.param(index).map(|v| v.value()) {
+        Some(serde_json::Value::String(s)) => s.clone(),
+        Some(serde_json::Value::Null) | None => String::new(),
+        Some(other) => other.to_string(),
+    }
+}
+
+/// Read a helper param as a number, accepting numeric strings (e.g. query params).
+fn numeric_param(h: &handlebars::Helper, index: usize) -> Option<f64> {
+    let value = h.param(index)?.value();
+    value
+        .as_f64()
+        .or_else(|| value.as_str().and_then(|s| s.trim().parse().ok()))
+}
+
+/// Write a number, without a fractional part when it is whole.
+fn write_number(out: &mut dyn handlebars::Output, value: f64) -> handlebars::HelperResult {
+    if value.fract() == 0.0 && value.abs() < i64::MAX as f64 {
+        out.write(&(value as i64).to_string())?;
+    } else {
+        out.write(&value.to_string())?;
+    }
+    Ok(())
+}
+
+fn min_helper(
+    h: &handlebars::Helper,
+    _: &Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    match (numeric_param(h, 0), numeric_param(h, 1)) {
+        (Some(a), Some(b)) => write_number(out, a.min(b)),
+        _ => Ok(()),
+    }
+}
+
+fn max_helper(
+    h: &handlebars::Helper,
+    _: &Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    match (numeric_param(h, 0), numeric_param(h, 1)) {
+        (Some(a), Some(b)) => write_number(out, a.max(b)),
+        _ => Ok(()),
+    }
+}
+
+/// Apply `op` to the first two params as numbers; non-numbers and a `None`
+/// result render empty.
+fn arithmetic(
+    h: &handlebars::Helper,
+    out: &mut dyn handlebars::Output,
+    op: fn(f64, f64) -> Option<f64>,
+) -> handlebars::HelperResult {
+    match (numeric_param(h, 0), numeric_param(h, 1)) {
+        (Some(a), Some(b)) => match op(a, b) {
+            Some(value) => write_number(out, value),
+            None => Ok(()),
+        },
+        _ => Ok(()),
+    }
+}
+
+fn add_helper(
+    h: &handlebars::Helper,
+    _: &Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    arithmetic(h, out, |a, b| Some(a + b))
+}
+
+fn sub_helper(
+    h: &handlebars::Helper,
+    _: &Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    arithmetic(h, out, |a, b| Some(a - b))
+}
+
+fn mul_helper(
+    h: &handlebars::Helper,
+    _: &Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    arithmetic(h, out, |a, b| Some(a * b))
+}
+
+/// Divide; division by zero renders empty.
+fn div_helper(
+    h: &handlebars::Helper,
+    _: &Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    arithmetic(h, out, |a, b| (b != 0.0).then(|| a / b))
+}
+
+fn concat_helper(
+    h: &handlebars::Helper,
+    _: &Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    for index in 0..h.params().len() {
+        out.write(&text_param(h, index))?;
+    }
+    Ok(())
+}
+
+/// Characters from `start`, at most `len` of them (default: the rest).
+fn substr_helper(
+    h: &handlebars::Helper,
+    _: &Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    let value = text_param(h, 0);
+    let start = numeric_param(h, 1).unwrap_or(0.0).max(0.0) as usize;
+    let len = numeric_param(h, 2).map_or(usize::MAX, |len| len.max(0.0) as usize);
+    let substring: String = value.chars().skip(start).take(len).collect();
+    out.write(&substring)?;
+    Ok(())
+}
+
+/// Characters in a string, or entries in an array or object.
+fn len_helper(
+    h: &handlebars::Helper,
+    _: &Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    let len = match h.param(0).map(|v| v.value()) {
+        Some(serde_json::Value::String(s)) => s.chars().count(),
+        Some(serde_json::Value::Array(items)) => items.len(),
+        Some(serde_json::Value::Object(map)) => map.len(),
+        _ => return Ok(()),
+    };
+    out.write(&len.to_string())?;
+    Ok(())
+}
+
+fn base64enc_helper(
+    h: &handlebars::Helper,
+    _: &Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    use base64::Engine;
+    out.write(&base64::engine::general_purpose::STANDARD.encode(text_param(h, 0)))?;
+    Ok(())
+}
+
+/// Decode standard base64; invalid input renders empty.
+fn base64dec_helper(
+    h: &handlebars::Helper,
+    _: &Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    use base64::Engine;
+    if let Ok(decoded) = base64::engine::general_purpose::STANDARD.decode(text_param(h, 0).trim()) {
+        out.write(&String::from_utf8_lossy(&decoded))?;
+    }
+    Ok(())
+}
+
+fn md5_helper(
+    h: &handlebars::Helper,
+    _: &Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    use md5::Digest;
+    out.write(&to_hex(&md5::Md5::digest(text_param(h, 0))))?;
+    Ok(())
+}
+
+fn sha256_helper(
+    h: &handlebars::Helper,
+    _: &Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    let digest = ring::digest::digest(&ring::digest::SHA256, text_param(h, 0).as_bytes());
+    out.write(&to_hex(digest.as_ref()))?;
+    Ok(())
+}
+
+/// HMAC-SHA256 of the second param keyed by the first, as hex.
+fn hmac_sha256_helper(
+    h: &handlebars::Helper,
+    _: &Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, text_param(h, 0).as_bytes());
+    let tag = ring::hmac::sign(&key, text_param(h, 1).as_bytes());
+    out.write(&to_hex(tag.as_ref()))?;
+    Ok(())
+}
+
+/// Lowercase hex of a digest.
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn clamp_helper(
+    h: &handlebars::Helper,
+    _: &Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    match (
+        numeric_param(h, 0),
+        numeric_param(h, 1),
+        numeric_param(h, 2),
+    ) {
+        (Some(value), Some(lo), Some(hi)) if lo <= hi => write_number(out, value.clamp(lo, hi)),
+        _ => Ok(()),
+    }
+}
+
+/// Picks one of `value:weight` params, e.g. `{{weighted "active:70" "banned:5"}}`.
+struct WeightedHelper {
+    rng: SharedRng,
+}
+
+impl handlebars::HelperDef for WeightedHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &handlebars::Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc handlebars::Context,
+        _: &mut handlebars::RenderContext<'reg, 'rc>,
+        out: &mut dyn handlebars::Output,
+    ) -> handlebars::HelperResult {
+        use rand::Rng;
+
+        // Split on the last colon so values may contain colons themselves
+        let choices: Vec<(&str, f64)> = h
+            .params()
+            .iter()
+            .filter_map(|p| p.value().as_str())
+            .filter_map(|p| p.rsplit_once(':'))
+            .filter_map(|(value, weight)| weight.trim().parse::<f64>().ok().map(|w| (value, w)))
+            .filter(|(_, weight)| *weight > 0.0)
+            .collect();
+
+        let total: f64 = choices.iter().map(|(_, w)| w).sum();
+        if total <= 0.0 {
+            return Ok(());
+        }
+
+        let mut roll = {
+            let mut rng = self.rng.lock().unwrap_or_else(|e| e.into_inner());
+            rng.gen_range(0.0..total)
+        };
+        for (value, weight) in &choices {
+            if roll < *weight {
+                out.write(value)?;
+                return Ok(());
+            }
+            roll -= weight;
+        }
+
+        // Floating point leftovers land on the last choice
+        if let Some((value, _)) = choices.last() {
+            out.write(value)?;
+        }
+        Ok(())
+    }
+}
+
+const FIRST_NAMES: &[&str] = &[
+    "Alice", "Bob", "Carol", "David", "Emma", "Frank", "Grace", "Henry", "Isla", "Jack", "Karen",
+    "Liam", "Maya", "Noah", "Olivia", "Peter", "Quinn", "Rosa", "Sam", "Tara",
+];
+
+const LAST_NAMES: &[&str] = &[
+    "Anderson", "Brown", "Chen", "Diaz", "Evans", "Fischer", "Garcia", "Hughes", "Ivanova",
+    "Johnson", "Kim", "Lopez", "Miller", "Nguyen", "Okafor", "Patel", "Rossi", "Smith", "Tanaka",
+    "Walsh",
+];
+
+const COMPANY_WORDS: &[&str] = &[
+    "Acme", "Blue", "Summit", "North", "Bright", "Iron", "Silver", "Pioneer", "Quantum", "Harbor",
+];
+
+const COMPANY_SUFFIXES: &[&str] = &["Inc", "LLC", "Labs", "Systems", "Group", "Industries"];
+
+const EMAIL_DOMAINS: &[&str] = &["example.com", "example.org", "example.net", "mail.test"];
+
+const WORDS: &[&str] = &[
+    "lorem",
+    "ipsum",
+    "dolor",
+    "sit",
+    "amet",
+    "consectetur",
+    "adipiscing",
+    "elit",
+    "sed",
+    "do",
+    "eiusmod",
+    "tempor",
+    "incididunt",
+    "ut",
+    "labore",
+    "et",
+    "dolore",
+    "magna",
+    "aliqua",
+    "enim",
+];
+
+/// Generates plausible fake data by category, e.g. `{{fake "email"}}`.
+///
+/// Categories are case-insensitive; unknown ones render as an empty string.
+struct FakeHelper {
+    rng: SharedRng,
+}
+
+impl FakeHelper {
+    fn generate(&self, category: &str) -> String {
+        use rand::seq::SliceRandom;
+        use rand::Rng;
+
+        let mut rng = self.rng.lock().unwrap_or_else(|e| e.into_inner());
+        let rng = &mut *rng;
+        let pick = |rng: &mut StdRng, words: &[&'static str]| *words.choose(rng).unwrap();
+
+        match category.to_lowercase().as_str() {
+            "name" => format!("{} {}", pick(rng, FIRST_NAMES), pick(rng, LAST_NAMES)),
+            "first_name" => pick(rng, FIRST_NAMES).to_string(),
+            "last_name" => pick(rng, LAST_NAMES).to_string(),
+            "email" => format!(
+                "{}.{}@{}",
+                pick(rng, FIRST_NAMES).to_lowercase(),
+                pick(rng, LAST_NAMES).to_lowercase(),
+                pick(rng, EMAIL_DOMAINS)
+            ),
+            "uuid" => random_uuid(rng),
+            "ipv4" => format!(
+                "{}.{}.{}.{}",
+                rng.gen_range(1..=223),
+                rng.gen_range(0..=255),
+                rng.gen_range(0..=255),
+                rng.gen_range(1..=254)
+            ),
+            "company" => format!(
+                "{} {}",
+                pick(rng, COMPANY_WORDS),
+                pick(rng, COMPANY_SUFFIXES)
+            ),
+            "sentence" => {
+                let len = rng.gen_range(4..=10);
+                let words: Vec<&str> = (0..len).map(|_| pick(rng, WORDS)).collect();
+                let mut sentence = words.join(" ");
+                sentence[..1].make_ascii_uppercase();
+                sentence.push('.');
+                sentence
+            }
+            _ => String::new(),
+        }
+    }
+}
+
+impl handlebars::HelperDef for FakeHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &handlebars::Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc handlebars::Context,
+        _: &mut handlebars::RenderContext<'reg, 'rc>,
+        out: &mut dyn handlebars::Output,
+    ) -> handlebars::HelperResult {
+        let category = h.param(0).and_then(|v| v.value().as_str()).unwrap_or("");
+        out.write(&self.generate(category))?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -305,6 +918,34 @@ mod tests {
         assert_eq!(result, "User ID: 123");
     }
 
+    #[test]
+    fn test_precompiled_templates() {
+        let engine = TemplateEngine::new();
+        let partials = HashMap::from([("who".to_string(), "user {{path.id}}".to_string())]);
+        engine
+            .set_templates(&partials, ["Hello {{> who}}", "{{#if}}", "Hello {{> who}}"])
+            .unwrap();
+        {
+            let registry = engine.registry.read().unwrap();
+            // Duplicates share one entry, and broken templates aren't kept
+            assert_eq!(registry.compiled.len(), 1);
+            assert!(registry.compiled.contains_key("Hello {{> who}}"));
+        }
+
+        let mut ctx = MatchContext::default();
+        ctx.path_params.insert("id".to_string(), "7".to_string());
+        let render = |template| engine.render(template, &ctx, "GET", "/", &HashMap::new(), None);
+        assert_eq!(render("Hello {{> who}}").unwrap(), "Hello user 7");
+        // Anything not precompiled is still parsed on the fly
+        assert_eq!(render("Bye {{path.id}}").unwrap(), "Bye 7");
+        assert!(render("{{#if}}").is_err());
+
+        // Replacing the set drops the old templates
+        engine.set_templates(&HashMap::new(), []).unwrap();
+        assert!(engine.registry.read().unwrap().compiled.is_empty());
+        assert!(render("Hello {{> who}}").is_err());
+    }
+
     #[test]
     fn test_query_params() {
         let engine = TemplateEngine::new();
@@ -323,6 +964,20 @@ mod tests {
             .unwrap();
 
         assert_eq!(result, "Page: 1");
+
+        ctx.query_all
+            .insert("tag".to_string(), vec!["a".to_string(), "b".to_string()]);
+        let result = engine
+            .render(
+                "{{#each query_all.tag}}{{this}};{{/each}}",
+                &ctx,
+                "GET",
+                "/list",
+                &HashMap::new(),
+                None,
+            )
+            .unwrap();
+        assert_eq!(result, "a;b;");
     }
 
     #[test]
@@ -346,6 +1001,60 @@ mod tests {
         assert_eq!(result, "Client: test-client");
     }
 
+    #[test]
+    fn test_headers_all() {
+        let engine = TemplateEngine::new();
+        let ctx = MatchContext {
+            headers_all: HashMap::from([(
+                "x-forwarded-for".to_string(),
+                vec!["10.0.0.1".to_string(), "10.0.0.2".to_string()],
+            )]),
+            ..Default::default()
+        };
+        let headers = HashMap::from([("x-forwarded-for".to_string(), "10.0.0.1".to_string())]);
+
+        let result = engine
+            .render(
+                "{{headers.x-forwarded-for}} {{#each headers_all.x-forwarded-for}}[{{this}}]{{/each}}",
+                &ctx,
+                "GET",
+                "/",
+                &headers,
+                None,
+            )
+            .unwrap();
+        assert_eq!(result, "10.0.0.1 [10.0.0.1][10.0.0.2]");
+    }
+
+    #[test]
+    fn test_raw_query_and_cookies() {
+        let engine = TemplateEngine::new();
+        let ctx = MatchContext {
+            raw_query: "a=1&b=%20".to_string(),
+            ..Default::default()
+        };
+        let headers =
+            HashMap::from([("cookie".to_string(), "session=abc; user=alice".to_string())]);
+
+        let result = engine
+            .render(
+                "{{raw_query}} {{cookies.user}}{{cookies.missing}}",
+                &ctx,
+                "GET",
+                "/",
+                &headers,
+                None,
+            )
+            .unwrap();
+        assert_eq!(result, "a=1&b=%20 alice");
+
+        let json = serde_json::json!({ "session": "{{cookies.session}}" });
+        let result = engine
+            .render_json(&json, &ctx, "GET", "/", &headers, None)
+            .unwrap();
+        assert_eq!(result["session"], "abc");
+    }
+
     #[test]
     fn test_request_body() {
         let engine = TemplateEngine::new();
@@ -401,6 +1110,36 @@ mod tests {
         assert_eq!(result, "Value: default_value");
     }
 
+    #[test]
+    fn test_now_helper_offsets() {
+        let engine = TemplateEngine::new();
+        let ctx = MatchContext::default();
+        let epoch_after = |offset: &str| {
+            let template = format!(r#"{{{{now "%s" "{}"}}}}"#, offset);
+            let rendered: i64 = engine
+                .render(&template, &ctx, "GET", "/", &HashMap::new(), None)
+                .unwrap()
+                .parse()
+                .unwrap();
+            rendered - chrono::Utc::now().timestamp()
+        };
+
+        assert!((86_399..=86_400).contains(&epoch_after("+1d")));
+        assert!((-7_200..=-7_199).contains(&epoch_after("-2h")));
+        assert!((1_799..=1_800).contains(&epoch_after("30m")));
+        assert!((44..=45).contains(&epoch_after("+45s")));
+        // Unparseable offsets count as zero
+        assert!((-1..=0).contains(&epoch_after("+1y")));
+        assert!((-1..=0).contains(&epoch_after("soon")));
+        // So do offsets that parse but leave the representable range
+        assert!((-1..=0).contains(&epoch_after("+100000000d")));
+        assert!((-1..=0).contains(&epoch_after("-100000000d")));
+
+        assert_eq!(parse_offset("+2w"), chrono::TimeDelta::try_days(14));
+        assert_eq!(parse_offset("-"), None);
+        assert_eq!(parse_offset("+d"), None);
+    }
+
     #[test]
     fn test_upper_lower_helpers() {
         let engine = TemplateEngine::new();
@@ -422,6 +1161,164 @@ mod tests {
         assert_eq!(result, "Upper: JOHN, Lower: john");
     }
 
+    #[test]
+    fn test_replace_helper() {
+        let engine = TemplateEngine::new();
+        let mut ctx = MatchContext::default();
+        ctx.path_params
+            .insert("card".to_string(), "4111-1111-1111-1234".to_string());
+
+        let render = |template: &str| {
+            engine
+                .render(template, &ctx, "GET", "/", &HashMap::new(), None)
+                .unwrap()
+        };
+
+        assert_eq!(
+            render(r#"{{replace path.card "[0-9]" "X"}}"#),
+            "XXXX-XXXX-XXXX-XXXX"
+        );
+        assert_eq!(
+            render(r#"{{replace path.card "[0-9]+$" "****"}}"#),
+            "4111-1111-1111-****"
+        );
+        // An invalid pattern leaves the value alone
+        assert_eq!(
+            render(r#"{{replace path.card "(" "X"}}"#),
+            "4111-1111-1111-1234"
+        );
+    }
+
+    #[test]
+    fn test_weighted_helper_distribution() {
+        let engine = TemplateEngine::with_seed(42);
+        let ctx = MatchContext::default();
+        let template = r#"{{weighted "active:70" "inactive:25" "banned:5"}}"#;
+
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        let renders = 10_000;
+        for _ in 0..renders {
+            let result = engine
+                .render(template, &ctx, "GET", "/", &HashMap::new(), None)
+                .unwrap();
+            *counts.entry(result).or_default() += 1;
+        }
+
+        assert_eq!(counts.len(), 3);
+        let share = |key: &str| counts[key] as f64 / renders as f64;
+        assert!((share("active") - 0.70).abs() < 0.03);
+        assert!((share("inactive") - 0.25).abs() < 0.03);
+        assert!((share("banned") - 0.05).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_weighted_helper_seeded() {
+        let ctx = MatchContext::default();
+        let template = r#"{{weighted "a:1" "b:1" "c:1"}}"#;
+        let render_many = |engine: &TemplateEngine| -> Vec<String> {
+            (0..20)
+                .map(|_| {
+                    engine
+                        .render(template, &ctx, "GET", "/", &HashMap::new(), None)
+                        .unwrap()
+                })
+                .collect()
+        };
+
+        let first = render_many(&TemplateEngine::with_seed(7));
+        let second = render_many(&TemplateEngine::with_seed(7));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_fake_helper() {
+        let engine = TemplateEngine::new();
+        let render = |template: &str| {
+            engine
+                .render(
+                    template,
+                    &MatchContext::default(),
+                    "GET",
+                    "/",
+                    &HashMap::new(),
+                    None,
+                )
+                .unwrap()
+        };
+
+        let name = render(r#"{{fake "name"}}"#);
+        assert_eq!(name.split(' ').count(), 2);
+
+        let email = render(r#"{{fake "EMAIL"}}"#);
+        assert!(email.contains('@') && email.contains('.'));
+
+        let ip = render(r#"{{fake "ipv4"}}"#);
+        assert_eq!(ip.split('.').filter(|o| o.parse::<u8>().is_ok()).count(), 4);
+
+        assert_eq!(render(r#"{{fake "uuid"}}"#).len(), 36);
+        assert!(!render(r#"{{fake "Company"}}"#).is_empty());
+        assert!(render(r#"{{fake "sentence"}}"#).ends_with('.'));
+        assert_eq!(render(r#"{{fake "unknown"}}"#), "");
+    }
+
+    #[test]
+    fn test_min_max_clamp_helpers() {
+        let engine = TemplateEngine::new();
+        let mut ctx = MatchContext::default();
+        ctx.query_params
+            .insert("size".to_string(), "500".to_string());
+        ctx.query_params
+            .insert("name".to_string(), "abc".to_string());
+        let render = |template: &str| {
+            engine
+                .render(template, &ctx, "GET", "/", &HashMap::new(), None)
+                .unwrap()
+        };
+
+        assert_eq!(render("{{clamp query.size 1 100}}"), "100");
+        assert_eq!(render("{{clamp 0 1 100}}"), "1");
+        assert_eq!(render("{{min query.size 250}}"), "250");
+        assert_eq!(render("{{max 1.5 query.size}}"), "500");
+        assert_eq!(render("{{min 1.5 2}}"), "1.5");
+        assert_eq!(render("{{clamp query.name 1 100}}"), "");
+        assert_eq!(render("{{max query.missing 1}}"), "");
+    }
+
+    #[test]
+    fn test_arithmetic_and_string_helpers() {
+        let engine = TemplateEngine::new();
+        let mut ctx = MatchContext::default();
+        ctx.query_params.insert("page".to_string(), "3".to_string());
+        ctx.query_params
+            .insert("name".to_string(), "Grace Hopper".to_string());
+        ctx.query_all
+            .insert("tag".to_string(), vec!["a".to_string(), "b".to_string()]);
+        let render = |template: &str| {
+            engine
+                .render(template, &ctx, "GET", "/", &HashMap::new(), None)
+                .unwrap()
+        };
+
+        assert_eq!(render("{{add query.page 1}}"), "4");
+        assert_eq!(render("{{sub query.page 0.5}}"), "2.5");
+        assert_eq!(render("{{mul query.page 20}}"), "60");
+        assert_eq!(render("{{div 7 2}}"), "3.5");
+        assert_eq!(render("{{div query.page 0}}"), "");
+        assert_eq!(render("{{add query.name 1}}"), "");
+        assert_eq!(render("{{add (mul query.page 10) 5}}"), "35");
+
+        assert_eq!(
+            render("{{concat \"page-\" query.page \"-\" 2}}"),
+            "page-3-2"
+        );
+        assert_eq!(render("{{substr query.name 6}}"), "Hopper");
+        assert_eq!(render("{{substr query.name 0 5}}"), "Grace");
+        assert_eq!(render("{{substr query.name 50 5}}"), "");
+        assert_eq!(render("{{len query.name}}"), "12");
+        assert_eq!(render("{{len query_all.tag}}"), "2");
+        assert_eq!(render("{{len query.missing}}"), "");
+    }
+
     #[test]
     fn test_render_json() {
         let engine = TemplateEngine::new();
@@ -442,4 +1339,35 @@ mod tests {
         assert_eq!(result["name"], "User 123");
         assert_eq!(result["static"], "no template");
     }
+
+    #[test]
+    fn test_encoding_and_hash_helpers() {
+        let engine = TemplateEngine::new();
+        let ctx = MatchContext::default();
+        let body = br#"{"content": "hello", "token": "aGVsbG8="}"#;
+        let render = |template: &str| {
+            engine
+                .render(template, &ctx, "POST", "/", &HashMap::new(), Some(body))
+                .unwrap()
+        };
+
+        assert_eq!(render("{{base64enc json.content}}"), "aGVsbG8=");
+        assert_eq!(render("{{base64dec json.token}}"), "hello");
+        assert_eq!(render("{{base64dec \"not base64!\"}}"), "");
+        assert_eq!(
+            render("{{sha256 json.content}}"),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        // RFC 4231 test case 2
+        assert_eq!(
+            render("{{hmac_sha256 \"Jefe\" \"what do ya want for nothing?\"}}"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        let json = serde_json::json!({ "etag": "{{md5 json.content}}" });
+        let result = engine
+            .render_json(&json, &ctx, "POST", "/", &HashMap::new(), Some(body))
+            .unwrap();
+        assert_eq!(result["etag"], "5d41402abc4b2a76b9719d911017c592");
+    }
 }
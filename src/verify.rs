@@ -0,0 +1,136 @@
+//! Verification of how often stubs matched.
+//!
+//! Used by [`MockServerAgent::verify`](crate::agent::MockServerAgent::verify)
+//! to assert on match counts in tests.
+
+use crate::journal::JournalEntry;
+use std::fmt;
+
+/// Expected number of matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Times {
+    /// Exactly this many matches
+    Exactly(u32),
+    /// This many matches or more
+    AtLeast(u32),
+    /// This many matches or fewer
+    AtMost(u32),
+    /// No matches at all
+    Never,
+}
+
+impl Times {
+    /// Whether `count` matches satisfy the expectation.
+    pub fn accepts(&self, count: u32) -> bool {
+        match *self {
+            Times::Exactly(n) => count == n,
+            Times::AtLeast(n) => count >= n,
+            Times::AtMost(n) => count <= n,
+            Times::Never => count == 0,
+        }
+    }
+}
+
+impl fmt::Display for Times {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Times::Exactly(n) => write!(f, "exactly {}", times(n)),
+            Times::AtLeast(n) => write!(f, "at least {}", times(n)),
+            Times::AtMost(n) => write!(f, "at most {}", times(n)),
+            Times::Never => write!(f, "never"),
+        }
+    }
+}
+
+fn times(n: u32) -> String {
+    if n == 1 {
+        "1 time".to_string()
+    } else {
+        format!("{} times", n)
+    }
+}
+
+/// Why a verification failed.
+#[derive(Debug, Clone)]
+pub enum VerificationError {
+    /// No stub has the given ID
+    UnknownStub(String),
+    /// The stub matched a different number of times than expected
+    CountMismatch {
+        /// Stub ID
+        stub_id: String,
+        /// What was expected
+        expected: Times,
+        /// How often the stub actually matched
+        actual: u32,
+        /// Journaled requests that fit the stub's path but were answered
+        /// otherwise, most recent last
+        near_misses: Vec<JournalEntry>,
+    },
+}
+
+impl fmt::Display for VerificationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerificationError::UnknownStub(id) => write!(f, "Stub '{}' not found", id),
+            VerificationError::CountMismatch {
+                stub_id,
+                expected,
+                actual,
+                near_misses,
+            } => {
+                writeln!(
+                    f,
+                    "Stub '{}' matched an unexpected number of times",
+                    stub_id
+                )?;
+                writeln!(f, "  expected: {}", expected)?;
+                write!(f, "  actual:   {}", times(*actual))?;
+                if !near_misses.is_empty() {
+                    write!(f, "\nNear misses (path fits, answered otherwise):")?;
+                    for entry in near_misses {
+                        write!(f, "\n  {}", describe(entry))?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for VerificationError {}
+
+/// One-line summary of a journaled request and how it was answered.
+fn describe(entry: &JournalEntry) -> String {
+    let mut line = format!("{} {}", entry.method, entry.path);
+    if let Some(query) = &entry.query {
+        line.push('?');
+        line.push_str(query);
+    }
+    match &entry.stub_id {
+        Some(id) => line.push_str(&format!(" -> stub '{}'", id)),
+        None => line.push_str(" -> unmatched"),
+    }
+    if let Some(status) = entry.status {
+        line.push_str(&format!(" ({})", status));
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_times() {
+        assert!(Times::Exactly(2).accepts(2));
+        assert!(!Times::Exactly(2).accepts(3));
+        assert!(Times::AtLeast(1).accepts(5));
+        assert!(!Times::AtLeast(1).accepts(0));
+        assert!(Times::AtMost(1).accepts(0));
+        assert!(Times::Never.accepts(0));
+        assert!(!Times::Never.accepts(1));
+        assert_eq!(Times::Exactly(1).to_string(), "exactly 1 time");
+        assert_eq!(Times::AtLeast(3).to_string(), "at least 3 times");
+    }
+}
@@ -0,0 +1,136 @@
+//! Configuration file watching.
+//!
+//! Reloads the agent's stubs whenever the configuration file changes.
+
+use crate::agent::MockServerAgent;
+use crate::config::MockServerConfig;
+use anyhow::Context;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, error, info};
+
+/// How long to wait for a burst of file events to settle before reloading.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Watch `path` and reload `agent` whenever the file changes.
+///
+/// The parent directory is watched rather than the file itself, so editors
+/// that save by writing a new file and renaming it over the old one are
+/// picked up too. A configuration that fails to parse or validate is logged
+/// and the last good one keeps serving.
+///
+/// Watching stops when the returned watcher is dropped.
+pub fn watch_config(
+    path: &Path,
+    agent: Arc<MockServerAgent>,
+) -> anyhow::Result<RecommendedWatcher> {
+    let path = std::path::absolute(path)
+        .with_context(|| format!("Failed to resolve config path {:?}", path))?;
+    let dir = path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."));
+    let file_name = path.file_name().map(|n| n.to_os_string());
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) => {
+                let touches_config = event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name().map(|n| n.to_os_string()) == file_name);
+                if touches_config && !event.kind.is_access() {
+                    let _ = tx.send(());
+                }
+            }
+            Err(e) => error!(error = %e, "Config watcher error"),
+        })?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+    info!(path = ?path, "Watching configuration for changes");
+
+    tokio::spawn(async move {
+        while rx.recv().await.is_some() {
+            // Editors often emit several events per save
+            tokio::time::sleep(DEBOUNCE).await;
+            while rx.try_recv().is_ok() {}
+
+            if !path.exists() {
+                debug!(path = ?path, "Config file missing, waiting for it to reappear");
+                continue;
+            }
+
+            match MockServerConfig::from_file(&path) {
+                Ok(config) => match agent.reload(config).await {
+                    Ok(()) => info!(path = ?path, "Configuration reloaded"),
+                    Err(e) => error!(error = %e, "Failed to apply configuration, keeping previous"),
+                },
+                Err(e) => error!(error = %e, "Invalid configuration, keeping previous"),
+            }
+        }
+    });
+
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONE_STUB: &str = r#"
+stubs:
+  - id: hello
+    request:
+      path: { type: exact, value: /hello }
+"#;
+
+    const TWO_STUBS: &str = r#"
+stubs:
+  - id: hello
+    request:
+      path: { type: exact, value: /hello }
+  - id: bye
+    request:
+      path: { type: exact, value: /bye }
+"#;
+
+    /// Wait until the agent has `expected` stubs, or give up.
+    async fn wait_for_stubs(agent: &MockServerAgent, expected: usize) -> bool {
+        for _ in 0..50 {
+            if agent.stub_count() == expected {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_reload_on_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mock-server.yaml");
+        std::fs::write(&path, ONE_STUB).unwrap();
+
+        let agent = Arc::new(MockServerAgent::new(
+            MockServerConfig::from_file(&path).unwrap(),
+        ));
+        let _watcher = watch_config(&path, agent.clone()).unwrap();
+
+        // Atomic save: write elsewhere, then rename over the original
+        let tmp = dir.path().join(".mock-server.yaml.swp");
+        std::fs::write(&tmp, TWO_STUBS).unwrap();
+        std::fs::rename(&tmp, &path).unwrap();
+        assert!(wait_for_stubs(&agent, 2).await);
+
+        // A broken edit keeps the last good configuration
+        std::fs::write(&path, "stubs: [").unwrap();
+        tokio::time::sleep(DEBOUNCE * 3).await;
+        assert_eq!(agent.stub_count(), 2);
+
+        std::fs::write(&path, ONE_STUB).unwrap();
+        assert!(wait_for_stubs(&agent, 1).await);
+    }
+}